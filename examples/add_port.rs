@@ -7,10 +7,9 @@ fn main() {
         Err(ref err) => println!("Error: {}", err),
         Ok(gateway) => {
             let local_addr = match std::env::args().nth(1) {
-                Some(local_addr) => local_addr,
-                None => panic!("Expected IP address (cargo run --example add_port <your IP here>)"),
+                Some(local_addr) => local_addr.parse::<Ipv4Addr>().unwrap(),
+                None => gateway.local_addr_hint().unwrap(),
             };
-            let local_addr = local_addr.parse::<Ipv4Addr>().unwrap();
             let local_addr = SocketAddrV4::new(local_addr, 8080u16);
 
             match gateway.add_port(igd::PortMappingProtocol::TCP, 80, local_addr, 60, "add_port example") {
@@ -0,0 +1,82 @@
+//! Map a port over smoltcp rather than the sockets of std, as firmware would.
+//!
+//! Only `igd::proto` is used, which builds without std too. smoltcp runs on a tap interface here,
+//! which has to reach the LAN of the gateway, e.g. bridged to it:
+//!
+//! ```text
+//! ip tuntap add name tap0 mode tap user $USER
+//! ip link set tap0 master br0 up
+//! cargo run --example smoltcp -- tap0 192.168.1.50/24 192.168.1.1
+//! ```
+
+#[cfg(target_os = "linux")]
+mod network;
+
+#[cfg(target_os = "linux")]
+fn main() {
+    use std::env;
+    use std::net::SocketAddrV4;
+
+    use igd::proto::{self, Action};
+    use igd::PortMappingProtocol;
+    use smoltcp::wire::{IpAddress, IpCidr};
+
+    use network::Network;
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        println!("Usage: {} <tap> <address/prefix> <router>", args[0]);
+        return;
+    }
+    let cidr: IpCidr = args[2].parse().expect("address/prefix");
+    let IpAddress::Ipv4(local_ip) = cidr.address();
+    let mut network = Network::new(&args[1], cidr, args[3].parse().expect("router address"));
+
+    let location = match network.search() {
+        Some(location) => location,
+        None => {
+            println!("No gateway answered");
+            return;
+        }
+    };
+    println!("Gateway description at {:?}", location);
+
+    let response = network.exchange(location.addr, &proto::format_description_request(&location));
+    let service = proto::parse_response(&response).and_then(|response| proto::find_connection_service(response.body));
+    let service = match service {
+        Some(service) => service,
+        None => {
+            println!("The gateway has no port mapping service");
+            return;
+        }
+    };
+    let control = location.resolve(&service.control_url).expect("control url");
+
+    let action = Action::get_external_ip(&service.service_type);
+    let response = network.exchange(control.addr, &proto::format_action_request(&control, &action));
+    if let Some(ip) = proto::parse_response(&response)
+        .and_then(|response| proto::response_value(response.body, "NewExternalIPAddress"))
+    {
+        println!("External IP address: {}", ip);
+    }
+
+    let action = Action::add_port_mapping(
+        &service.service_type,
+        PortMappingProtocol::TCP,
+        8080,
+        SocketAddrV4::new(local_ip, 8080),
+        60,
+        "smoltcp example",
+    );
+    let response = network.exchange(control.addr, &proto::format_action_request(&control, &action));
+    match proto::parse_response(&response) {
+        Some(response) if response.status == 200 => println!("It worked"),
+        Some(response) => println!("There was an error! {:?}", response.error_code()),
+        None => println!("The gateway didn't answer"),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    println!("The example runs smoltcp on a tap interface, which needs Linux");
+}
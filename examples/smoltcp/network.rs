@@ -0,0 +1,127 @@
+use std::net::SocketAddrV4;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant as StdInstant};
+
+use igd::proto::{self, Location};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Medium, TunTapInterface};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpCidr, IpEndpoint, Ipv4Address};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Network {
+    device: TunTapInterface,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    next_port: u16,
+}
+
+impl Network {
+    pub fn new(tap: &str, cidr: IpCidr, router: Ipv4Address) -> Network {
+        let mut device = TunTapInterface::new(tap, Medium::Ethernet).expect("open the tap interface");
+        let config = Config::new(EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]).into());
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+        iface.update_ip_addrs(|addrs| addrs.push(cidr).unwrap());
+        iface.routes_mut().add_default_ipv4_route(router).unwrap();
+        Network {
+            device,
+            iface,
+            sockets: SocketSet::new(vec![]),
+            next_port: 49152,
+        }
+    }
+
+    fn local_port(&mut self) -> u16 {
+        self.next_port += 1;
+        self.next_port
+    }
+
+    fn poll(&mut self) {
+        let now = Instant::now();
+        self.iface.poll(now, &mut self.device, &mut self.sockets);
+        let delay = self
+            .iface
+            .poll_delay(now, &self.sockets)
+            .map_or(Duration::from_millis(50), Duration::from);
+        phy::wait(
+            self.device.as_raw_fd(),
+            Some(delay.min(Duration::from_millis(50)).into()),
+        )
+        .unwrap();
+    }
+
+    /// Search the gateway, the way `igd::search_gateway` does over UDP.
+    pub fn search(&mut self) -> Option<Location> {
+        let socket = udp::Socket::new(
+            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 4096]),
+            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 1], vec![0; 512]),
+        );
+        let handle = self.sockets.add(socket);
+        let port = self.local_port();
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        socket.bind(port).unwrap();
+        let request = proto::format_search_request(proto::INTERNET_GATEWAY_DEVICE, 3);
+        socket
+            .send_slice(request.as_bytes(), IpEndpoint::from(proto::SSDP_ADDR))
+            .unwrap();
+
+        let start = StdInstant::now();
+        let mut buf = [0; 2048];
+        let location = loop {
+            if start.elapsed() > TIMEOUT {
+                break None;
+            }
+            self.poll();
+            let socket = self.sockets.get_mut::<udp::Socket>(handle);
+            if let Ok((len, _)) = socket.recv_slice(&mut buf) {
+                if let Some(location) = proto::parse_location(&buf[..len]) {
+                    break Some(location);
+                }
+            }
+        };
+        self.sockets.remove(handle);
+        location
+    }
+
+    /// Send an HTTP request to `addr` and read the response until the gateway closes the
+    /// connection.
+    pub fn exchange(&mut self, addr: SocketAddrV4, request: &str) -> Vec<u8> {
+        let socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; 4096]),
+            tcp::SocketBuffer::new(vec![0; 4096]),
+        );
+        let handle: SocketHandle = self.sockets.add(socket);
+        let port = self.local_port();
+        self.sockets
+            .get_mut::<tcp::Socket>(handle)
+            .connect(self.iface.context(), IpEndpoint::from(addr), port)
+            .unwrap();
+
+        let start = StdInstant::now();
+        let mut sent = 0;
+        let mut response = Vec::new();
+        while start.elapsed() < TIMEOUT {
+            self.poll();
+            let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+            if sent < request.len() && socket.can_send() {
+                sent += socket.send_slice(&request.as_bytes()[sent..]).unwrap();
+            }
+            if socket.can_recv() {
+                socket
+                    .recv(|data| {
+                        response.extend_from_slice(data);
+                        (data.len(), ())
+                    })
+                    .unwrap();
+            } else if sent == request.len() && !socket.may_recv() {
+                break;
+            }
+        }
+        self.sockets.get_mut::<tcp::Socket>(handle).abort();
+        self.poll();
+        self.sockets.remove(handle);
+        response
+    }
+}
@@ -0,0 +1,231 @@
+#ifndef IGD_H
+#define IGD_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Success.
+ */
+#define IGD_OK 0
+
+/**
+ * A pointer was null, a string was not valid, or a value was out of range.
+ */
+#define IGD_ERROR_INVALID_ARGUMENT -1
+
+/**
+ * No gateway was found.
+ */
+#define IGD_ERROR_SEARCH -2
+
+/**
+ * The request failed or the gateway sent an invalid response.
+ */
+#define IGD_ERROR_REQUEST -3
+
+/**
+ * The gateway refused the action.
+ */
+#define IGD_ERROR_NOT_AUTHORIZED -4
+
+/**
+ * The external port is already mapped to another client.
+ */
+#define IGD_ERROR_PORT_IN_USE -5
+
+/**
+ * There is no such port mapping.
+ */
+#define IGD_ERROR_NO_SUCH_MAPPING -6
+
+/**
+ * The port mapping index is out of bounds, i.e. there are no more mappings.
+ */
+#define IGD_ERROR_INDEX_INVALID -7
+
+/**
+ * The gateway has no free external port left.
+ */
+#define IGD_ERROR_NO_PORTS_AVAILABLE -8
+
+/**
+ * The gateway only supports permanent leases, i.e. a lease duration of 0.
+ */
+#define IGD_ERROR_ONLY_PERMANENT_LEASES -9
+
+/**
+ * The gateway requires the external port to be the same as the internal port.
+ */
+#define IGD_ERROR_SAME_PORT_VALUES_REQUIRED -10
+
+/**
+ * The library panicked. This is a bug, please report it with the error message.
+ */
+#define IGD_ERROR_PANIC -11
+
+/**
+ * Protocol value for TCP.
+ */
+#define IGD_TCP 0
+
+/**
+ * Protocol value for UDP.
+ */
+#define IGD_UDP 1
+
+/**
+ * A gateway found by `igd_search_gateway`, freed with `igd_gateway_free`.
+ */
+typedef struct IgdGateway IgdGateway;
+
+/**
+ * A port mapping, as returned by `igd_get_port_mapping`.
+ *
+ * Strings are NUL terminated and truncated to the size of their buffer.
+ */
+typedef struct IgdPortMapping {
+  /**
+   * `IGD_TCP` or `IGD_UDP`
+   */
+  int protocol;
+  /**
+   * External port of the mapping
+   */
+  uint16_t external_port;
+  /**
+   * Internal (local) port of the mapping
+   */
+  uint16_t internal_port;
+  /**
+   * Internal client of the mapping
+   */
+  char internal_client[64];
+  /**
+   * Remote host the mapping is restricted to, empty for any
+   */
+  char remote_host[64];
+  /**
+   * Description of the mapping
+   */
+  char description[256];
+  /**
+   * Lease duration in seconds, 0 for permanent
+   */
+  uint32_t lease_duration;
+  /**
+   * 1 if the mapping is enabled, 0 otherwise
+   */
+  int enabled;
+} IgdPortMapping;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Message of the last error that occurred on the calling thread.
+ *
+ * The string stays valid until the next call on the same thread. Null if it is not available,
+ * e.g. while the thread exits.
+ */
+const char *igd_last_error_message(void);
+
+/**
+ * Search a gateway, waiting at most `timeout_ms` milliseconds.
+ *
+ * # Safety
+ * `gateway` must be a valid pointer. On success it receives a gateway to free with
+ * `igd_gateway_free`.
+ */
+int igd_search_gateway(uint32_t timeout_ms, IgdGateway **gateway);
+
+/**
+ * Free a gateway returned by `igd_search_gateway`.
+ *
+ * # Safety
+ * `gateway` must be null or a gateway returned by `igd_search_gateway` that was not freed yet.
+ */
+void igd_gateway_free(IgdGateway *gateway);
+
+/**
+ * Allow port mappings to other hosts of the LAN when `allow` is not 0. By default, `local_ip`
+ * has to be an address of this host, or the mapping fails with `IGD_ERROR_INVALID_ARGUMENT`.
+ *
+ * # Safety
+ * `gateway` must be a valid gateway.
+ */
+int igd_gateway_set_allow_third_party(IgdGateway *gateway, int allow);
+
+/**
+ * Write the external IP address of the gateway to `buf`, as a NUL terminated string.
+ *
+ * A buffer of 16 bytes is large enough for any address.
+ *
+ * # Safety
+ * `gateway` must be a valid gateway and `buf` must point to at least `len` writable bytes.
+ */
+int igd_get_external_ip(const IgdGateway *gateway, char *buf, size_t len);
+
+/**
+ * Add a port mapping from `external_port` to `local_ip:local_port`.
+ *
+ * The lease duration is in seconds, 0 is infinite. `description` may be null. `local_ip` has to
+ * be an address of this host, see `igd_gateway_set_allow_third_party`.
+ *
+ * # Safety
+ * `gateway` must be a valid gateway, `local_ip` and `description` must be null or NUL
+ * terminated strings.
+ */
+int igd_add_port(const IgdGateway *gateway,
+                 int protocol,
+                 uint16_t external_port,
+                 const char *local_ip,
+                 uint16_t local_port,
+                 uint32_t lease_duration,
+                 const char *description);
+
+/**
+ * Add a port mapping to `local_ip:local_port` with any external port.
+ *
+ * On success, the external port is written to `external_port`.
+ *
+ * # Safety
+ * Same as `igd_add_port`, and `external_port` must be a valid pointer.
+ */
+int igd_add_any_port(const IgdGateway *gateway,
+                     int protocol,
+                     const char *local_ip,
+                     uint16_t local_port,
+                     uint32_t lease_duration,
+                     const char *description,
+                     uint16_t *external_port);
+
+/**
+ * Remove the port mapping of `external_port`.
+ *
+ * # Safety
+ * `gateway` must be a valid gateway.
+ */
+int igd_remove_port(const IgdGateway *gateway, int protocol, uint16_t external_port);
+
+/**
+ * Get the port mapping at `index`.
+ *
+ * Mappings are listed by calling this with increasing indexes until it returns
+ * `IGD_ERROR_INDEX_INVALID`.
+ *
+ * # Safety
+ * `gateway` must be a valid gateway and `mapping` a valid pointer.
+ */
+int igd_get_port_mapping(const IgdGateway *gateway, uint32_t index, IgdPortMapping *mapping);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* IGD_H */
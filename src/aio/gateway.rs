@@ -1,12 +1,35 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use futures::stream::{self, StreamExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use super::search;
 use super::soap;
-use crate::errors::{self, AddAnyPortError, AddPortError, GetExternalIpError, RemovePortError, RequestError};
+use crate::backoff::Backoff;
+use crate::errors::{
+    self, AddAnyPortError, AddPortError, GetExternalIpError, RemovePortError, RequestContext, RequestError, SearchError,
+};
 
-use crate::common::{self, messages, parsing, parsing::RequestReponse};
+use crate::capabilities::Capabilities;
+use crate::common::actions::{self, BoxFuture, Control, Settings};
+use crate::common::options::DEFAULT_FETCH_TIMEOUT;
+use crate::common::parsing::{DeviceInfo, MappedPort, PortMappingRequest, RequestResult, StatusInfo, TrafficStats};
+use crate::common::{
+    self, parsing, AnyPortOptions, DiscoveryTiming, IpCache, MappingFilter, RateLimit, RateLimiter, RequestFormat,
+    RequestTimeouts, SearchOptions,
+};
+use crate::deadline::Deadline;
+use crate::description::{RootDescription, ServiceDescription, WanConnection};
+use crate::quirks::Quirks;
+#[cfg(feature = "stun")]
+use crate::stun;
 use crate::PortMappingProtocol;
 
 /// This structure represents a gateway found by the search functions.
@@ -14,6 +37,8 @@ use crate::PortMappingProtocol;
 pub struct Gateway {
     /// Socket address of the gateway
     pub addr: SocketAddrV4,
+    /// Local address the gateway was discovered from, if known
+    pub local_addr: Option<SocketAddrV4>,
     /// Root url of the device
     pub root_url: String,
     /// Control url of the device
@@ -22,25 +47,385 @@ pub struct Gateway {
     pub control_schema_url: String,
     /// Control schema for all actions
     pub control_schema: HashMap<String, Vec<String>>,
+    /// Control url of the WANCommonInterfaceConfig service, if the device has one
+    pub common_interface_control_url: Option<String>,
+    /// Control url of the WANIPv6FirewallControl service, if the device has one
+    pub firewall_control_url: Option<String>,
+    /// Information about the device
+    pub device_info: DeviceInfo,
+    /// Firmware bugs worked around when sending requests
+    pub quirks: Quirks,
+    /// Formatting of the SOAP requests
+    pub request_format: RequestFormat,
+    /// Allow port mappings to other hosts of the LAN, see `add_port`
+    pub allow_third_party: bool,
+    /// Retry with a permanent lease when the gateway only supports those, see `map_port` and
+    /// `map_any_port`
+    pub permanent_lease_fallback: bool,
+    /// How long the search of the gateway took, if it was found by searching
+    pub discovery_timing: Option<DiscoveryTiming>,
+    /// Timeouts of the requests to the gateway, see `with_timeouts` to override them for a call
+    pub timeouts: RequestTimeouts,
+    /// Delays between the retries of requests that failed with an error that may go away, see
+    /// `RequestError::is_retryable` (defaults to `None`, no retries)
+    pub retry_backoff: Option<Arc<dyn Backoff>>,
+    /// Check the arguments of the actions against the SCPD of the gateway, failing with
+    /// `RequestError::UnsupportedAction` for the actions it lacks (defaults to `true`). Without
+    /// it, the actions are sent with the arguments of the UPnP specification, saving the fetch
+    /// of the SCPD, see `SearchOptions::validate_arguments`.
+    pub validate_arguments: bool,
+    pub(crate) external_ip_cache: IpCache,
+    pub(crate) rate_limiter: RateLimiter,
+    pub(crate) description: Arc<RootDescription>,
+    pub(crate) deadline: Deadline,
 }
 
 impl Gateway {
-    async fn perform_request(&self, header: &str, body: &str, ok: &str) -> Result<RequestReponse, RequestError> {
-        let url = format!("{}", self);
-        let text = soap::send_async(&url, soap::Action::new(header), body).await?;
-        parsing::parse_response(text, ok)
+    async fn perform_request_at(&self, control_url: &str, header: &str, body: &str, ok: &str) -> RequestResult {
+        let url = format!("http://{}{}", self.addr, control_url);
+        let sent = Instant::now();
+        let result = soap::within(
+            self.deadline.limit(self.timeouts.deadline),
+            self.send_request_with_retries(&url, header, body, ok, sent),
+        )
+        .await
+        .and_then(|result| result)
+        .map_err(|e| e.with_context(RequestContext::new(self.addr, &url, header, sent.elapsed())));
+        #[cfg(feature = "prometheus")]
+        crate::metrics::record_request(self.addr, header, &result);
+        result
+    }
+
+    async fn send_request_with_retries(
+        &self,
+        url: &str,
+        header: &str,
+        body: &str,
+        ok: &str,
+        sent: Instant,
+    ) -> RequestResult {
+        let deadline = Deadline::from_timeout(sent, self.timeouts.deadline)
+            .min(self.deadline)
+            .instant();
+        let mut retry = 0;
+        loop {
+            let result = self.send_request(url, header, body, ok).await;
+            if let Err(ref e) = result {
+                if let Some(delay) = common::retry_delay(self.retry_backoff.as_deref(), retry, e, deadline) {
+                    debug!("retrying {} in {:?} after: {}", header, delay, e);
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                    continue;
+                }
+            }
+            return result;
+        }
+    }
+
+    async fn send_request(&self, url: &str, header: &str, body: &str, ok: &str) -> RequestResult {
+        let response = {
+            let _permit = self.rate_limiter.acquire_async().await;
+            soap::send_async(
+                url,
+                soap::Action::new(header),
+                body,
+                &self.request_format,
+                &self.timeouts,
+            )
+            .await?
+        };
+        let status = response.status;
+        let result = parsing::parse_response_with_status(status, response.headers, response.text, ok);
+        common::log_response(url, header, status, &result);
+
+        match self.request_format.alternate(status, &result) {
+            Some(format) => {
+                debug!("retrying {} with alternate request format", header);
+                let response = {
+                    let _permit = self.rate_limiter.acquire_async().await;
+                    soap::send_async(url, soap::Action::new(header), body, &format, &self.timeouts).await?
+                };
+                let status = response.status;
+                let result = parsing::parse_response_with_status(status, response.headers, response.text, ok);
+                common::log_response(url, header, status, &result);
+                result
+            }
+            None => result,
+        }
+    }
+
+    /// A copy of the gateway sending its requests with other timeouts, to override them for
+    /// some calls.
+    pub fn with_timeouts(&self, timeouts: RequestTimeouts) -> Gateway {
+        Gateway {
+            timeouts,
+            ..self.clone()
+        }
+    }
+
+    /// A copy of the gateway whose requests all end by `deadline`, whatever their timeouts, e.g.
+    /// to give up on a whole sequence of calls at once.
+    ///
+    /// The gateways found by `search_gateway_within` keep the deadline of the search.
+    pub fn with_deadline(&self, deadline: Deadline) -> Gateway {
+        Gateway {
+            deadline,
+            ..self.clone()
+        }
+    }
+
+    /// The deadline of the requests of the gateway, see `with_deadline`.
+    pub fn deadline(&self) -> Deadline {
+        self.deadline
+    }
+
+    /// The root device description of the gateway, as fetched by the search.
+    ///
+    /// It has both the XML document and the whole device tree, for what `device_info` and the
+    /// control urls leave out, e.g. the icons or the other services.
+    pub fn description(&self) -> &RootDescription {
+        &self.description
+    }
+
+    /// Limit the rate of the requests to the gateway, or stop limiting it with `None`.
+    ///
+    /// The limit is shared by the copies of the gateway, so it holds across tasks. A request
+    /// waiting for the limit counts toward its deadline, see `RequestTimeouts::deadline`.
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        self.rate_limiter.set_limit(limit);
+    }
+
+    /// The limit of the rate of the requests to the gateway, if any, see `set_rate_limit`.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limiter.limit()
     }
 
     /// Get the external IP address of the gateway in a tokio compatible way
     pub async fn get_external_ip(&self) -> Result<Ipv4Addr, GetExternalIpError> {
-        let result = self
-            .perform_request(
-                messages::GET_EXTERNAL_IP_HEADER,
-                &messages::format_get_external_ip_message(),
-                "GetExternalIPAddressResponse",
+        actions::get_external_ip(self).await
+    }
+
+    /// Report what the gateway supports, from what the search fetched.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(&self.description, &self.control_url, &self.control_schema, self.quirks)
+    }
+
+    /// All services of the gateway and its embedded devices, in the order of its description.
+    ///
+    /// Services the crate has no methods for, e.g. vendor-specific ones, can be reached with
+    /// `call_action`.
+    pub fn services(&self) -> Vec<ServiceDescription> {
+        self.description
+            .device
+            .all_devices()
+            .into_iter()
+            .flat_map(|device| device.services.iter().cloned())
+            .collect()
+    }
+
+    /// Send any action to a service of the gateway, see `services`, returning the output
+    /// arguments by name.
+    ///
+    /// The arguments are sent in order, their values are escaped for XML.
+    pub async fn call_action(
+        &self,
+        service: &ServiceDescription,
+        action: &str,
+        arguments: &[(&str, String)],
+    ) -> Result<HashMap<String, String>, RequestError> {
+        actions::call_action(self, service, action, arguments).await
+    }
+
+    /// The WAN connection services of the gateway, in the order of its description.
+    ///
+    /// Routers with several WAN links have one for each. The requests go to the one at
+    /// `control_url`, the default connection of the Layer3Forwarding service after a search,
+    /// see `select_wan_connection` to pick another.
+    pub fn wan_connections(&self) -> Vec<WanConnection> {
+        self.description.wan_connections()
+    }
+
+    /// Send the requests to `connection` from now on, fetching the description of its actions to
+    /// `validate_arguments`.
+    pub async fn select_wan_connection(&mut self, connection: &WanConnection) -> Result<(), SearchError> {
+        let control_schema = if self.validate_arguments {
+            search::get_control_schemas(
+                &SocketAddr::V4(self.addr),
+                &connection.service.scpd_url,
+                &self.device_info,
+                None,
+                self.deadline.limit(Some(DEFAULT_FETCH_TIMEOUT)),
             )
-            .await;
-        parsing::parse_get_external_ip_response(result)
+            .await?
+        } else {
+            HashMap::new()
+        };
+        self.control_url = connection.service.control_url.clone();
+        self.control_schema_url = connection.service.scpd_url.clone();
+        self.control_schema = control_schema;
+        self.external_ip_cache = IpCache::default();
+        Ok(())
+    }
+
+    /// Ask the Layer3Forwarding service which WAN connection is the default one.
+    ///
+    /// Returns `None` if it names none of `wan_connections`. Fails with `UnsupportedAction` if
+    /// the device has no Layer3Forwarding service.
+    pub async fn default_wan_connection(&self) -> Result<Option<WanConnection>, RequestError> {
+        actions::default_wan_connection(self).await
+    }
+
+    /// The DeviceConfig service of the gateway, of TR-064 or of UPnP, if it has one, e.g. to send
+    /// the actions `reboot` and the others here don't cover with `call_action`.
+    pub fn device_config_service(&self) -> Option<ServiceDescription> {
+        actions::device_config_service(&self.description)
+    }
+
+    /// Reboot the gateway with its DeviceConfig service, e.g. when `configuration_finished` says
+    /// the changes need it. Fails with `UnsupportedAction` if the gateway has no such service.
+    ///
+    /// The gateway is unreachable while it restarts, and its mappings may be gone afterwards.
+    pub async fn reboot(&self) -> Result<(), RequestError> {
+        actions::reboot(self).await
+    }
+
+    /// Tell the DeviceConfig service that the client starts changing the configuration, in the
+    /// session `session_id`, a UUID, so other clients are locked out until it is finished.
+    pub async fn configuration_started(&self, session_id: &str) -> Result<(), RequestError> {
+        actions::configuration_started(self, session_id).await
+    }
+
+    /// Tell the DeviceConfig service that the client finished changing the configuration,
+    /// returning whether the gateway has to be rebooted for the changes to take effect.
+    pub async fn configuration_finished(&self) -> Result<bool, RequestError> {
+        actions::configuration_finished(self).await
+    }
+
+    /// Get the data the DeviceConfig service keeps for the clients across reboots.
+    pub async fn get_persistent_data(&self) -> Result<String, RequestError> {
+        actions::get_persistent_data(self).await
+    }
+
+    /// Fetch the description of the gateway again and update the urls the requests are sent to,
+    /// e.g. when they fail with 404 after a firmware update or a reboot moved them.
+    ///
+    /// The description is fetched from `root_url`. If that fails, or it describes another device
+    /// now, the gateway is searched again by the UDN of `device_info`, which may change `addr`
+    /// and `root_url` too. The WAN connection selected before is kept if the device still has
+    /// it, and the settings of the gateway, e.g. its timeouts and quirks, are kept as they are.
+    ///
+    /// The search is sent from the address the gateway was found from, if known, with the
+    /// default options otherwise, see `refresh_with`.
+    pub async fn refresh(&mut self) -> Result<(), SearchError> {
+        let options = SearchOptions {
+            bind_addr: match self.local_addr {
+                Some(local_addr) => SocketAddr::V4(SocketAddrV4::new(*local_addr.ip(), 0)),
+                None => SearchOptions::default().bind_addr,
+            },
+            ..Default::default()
+        };
+        self.refresh_with(options).await
+    }
+
+    /// Refresh the gateway like `refresh`, searching it with `options` if needed. Their filter is
+    /// replaced by the UDN of the gateway.
+    pub async fn refresh_with(&mut self, options: SearchOptions) -> Result<(), SearchError> {
+        let selected = self
+            .wan_connections()
+            .into_iter()
+            .find(|connection| connection.service.control_url == self.control_url);
+        let mut fetched = search::refetch_gateway(self, options).await?;
+        let connection = selected.and_then(|selected| {
+            fetched.wan_connections().into_iter().find(|connection| {
+                connection.device_udn == selected.device_udn
+                    && connection.service.service_id == selected.service.service_id
+            })
+        });
+        if let Some(connection) = connection {
+            if connection.service.control_url != fetched.control_url {
+                fetched.select_wan_connection(&connection).await?;
+            }
+        }
+        if fetched.addr != self.addr {
+            self.local_addr = fetched.local_addr;
+        }
+        self.addr = fetched.addr;
+        self.root_url = fetched.root_url;
+        self.control_url = fetched.control_url;
+        self.control_schema_url = fetched.control_schema_url;
+        self.control_schema = fetched.control_schema;
+        self.common_interface_control_url = fetched.common_interface_control_url;
+        self.firewall_control_url = fetched.firewall_control_url;
+        self.device_info = fetched.device_info;
+        self.description = fetched.description;
+        self.external_ip_cache = IpCache::default();
+        Ok(())
+    }
+
+    /// Get the external IP address of the gateway, reusing the last answer for up to `ttl`.
+    ///
+    /// Some firmwares become unstable when they are polled often, this keeps the number of
+    /// requests down. The cache is shared by the clones of the gateway.
+    pub async fn external_ip_cached(&self, ttl: Duration) -> Result<Ipv4Addr, GetExternalIpError> {
+        actions::external_ip_cached(self, ttl).await
+    }
+
+    /// Forget the external IP address cached by `external_ip_cached`.
+    pub fn invalidate_external_ip(&self) {
+        self.external_ip_cache.clear();
+    }
+
+    /// Get the external IP address of the gateway, asking STUN servers when the gateway can't tell.
+    ///
+    /// The servers, given as `host:port`, are only queried when `GetExternalIPAddress` fails or
+    /// returns `0.0.0.0`. If none of them answers, the result of the gateway is returned.
+    #[cfg(feature = "stun")]
+    pub async fn get_external_ip_with_stun<S: AsRef<str>>(
+        &self,
+        stun_servers: &[S],
+    ) -> Result<Ipv4Addr, GetExternalIpError> {
+        match self.get_external_ip().await {
+            Ok(ip) if !ip.is_unspecified() => Ok(ip),
+            result => match stun::external_ip_async(stun_servers, stun::DEFAULT_TIMEOUT).await {
+                Ok(ip) => Ok(ip),
+                Err(e) => {
+                    debug!("STUN fallback failed: {}", e);
+                    result
+                }
+            },
+        }
+    }
+
+    /// Get the state of the WAN connection.
+    pub async fn get_status_info(&self) -> Result<StatusInfo, RequestError> {
+        actions::get_status_info(self).await
+    }
+
+    /// Wait until the WAN connection is up, polling its status for at most `timeout`.
+    ///
+    /// Errors are tolerated while waiting, since gateways that are still booting often fail
+    /// requests, unless they are permanent, see `RequestError::is_permanent`. If the connection
+    /// isn't up in time, the last error is returned, or a `TimedOut` error if the gateway answered.
+    pub async fn wait_for_connected(&self, timeout: Duration) -> Result<StatusInfo, RequestError> {
+        actions::wait_for_connected(self, timeout).await
+    }
+
+    /// Get the traffic counters of the WAN interface.
+    ///
+    /// Fails with `UnsupportedAction` if the device has no WANCommonInterfaceConfig service.
+    pub async fn get_traffic_stats(&self) -> Result<TrafficStats, RequestError> {
+        actions::get_traffic_stats(self).await
+    }
+
+    /// Find the local address that faces the gateway, to use as local address of port mappings.
+    ///
+    /// This is the address the gateway was discovered from. If it isn't known, a UDP socket is
+    /// connected towards the gateway to find it, no packet is sent.
+    pub fn local_addr_hint(&self) -> io::Result<Ipv4Addr> {
+        match self.local_addr {
+            Some(local_addr) => Ok(*local_addr.ip()),
+            None => common::local_ip_towards(self.addr),
+        }
     }
 
     /// Get an external socket address with our external ip and any port. This is a convenience
@@ -59,18 +444,34 @@ impl Gateway {
         lease_duration: u32,
         description: &str,
     ) -> Result<SocketAddrV4, AddAnyPortError> {
-        let description = description.to_owned();
-        let ip = self.get_external_ip().await?;
-        let port = self
-            .add_any_port(protocol, local_addr, lease_duration, &description)
-            .await?;
-        Ok(SocketAddrV4::new(ip, port))
+        self.get_any_address_with(
+            protocol,
+            local_addr,
+            lease_duration,
+            description,
+            &AnyPortOptions::default(),
+        )
+        .await
+    }
+
+    /// Like `get_any_address`, choosing the external port as configured by `options`.
+    pub async fn get_any_address_with(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+        options: &AnyPortOptions,
+    ) -> Result<SocketAddrV4, AddAnyPortError> {
+        actions::get_any_address_with(self, protocol, local_addr, lease_duration, description, options).await
     }
 
     /// Add a port mapping.with any external port.
     ///
-    /// The local_addr is the address where the traffic is sent to.
-    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    /// The local_addr is the address where the traffic is sent to. As with `add_port`, it has to
+    /// be an address of this host unless `allow_third_party` is set.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite. With
+    /// `permanent_lease_fallback`, the lease may become permanent, see `map_any_port`.
     ///
     /// # Returns
     ///
@@ -82,183 +483,335 @@ impl Gateway {
         lease_duration: u32,
         description: &str,
     ) -> Result<u16, AddAnyPortError> {
-        // This function first attempts to call AddAnyPortMapping on the IGD with a random port
-        // number. If that fails due to the method being unknown it attempts to call AddPortMapping
-        // instead with a random port number. If that fails due to ConflictInMappingEntry it retrys
-        // with another port up to a maximum of 20 times. If it fails due to SamePortValuesRequired
-        // it retrys once with the same port values.
-
-        if local_addr.port() == 0 {
-            return Err(AddAnyPortError::InternalPortZeroInvalid);
-        }
-
-        let schema = self.control_schema.get("AddAnyPortMapping");
-        if let Some(schema) = schema {
-            let external_port = common::random_port();
-
-            let description = description.to_owned();
-
-            let resp = self
-                .perform_request(
-                    messages::ADD_ANY_PORT_MAPPING_HEADER,
-                    &messages::format_add_any_port_mapping_message(
-                        schema,
-                        protocol,
-                        external_port,
-                        local_addr,
-                        lease_duration,
-                        &description,
-                    ),
-                    "AddAnyPortMappingResponse",
-                )
-                .await;
-            parsing::parse_add_any_port_mapping_response(resp)
-        } else {
-            // The router does not have the AddAnyPortMapping method.
-            // Fall back to using AddPortMapping with a random port.
-            let gateway = self.clone();
-            gateway
-                .retry_add_random_port_mapping(protocol, local_addr, lease_duration, &description)
-                .await
-        }
+        self.add_any_port_with(
+            protocol,
+            local_addr,
+            lease_duration,
+            description,
+            &AnyPortOptions::default(),
+        )
+        .await
     }
 
-    async fn retry_add_random_port_mapping(
+    /// Like `add_any_port`, choosing the external port as configured by `options`.
+    ///
+    /// If the gateway reserves a port outside of the range, that mapping is removed again and
+    /// random ports of the range are tried instead.
+    pub async fn add_any_port_with(
         &self,
         protocol: PortMappingProtocol,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
+        options: &AnyPortOptions,
     ) -> Result<u16, AddAnyPortError> {
-        for _ in 0u8..20u8 {
-            match self
-                .add_random_port_mapping(protocol, local_addr, lease_duration, &description)
-                .await
-            {
-                Ok(port) => return Ok(port),
-                Err(AddAnyPortError::NoPortsAvailable) => continue,
-                e => return e,
-            }
-        }
-        Err(AddAnyPortError::NoPortsAvailable)
+        actions::add_any_port_with(self, protocol, local_addr, lease_duration, description, options).await
     }
 
-    async fn add_random_port_mapping(
+    /// Add a port mapping with any external port, returning the mapping that the gateway created.
+    ///
+    /// This works like `add_any_port`, and tells the lease duration the mapping got. When
+    /// `permanent_lease_fallback` is set and the gateway only supports permanent leases, the
+    /// mapping is added again with a lease duration of 0, see `map_port`.
+    pub async fn map_any_port(
         &self,
         protocol: PortMappingProtocol,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
-    ) -> Result<u16, AddAnyPortError> {
-        let description = description.to_owned();
-        let gateway = self.clone();
-
-        let external_port = common::random_port();
-        let res = self
-            .add_port_mapping(protocol, external_port, local_addr, lease_duration, &description)
-            .await;
-
-        match res {
-            Ok(_) => Ok(external_port),
-            Err(err) => match parsing::convert_add_random_port_mapping_error(err) {
-                Some(err) => Err(err),
-                None => {
-                    gateway
-                        .add_same_port_mapping(protocol, local_addr, lease_duration, &description)
-                        .await
-                }
-            },
-        }
+    ) -> Result<MappedPort, AddAnyPortError> {
+        self.map_any_port_with(
+            protocol,
+            local_addr,
+            lease_duration,
+            description,
+            &AnyPortOptions::default(),
+        )
+        .await
     }
 
-    async fn add_same_port_mapping(
+    /// Like `map_any_port`, choosing the external port as configured by `options`, see
+    /// `add_any_port_with`.
+    pub async fn map_any_port_with(
         &self,
         protocol: PortMappingProtocol,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
-    ) -> Result<u16, AddAnyPortError> {
-        let res = self
-            .add_port_mapping(protocol, local_addr.port(), local_addr, lease_duration, description)
-            .await;
-        match res {
-            Ok(_) => Ok(local_addr.port()),
-            Err(err) => Err(parsing::convert_add_same_port_mapping_error(err)),
+        options: &AnyPortOptions,
+    ) -> Result<MappedPort, AddAnyPortError> {
+        actions::map_any_port_with(self, protocol, local_addr, lease_duration, description, options).await
+    }
+
+    /// Add a port mapping.
+    ///
+    /// The local_addr is the address where the traffic is sent to.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    ///
+    /// The local_addr has to be an address of this host, unless `allow_third_party` is set to
+    /// forward the port to another host of the LAN, e.g. a NAS or a camera. Many gateways refuse
+    /// such mappings anyway.
+    ///
+    /// Descriptions are cut to the length the gateway is known to accept, see `Quirks`, and
+    /// those still longer than 256 bytes are refused with `DescriptionTooLong`.
+    pub async fn add_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(), AddPortError> {
+        // The port chosen by the gateway would be lost, see `map_port`.
+        if external_port == 0 {
+            return Err(AddPortError::ExternalPortZeroInvalid);
         }
+        self.map_port(protocol, external_port, local_addr, lease_duration, description)
+            .await?;
+        Ok(())
     }
 
-    async fn add_port_mapping(
+    /// Add a port mapping, returning the mapping that the gateway created.
+    ///
+    /// This works like `add_port`, but if the gateway supports `AddAnyPortMapping`, an
+    /// `external_port` of 0 lets it choose the port, which is returned.
+    ///
+    /// When `permanent_lease_fallback` is set and the gateway only
+    /// supports permanent leases, the mapping is added again with a lease duration of 0. Such a
+    /// mapping doesn't expire, so it has to be removed explicitly once it is no longer needed.
+    pub async fn map_port(
         &self,
         protocol: PortMappingProtocol,
         external_port: u16,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
-    ) -> Result<(), RequestError> {
-        self.perform_request(
-            messages::ADD_PORT_MAPPING_HEADER,
-            &messages::format_add_port_mapping_message(
-                self.control_schema
-                    .get("AddPortMapping")
-                    .ok_or_else(|| RequestError::UnsupportedAction("AddPortMapping".to_string()))?,
-                protocol,
-                external_port,
-                local_addr,
-                lease_duration,
-                description,
-            ),
-            "AddPortMappingResponse",
+    ) -> Result<MappedPort, AddPortError> {
+        actions::map_port_enabled(
+            self,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+            true,
         )
-        .await?;
-        Ok(())
+        .await
     }
 
-    /// Add a port mapping.
+    /// Add a port mapping that forwards nothing until it is enabled with `set_port_enabled`,
+    /// e.g. to reserve the external port, returning the mapping that the gateway created.
     ///
-    /// The local_addr is the address where the traffic is sent to.
-    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
-    pub async fn add_port(
+    /// This works like `map_port` otherwise.
+    pub async fn add_port_disabled(
         &self,
         protocol: PortMappingProtocol,
         external_port: u16,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
-    ) -> Result<(), AddPortError> {
-        if external_port == 0 {
-            return Err(AddPortError::ExternalPortZeroInvalid);
-        }
-        if local_addr.port() == 0 {
-            return Err(AddPortError::InternalPortZeroInvalid);
-        }
+    ) -> Result<MappedPort, AddPortError> {
+        actions::map_port_enabled(
+            self,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+            false,
+        )
+        .await
+    }
+
+    /// Enable or disable the mapping of `external_port`, keeping its other settings.
+    ///
+    /// The mapping is read with `get_specific_port_mapping_entry` and added again with the
+    /// flag changed and the lease it has left, so it fails with `NoSuchEntryInArray` if the
+    /// gateway has no such mapping.
+    pub async fn set_port_enabled(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        enabled: bool,
+    ) -> Result<(), RequestError> {
+        actions::set_port_enabled(self, protocol, external_port, enabled).await
+    }
+
+    /// Change the address an existing mapping of `external_port` forwards to, its description and
+    /// its lease, keeping whether it is enabled, and return the mapping the gateway made.
+    ///
+    /// The mapping is overwritten in place if the gateway lets it, as most do when the internal
+    /// client stays the same. Otherwise it is removed and added again, and if adding it fails the
+    /// old mapping is restored as far as possible before the error is returned. Fails with a
+    /// `RequestError` of `NoSuchEntryInArray` if the gateway has no such mapping.
+    pub async fn update_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<MappedPort, AddPortError> {
+        actions::update_port(self, protocol, external_port, local_addr, lease_duration, description).await
+    }
+
+    /// Map a port to a bound TCP listener.
+    ///
+    /// The local address is taken from the listener. If it is bound to `0.0.0.0`, the address
+    /// of the interface facing the gateway is used. The external_port can be 0 as in `map_port`.
+    pub async fn add_port_for(
+        &self,
+        listener: &TcpListener,
+        external_port: u16,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<MappedPort, AddPortError> {
+        let local_addr = listener
+            .local_addr()
+            .and_then(|local_addr| common::mapping_addr(local_addr, self.addr))
+            .map_err(|e| AddPortError::RequestError(e.into()))?;
+        self.map_port(
+            PortMappingProtocol::TCP,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        )
+        .await
+    }
 
-        let res = self
-            .add_port_mapping(protocol, external_port, local_addr, lease_duration, description)
-            .await;
-        if let Err(err) = res {
-            return Err(parsing::convert_add_port_error(err));
+    /// Check whether the gateway forwards connections to its external address from the LAN
+    /// back into the LAN (NAT loopback, or hairpinning).
+    ///
+    /// A TCP listener of this host is mapped, then connected to at the external address and
+    /// mapped port, waiting at most `timeout`. Without hairpinning, peers on the same LAN have
+    /// to be reached at their internal address. The mapping is removed afterwards.
+    pub async fn supports_hairpinning(&self, timeout: Duration) -> Result<bool, errors::Error> {
+        let local_ip = self.local_addr_hint().map_err(RequestError::from)?;
+        let listener = TcpListener::bind((local_ip, 0)).await.map_err(RequestError::from)?;
+        let port = listener.local_addr().map_err(RequestError::from)?.port();
+        let map = |external_port| {
+            self.add_port_for(
+                &listener,
+                external_port,
+                common::HAIRPIN_CHECK_LEASE,
+                common::HAIRPIN_CHECK_DESCRIPTION,
+            )
         };
-        Ok(())
+        // The port of the listener if the gateway has it free, any other port otherwise.
+        let mapped = match map(port).await {
+            Err(AddPortError::PortInUse) => map(0).await?,
+            result => result?,
+        };
+        let result = match self.get_external_ip().await {
+            Ok(ip) => Ok(hairpins(&listener, SocketAddrV4::new(ip, mapped.external_port), timeout).await),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = self.remove_port(PortMappingProtocol::TCP, mapped.external_port).await {
+            debug!("removing the mapping of the hairpinning check failed: {}", e);
+        }
+        Ok(result?)
+    }
+
+    /// Map a port to a bound UDP socket, like `add_port_for` does for TCP listeners.
+    pub async fn add_port_for_udp(
+        &self,
+        socket: &UdpSocket,
+        external_port: u16,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<MappedPort, AddPortError> {
+        let local_addr = socket
+            .local_addr()
+            .and_then(|local_addr| common::mapping_addr(local_addr, self.addr))
+            .map_err(|e| AddPortError::RequestError(e.into()))?;
+        self.map_port(
+            PortMappingProtocol::UDP,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        )
+        .await
     }
 
     /// Remove a port mapping.
     pub async fn remove_port(&self, protocol: PortMappingProtocol, external_port: u16) -> Result<(), RemovePortError> {
-        let res = self
-            .perform_request(
-                messages::DELETE_PORT_MAPPING_HEADER,
-                &messages::format_delete_port_message(
-                    self.control_schema
-                        .get("DeletePortMapping")
-                        .ok_or_else(|| RemovePortError::RequestError(RequestError::UnsupportedAction(
-                            "DeletePortMapping".to_string(),
-                        )))?,
-                    protocol,
-                    external_port,
-                ),
-                "DeletePortMappingResponse",
-            )
-            .await;
-        parsing::parse_delete_port_mapping_response(res)
+        actions::remove_port(self, protocol, external_port).await
+    }
+
+    /// Add several port mappings, sending up to four requests at once.
+    ///
+    /// Each mapping is added as by `map_port`, the results are in the order of the requests.
+    /// Every request uses its own connection, since many gateways mishandle persistent ones.
+    pub async fn add_ports(&self, requests: &[PortMappingRequest]) -> Vec<Result<MappedPort, AddPortError>> {
+        self.map_batch(requests).await
+    }
+
+    /// Remove several port mappings, given by protocol and external port, like `add_ports`.
+    pub async fn remove_ports(&self, mappings: &[(PortMappingProtocol, u16)]) -> Vec<Result<(), RemovePortError>> {
+        self.remove_batch(mappings).await
+    }
+
+    /// Map the external ports of `external_ports` to the consecutive ports of `internal_start`,
+    /// e.g. for the passive ports of an FTP server or the ports of a game server.
+    ///
+    /// The mappings are added as by `add_ports`, returning them in order. If one fails, those
+    /// already added are removed again and its error is returned. Fails with `InvalidPortRange`
+    /// if the range is empty, starts at port 0, or its internal ports go past 65535.
+    pub async fn add_port_range(
+        &self,
+        external_ports: RangeInclusive<u16>,
+        internal_start: SocketAddrV4,
+        protocol: PortMappingProtocol,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<Vec<MappedPort>, AddPortError> {
+        self.add_port_range_with(
+            external_ports,
+            internal_start,
+            protocol,
+            lease_duration,
+            description,
+            |_, _| {},
+        )
+        .await
+    }
+
+    /// Like `add_port_range`, calling `progress` with the number of mappings added so far and
+    /// their total each time a batch of them is added, e.g. to show the progress of large ranges.
+    pub async fn add_port_range_with<F: FnMut(usize, usize)>(
+        &self,
+        external_ports: RangeInclusive<u16>,
+        internal_start: SocketAddrV4,
+        protocol: PortMappingProtocol,
+        lease_duration: u32,
+        description: &str,
+        progress: F,
+    ) -> Result<Vec<MappedPort>, AddPortError> {
+        actions::add_port_range_with(
+            self,
+            external_ports,
+            internal_start,
+            protocol,
+            lease_duration,
+            description,
+            progress,
+        )
+        .await
+    }
+
+    /// Remove the mappings of this host in `external_ports`.
+    ///
+    /// Gateways with `DeletePortMappingRange` (see `Capabilities`) remove them with one request,
+    /// leaving the mappings of other hosts. With others, every port of the range is removed as by
+    /// `remove_ports`, whoever it is mapped to, and ports without a mapping are skipped.
+    pub async fn remove_port_range(
+        &self,
+        protocol: PortMappingProtocol,
+        external_ports: RangeInclusive<u16>,
+    ) -> Result<(), RemovePortError> {
+        actions::remove_port_range(self, protocol, external_ports).await
     }
 
     /// Get one port mapping entry
@@ -270,14 +823,179 @@ impl Gateway {
         &self,
         index: u32,
     ) -> Result<parsing::PortMappingEntry, errors::GetGenericPortMappingEntryError> {
-        let result = self
-            .perform_request(
-                messages::GET_GENERIC_PORT_MAPPING_ENTRY,
-                &messages::formate_get_generic_port_mapping_entry_message(index),
-                "GetGenericPortMappingEntryResponse",
-            )
-            .await;
-        parsing::parse_get_generic_port_mapping_entry(result)
+        actions::get_generic_port_mapping_entry(self, index).await
+    }
+    /// Get the port mapping with the given protocol and external port.
+    ///
+    /// Fails with `NoSuchEntryInArray` if the gateway has no such mapping.
+    pub async fn get_specific_port_mapping_entry(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<parsing::PortMappingEntry, RequestError> {
+        actions::get_specific_port_mapping_entry(self, protocol, external_port).await
+    }
+
+    /// Get all port mappings visible to this client.
+    ///
+    /// Calls `get_generic_port_mapping_entry` with increasing indices until the gateway reports
+    /// the end of the list.
+    pub async fn get_port_mappings(
+        &self,
+    ) -> Result<Vec<parsing::PortMappingEntry>, errors::GetGenericPortMappingEntryError> {
+        actions::get_port_mappings(self).await
+    }
+
+    /// Dump all port mappings, as listed by `get_port_mappings`, in `format`, e.g. to back up
+    /// or audit the forwarding table of the gateway.
+    #[cfg(feature = "export")]
+    pub async fn export_port_mappings(
+        &self,
+        format: crate::ExportFormat,
+    ) -> Result<String, errors::GetGenericPortMappingEntryError> {
+        Ok(crate::export::export(&self.get_port_mappings().await?, format))
+    }
+
+    /// Remove the port mappings selected by `filter`, returning the removed entries.
+    ///
+    /// This is meant to clean up mappings leaked by crashed instances of an application, which
+    /// otherwise pile up and eventually cause conflicts. The filter is either a description
+    /// prefix like `"myapp:"`, or a closure that also has to skip the mappings still owned by
+    /// running instances. Mappings that fail to be removed are skipped.
+    ///
+    pub async fn cleanup_matching<F: MappingFilter>(
+        &self,
+        filter: F,
+    ) -> Result<Vec<parsing::PortMappingEntry>, errors::GetGenericPortMappingEntryError> {
+        actions::cleanup_matching(self, filter).await
+    }
+
+    /// Open a pinhole in the IPv6 firewall of the gateway, letting any remote host reach `internal`.
+    ///
+    /// The lease time is in seconds, from 1 to 86400. Returns the id of the pinhole, which is
+    /// needed to renew or delete it. Fails with `UnsupportedAction` if the device has no
+    /// WANIPv6FirewallControl service.
+    pub async fn add_pinhole(
+        &self,
+        protocol: PortMappingProtocol,
+        internal: SocketAddrV6,
+        lease_time: u32,
+    ) -> Result<u16, RequestError> {
+        actions::add_pinhole(self, protocol, internal, lease_time).await
+    }
+
+    /// Set the lease time of a pinhole, counted from now.
+    pub async fn update_pinhole(&self, unique_id: u16, lease_time: u32) -> Result<(), RequestError> {
+        actions::update_pinhole(self, unique_id, lease_time).await
+    }
+
+    /// Close a pinhole before its lease expires.
+    pub async fn delete_pinhole(&self, unique_id: u16) -> Result<(), RequestError> {
+        actions::delete_pinhole(self, unique_id).await
+    }
+
+    /// Get how long the firewall keeps outbound connections of `internal` open without traffic.
+    ///
+    /// Applications sending UDP keepalives can use this instead of guessing an interval. Fails
+    /// with `UnsupportedAction` if the device has no WANIPv6FirewallControl service.
+    pub async fn get_outbound_pinhole_timeout(
+        &self,
+        protocol: PortMappingProtocol,
+        internal: SocketAddrV6,
+    ) -> Result<Duration, RequestError> {
+        actions::get_outbound_pinhole_timeout(self, protocol, internal).await
+    }
+
+    /// Check whether a pinhole is letting traffic through.
+    ///
+    /// Some firewalls accept a pinhole but then drop its traffic, e.g. when it conflicts with
+    /// another rule. Fails with `UnsupportedAction` if the device has no WANIPv6FirewallControl
+    /// service, and the device may answer with a fault if it can't tell.
+    pub async fn check_pinhole_working(&self, unique_id: u16) -> Result<bool, RequestError> {
+        actions::check_pinhole_working(self, unique_id).await
+    }
+
+    /// Get the number of packets that went through a pinhole.
+    pub async fn get_pinhole_packets(&self, unique_id: u16) -> Result<u32, RequestError> {
+        actions::get_pinhole_packets(self, unique_id).await
+    }
+}
+
+async fn hairpins(listener: &TcpListener, external_addr: SocketAddrV4, timeout: Duration) -> bool {
+    let check = async {
+        let _stream = match TcpStream::connect(external_addr).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("connecting to {} from the LAN failed: {}", external_addr, e);
+                return false;
+            }
+        };
+        listener.accept().await.is_ok()
+    };
+    tokio::time::timeout(timeout, check).await.unwrap_or(false)
+}
+
+impl Control for Gateway {
+    fn settings(&self) -> Settings<'_> {
+        Settings {
+            addr: self.addr,
+            control_url: &self.control_url,
+            control_schema: &self.control_schema,
+            common_interface_control_url: self.common_interface_control_url.as_deref(),
+            firewall_control_url: self.firewall_control_url.as_deref(),
+            description: &self.description,
+            quirks: self.quirks,
+            allow_third_party: self.allow_third_party,
+            permanent_lease_fallback: self.permanent_lease_fallback,
+            validate_arguments: self.validate_arguments,
+            external_ip_cache: &self.external_ip_cache,
+        }
+    }
+
+    fn request_at<'a>(
+        &'a self,
+        control_url: &'a str,
+        header: &'a str,
+        body: &'a str,
+        ok: &'a str,
+    ) -> BoxFuture<'a, RequestResult> {
+        Box::pin(self.perform_request_at(control_url, header, body, ok))
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn map_batch<'a>(
+        &'a self,
+        requests: &'a [PortMappingRequest],
+    ) -> BoxFuture<'a, Vec<Result<MappedPort, AddPortError>>> {
+        Box::pin(
+            stream::iter(requests)
+                .map(move |request| {
+                    self.map_port(
+                        request.protocol,
+                        request.external_port,
+                        request.local_addr,
+                        request.lease_duration,
+                        &request.description,
+                    )
+                })
+                .buffered(common::BATCH_CONCURRENCY)
+                .collect(),
+        )
+    }
+
+    fn remove_batch<'a>(
+        &'a self,
+        mappings: &'a [(PortMappingProtocol, u16)],
+    ) -> BoxFuture<'a, Vec<Result<(), RemovePortError>>> {
+        Box::pin(
+            stream::iter(mappings)
+                .map(move |&(protocol, external_port)| self.remove_port(protocol, external_port))
+                .buffered(common::BATCH_CONCURRENCY)
+                .collect(),
+        )
     }
 }
 
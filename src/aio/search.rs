@@ -1,36 +1,132 @@
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::prelude::*;
-use hyper::Client;
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING};
+use hyper::{Body, Client, Request};
 use tokio::net::UdpSocket;
 use tokio::time::timeout;
 
 use crate::aio::Gateway;
-use crate::common::{messages, parsing, SearchOptions};
+use crate::common::{
+    self, cache, parsing, parsing::Description, parsing::DeviceInfo, DiscoveryTiming, GatewayFilter, SearchOptions,
+};
+use crate::deadline::Deadline;
+use crate::description::{self, RootDescription};
 use crate::errors::SearchError;
+use crate::proto::Headers;
+use crate::quirks;
+use crate::search::{self, Retransmission, SearchAttempts};
 
 const MAX_RESPONSE_SIZE: usize = 1500;
 
 /// Search for a gateway with the provided options
 pub async fn search_gateway(options: SearchOptions) -> Result<Gateway, SearchError> {
-    // Create socket for future calls
-    let mut socket = UdpSocket::bind(&options.bind_addr).await?;
+    search_gateway_until(options, Deadline::never()).await
+}
 
-    send_search_request(&mut socket, options.broadcast_address).await?;
+/// Search gateway like `search_gateway`, giving up at `deadline` whatever the timeouts.
+///
+/// The wait for a response and the fetches of the descriptions end by the deadline, and so do
+/// the requests of the gateway found, which keeps it, see `Gateway::with_deadline`.
+pub async fn search_gateway_within(options: SearchOptions, deadline: Deadline) -> Result<Gateway, SearchError> {
+    if deadline.is_expired() {
+        return Err(SearchError::NoResponse(Duration::ZERO));
+    }
+    let options = SearchOptions {
+        timeout: deadline.limit(options.timeout),
+        ..options
+    };
+    match deadline.remaining() {
+        Some(remaining) => match timeout(remaining, search_gateway_until(options, deadline)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "the deadline passed").into()),
+        },
+        None => search_gateway_until(options, deadline).await,
+    }
+}
 
-    let search_response = receive_search_response(&mut socket);
+async fn search_gateway_until(options: SearchOptions, deadline: Deadline) -> Result<Gateway, SearchError> {
+    // Create socket for future calls
+    let socket = common::bind_search_socket(&options)?;
+    socket.set_nonblocking(true)?;
+    let mut socket = UdpSocket::from_std(socket)?;
 
-    // Receive search response, optionally with a timeout
-    let (response_body, from) = match options.timeout {
-        Some(t) => timeout(t, search_response).await?,
-        None => search_response.await,
-    }?;
+    send_search_request(&mut socket, &options.request.to_string(), options.broadcast_address).await?;
+    let sent = Instant::now();
 
-    let (addr, root_url) = handle_broadcast_resp(&from, &response_body)?;
+    // Responses of other devices to `ssdp:all`, and gateways not selected by the filter, are
+    // skipped.
+    let mut attempts = SearchAttempts::default();
+    let mut retransmission = Retransmission::new(&options, sent, None);
+    let search_response = async {
+        loop {
+            let (body, from) = match retransmission.next() {
+                Some(next) => match timeout(
+                    next.saturating_duration_since(Instant::now()),
+                    receive_search_response(&mut socket),
+                )
+                .await
+                {
+                    Ok(received) => received?,
+                    Err(_) => {
+                        debug!("sending the search request to {} again", options.broadcast_address);
+                        send_search_request(&mut socket, &options.request.to_string(), options.broadcast_address)
+                            .await?;
+                        retransmission.schedule(Instant::now());
+                        continue;
+                    }
+                },
+                None => receive_search_response(&mut socket).await?,
+            };
+            let response_time = sent.elapsed();
+            if !options.request.accepts_response(&Headers::parse(&body)) {
+                continue;
+            }
+            let (addr, root_url, server, max_age) = match handle_broadcast_resp(&from, &body) {
+                Ok(response) => response,
+                Err(e) => {
+                    attempts.invalid(e);
+                    continue;
+                }
+            };
+            let url = format!("http://{}{}", addr, root_url);
+            if let Err(e) = search::check_location(options.url_policy, from.ip(), addr, &root_url) {
+                attempts.failed(url, e);
+                continue;
+            }
+            let limit = deadline.limit(options.fetch_timeout);
+            let (mut description, root_description) = match get_description(&addr, &root_url, max_age, limit).await {
+                Ok(description) => description,
+                Err(e) => {
+                    attempts.failed(url, e);
+                    continue;
+                }
+            };
+            description.device_info.server = server;
+            description.device_info.presentation_url = description
+                .device_info
+                .presentation_url
+                .map(|presentation_url| parsing::resolve_url(&url, &presentation_url));
+            if options.filter.matches(&description.device_info) {
+                return Ok::<_, SearchError>((addr, root_url, description, root_description, max_age, response_time));
+            }
+            debug!("skipping {}, not selected by the filter", addr);
+            attempts.failed(url, SearchError::NotSelected);
+        }
+    };
 
-    let (control_schema_url, control_url) = get_control_urls(&addr, &root_url).await?;
-    let control_schema = get_control_schemas(&addr, &control_schema_url).await?;
+    // Receive search response, optionally with a timeout
+    let (addr, root_url, description, root_description, max_age, response_time) = match options.timeout {
+        Some(t) => match timeout(t, search_response).await {
+            Ok(result) => result?,
+            Err(_) => return Err(attempts.into_error(t)),
+        },
+        None => search_response.await?,
+    };
 
     let addr = match addr {
         SocketAddr::V4(a) => Ok(a),
@@ -40,24 +136,159 @@ pub async fn search_gateway(options: SearchOptions) -> Result<Gateway, SearchErr
         }
     }?;
 
-    Ok(Gateway {
+    let mut gateway = get_gateway(
+        addr,
+        root_url,
+        description,
+        root_description,
+        max_age,
+        options.validate_arguments,
+        options.fetch_timeout,
+        deadline,
+    )
+    .await?;
+    gateway.local_addr = socket
+        .local_addr()
+        .and_then(|local_addr| common::mapping_addr(local_addr, addr))
+        .ok();
+    gateway.discovery_timing = Some(DiscoveryTiming {
+        response: response_time,
+        description: sent.elapsed(),
+    });
+    Ok(gateway)
+}
+
+/// Make the gateway of the fetched `description`, fetching its SCPD to `validate_arguments` for
+/// `fetch_timeout` at most.
+#[allow(clippy::too_many_arguments)]
+async fn get_gateway(
+    addr: SocketAddrV4,
+    root_url: String,
+    description: Description,
+    root_description: RootDescription,
+    max_age: Option<Duration>,
+    validate_arguments: bool,
+    fetch_timeout: Option<Duration>,
+    deadline: Deadline,
+) -> Result<Gateway, SearchError> {
+    let control_schema = if validate_arguments {
+        get_control_schemas(
+            &SocketAddr::V4(addr),
+            &description.control_schema_url,
+            &description.device_info,
+            max_age,
+            deadline.limit(fetch_timeout),
+        )
+        .await?
+    } else {
+        HashMap::new()
+    };
+
+    let quirks = quirks::lookup(&description.device_info);
+
+    let mut gateway = Gateway {
         addr,
+        local_addr: None,
         root_url,
-        control_url,
-        control_schema_url,
+        control_url: description.control_url,
+        control_schema_url: description.control_schema_url,
         control_schema,
-    })
+        common_interface_control_url: description.common_interface_control_url,
+        firewall_control_url: description.firewall_control_url,
+        device_info: description.device_info,
+        request_format: quirks.request_format(),
+        allow_third_party: false,
+        permanent_lease_fallback: false,
+        quirks,
+        discovery_timing: None,
+        timeouts: Default::default(),
+        external_ip_cache: Default::default(),
+        rate_limiter: Default::default(),
+        retry_backoff: None,
+        description: Arc::new(root_description),
+        deadline,
+        validate_arguments,
+    };
+    select_default_connection(&mut gateway).await;
+    Ok(gateway)
+}
+
+/// Fetch the descriptions of `gateway` again, bypassing the cache, from its root url, or else
+/// from the device with its UDN found by searching again with `options`, e.g. after a reboot
+/// moved it.
+pub(crate) async fn refetch_gateway(gateway: &Gateway, options: SearchOptions) -> Result<Gateway, SearchError> {
+    let url = format!("http://{}{}", gateway.addr, gateway.root_url);
+    cache::remove(&url);
+    let udn = &gateway.device_info.udn;
+    let fetched = async {
+        let limit = gateway.deadline.limit(options.fetch_timeout);
+        let (mut description, root_description) =
+            get_description(&SocketAddr::V4(gateway.addr), &gateway.root_url, None, limit).await?;
+        description.device_info.server = gateway.device_info.server.clone();
+        description.device_info.presentation_url = description
+            .device_info
+            .presentation_url
+            .map(|presentation_url| parsing::resolve_url(&url, &presentation_url));
+        get_gateway(
+            gateway.addr,
+            gateway.root_url.clone(),
+            description,
+            root_description,
+            None,
+            gateway.validate_arguments,
+            options.fetch_timeout,
+            gateway.deadline,
+        )
+        .await
+    };
+    let e = match fetched.await {
+        Ok(fetched) if udn.is_empty() || fetched.device_info.udn.eq_ignore_ascii_case(udn) => return Ok(fetched),
+        Ok(_) => {
+            debug!("{} describes another device now, searching {}", url, udn);
+            SearchError::NotSelected
+        }
+        Err(e) => {
+            debug!("fetching {} failed, searching {}: {}", url, udn, e);
+            e
+        }
+    };
+    if udn.is_empty() {
+        return Err(e);
+    }
+    let options = SearchOptions {
+        filter: GatewayFilter::Udn(udn.clone()),
+        validate_arguments: gateway.validate_arguments,
+        ..options
+    };
+    search_gateway_within(options, gateway.deadline).await
+}
+
+/// Switch a gateway with several WAN connections to the default one of its Layer3Forwarding
+/// service. It keeps the first one if that fails.
+async fn select_default_connection(gateway: &mut Gateway) {
+    if gateway.wan_connections().len() < 2 {
+        return;
+    }
+    match gateway.default_wan_connection().await {
+        Ok(Some(connection)) if connection.service.control_url != gateway.control_url.trim() => {
+            if let Err(e) = gateway.select_wan_connection(&connection).await {
+                debug!("selecting the default WAN connection of {} failed: {}", gateway, e);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => debug!("getting the default WAN connection of {} failed: {}", gateway, e),
+    }
 }
 
 // Create a new search
-async fn send_search_request(socket: &mut UdpSocket, addr: SocketAddr) -> Result<(), SearchError> {
+async fn send_search_request(socket: &mut UdpSocket, request: &str, addr: SocketAddr) -> Result<(), SearchError> {
     debug!(
         "sending broadcast request to: {} on interface: {:?}",
         addr,
         socket.local_addr()
     );
     socket
-        .send_to(messages::SEARCH_REQUEST.as_bytes(), &addr)
+        .send_to(request.as_bytes(), &addr)
         .map_ok(|_| ())
         .map_err(SearchError::from)
         .await
@@ -71,51 +302,160 @@ async fn receive_search_response(socket: &mut UdpSocket) -> Result<(Vec<u8>, Soc
 }
 
 // Handle a UDP response message
-fn handle_broadcast_resp(from: &SocketAddr, data: &[u8]) -> Result<(SocketAddr, String), SearchError> {
+fn handle_broadcast_resp(
+    from: &SocketAddr,
+    data: &[u8],
+) -> Result<(SocketAddr, String, String, Option<Duration>), SearchError> {
     debug!("handling broadcast response from: {}", from);
 
-    // Convert response to text
-    let text = std::str::from_utf8(&data).map_err(SearchError::from)?;
-
     // Parse socket address and path
-    let (addr, root_url) = parsing::parse_search_result(text)?;
+    let headers = Headers::parse(data);
+    let (addr, root_url) = parsing::parse_search_result(&headers).map_err(|e| e.with_data(data))?;
+    let server = headers.get("server").unwrap_or_default();
+    let max_age = headers.get("cache-control").and_then(parsing::parse_max_age);
 
-    Ok((SocketAddr::V4(addr), root_url))
+    Ok((SocketAddr::V4(addr), root_url, server.to_string(), max_age))
 }
 
-async fn get_control_urls(addr: &SocketAddr, path: &str) -> Result<(String, String), SearchError> {
-    let uri = match format!("http://{}{}", addr, path).parse() {
-        Ok(uri) => uri,
-        Err(err) => return Err(SearchError::from(err)),
+/// Get the description at `path`, from the cache if it is there, fetching it for `limit` at most
+/// otherwise.
+async fn get_description(
+    addr: &SocketAddr,
+    path: &str,
+    max_age: Option<Duration>,
+    limit: Option<Duration>,
+) -> Result<(Description, RootDescription), SearchError> {
+    let url = format!("http://{}{}", addr, path);
+    let parse = |document: &[u8]| -> Result<_, SearchError> {
+        Ok((parsing::parse_description(document)?, description::parse(document)?))
     };
-
-    debug!("requesting control url from: {}", uri);
-    let client = Client::new();
-    let resp = hyper::body::to_bytes(client.get(uri).await?.into_body())
-        .map_err(SearchError::from)
-        .await?;
+    if let Some(description) = cache::get(&url).and_then(|document| parse(&document).ok()) {
+        debug!("using the cached {}", url);
+        return Ok(description);
+    }
+    debug!("requesting control url from: {}", url);
+    let resp = fetch(&url, limit).await.map_err(|e| e.at_url(&url))?;
 
     debug!("handling control response from: {}", addr);
-    let c = std::io::Cursor::new(&resp);
-    parsing::parse_control_urls(c)
+    let description = parse(&resp).map_err(|e| e.with_data(&resp))?;
+    if let Some(max_age) = max_age {
+        cache::insert(url, resp.to_vec(), max_age);
+    }
+    Ok(description)
+}
+
+/// Fetch the document at `url`, for `limit` at most.
+async fn fetch(url: &str, limit: Option<Duration>) -> Result<hyper::body::Bytes, SearchError> {
+    let uri = url.parse::<hyper::Uri>()?;
+    let client = Client::new();
+    let mut request = Request::new(Body::empty());
+    *request.uri_mut() = uri;
+    request
+        .headers_mut()
+        .insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    let response = async {
+        let response = client.request(request).await?;
+        common::check_content_encoding(
+            response
+                .headers()
+                .get(CONTENT_ENCODING)
+                .and_then(|value| value.to_str().ok()),
+        )?;
+        Ok::<_, SearchError>(hyper::body::to_bytes(response.into_body()).await?)
+    };
+    match limit {
+        Some(limit) => match timeout(limit, response).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "fetching the document timed out").into()),
+        },
+        None => response.await,
+    }
 }
 
-async fn get_control_schemas(
+/// Get the SCPD at `control_schema_url` of the device described by `device_info`, parsed, from
+/// the SCPDs of the same model if it is there, fetching it for `limit` at most otherwise.
+pub(crate) async fn get_control_schemas(
     addr: &SocketAddr,
     control_schema_url: &str,
+    device_info: &DeviceInfo,
+    max_age: Option<Duration>,
+    limit: Option<Duration>,
 ) -> Result<HashMap<String, Vec<String>>, SearchError> {
-    let uri = match format!("http://{}{}", addr, control_schema_url).parse() {
-        Ok(uri) => uri,
-        Err(err) => return Err(SearchError::from(err)),
-    };
-
-    debug!("requesting control schema from: {}", uri);
-    let client = Client::new();
-    let resp = hyper::body::to_bytes(client.get(uri).await?.into_body())
-        .map_err(SearchError::from)
-        .await?;
+    let key = cache::schema_key(device_info, *addr, control_schema_url);
+    if let Some(schemas) = cache::get_schemas(&key) {
+        debug!("using the cached SCPD of {}", key);
+        return Ok((*schemas).clone());
+    }
+    let url = format!("http://{}{}", addr, control_schema_url);
+    if let Some(schemas) = cache::get(&url).and_then(|document| parsing::parse_schemas(&document[..]).ok()) {
+        debug!("using the cached {}", url);
+        return Ok(schemas);
+    }
+    debug!("requesting control schema from: {}", url);
+    let resp = fetch(&url, limit).await.map_err(|e| e.at_url(&url))?;
 
     debug!("handling schema response from: {}", addr);
     let c = std::io::Cursor::new(&resp);
-    parsing::parse_schemas(c)
+    let schemas = parsing::parse_schemas(c).map_err(|e| e.with_data(&resp))?;
+    if let Some(max_age) = max_age {
+        cache::insert(url, resp.to_vec(), max_age);
+    }
+    cache::insert_schemas(key, schemas.clone());
+    Ok(schemas)
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_search_fetch_timeout() {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    // Accepts the connections, and never answers.
+    let silent = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    // Answers each search with the silent device first, then with the mock.
+    let responder = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    responder.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let locations = [silent.local_addr().unwrap(), SocketAddr::V4(mock.http_addr())];
+    std::thread::spawn(move || {
+        let mut buf = [0; 1500];
+        while let Ok((_, from)) = responder.recv_from(&mut buf) {
+            for location in locations {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                     LOCATION: http://{}/rootDesc.xml\r\n\
+                     \r\n",
+                    location
+                );
+                let _ = responder.send_to(response.as_bytes(), from);
+            }
+        }
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let options = SearchOptions {
+        broadcast_address,
+        timeout: Some(Duration::from_secs(3)),
+        fetch_timeout: Some(Duration::from_millis(200)),
+        ..mock.search_options()
+    };
+    let started = Instant::now();
+    let gateway = runtime.block_on(search_gateway(options)).unwrap();
+    assert_eq!(gateway.addr, mock.http_addr());
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // Without a fetch timeout, the silent device holds the search until its end.
+    let options = SearchOptions {
+        broadcast_address,
+        timeout: Some(Duration::from_millis(500)),
+        fetch_timeout: None,
+        ..mock.search_options()
+    };
+    let started = Instant::now();
+    assert!(runtime.block_on(search_gateway(options)).is_err());
+    assert!(started.elapsed() < Duration::from_secs(2));
 }
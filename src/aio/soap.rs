@@ -1,9 +1,12 @@
-use hyper::{
-    header::{CONTENT_LENGTH, CONTENT_TYPE},
-    Body, Client, Request,
-};
+use std::future::Future;
+use std::time::Duration;
 
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::common::{RequestFormat, RequestTimeouts};
 use crate::errors::RequestError;
+use crate::soap::{self, Framing, Response, MAX_BODY_SIZE, MAX_HEAD_SIZE, MAX_LINE_SIZE};
 
 #[derive(Clone, Debug)]
 pub struct Action(String);
@@ -14,21 +17,189 @@ impl Action {
     }
 }
 
-const HEADER_NAME: &str = "SOAPAction";
+/// Send a SOAP request.
+///
+/// Like the blocking API, the request is written by hand so that `format.header_case` is
+/// honored, which hyper can't do: it sends header names in lowercase or title case only.
+pub async fn send_async(
+    url: &str,
+    action: Action,
+    body: &str,
+    format: &RequestFormat,
+    timeouts: &RequestTimeouts,
+) -> Result<Response, RequestError> {
+    let (host, port, request) = soap::request(url, &action.0, body, format)?;
+    let mut stream = within(timeouts.connect, TcpStream::connect((host.as_str(), port))).await??;
+    within(timeouts.read, stream.write_all(request.as_bytes())).await??;
+    let mut reader = BufReader::new(stream);
+
+    // The head is received whole, then parsed once.
+    let mut head = Vec::new();
+    loop {
+        if head.len() == MAX_HEAD_SIZE {
+            return Err(RequestError::InvalidResponse(format!(
+                "Response head larger than {} bytes",
+                MAX_HEAD_SIZE
+            )));
+        }
+        let max = MAX_HEAD_SIZE - head.len();
+        let start = head.len();
+        let read = within(timeouts.read, read_line(&mut reader, &mut head, max)).await??;
+        if read == 0 || head[start..].iter().all(|&byte| byte == b'\r' || byte == b'\n') {
+            break;
+        }
+    }
+    let head = soap::read_head(&head[..])?;
+
+    let mut body = Vec::new();
+    match head.framing {
+        Framing::Chunked => {
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                within(timeouts.read, read_line(&mut reader, &mut line, MAX_LINE_SIZE)).await??;
+                let start = body.len();
+                match soap::chunk_end(&String::from_utf8_lossy(&line), start)? {
+                    Some(end) => body.resize(end, 0),
+                    None => break,
+                }
+                within(timeouts.read, reader.read_exact(&mut body[start..])).await??;
+                line.clear();
+                within(timeouts.read, read_line(&mut reader, &mut line, MAX_LINE_SIZE)).await??;
+            }
+        }
+        Framing::Length(length) => {
+            body.resize(length, 0);
+            within(timeouts.read, reader.read_exact(&mut body)).await??;
+        }
+        Framing::Close => {
+            let mut limited = reader.take(MAX_BODY_SIZE as u64 + 1);
+            within(timeouts.read, limited.read_to_end(&mut body)).await??;
+            if body.len() > MAX_BODY_SIZE {
+                return Err(soap::body_too_large());
+            }
+        }
+    }
+    let text = String::from_utf8(body)?;
+    Ok(Response {
+        status: head.status,
+        headers: head.headers,
+        text,
+    })
+}
+
+/// Append a line of `max` bytes at most to `buf`, returning its length, 0 at the end.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    max: usize,
+) -> Result<usize, RequestError> {
+    let read = reader.take(max as u64).read_until(b'\n', buf).await?;
+    if read == max && buf.last() != Some(&b'\n') {
+        return Err(RequestError::InvalidResponse(format!("Line longer than {} bytes", max)));
+    }
+    Ok(read)
+}
+
+/// Run `future`, failing with a `TimedOut` error if it takes longer than `limit`.
+pub async fn within<F: Future>(limit: Option<Duration>, future: F) -> Result<F::Output, RequestError> {
+    match limit {
+        Some(limit) => Ok(tokio::time::timeout(limit, future).await?),
+        None => Ok(future.await),
+    }
+}
 
-pub async fn send_async(url: &str, action: Action, body: &str) -> Result<String, RequestError> {
-    let client = Client::new();
+#[test]
+fn test_send_async_header_case() {
+    use crate::common::HeaderCase;
+    use std::io::{Read, Write};
 
-    let req = Request::builder()
-        .uri(url)
-        .method("POST")
-        .header(HEADER_NAME, action.0)
-        .header(CONTENT_TYPE, "text/xml")
-        .header(CONTENT_LENGTH, body.len() as u64)
-        .body(Body::from(body.to_string()))?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/control", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"<body/>") {
+            let read = stream.read(&mut buf).unwrap();
+            assert_ne!(read, 0);
+            request.extend_from_slice(&buf[..read]);
+        }
+        // A chunked response, in two parts.
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n<ok/\r\n")
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        stream.write_all(b"1\r\n>\r\n0\r\n\r\n").unwrap();
+        // The connection stays open, the response is complete without it being closed.
+        (String::from_utf8(request).unwrap(), stream)
+    });
 
-    let resp = client.request(req).await?;
-    let body = hyper::body::to_bytes(resp.into_body()).await?;
-    let string = String::from_utf8(body.to_vec())?;
-    Ok(string)
+    let format = RequestFormat {
+        header_case: HeaderCase::Upper,
+        ..Default::default()
+    };
+    let timeouts = RequestTimeouts {
+        read: Some(Duration::from_secs(5)),
+        ..Default::default()
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let response = runtime
+        .block_on(send_async(
+            &url,
+            Action::new("urn:test#Act"),
+            "<body/>",
+            &format,
+            &timeouts,
+        ))
+        .unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text, "<ok/>");
+
+    let (request, _stream) = server.join().unwrap();
+    assert!(request.starts_with("POST /control HTTP/1.1\r\n"), "{}", request);
+    assert!(request.contains("\r\nSOAPACTION: \"urn:test#Act\"\r\n"), "{}", request);
+    assert!(request.contains("\r\nCONTENT-TYPE: text/xml\r\n"), "{}", request);
+    assert!(request.contains("\r\nContent-Length: 7\r\n"), "{}", request);
+}
+
+#[test]
+fn test_send_async_too_large() {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/control", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        for response in [
+            "HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n",
+        ] {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    for _ in 0..2 {
+        let result = runtime.block_on(send_async(
+            &url,
+            Action::new("urn:test#Act"),
+            "",
+            &RequestFormat::default(),
+            &RequestTimeouts::default(),
+        ));
+        match result {
+            Err(RequestError::InvalidResponse(ref e)) => assert!(e.contains("larger than"), "{}", e),
+            other => panic!("unexpected {:?}", other.map(|response| response.status)),
+        }
+    }
+    server.join().unwrap();
 }
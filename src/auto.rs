@@ -0,0 +1,823 @@
+//! Making a port reachable with whichever protocol the gateway speaks.
+//!
+//! `open_port` tries UPnP IGD, PCP and NAT-PMP in turn, in a configurable order, and returns a
+//! handle that renews the mapping with the protocol that made it. `PortMapper` is the common
+//! async interface of the protocols, for applications that pick one themselves, and
+//! `BlockingPortMapper` the blocking one.
+
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub use crate::common::actions::BoxFuture;
+use crate::common::parsing::MappedPort;
+use crate::errors::{Error, RequestError};
+use crate::natpmp::{self, NatPmpClient, NatPmpError};
+use crate::pcp::{self, PcpClient, PcpError};
+use crate::{search_gateway, FractionScheduler, Gateway, PortMappingProtocol, RenewalScheduler, SearchOptions};
+
+/// A protocol to map ports with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    /// UPnP Internet Gateway Device
+    Upnp,
+    /// Port Control Protocol (RFC 6887)
+    Pcp,
+    /// NAT Port Mapping Protocol (RFC 6886)
+    NatPmp,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Method::Upnp => write!(f, "UPnP"),
+            Method::Pcp => write!(f, "PCP"),
+            Method::NatPmp => write!(f, "NAT-PMP"),
+        }
+    }
+}
+
+/// Configuration of `open_port`.
+///
+/// # Example
+/// ```
+/// # use igd::auto::{AutoOptions, Method};
+/// let opts = AutoOptions {
+///     methods: vec![Method::Pcp, Method::Upnp],
+///     ..Default::default()
+/// };
+/// ```
+pub struct AutoOptions {
+    /// Protocols to try, in order (defaults to UPnP, PCP, then NAT-PMP)
+    pub methods: Vec<Method>,
+    /// Options of the UPnP search
+    pub search: SearchOptions,
+    /// Address of the router for PCP and NAT-PMP (defaults to the address of the UPnP gateway,
+    /// if the search found one)
+    pub router: Option<Ipv4Addr>,
+    /// Description of UPnP mappings (defaults to `"igd"`)
+    pub description: String,
+    /// When the mapping is renewed and a renewal that failed is retried, see
+    /// `AutoMapping::poll` (defaults to a `FractionScheduler` renewing halfway through the lease)
+    pub renewal: Arc<dyn RenewalScheduler>,
+}
+
+impl Default for AutoOptions {
+    fn default() -> Self {
+        Self {
+            methods: vec![Method::Upnp, Method::Pcp, Method::NatPmp],
+            search: Default::default(),
+            router: None,
+            description: "igd".to_string(),
+            renewal: Arc::new(FractionScheduler::default()),
+        }
+    }
+}
+
+/// Errors of `open_port` and `AutoMapping`.
+#[derive(Debug)]
+pub enum AutoError {
+    /// UPnP failed.
+    Upnp(Error),
+    /// PCP failed.
+    Pcp(PcpError),
+    /// NAT-PMP failed.
+    NatPmp(NatPmpError),
+    /// PCP or NAT-PMP can't be tried without the address of the router.
+    NoRouter,
+    /// No protocol was given to try.
+    NoMethod,
+}
+
+impl fmt::Display for AutoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AutoError::Upnp(ref e) => write!(f, "UPnP failed: {}", e),
+            AutoError::Pcp(ref e) => write!(f, "PCP failed: {}", e),
+            AutoError::NatPmp(ref e) => write!(f, "NAT-PMP failed: {}", e),
+            AutoError::NoRouter => write!(f, "The address of the router is unknown"),
+            AutoError::NoMethod => write!(f, "No port mapping protocol to try"),
+        }
+    }
+}
+
+impl error::Error for AutoError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            AutoError::Upnp(ref e) => Some(e),
+            AutoError::Pcp(ref e) => Some(e),
+            AutoError::NatPmp(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for AutoError {
+    fn from(err: Error) -> AutoError {
+        AutoError::Upnp(err)
+    }
+}
+
+impl From<PcpError> for AutoError {
+    fn from(err: PcpError) -> AutoError {
+        AutoError::Pcp(err)
+    }
+}
+
+impl From<NatPmpError> for AutoError {
+    fn from(err: NatPmpError) -> AutoError {
+        AutoError::NatPmp(err)
+    }
+}
+
+#[derive(Debug)]
+enum Handle {
+    Upnp {
+        gateway: Box<Gateway>,
+        local_addr: SocketAddrV4,
+        mapped: MappedPort,
+    },
+    Pcp(PcpClient),
+    NatPmp(NatPmpClient),
+}
+
+/// A port made reachable by `open_port`.
+///
+/// Call `poll` in a loop to keep the mapping alive, it renews the mapping with the protocol that
+/// made it. Nothing is removed when the handle is dropped.
+#[derive(Debug)]
+pub struct AutoMapping {
+    handle: Handle,
+    protocol: PortMappingProtocol,
+    port: u16,
+    lifetime: u32,
+    description: String,
+    renew_at: Option<Instant>,
+    renewal: Arc<dyn RenewalScheduler>,
+    failed_renewals: u32,
+    /// When a renewal that failed is retried
+    retry_at: Option<Instant>,
+}
+
+/// Make `port` of this host reachable from outside for `lifetime` seconds.
+///
+/// The protocols of `options.methods` are tried in order until one succeeds. The external port
+/// is the same as `port` if the gateway allows it. Errors of the protocols that failed are
+/// logged, the last one is returned.
+///
+/// # Example
+/// ```no_run
+/// use std::time::Duration;
+/// use igd::PortMappingProtocol;
+///
+/// let mut mapping = igd::auto::open_port(PortMappingProtocol::TCP, 8080, 3600, Default::default()).unwrap();
+/// println!("mapped with {} to port {}", mapping.method(), mapping.external_port());
+/// loop {
+///     mapping.poll(Duration::from_secs(60)).unwrap();
+/// }
+/// ```
+pub fn open_port(
+    protocol: PortMappingProtocol,
+    port: u16,
+    lifetime: u32,
+    options: AutoOptions,
+) -> Result<AutoMapping, AutoError> {
+    let AutoOptions {
+        methods,
+        search,
+        mut router,
+        description,
+        renewal,
+    } = options;
+    let mut search = Some(search);
+    let mut last_error = AutoError::NoMethod;
+    for method in methods {
+        let handle = match method {
+            Method::Upnp => {
+                let search = match search.take() {
+                    Some(search) => search,
+                    None => continue,
+                };
+                open_upnp(search, &mut router, protocol, port, lifetime, &description)
+            }
+            Method::Pcp => open_pcp(router, protocol, port, lifetime),
+            Method::NatPmp => open_natpmp(router, protocol, port, lifetime),
+        };
+        match handle {
+            Ok(handle) => {
+                let mut mapping = AutoMapping {
+                    handle,
+                    protocol,
+                    port,
+                    lifetime,
+                    description,
+                    renew_at: None,
+                    renewal,
+                    failed_renewals: 0,
+                    retry_at: None,
+                };
+                mapping.granted();
+                return Ok(mapping);
+            }
+            Err(e) => {
+                debug!("mapping {} port {} with {} failed: {}", protocol, port, method, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+fn open_upnp(
+    search: SearchOptions,
+    router: &mut Option<Ipv4Addr>,
+    protocol: PortMappingProtocol,
+    port: u16,
+    lifetime: u32,
+    description: &str,
+) -> Result<Handle, AutoError> {
+    let gateway = search_gateway(search).map_err(Error::from)?;
+    router.get_or_insert(*gateway.addr.ip());
+    let local_addr = SocketAddrV4::new(
+        gateway
+            .local_addr_hint()
+            .map_err(|e| Error::from(RequestError::from(e)))?,
+        port,
+    );
+    let mapped = gateway
+        .map_port(protocol, port, local_addr, lifetime, description)
+        .map_err(Error::from)?;
+    Ok(Handle::Upnp {
+        gateway: Box::new(gateway),
+        local_addr,
+        mapped,
+    })
+}
+
+fn open_pcp(
+    router: Option<Ipv4Addr>,
+    protocol: PortMappingProtocol,
+    port: u16,
+    lifetime: u32,
+) -> Result<Handle, AutoError> {
+    let router = router.ok_or(AutoError::NoRouter)?;
+    let mut client = PcpClient::new((router, pcp::SERVER_PORT).into()).map_err(|e| AutoError::Pcp(e.into()))?;
+    client.map(protocol, port, port, lifetime).map_err(AutoError::Pcp)?;
+    Ok(Handle::Pcp(client))
+}
+
+fn open_natpmp(
+    router: Option<Ipv4Addr>,
+    protocol: PortMappingProtocol,
+    port: u16,
+    lifetime: u32,
+) -> Result<Handle, AutoError> {
+    let router = router.ok_or(AutoError::NoRouter)?;
+    let mut client =
+        NatPmpClient::new(SocketAddrV4::new(router, natpmp::SERVER_PORT)).map_err(|e| AutoError::NatPmp(e.into()))?;
+    client.map(protocol, port, port, lifetime).map_err(AutoError::NatPmp)?;
+    Ok(Handle::NatPmp(client))
+}
+
+impl AutoMapping {
+    /// The protocol that made the mapping.
+    pub fn method(&self) -> Method {
+        match self.handle {
+            Handle::Upnp { .. } => Method::Upnp,
+            Handle::Pcp(_) => Method::Pcp,
+            Handle::NatPmp(_) => Method::NatPmp,
+        }
+    }
+
+    /// The protocol of the mapped port.
+    pub fn protocol(&self) -> PortMappingProtocol {
+        self.protocol
+    }
+
+    /// The port of this host the mapping forwards to.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The external port of the mapping.
+    pub fn external_port(&self) -> u16 {
+        match self.handle {
+            Handle::Upnp { ref mapped, .. } => mapped.external_port,
+            Handle::Pcp(ref client) => client.mappings()[0].external_addr.port(),
+            Handle::NatPmp(ref client) => client.mappings()[0].external_port,
+        }
+    }
+
+    /// The external address of the mapping, if the protocol tells it without another request.
+    ///
+    /// PCP does, for UPnP and NAT-PMP ask the gateway for its external IP.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        match self.handle {
+            Handle::Pcp(ref client) => Some(client.mappings()[0].external_addr),
+            _ => None,
+        }
+    }
+
+    /// Wait up to `timeout`, renewing the mapping when `AutoOptions::renewal` says so, by
+    /// default when half of its lifetime has passed.
+    ///
+    /// NAT-PMP mappings are also made again when the gateway restarted, as are PCP mappings,
+    /// which are checked when they are renewed. A renewal that failed is retried after the
+    /// delays of `AutoOptions::renewal`, and on every call once those run out.
+    pub fn poll(&mut self, timeout: Duration) -> Result<(), AutoError> {
+        let now = Instant::now();
+        if let Some(retry_at) = self.retry_at {
+            thread::sleep(retry_at.min(now + timeout).saturating_duration_since(now));
+            if retry_at > Instant::now() {
+                return Ok(());
+            }
+            let result = self.renew();
+            return self.renewed(result);
+        }
+
+        if let Handle::NatPmp(ref mut client) = self.handle {
+            let result = client.poll(timeout).map(drop).map_err(AutoError::NatPmp);
+            return self.renewed(result);
+        }
+
+        let wake = self
+            .renew_at
+            .map_or(now + timeout, |renew_at| renew_at.min(now + timeout));
+        thread::sleep(wake.saturating_duration_since(now));
+        if self.renew_at.is_none_or(|renew_at| renew_at > Instant::now()) {
+            return Ok(());
+        }
+        let result = self.renew();
+        self.renewed(result)
+    }
+
+    /// Schedule the retry of a renewal that failed.
+    fn renewed(&mut self, result: Result<(), AutoError>) -> Result<(), AutoError> {
+        #[cfg(feature = "prometheus")]
+        crate::metrics::record_renewals(result.is_ok() as usize, result.is_err() as usize);
+        match result {
+            Ok(()) => {
+                self.failed_renewals = 0;
+                self.retry_at = None;
+            }
+            Err(ref e) => {
+                let delay = self.renewal.retry_after(self.failed_renewals);
+                debug!(
+                    "renewing {} port {} failed, retrying in {:?}: {}",
+                    self.protocol, self.port, delay, e
+                );
+                self.retry_at = delay.map(|delay| Instant::now() + delay);
+                self.failed_renewals += 1;
+            }
+        }
+        result
+    }
+
+    /// Renew the mapping now.
+    pub fn renew(&mut self) -> Result<(), AutoError> {
+        let external_port = self.external_port();
+        match self.handle {
+            Handle::Upnp {
+                ref gateway,
+                local_addr,
+                ref mut mapped,
+            } => {
+                *mapped = gateway
+                    .map_port(
+                        self.protocol,
+                        mapped.external_port,
+                        local_addr,
+                        self.lifetime,
+                        &self.description,
+                    )
+                    .map_err(Error::from)?;
+            }
+            Handle::Pcp(ref mut client) => client.refresh().map_err(AutoError::Pcp)?,
+            Handle::NatPmp(ref mut client) => {
+                client
+                    .map(self.protocol, self.port, external_port, self.lifetime)
+                    .map_err(AutoError::NatPmp)?;
+            }
+        }
+        self.granted();
+        Ok(())
+    }
+
+    /// Remove the mapping.
+    pub fn remove(self) -> Result<(), AutoError> {
+        match self.handle {
+            Handle::Upnp { gateway, mapped, .. } => gateway
+                .remove_port(self.protocol, mapped.external_port)
+                .map_err(Error::from)?,
+            Handle::Pcp(mut client) => client.unmap(self.protocol, self.port).map_err(AutoError::Pcp)?,
+            Handle::NatPmp(mut client) => client.unmap(self.protocol, self.port).map_err(AutoError::NatPmp)?,
+        }
+        Ok(())
+    }
+
+    /// Schedule the next renewal after the mapping was granted, at half of its lifetime.
+    fn granted(&mut self) {
+        let lifetime = match self.handle {
+            Handle::Upnp { ref mapped, .. } => mapped.lease_duration,
+            Handle::Pcp(ref client) => client.mappings()[0].lifetime,
+            Handle::NatPmp(ref client) => client.mappings()[0].lifetime,
+        };
+        self.renew_at = match lifetime {
+            // A permanent UPnP mapping doesn't need renewing.
+            0 => None,
+            lifetime => Some(Instant::now() + self.renewal.renew_after(Duration::from_secs(u64::from(lifetime)))),
+        };
+    }
+}
+
+/// A port mapping protocol as an object, so the backend can be chosen at runtime.
+///
+/// It is implemented by `aio::Gateway` for UPnP. The blocking backends, `Gateway`, `PcpClient`
+/// and `NatPmpClient`, implement `BlockingPortMapper`, and are used as a `PortMapper` through
+/// `Threaded`, which sends their requests on a helper thread so the runtime isn't blocked.
+///
+/// # Example
+/// ```no_run
+/// use std::net::{Ipv4Addr, SocketAddrV4};
+/// use igd::auto::{AutoError, Method, PortMapper, Threaded};
+/// use igd::natpmp::{self, NatPmpClient};
+/// use igd::pcp::{self, PcpClient};
+/// use igd::PortMappingProtocol;
+///
+/// # async fn run(method: Method) -> Result<(), AutoError> {
+/// let router = Ipv4Addr::new(192, 168, 1, 1);
+/// let mut mapper: Box<dyn PortMapper> = match method {
+///     Method::Upnp => Box::new(Threaded::new(
+///         igd::search_gateway(Default::default()).map_err(igd::Error::from)?,
+///     )),
+///     Method::Pcp => Box::new(Threaded::new(
+///         PcpClient::new((router, pcp::SERVER_PORT).into()).map_err(pcp::PcpError::from)?,
+///     )),
+///     Method::NatPmp => {
+///         let gateway = SocketAddrV4::new(router, natpmp::SERVER_PORT);
+///         Box::new(Threaded::new(NatPmpClient::new(gateway).map_err(natpmp::NatPmpError::from)?))
+///     }
+/// };
+/// let mapped = mapper.map(PortMappingProtocol::TCP, 8080, 8080, 3600, "my server").await?;
+/// println!("mapped with {} to port {}", mapper.method(), mapped.external_port);
+/// mapper.unmap(PortMappingProtocol::TCP, 8080, mapped.external_port).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait PortMapper: fmt::Debug + Send {
+    /// The protocol of the backend.
+    fn method(&self) -> Method;
+
+    /// Map an external port to `internal_port` of this host for `lifetime` seconds, returning
+    /// the mapping that was made.
+    ///
+    /// The external port is `external_port` if the gateway allows it, or any if it is 0, with the
+    /// same limits as the backend otherwise, e.g. `Gateway::map_port`. Only UPnP keeps the
+    /// `description`. Mapping the same port again renews the mapping.
+    fn map<'a>(
+        &'a mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+        description: &'a str,
+    ) -> BoxFuture<'a, Result<MappedPort, AutoError>>;
+
+    /// Remove a mapping made by `map`, found by its internal port with PCP and NAT-PMP, by its
+    /// external port with UPnP.
+    fn unmap(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+    ) -> BoxFuture<'_, Result<(), AutoError>>;
+}
+
+/// A port mapping protocol as an object, like `PortMapper`, whose calls block until the gateway
+/// answered.
+///
+/// It is implemented by `Gateway` for UPnP, by `PcpClient` and by `NatPmpClient`. Wrap it in
+/// `Threaded` to use it as a `PortMapper`.
+pub trait BlockingPortMapper: fmt::Debug + Send {
+    /// The protocol of the backend.
+    fn method(&self) -> Method;
+
+    /// Map an external port to `internal_port` of this host, see `PortMapper::map`.
+    fn map(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+        description: &str,
+    ) -> Result<MappedPort, AutoError>;
+
+    /// Remove a mapping made by `map`, see `PortMapper::unmap`.
+    fn unmap(&mut self, protocol: PortMappingProtocol, internal_port: u16, external_port: u16)
+        -> Result<(), AutoError>;
+}
+
+#[cfg(feature = "aio")]
+impl PortMapper for crate::aio::Gateway {
+    fn method(&self) -> Method {
+        Method::Upnp
+    }
+
+    fn map<'a>(
+        &'a mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+        description: &'a str,
+    ) -> BoxFuture<'a, Result<MappedPort, AutoError>> {
+        Box::pin(async move {
+            let local_ip = self.local_addr_hint().map_err(|e| Error::from(RequestError::from(e)))?;
+            let local_addr = SocketAddrV4::new(local_ip, internal_port);
+            Ok(self
+                .map_port(protocol, external_port, local_addr, lifetime, description)
+                .await
+                .map_err(Error::from)?)
+        })
+    }
+
+    fn unmap(
+        &mut self,
+        protocol: PortMappingProtocol,
+        _internal_port: u16,
+        external_port: u16,
+    ) -> BoxFuture<'_, Result<(), AutoError>> {
+        Box::pin(async move { Ok(self.remove_port(protocol, external_port).await.map_err(Error::from)?) })
+    }
+}
+
+impl BlockingPortMapper for Gateway {
+    fn method(&self) -> Method {
+        Method::Upnp
+    }
+
+    fn map(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+        description: &str,
+    ) -> Result<MappedPort, AutoError> {
+        let local_ip = self.local_addr_hint().map_err(|e| Error::from(RequestError::from(e)))?;
+        let local_addr = SocketAddrV4::new(local_ip, internal_port);
+        Ok(self
+            .map_port(protocol, external_port, local_addr, lifetime, description)
+            .map_err(Error::from)?)
+    }
+
+    fn unmap(
+        &mut self,
+        protocol: PortMappingProtocol,
+        _internal_port: u16,
+        external_port: u16,
+    ) -> Result<(), AutoError> {
+        Ok(self.remove_port(protocol, external_port).map_err(Error::from)?)
+    }
+}
+
+impl BlockingPortMapper for PcpClient {
+    fn method(&self) -> Method {
+        Method::Pcp
+    }
+
+    fn map(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+        _description: &str,
+    ) -> Result<MappedPort, AutoError> {
+        let mapping = PcpClient::map(self, protocol, internal_port, external_port, lifetime)?;
+        Ok(MappedPort {
+            external_port: mapping.external_addr.port(),
+            lease_duration: mapping.lifetime,
+        })
+    }
+
+    fn unmap(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        _external_port: u16,
+    ) -> Result<(), AutoError> {
+        Ok(PcpClient::unmap(self, protocol, internal_port)?)
+    }
+}
+
+impl BlockingPortMapper for NatPmpClient {
+    fn method(&self) -> Method {
+        Method::NatPmp
+    }
+
+    fn map(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+        _description: &str,
+    ) -> Result<MappedPort, AutoError> {
+        let mapping = NatPmpClient::map(self, protocol, internal_port, external_port, lifetime)?;
+        Ok(MappedPort {
+            external_port: mapping.external_port,
+            lease_duration: mapping.lifetime,
+        })
+    }
+
+    fn unmap(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        _external_port: u16,
+    ) -> Result<(), AutoError> {
+        Ok(NatPmpClient::unmap(self, protocol, internal_port)?)
+    }
+}
+
+/// A `BlockingPortMapper` used as a `PortMapper`, its calls made on a helper thread.
+///
+/// Each call runs on a thread of its own, which wakes the future when the gateway answered, so
+/// awaiting it doesn't block the runtime. A future dropped early doesn't cancel the request, the
+/// next call waits for it to finish.
+#[derive(Debug)]
+pub struct Threaded<M> {
+    method: Method,
+    mapper: Arc<Mutex<M>>,
+}
+
+impl<M: BlockingPortMapper + 'static> Threaded<M> {
+    /// Make the calls of `mapper` on helper threads.
+    pub fn new(mapper: M) -> Threaded<M> {
+        Threaded {
+            method: mapper.method(),
+            mapper: Arc::new(Mutex::new(mapper)),
+        }
+    }
+
+    /// Run `call` with the mapper on a helper thread.
+    fn run<T, F>(&self, call: F) -> BoxFuture<'static, T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut M) -> T + Send + 'static,
+    {
+        let mapper = self.mapper.clone();
+        Box::pin(OnThread::spawn(move || {
+            call(&mut mapper.lock().unwrap_or_else(|e| e.into_inner()))
+        }))
+    }
+}
+
+impl<M: BlockingPortMapper + 'static> PortMapper for Threaded<M> {
+    fn method(&self) -> Method {
+        self.method
+    }
+
+    fn map<'a>(
+        &'a mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+        description: &'a str,
+    ) -> BoxFuture<'a, Result<MappedPort, AutoError>> {
+        let description = description.to_string();
+        self.run(move |mapper| mapper.map(protocol, internal_port, external_port, lifetime, &description))
+    }
+
+    fn unmap(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+    ) -> BoxFuture<'_, Result<(), AutoError>> {
+        self.run(move |mapper| mapper.unmap(protocol, internal_port, external_port))
+    }
+}
+
+/// The result of a function run on a thread of its own, woken when it is done.
+struct OnThread<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+struct Slot<T> {
+    output: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T: Send + 'static> OnThread<T> {
+    fn spawn<F: FnOnce() -> T + Send + 'static>(f: F) -> OnThread<T> {
+        let slot = Arc::new(Mutex::new(Slot {
+            output: None,
+            waker: None,
+        }));
+        let done = slot.clone();
+        thread::spawn(move || {
+            let output = panic::catch_unwind(AssertUnwindSafe(f));
+            let waker = {
+                let mut slot = done.lock().unwrap_or_else(|e| e.into_inner());
+                slot.output = Some(output);
+                slot.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+        OnThread { slot }
+    }
+}
+
+impl<T> Future for OnThread<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot.lock().unwrap_or_else(|e| e.into_inner());
+        match slot.output.take() {
+            Some(Ok(output)) => Poll::Ready(output),
+            // The call panicked, so does the future.
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => {
+                slot.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[test]
+fn test_open_port_without_router() {
+    let options = AutoOptions {
+        methods: vec![Method::Pcp, Method::NatPmp],
+        ..Default::default()
+    };
+    match open_port(PortMappingProtocol::TCP, 8080, 3600, options) {
+        Err(AutoError::NoRouter) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    let options = AutoOptions {
+        methods: vec![],
+        ..Default::default()
+    };
+    assert!(matches!(
+        open_port(PortMappingProtocol::TCP, 8080, 3600, options),
+        Err(AutoError::NoMethod)
+    ));
+}
+
+#[test]
+fn test_open_port_falls_back() {
+    use std::net::UdpSocket;
+
+    // A NAT-PMP only gateway, which answers PCP requests with an unsupported version.
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, natpmp::SERVER_PORT)).unwrap();
+    thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        while let Ok((read, from)) = server.recv_from(&mut buf) {
+            let request = &buf[..read];
+            let response = if request[0] != 0 {
+                vec![0, request[1] | 0x80, 0, 1, 0, 0, 0, 100]
+            } else {
+                let mut response = vec![0, request[1] | 0x80, 0, 0, 0, 0, 0, 100];
+                response.extend_from_slice(&request[4..6]);
+                response.extend_from_slice(&request[4..6]);
+                response.extend_from_slice(&request[8..12]);
+                response
+            };
+            server.send_to(&response, from).unwrap();
+        }
+    });
+
+    let options = AutoOptions {
+        methods: vec![Method::Pcp, Method::NatPmp],
+        router: Some(Ipv4Addr::LOCALHOST),
+        ..Default::default()
+    };
+    let mapping = open_port(PortMappingProtocol::UDP, 9000, 3600, options).unwrap();
+    assert_eq!(mapping.method(), Method::NatPmp);
+    assert_eq!(mapping.external_port(), 9000);
+    assert_eq!(mapping.external_addr(), None);
+    mapping.remove().unwrap();
+
+    use crate::common::actions::block_on;
+
+    let client = NatPmpClient::new(SocketAddrV4::new(Ipv4Addr::LOCALHOST, natpmp::SERVER_PORT)).unwrap();
+    let mut mapper: Box<dyn PortMapper> = Box::new(Threaded::new(client));
+    assert_eq!(mapper.method(), Method::NatPmp);
+    let mapped = block_on(mapper.map(PortMappingProtocol::TCP, 9001, 0, 3600, "igd test")).unwrap();
+    assert_eq!(mapped.external_port, 9001);
+    assert_eq!(mapped.lease_duration, 3600);
+    block_on(mapper.unmap(PortMappingProtocol::TCP, 9001, mapped.external_port)).unwrap();
+}
@@ -0,0 +1,190 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::search;
+use crate::ssdp::{Notification, NotifyListener};
+use crate::Gateway;
+
+/// How long an `ssdp:alive` announcement without `CACHE-CONTROL` is valid, the minimum the
+/// UPnP device architecture allows.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(1800);
+
+/// A change of availability of the gateway tracked by a `GatewayTracker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GatewayEvent {
+    /// The gateway announced itself again, e.g. after a reboot. Its description was fetched
+    /// again, see `GatewayTracker::gateway`.
+    GatewayUp,
+    /// The gateway said goodbye, or its last announcement expired.
+    GatewayDown,
+}
+
+/// Tracks whether a gateway is available from its `ssdp:alive` and `ssdp:byebye` announcements.
+///
+/// The gateway is matched by the UDN of its description, so it is recognized after a reboot
+/// even if its address changed. It is assumed up at first. It goes down when it says goodbye,
+/// or when its last announcement expires without a new one, and up again on its next
+/// announcement, with its description fetched again.
+///
+/// # Example
+/// ```no_run
+/// use std::time::Duration;
+/// use igd::ssdp::NotifyListener;
+/// use igd::{GatewayEvent, GatewayTracker};
+///
+/// let gateway = igd::search_gateway(Default::default()).unwrap();
+/// let mut tracker = GatewayTracker::new(gateway, NotifyListener::bind().unwrap());
+/// loop {
+///     match tracker.next_event(Duration::from_secs(60)).unwrap() {
+///         Some(GatewayEvent::GatewayDown) => println!("the gateway left"),
+///         Some(GatewayEvent::GatewayUp) => println!("{} is back", tracker.gateway()),
+///         None => {}
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct GatewayTracker {
+    listener: NotifyListener,
+    gateway: Gateway,
+    up: bool,
+    expires: Option<Instant>,
+}
+
+impl GatewayTracker {
+    /// Track `gateway` with the announcements received by `listener`.
+    pub fn new(gateway: Gateway, listener: NotifyListener) -> GatewayTracker {
+        GatewayTracker {
+            listener,
+            gateway,
+            up: true,
+            expires: None,
+        }
+    }
+
+    /// The gateway, as of its last announcement.
+    pub fn gateway(&self) -> &Gateway {
+        &self.gateway
+    }
+
+    /// Whether the gateway is up.
+    pub fn is_up(&self) -> bool {
+        self.up
+    }
+
+    /// Wait up to `timeout` for the availability of the gateway to change.
+    ///
+    /// Returns `None` if it didn't change before the timeout expired.
+    pub fn next_event(&mut self, timeout: Duration) -> io::Result<Option<GatewayEvent>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if let Some(expires) = self.expires.filter(|expires| *expires <= now) {
+                debug!("the announcement of {} expired at {:?}", self.gateway, expires);
+                self.up = false;
+                self.expires = None;
+                return Ok(Some(GatewayEvent::GatewayDown));
+            }
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            let wake = self.expires.map_or(deadline, |expires| expires.min(deadline));
+            if let Some(notification) = self.listener.recv(Some(wake - now))? {
+                if let Some(event) = self.handle(&notification) {
+                    return Ok(Some(event));
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, notification: &Notification) -> Option<GatewayEvent> {
+        if notification.udn() != Some(self.gateway.device_info.udn.as_str()) {
+            return None;
+        }
+
+        if notification.is_byebye() {
+            self.expires = None;
+            if self.up {
+                self.up = false;
+                return Some(GatewayEvent::GatewayDown);
+            }
+        } else if notification.is_alive() {
+            if !self.up {
+                match search::get_announced_gateway(notification, Default::default()) {
+                    Ok(mut gateway) => {
+                        gateway.local_addr = self.gateway.local_addr;
+                        self.gateway = gateway;
+                    }
+                    Err(e) => {
+                        debug!("fetching the description of {} failed: {}", self.gateway, e);
+                        return None;
+                    }
+                }
+            }
+            self.expires = Some(Instant::now() + notification.max_age().unwrap_or(DEFAULT_MAX_AGE));
+            if !self.up {
+                self.up = true;
+                return Some(GatewayEvent::GatewayUp);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_gateway_tracker() {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let listener_addr = socket.local_addr().unwrap();
+    let mut tracker = GatewayTracker::new(gateway, NotifyListener::from_socket(socket));
+
+    let announce = |nts: &str, udn: &str| {
+        let message = format!(
+            "NOTIFY * HTTP/1.1\r\n\
+             HOST: 239.255.255.250:1900\r\n\
+             CACHE-CONTROL: max-age=1\r\n\
+             LOCATION: http://{}/rootDesc.xml\r\n\
+             NT: upnp:rootdevice\r\n\
+             NTS: {}\r\n\
+             USN: {}::upnp:rootdevice\r\n\
+             \r\n",
+            mock.http_addr(),
+            nts,
+            udn
+        );
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .send_to(message.as_bytes(), listener_addr)
+            .unwrap();
+    };
+    let udn = tracker.gateway().device_info.udn.clone();
+
+    // Announcements of other devices are ignored.
+    announce("ssdp:byebye", "uuid:00000000-0000-0000-0000-0000000000ff");
+    assert!(tracker.next_event(Duration::from_millis(200)).unwrap().is_none());
+    assert!(tracker.is_up());
+
+    announce("ssdp:byebye", &udn);
+    assert_eq!(
+        tracker.next_event(Duration::from_secs(1)).unwrap(),
+        Some(GatewayEvent::GatewayDown)
+    );
+    assert!(!tracker.is_up());
+
+    announce("ssdp:alive", &udn);
+    assert_eq!(
+        tracker.next_event(Duration::from_secs(5)).unwrap(),
+        Some(GatewayEvent::GatewayUp)
+    );
+    assert_eq!(tracker.gateway().device_info.udn, udn);
+
+    // No announcement follows within the max-age.
+    assert_eq!(
+        tracker.next_event(Duration::from_secs(5)).unwrap(),
+        Some(GatewayEvent::GatewayDown)
+    );
+}
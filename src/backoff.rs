@@ -0,0 +1,137 @@
+use std::fmt;
+use std::time::Duration;
+
+use rand::Rng;
+
+/// A schedule of the delays between the attempts of an operation that failed.
+///
+/// It is used to retry the requests to a gateway, see `Gateway::retry_backoff`, to send the
+/// search request again, see `SearchOptions::retransmission`, by the PCP and NAT-PMP clients to
+/// retransmit their requests, and by `FractionScheduler` to retry renewals.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use igd::{Backoff, ExponentialBackoff};
+///
+/// let backoff = ExponentialBackoff {
+///     initial: Duration::from_millis(100),
+///     jitter: 0.0,
+///     ..Default::default()
+/// };
+/// assert_eq!(backoff.delay(2), Some(Duration::from_millis(400)));
+/// ```
+pub trait Backoff: fmt::Debug + Send + Sync {
+    /// The delay before retry `retry`, counting from 0, or `None` to give up.
+    fn delay(&self, retry: u32) -> Option<Duration>;
+}
+
+/// The same delay before every retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FixedBackoff {
+    /// Delay before each retry (defaults to 1 second)
+    pub delay: Duration,
+    /// Most retries, or `None` for no limit (defaults to 3)
+    pub max_retries: Option<u32>,
+}
+
+impl Default for FixedBackoff {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(1),
+            max_retries: Some(3),
+        }
+    }
+}
+
+impl Backoff for FixedBackoff {
+    fn delay(&self, retry: u32) -> Option<Duration> {
+        if self.max_retries.is_some_and(|max_retries| retry >= max_retries) {
+            return None;
+        }
+        Some(self.delay)
+    }
+}
+
+/// A delay doubling with every retry, up to a maximum, and spread randomly.
+///
+/// The jitter keeps clients that failed together, e.g. when the gateway restarted, from
+/// retrying together.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExponentialBackoff {
+    /// Delay before the first retry (defaults to 500 milliseconds)
+    pub initial: Duration,
+    /// Longest delay, before the jitter is applied (defaults to 30 seconds)
+    pub max: Duration,
+    /// Fraction of the delay it is randomly made longer or shorter by, from 0 to 1 (defaults
+    /// to 0.2)
+    pub jitter: f64,
+    /// Most retries, or `None` for no limit (defaults to 4)
+    pub max_retries: Option<u32>,
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            jitter: 0.2,
+            max_retries: Some(4),
+        }
+    }
+}
+
+impl Backoff for ExponentialBackoff {
+    fn delay(&self, retry: u32) -> Option<Duration> {
+        if self.max_retries.is_some_and(|max_retries| retry >= max_retries) {
+            return None;
+        }
+        let delay = self
+            .initial
+            .checked_mul(1 << retry.min(31))
+            .map_or(self.max, |delay| delay.min(self.max));
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return Some(delay);
+        }
+        Some(delay.mul_f64(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)))
+    }
+}
+
+#[test]
+fn test_fixed_backoff() {
+    let backoff = FixedBackoff::default();
+    assert_eq!(backoff.delay(0), Some(Duration::from_secs(1)));
+    assert_eq!(backoff.delay(2), Some(Duration::from_secs(1)));
+    assert_eq!(backoff.delay(3), None);
+
+    let unlimited = FixedBackoff {
+        max_retries: None,
+        ..backoff
+    };
+    assert_eq!(unlimited.delay(1000), Some(Duration::from_secs(1)));
+}
+
+#[test]
+fn test_exponential_backoff() {
+    let backoff = ExponentialBackoff {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(5),
+        jitter: 0.0,
+        max_retries: None,
+    };
+    let delays: Vec<_> = (0..5).map(|retry| backoff.delay(retry).unwrap().as_secs()).collect();
+    assert_eq!(delays, [1, 2, 4, 5, 5]);
+    assert_eq!(backoff.delay(u32::MAX), Some(Duration::from_secs(5)));
+
+    let backoff = ExponentialBackoff {
+        jitter: 0.5,
+        max_retries: Some(2),
+        ..backoff
+    };
+    for _ in 0..100 {
+        let delay = backoff.delay(1).unwrap();
+        assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(3));
+    }
+    assert_eq!(backoff.delay(2), None);
+}
@@ -0,0 +1,194 @@
+//! Command line client for UPnP Internet Gateway Devices.
+//!
+//! Run with `cargo run --features cli --bin igd-cli -- <command>`. Pass `-v` to log the
+//! requests exchanged with the gateway, e.g. to attach them to a bug report.
+
+extern crate igd;
+extern crate simplelog;
+
+use std::net::{SocketAddr, SocketAddrV4};
+use std::process;
+use std::time::Duration;
+
+use igd::{Gateway, GetGenericPortMappingEntryError, PortMappingProtocol, SearchOptions};
+
+const USAGE: &str = "Usage: igd-cli [options] <command> [arguments]
+
+Options:
+    --timeout <seconds>     Search timeout (defaults to 10)
+    --bind <address>        Local address to search from, e.g. 192.168.1.2:0
+    --third-party           Allow mappings to other hosts of the LAN
+    -v                      Log the exchanged requests
+
+Commands:
+    discover                List all gateways that answer the search
+    diagnostics             Print a redacted report of what the gateways say about
+                            themselves as JSON, for bug reports (with the diagnostics
+                            feature)
+    external-ip             Print the external IP address
+    list                    List the port mappings
+    add <TCP|UDP> <external port> <local address> [lease seconds] [description]
+                            Add a port mapping
+    delete <TCP|UDP> <external port>
+                            Remove a port mapping
+    capabilities            Print what the gateway supports
+    status                  Print the state of the WAN connection
+    stats                   Print the traffic counters of the WAN interface
+    apply <config file>     Make the mappings match a TOML or JSON config (with the config
+                            feature)";
+
+fn main() {
+    if let Err(e) = run(std::env::args().skip(1).collect()) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let mut options = SearchOptions::default();
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+    let mut allow_third_party = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let seconds = parse::<u64>(args.next(), "timeout")?;
+                options.timeout = Some(Duration::from_secs(seconds));
+            }
+            "--bind" => options.bind_addr = parse::<SocketAddr>(args.next(), "bind address")?,
+            "--third-party" => allow_third_party = true,
+            "-v" => {
+                let _ = simplelog::SimpleLogger::init(simplelog::LevelFilter::Debug, simplelog::Config::default());
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = positional.next().ok_or(USAGE)?;
+    if command == "discover" {
+        return discover(options);
+    }
+    #[cfg(feature = "diagnostics")]
+    if command == "diagnostics" {
+        println!("{}", igd::diagnostics::collect(options).to_json());
+        return Ok(());
+    }
+
+    let mut gateway = igd::search_gateway(options).map_err(|e| e.to_string())?;
+    gateway.allow_third_party = allow_third_party;
+    match command.as_str() {
+        "external-ip" => {
+            println!("{}", gateway.get_external_ip().map_err(|e| e.to_string())?);
+        }
+        "list" => list(&gateway)?,
+        "add" => {
+            let protocol = parse_protocol(positional.next())?;
+            let external_port = parse::<u16>(positional.next(), "external port")?;
+            let local_addr = parse::<SocketAddrV4>(positional.next(), "local address")?;
+            let lease_duration = match positional.next() {
+                Some(lease) => parse::<u32>(Some(lease), "lease duration")?,
+                None => 0,
+            };
+            let description = positional.next().unwrap_or_else(|| "igd-cli".to_string());
+            gateway
+                .add_port(protocol, external_port, local_addr, lease_duration, &description)
+                .map_err(|e| e.to_string())?;
+            println!("{} {} -> {}", protocol, external_port, local_addr);
+        }
+        "delete" => {
+            let protocol = parse_protocol(positional.next())?;
+            let external_port = parse::<u16>(positional.next(), "external port")?;
+            gateway
+                .remove_port(protocol, external_port)
+                .map_err(|e| e.to_string())?;
+        }
+        "capabilities" => println!("{}", gateway.capabilities()),
+        "status" => {
+            let status = gateway.get_status_info().map_err(|e| e.to_string())?;
+            println!("Connection status:     {}", status.connection_status);
+            println!("Last connection error: {}", status.last_connection_error);
+            println!("Uptime:                {}s", status.uptime);
+        }
+        "stats" => {
+            let stats = gateway.get_traffic_stats().map_err(|e| e.to_string())?;
+            println!("Bytes sent:       {}", stats.bytes_sent);
+            println!("Bytes received:   {}", stats.bytes_received);
+            println!("Packets sent:     {}", stats.packets_sent);
+            println!("Packets received: {}", stats.packets_received);
+        }
+        #[cfg(feature = "config")]
+        "apply" => {
+            let path = positional
+                .next()
+                .ok_or_else(|| format!("Missing config file\n\n{}", USAGE))?;
+            let config = igd::config::MappingConfig::load(path).map_err(|e| e.to_string())?;
+            let mut manager = config.manager(gateway);
+            let report = config.apply(&mut manager).map_err(|e| e.to_string())?;
+            for mapped in report.added.iter().chain(&report.updated) {
+                println!("mapped external port {}", mapped.external_port);
+            }
+            for (protocol, external_port) in &report.removed {
+                println!("removed {} {}", protocol, external_port);
+            }
+            for (description, e) in &report.failed {
+                eprintln!("{}: {}", description, e);
+            }
+        }
+        _ => return Err(format!("Unknown command {}\n\n{}", command, USAGE)),
+    }
+    Ok(())
+}
+
+fn discover(options: SearchOptions) -> Result<(), String> {
+    for gateway in igd::search_multi_gateways(options).map_err(|e| e.to_string())? {
+        let info = &gateway.device_info;
+        println!("{}", gateway);
+        println!("    Name:         {}", info.friendly_name);
+        println!("    Manufacturer: {}", info.manufacturer);
+        println!("    Model:        {} {}", info.model_name, info.model_number);
+        println!("    Server:       {}", info.server);
+        println!("    UDN:          {}", info.udn);
+        if let Some(ref url) = info.presentation_url {
+            println!("    Web UI:       {}", url);
+        }
+    }
+    Ok(())
+}
+
+fn list(gateway: &Gateway) -> Result<(), String> {
+    for index in 0.. {
+        match gateway.get_generic_port_mapping_entry(index) {
+            Ok(entry) => println!(
+                "{} {} -> {}:{} lease {}s enabled {} \"{}\"",
+                entry.protocol,
+                entry.external_port,
+                entry.internal_client,
+                entry.internal_port,
+                entry.lease_duration,
+                entry.enabled,
+                entry.port_mapping_description
+            ),
+            Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => break,
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(())
+}
+
+fn parse<T: std::str::FromStr>(arg: Option<String>, name: &str) -> Result<T, String> {
+    let arg = arg.ok_or_else(|| format!("Missing {}", name))?;
+    arg.parse().map_err(|_| format!("Invalid {}: {}", name, arg))
+}
+
+fn parse_protocol(arg: Option<String>) -> Result<PortMappingProtocol, String> {
+    match arg.as_deref().map(str::to_ascii_uppercase).as_deref() {
+        Some("TCP") => Ok(PortMappingProtocol::TCP),
+        Some("UDP") => Ok(PortMappingProtocol::UDP),
+        _ => Err(format!("Expected TCP or UDP\n\n{}", USAGE)),
+    }
+}
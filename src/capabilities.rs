@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::common::messages;
+use crate::description::RootDescription;
+use crate::quirks::Quirks;
+
+/// What a gateway supports, see `Gateway::capabilities`.
+///
+/// It is built from the description and SCPD fetched by the search, without sending any
+/// request. Its `Display` lists everything, e.g. to attach to a bug report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Version of the InternetGatewayDevice, e.g. 2, if the root device is one
+    pub igd_version: Option<u32>,
+    /// Type of the WAN connection service the requests go to, e.g.
+    /// `urn:schemas-upnp-org:service:WANIPConnection:2`
+    pub connection_service: Option<String>,
+    /// Types of all services of the gateway and its embedded devices
+    pub services: Vec<String>,
+    /// The connection service has `AddAnyPortMapping`, so the gateway picks a free port
+    pub add_any_port_mapping: bool,
+    /// The connection service has `GetListOfPortMappings`, listing the mappings at once
+    pub get_list_of_port_mappings: bool,
+    /// The connection service has `DeletePortMappingRange`
+    pub delete_port_mapping_range: bool,
+    /// The gateway has a WANIPv6FirewallControl service, so pinholes can be opened
+    pub pinholes: bool,
+    /// The gateway has a WANCommonInterfaceConfig service, so traffic counters can be read
+    pub traffic_stats: bool,
+    /// The connection service has an event subscription url
+    pub eventing: bool,
+    /// The gateway has a DeviceConfig service, so it can be rebooted
+    pub device_config: bool,
+    /// Firmware bugs worked around
+    pub quirks: Quirks,
+}
+
+impl Capabilities {
+    pub(crate) fn new(
+        description: &RootDescription,
+        control_url: &str,
+        control_schema: &HashMap<String, Vec<String>>,
+        quirks: Quirks,
+    ) -> Capabilities {
+        let devices = description.device.all_devices();
+        let services: Vec<_> = devices.iter().flat_map(|device| device.services.iter()).collect();
+        let connection = services
+            .iter()
+            .find(|service| service.control_url == control_url.trim());
+        let has_service = |service_type: &str| services.iter().any(|service| service.service_type == service_type);
+        Capabilities {
+            igd_version: description
+                .device
+                .device_type
+                .strip_prefix("urn:schemas-upnp-org:device:InternetGatewayDevice:")
+                .and_then(|version| version.parse().ok()),
+            connection_service: connection.map(|service| service.service_type.clone()),
+            services: services.iter().map(|service| service.service_type.clone()).collect(),
+            add_any_port_mapping: control_schema.contains_key("AddAnyPortMapping"),
+            get_list_of_port_mappings: control_schema.contains_key("GetListOfPortMappings"),
+            delete_port_mapping_range: control_schema.contains_key("DeletePortMappingRange"),
+            pinholes: has_service(messages::WAN_IPV6_FIREWALL_CONTROL_SERVICE),
+            traffic_stats: has_service(messages::WAN_COMMON_INTERFACE_CONFIG_SERVICE),
+            eventing: connection.is_some_and(|service| !service.event_sub_url.is_empty()),
+            device_config: messages::DEVICE_CONFIG_SERVICES
+                .iter()
+                .any(|service_type| has_service(service_type)),
+            quirks,
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+        match self.igd_version {
+            Some(version) => writeln!(f, "IGD version:               {}", version)?,
+            None => writeln!(f, "IGD version:               unknown")?,
+        }
+        writeln!(
+            f,
+            "Connection service:        {}",
+            self.connection_service.as_deref().unwrap_or("unknown")
+        )?;
+        writeln!(f, "AddAnyPortMapping:         {}", yes_no(self.add_any_port_mapping))?;
+        writeln!(
+            f,
+            "GetListOfPortMappings:     {}",
+            yes_no(self.get_list_of_port_mappings)
+        )?;
+        writeln!(
+            f,
+            "DeletePortMappingRange:    {}",
+            yes_no(self.delete_port_mapping_range)
+        )?;
+        writeln!(f, "Pinholes:                  {}", yes_no(self.pinholes))?;
+        writeln!(f, "Traffic stats:             {}", yes_no(self.traffic_stats))?;
+        writeln!(f, "Eventing:                  {}", yes_no(self.eventing))?;
+        writeln!(f, "Device config:             {}", yes_no(self.device_config))?;
+        writeln!(f, "Quirks:                    {:?}", self.quirks)?;
+        write!(f, "Services:")?;
+        for service in &self.services {
+            write!(f, "\n    {}", service)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_capabilities() {
+    let text = r#"<?xml version="1.0"?>
+    <root xmlns="urn:schemas-upnp-org:device-1-0">
+        <device>
+            <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:2</deviceType>
+            <deviceList>
+                <device>
+                    <serviceList>
+                        <service>
+                            <serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType>
+                            <controlURL>/ctl/CmnIfCfg</controlURL>
+                        </service>
+                        <service>
+                            <serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>
+                            <controlURL>/ctl/IPConn</controlURL>
+                            <eventSubURL>/evt/IPConn</eventSubURL>
+                        </service>
+                    </serviceList>
+                </device>
+            </deviceList>
+        </device>
+    </root>
+    "#;
+    let description = crate::description::parse(text.as_bytes()).unwrap();
+    let mut schema = HashMap::new();
+    schema.insert("AddAnyPortMapping".to_string(), vec![]);
+    let quirks = Quirks {
+        http_1_0: true,
+        ..Default::default()
+    };
+
+    let capabilities = Capabilities::new(&description, "/ctl/IPConn", &schema, quirks);
+    assert_eq!(capabilities.igd_version, Some(2));
+    assert_eq!(
+        capabilities.connection_service.as_deref(),
+        Some("urn:schemas-upnp-org:service:WANIPConnection:2")
+    );
+    assert_eq!(capabilities.services.len(), 2);
+    assert!(capabilities.add_any_port_mapping);
+    assert!(!capabilities.get_list_of_port_mappings);
+    assert!(!capabilities.pinholes);
+    assert!(capabilities.traffic_stats);
+    assert!(capabilities.eventing);
+    assert!(!capabilities.device_config);
+    assert!(capabilities.quirks.http_1_0);
+    assert!(capabilities.to_string().contains("Pinholes:                  no"));
+
+    let unknown = Capabilities::new(&Default::default(), "/ctl/IPConn", &schema, Quirks::default());
+    assert_eq!(unknown.igd_version, None);
+    assert_eq!(unknown.connection_service, None);
+    assert!(!unknown.eventing);
+}
@@ -0,0 +1,423 @@
+//! Recording and replaying of the traffic exchanged with gateways.
+//!
+//! While a recording session is active, every SSDP datagram of `search_gateway` and
+//! `search_multi_gateways` and every HTTP exchange of the blocking API is captured. A cassette
+//! saved to a file can be attached to a bug report and replayed later, with the exact responses
+//! of the router, without any network access.
+//!
+//! A session only applies to the thread that started it. Only the blocking API is covered;
+//! searching with a custom `SearchTransport` is neither recorded nor replayed.
+//!
+//! # Example
+//! ```no_run
+//! use igd::cassette::{self, Cassette};
+//!
+//! let session = cassette::record();
+//! let gateway = igd::search_gateway(Default::default());
+//! session.finish().save("igd.cassette").unwrap();
+//!
+//! let _session = cassette::replay(Cassette::load("igd.cassette").unwrap());
+//! let replayed = igd::search_gateway(Default::default());
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::search::SearchTransport;
+
+/// One exchange with a gateway.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A datagram sent to the given address
+    SsdpSent {
+        /// Destination of the datagram
+        to: SocketAddr,
+        /// Content of the datagram
+        data: Vec<u8>,
+    },
+    /// A datagram received from the given address
+    SsdpReceived {
+        /// Source of the datagram
+        from: SocketAddr,
+        /// Content of the datagram
+        data: Vec<u8>,
+    },
+    /// An HTTP request and its response
+    Http {
+        /// Method of the request
+        method: String,
+        /// Url of the request
+        url: String,
+        /// Body of the request
+        request: Vec<u8>,
+        /// Status of the response
+        status: u16,
+        /// Body of the response
+        response: Vec<u8>,
+    },
+}
+
+/// A sequence of recorded events.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cassette {
+    /// Events in the order they happened
+    pub events: Vec<Event>,
+}
+
+impl Cassette {
+    /// Read a cassette from a file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Cassette> {
+        Cassette::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// Write the cassette to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Read a cassette in the format written by `write_to`.
+    pub fn read_from<R: BufRead>(mut reader: R) -> io::Result<Cassette> {
+        fn invalid(line: &str) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid cassette line: {}", line))
+        }
+        fn block<R: BufRead>(reader: &mut R, length: &str) -> io::Result<Vec<u8>> {
+            let length = length.parse::<usize>().map_err(|_| invalid(length))?;
+            let mut data = vec![0; length + 1];
+            reader.read_exact(&mut data)?;
+            data.pop();
+            Ok(data)
+        }
+
+        let mut events = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let header = line.trim_end().to_string();
+            let parts: Vec<&str> = header.split(' ').collect();
+            let event = match parts[..] {
+                [] | [""] => continue,
+                ["ssdp-sent", to, length] => Event::SsdpSent {
+                    to: to.parse().map_err(|_| invalid(&header))?,
+                    data: block(&mut reader, length)?,
+                },
+                ["ssdp-received", from, length] => Event::SsdpReceived {
+                    from: from.parse().map_err(|_| invalid(&header))?,
+                    data: block(&mut reader, length)?,
+                },
+                ["http", method, url, status, request_length, response_length] => Event::Http {
+                    method: method.to_string(),
+                    url: url.to_string(),
+                    status: status.parse().map_err(|_| invalid(&header))?,
+                    request: block(&mut reader, request_length)?,
+                    response: block(&mut reader, response_length)?,
+                },
+                _ => return Err(invalid(&header)),
+            };
+            events.push(event);
+        }
+        Ok(Cassette { events })
+    }
+
+    /// Write the cassette in a line based format, with the raw data of each event.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for event in &self.events {
+            match *event {
+                Event::SsdpSent { ref to, ref data } => {
+                    writeln!(writer, "ssdp-sent {} {}", to, data.len())?;
+                    writer.write_all(data)?;
+                }
+                Event::SsdpReceived { ref from, ref data } => {
+                    writeln!(writer, "ssdp-received {} {}", from, data.len())?;
+                    writer.write_all(data)?;
+                }
+                Event::Http {
+                    ref method,
+                    ref url,
+                    ref request,
+                    status,
+                    ref response,
+                } => {
+                    writeln!(
+                        writer,
+                        "http {} {} {} {} {}",
+                        method,
+                        url,
+                        status,
+                        request.len(),
+                        response.len()
+                    )?;
+                    writer.write_all(request)?;
+                    writer.write_all(b"\n")?;
+                    writer.write_all(response)?;
+                }
+            }
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+enum Mode {
+    Record(Vec<Event>),
+    Replay {
+        ssdp: VecDeque<(SocketAddr, Vec<u8>)>,
+        http: Vec<Option<Event>>,
+    },
+}
+
+thread_local! {
+    static SESSION: RefCell<Option<Mode>> = const { RefCell::new(None) };
+}
+
+fn with_session<R, F: FnOnce(&mut Option<Mode>) -> R>(f: F) -> R {
+    SESSION.with(|session| f(&mut session.borrow_mut()))
+}
+
+/// A recording or replaying session, active until it is finished or dropped.
+pub struct Session {
+    // Sessions are bound to the thread that started them.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Session {
+    /// End the session and return the events recorded, or left unused when replaying.
+    pub fn finish(self) -> Cassette {
+        let events = match with_session(Option::take) {
+            Some(Mode::Record(events)) => events,
+            Some(Mode::Replay { ssdp, http }) => ssdp
+                .into_iter()
+                .map(|(from, data)| Event::SsdpReceived { from, data })
+                .chain(http.into_iter().flatten())
+                .collect(),
+            None => Vec::new(),
+        };
+        Cassette { events }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        with_session(Option::take);
+    }
+}
+
+/// Start recording, replacing any active session.
+pub fn record() -> Session {
+    with_session(|session| *session = Some(Mode::Record(Vec::new())));
+    Session { _not_send: PhantomData }
+}
+
+/// Start replaying the given cassette, replacing any active session.
+///
+/// Search responses are returned in order. HTTP requests are answered with the first unused
+/// recorded exchange with the same method and url, or fail if there is none.
+pub fn replay(cassette: Cassette) -> Session {
+    let mut ssdp = VecDeque::new();
+    let mut http = Vec::new();
+    for event in cassette.events {
+        match event {
+            Event::SsdpSent { .. } => {}
+            Event::SsdpReceived { from, data } => ssdp.push_back((from, data)),
+            event @ Event::Http { .. } => http.push(Some(event)),
+        }
+    }
+    with_session(|session| *session = Some(Mode::Replay { ssdp, http }));
+    Session { _not_send: PhantomData }
+}
+
+/// Perform an HTTP exchange through the active session, if any.
+pub(crate) fn http<E, F>(method: &str, url: &str, request: &[u8], send: F) -> Result<(u16, Vec<u8>), E>
+where
+    E: From<io::Error>,
+    F: FnOnce() -> Result<(u16, Vec<u8>), E>,
+{
+    let replayed = with_session(|session| match *session {
+        Some(Mode::Replay { ref mut http, .. }) => Some(
+            http.iter_mut()
+                .find(|event| match **event {
+                    Some(Event::Http {
+                        method: ref m,
+                        url: ref u,
+                        ..
+                    }) => m == method && u == url,
+                    _ => false,
+                })
+                .and_then(Option::take),
+        ),
+        _ => None,
+    });
+    match replayed {
+        Some(Some(Event::Http { status, response, .. })) => return Ok((status, response)),
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No recorded response for {} {}", method, url),
+            )
+            .into())
+        }
+        None => {}
+    }
+
+    let (status, response) = send()?;
+    with_session(|session| {
+        if let Some(Mode::Record(ref mut events)) = *session {
+            events.push(Event::Http {
+                method: method.to_string(),
+                url: url.to_string(),
+                request: request.to_vec(),
+                status,
+                response: response.clone(),
+            });
+        }
+    });
+    Ok((status, response))
+}
+
+/// A search transport going through the active session.
+pub(crate) struct Transport<'a, T: ?Sized> {
+    inner: &'a T,
+    timeout: Cell<Option<Duration>>,
+}
+
+impl<'a, T: ?Sized> Transport<'a, T> {
+    pub fn new(inner: &'a T) -> Transport<'a, T> {
+        Transport {
+            inner,
+            timeout: Cell::new(None),
+        }
+    }
+}
+
+impl<'a, T: SearchTransport + ?Sized> SearchTransport for Transport<'a, T> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let replaying = with_session(|session| match *session {
+            Some(Mode::Replay { .. }) => true,
+            Some(Mode::Record(ref mut events)) => {
+                events.push(Event::SsdpSent {
+                    to: addr,
+                    data: buf.to_vec(),
+                });
+                false
+            }
+            None => false,
+        });
+        if replaying {
+            return Ok(buf.len());
+        }
+        self.inner.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let replayed = with_session(|session| match *session {
+            Some(Mode::Replay { ref mut ssdp, .. }) => Some(ssdp.pop_front()),
+            _ => None,
+        });
+        match replayed {
+            Some(Some((from, data))) => {
+                let read = data.len().min(buf.len());
+                buf[..read].copy_from_slice(&data[..read]);
+                return Ok((read, from));
+            }
+            Some(None) => {
+                // Nothing more was received during the recording, wait like the socket did.
+                if let Some(timeout) = self.timeout.get() {
+                    thread::sleep(timeout);
+                }
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            None => {}
+        }
+
+        let (read, from) = self.inner.recv_from(buf)?;
+        with_session(|session| {
+            if let Some(Mode::Record(ref mut events)) = *session {
+                events.push(Event::SsdpReceived {
+                    from,
+                    data: buf[..read].to_vec(),
+                });
+            }
+        });
+        Ok((read, from))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout.set(timeout);
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[test]
+fn test_read_write() {
+    let cassette = Cassette {
+        events: vec![
+            Event::SsdpSent {
+                to: "239.255.255.250:1900".parse().unwrap(),
+                data: b"M-SEARCH * HTTP/1.1\r\n\r\n".to_vec(),
+            },
+            Event::SsdpReceived {
+                from: "192.168.1.1:1900".parse().unwrap(),
+                data: b"HTTP/1.1 200 OK\r\n\r\n".to_vec(),
+            },
+            Event::Http {
+                method: "POST".into(),
+                url: "http://192.168.1.1:5000/ctl/IPConn".into(),
+                request: b"<s:Envelope/>\n".to_vec(),
+                status: 500,
+                response: Vec::new(),
+            },
+        ],
+    };
+    let mut written = Vec::new();
+    cassette.write_to(&mut written).unwrap();
+    assert_eq!(Cassette::read_from(&written[..]).unwrap(), cassette);
+    assert!(Cassette::read_from(&b"tape 1\n"[..]).is_err());
+}
+
+#[test]
+fn test_replay() {
+    let http = |url: &str, response: &str| Event::Http {
+        method: "GET".into(),
+        url: url.into(),
+        request: Vec::new(),
+        status: 200,
+        response: response.as_bytes().to_vec(),
+    };
+    let _session = replay(Cassette {
+        events: vec![
+            Event::SsdpReceived {
+                from: "192.0.2.1:1900".parse().unwrap(),
+                data: b"HTTP/1.1 200 OK\r\nLOCATION: http://192.0.2.1:5000/rootDesc.xml\r\n\r\n".to_vec(),
+            },
+            http(
+                "http://192.0.2.1:5000/rootDesc.xml",
+                r#"<root><device><friendlyName>Replayed</friendlyName><serviceList><service>
+                <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+                <SCPDURL>/scpd.xml</SCPDURL><controlURL>/ctl</controlURL>
+                </service></serviceList></device></root>"#,
+            ),
+            http(
+                "http://192.0.2.1:5000/scpd.xml",
+                "<scpd><actionList></actionList></scpd>",
+            ),
+        ],
+    });
+    let gateway = crate::search_gateway(Default::default()).unwrap();
+    assert_eq!(gateway.device_info.friendly_name, "Replayed");
+    assert_eq!(gateway.control_url, "/ctl");
+}
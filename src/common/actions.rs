@@ -0,0 +1,1017 @@
+//! The actions of a gateway, written once as futures for `Gateway` and `aio::Gateway`.
+//!
+//! Both gateways implement `Control`, the requests and the waiting, and their methods are thin
+//! facades over the functions here: the async one awaits them, the blocking one drives them with
+//! `block_on`. Its futures never wait for a wakeup, its requests block inside `poll`.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddrV4, SocketAddrV6};
+use std::ops::RangeInclusive;
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use crate::common::parsing::{
+    self, ConnectionStatus, MappedPort, PortMappingEntry, PortMappingRequest, RequestResult, StatusInfo, TrafficStats,
+};
+use crate::common::{self, messages, AnyPortOptions, IpCache, MappingFilter};
+use crate::description::{RootDescription, ServiceDescription, WanConnection};
+use crate::errors::{
+    AddAnyPortError, AddPortError, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError, RequestError,
+};
+use crate::quirks::Quirks;
+use crate::PortMappingProtocol;
+
+/// A boxed future, so traits returning one can be used as objects, e.g. `auto::PortMapper`.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The fields of a gateway the actions read.
+pub struct Settings<'a> {
+    #[cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+    pub addr: SocketAddrV4,
+    pub control_url: &'a str,
+    pub control_schema: &'a HashMap<String, Vec<String>>,
+    pub common_interface_control_url: Option<&'a str>,
+    pub firewall_control_url: Option<&'a str>,
+    pub description: &'a RootDescription,
+    pub quirks: Quirks,
+    pub allow_third_party: bool,
+    pub permanent_lease_fallback: bool,
+    pub validate_arguments: bool,
+    pub external_ip_cache: &'a IpCache,
+}
+
+/// What a gateway provides for the actions to run on it.
+pub trait Control: fmt::Display + Sync {
+    fn settings(&self) -> Settings<'_>;
+
+    /// Send a request to the control point at `control_url`, with its retries and context.
+    fn request_at<'a>(
+        &'a self,
+        control_url: &'a str,
+        header: &'a str,
+        body: &'a str,
+        ok: &'a str,
+    ) -> BoxFuture<'a, RequestResult>;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    /// Map every request as `map_port` does, several at once.
+    fn map_batch<'a>(
+        &'a self,
+        requests: &'a [PortMappingRequest],
+    ) -> BoxFuture<'a, Vec<Result<MappedPort, AddPortError>>>;
+
+    /// Remove every mapping as `remove_port` does, several at once.
+    fn remove_batch<'a>(
+        &'a self,
+        mappings: &'a [(PortMappingProtocol, u16)],
+    ) -> BoxFuture<'a, Vec<Result<(), RemovePortError>>>;
+}
+
+/// Run `future` to completion on the calling thread.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Arguments of `action` in the order they are sent, from the SCPD, or from the UPnP
+/// specification without `validate_arguments`.
+fn schema<'a>(settings: &Settings<'a>, action: &str) -> Result<Cow<'a, [String]>, RequestError> {
+    if !settings.validate_arguments {
+        return Ok(Cow::Owned(
+            settings
+                .quirks
+                .schema(action, &messages::standard_arguments(action))
+                .into_owned(),
+        ));
+    }
+    let schema = settings
+        .control_schema
+        .get(action)
+        .ok_or_else(|| RequestError::UnsupportedAction(action.to_string()))?;
+    Ok(settings.quirks.schema(action, schema))
+}
+
+async fn perform_request<C: Control>(gateway: &C, header: &str, body: &str, ok: &str) -> RequestResult {
+    gateway
+        .request_at(gateway.settings().control_url, header, body, ok)
+        .await
+}
+
+pub async fn get_external_ip<C: Control>(gateway: &C) -> Result<Ipv4Addr, GetExternalIpError> {
+    let ip = parsing::parse_get_external_ip_response(
+        perform_request(
+            gateway,
+            messages::GET_EXTERNAL_IP_HEADER,
+            &messages::format_get_external_ip_message(messages::WAN_IP_CONNECTION_SERVICE),
+            "GetExternalIPAddressResponse",
+        )
+        .await,
+    )?;
+    #[cfg(feature = "prometheus")]
+    crate::metrics::record_external_ip(gateway.settings().addr, ip);
+    Ok(ip)
+}
+
+pub async fn external_ip_cached<C: Control>(gateway: &C, ttl: Duration) -> Result<Ipv4Addr, GetExternalIpError> {
+    let cache = gateway.settings().external_ip_cache;
+    if let Some(ip) = cache.get(ttl) {
+        return Ok(ip);
+    }
+    let ip = get_external_ip(gateway).await?;
+    cache.set(ip);
+    Ok(ip)
+}
+
+pub async fn get_status_info<C: Control>(gateway: &C) -> Result<StatusInfo, RequestError> {
+    parsing::parse_get_status_info_response(
+        perform_request(
+            gateway,
+            &messages::format_action_header(messages::WAN_IP_CONNECTION_SERVICE, "GetStatusInfo"),
+            &messages::format_get_status_info_message(messages::WAN_IP_CONNECTION_SERVICE),
+            "GetStatusInfoResponse",
+        )
+        .await,
+    )
+}
+
+pub async fn wait_for_connected<C: Control>(gateway: &C, timeout: Duration) -> Result<StatusInfo, RequestError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let result = get_status_info(gateway).await;
+        match result {
+            Ok(ref status) if status.connection_status == ConnectionStatus::Connected => return result,
+            Ok(ref status) => debug!("WAN connection of {} is {}", gateway, status.connection_status),
+            Err(ref e) if e.is_permanent() => return result,
+            Err(ref e) => debug!("getting the status of {} failed: {}", gateway, e),
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(common::not_connected(result));
+        }
+        gateway.sleep(common::STATUS_POLL_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+pub async fn get_traffic_stats<C: Control>(gateway: &C) -> Result<TrafficStats, RequestError> {
+    Ok(TrafficStats {
+        bytes_sent: get_counter(gateway, "GetTotalBytesSent", "NewTotalBytesSent").await?,
+        bytes_received: get_counter(gateway, "GetTotalBytesReceived", "NewTotalBytesReceived").await?,
+        packets_sent: get_counter(gateway, "GetTotalPacketsSent", "NewTotalPacketsSent").await?,
+        packets_received: get_counter(gateway, "GetTotalPacketsReceived", "NewTotalPacketsReceived").await?,
+    })
+}
+
+pub async fn get_counter<C: Control>(gateway: &C, action: &str, field: &str) -> Result<u64, RequestError> {
+    let control_url = gateway
+        .settings()
+        .common_interface_control_url
+        .ok_or_else(|| RequestError::UnsupportedAction(action.to_string()))?;
+    let service_type = messages::WAN_COMMON_INTERFACE_CONFIG_SERVICE;
+    parsing::parse_counter_response(
+        gateway
+            .request_at(
+                control_url,
+                &messages::format_action_header(service_type, action),
+                &messages::format_no_arguments_message(service_type, action),
+                &format!("{}Response", action),
+            )
+            .await,
+        field,
+    )
+}
+
+pub async fn call_action<C: Control>(
+    gateway: &C,
+    service: &ServiceDescription,
+    action: &str,
+    arguments: &[(&str, String)],
+) -> Result<HashMap<String, String>, RequestError> {
+    parsing::parse_action_response(
+        gateway
+            .request_at(
+                &service.control_url,
+                &messages::format_action_header(&service.service_type, action),
+                &messages::format_action_message(&service.service_type, action, arguments),
+                &format!("{}Response", action),
+            )
+            .await,
+    )
+}
+
+pub async fn default_wan_connection<C: Control>(gateway: &C) -> Result<Option<WanConnection>, RequestError> {
+    let action = "GetDefaultConnectionService";
+    let service_type = messages::LAYER3_FORWARDING_SERVICE;
+    let description = gateway.settings().description;
+    let service = description
+        .device
+        .find_service(service_type)
+        .ok_or_else(|| RequestError::UnsupportedAction(action.to_string()))?;
+    let name: String = parsing::parse_field(
+        gateway
+            .request_at(
+                &service.control_url,
+                &messages::format_action_header(service_type, action),
+                &messages::format_no_arguments_message(service_type, action),
+                &format!("{}Response", action),
+            )
+            .await,
+        "NewDefaultConnectionService",
+    )?;
+    Ok(description
+        .wan_connections()
+        .into_iter()
+        .find(|connection| connection.is_named(&name)))
+}
+
+/// The DeviceConfig service of TR-064 or of UPnP of the gateway described by `description`.
+pub fn device_config_service(description: &RootDescription) -> Option<ServiceDescription> {
+    messages::DEVICE_CONFIG_SERVICES
+        .iter()
+        .find_map(|service_type| description.device.find_service(service_type))
+        .cloned()
+}
+
+async fn device_config_action<C: Control>(
+    gateway: &C,
+    action: &str,
+    arguments: &[(&str, String)],
+) -> Result<HashMap<String, String>, RequestError> {
+    let service = device_config_service(gateway.settings().description)
+        .ok_or_else(|| RequestError::UnsupportedAction(action.to_string()))?;
+    call_action(gateway, &service, action, arguments).await
+}
+
+pub async fn reboot<C: Control>(gateway: &C) -> Result<(), RequestError> {
+    device_config_action(gateway, "Reboot", &[]).await?;
+    Ok(())
+}
+
+pub async fn configuration_started<C: Control>(gateway: &C, session_id: &str) -> Result<(), RequestError> {
+    device_config_action(
+        gateway,
+        "ConfigurationStarted",
+        &[("NewSessionID", session_id.to_string())],
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn configuration_finished<C: Control>(gateway: &C) -> Result<bool, RequestError> {
+    let response = device_config_action(gateway, "ConfigurationFinished", &[]).await?;
+    Ok(response
+        .get("NewStatus")
+        .is_some_and(|status| status == "RebootRequired"))
+}
+
+pub async fn get_persistent_data<C: Control>(gateway: &C) -> Result<String, RequestError> {
+    let mut response = device_config_action(gateway, "GetPersistentData", &[]).await?;
+    response
+        .remove("NewPersistentData")
+        .ok_or_else(|| RequestError::InvalidResponse("NewPersistentData is missing".to_string()))
+}
+
+pub async fn get_any_address_with<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    options: &AnyPortOptions,
+) -> Result<SocketAddrV4, AddAnyPortError> {
+    let ip = get_external_ip(gateway).await?;
+    let port = add_any_port_with(gateway, protocol, local_addr, lease_duration, description, options).await?;
+    Ok(SocketAddrV4::new(ip, port))
+}
+
+pub async fn add_any_port_with<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    options: &AnyPortOptions,
+) -> Result<u16, AddAnyPortError> {
+    map_any_port_with(gateway, protocol, local_addr, lease_duration, description, options)
+        .await
+        .map(|mapped| mapped.external_port)
+}
+
+pub async fn map_any_port_with<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    options: &AnyPortOptions,
+) -> Result<MappedPort, AddAnyPortError> {
+    let settings = gateway.settings();
+    let lease_duration = settings.quirks.lease_duration(lease_duration);
+    match try_add_any_port(gateway, protocol, local_addr, lease_duration, description, options).await {
+        Ok(external_port) => Ok(MappedPort {
+            external_port,
+            lease_duration,
+        }),
+        Err(AddAnyPortError::OnlyPermanentLeasesSupported)
+            if settings.permanent_lease_fallback && lease_duration != 0 =>
+        {
+            debug!(
+                "{} only supports permanent leases, retrying with lease duration 0",
+                gateway
+            );
+            let external_port = try_add_any_port(gateway, protocol, local_addr, 0, description, options).await?;
+            Ok(MappedPort {
+                external_port,
+                lease_duration: 0,
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn try_add_any_port<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    options: &AnyPortOptions,
+) -> Result<u16, AddAnyPortError> {
+    // This function first attempts to call AddAnyPortMapping on the IGD with a random port
+    // number. If that fails due to the method being unknown it attempts to call AddPortMapping
+    // instead with a random port number. If that fails due to ConflictInMappingEntry it retrys
+    // with another port up to `options.attempts` times. If it fails due to
+    // SamePortValuesRequired it retrys once with the same port values. Gateways known to only
+    // map the same port are asked for it right away.
+
+    let settings = gateway.settings();
+    if local_addr.port() == 0 {
+        return Err(AddAnyPortError::InternalPortZeroInvalid);
+    }
+    if !settings.allow_third_party && !common::is_local_address(*local_addr.ip()) {
+        return Err(AddAnyPortError::InternalClientNotLocal);
+    }
+    if settings.quirks.description(description).len() > common::MAX_DESCRIPTION_LEN {
+        return Err(AddAnyPortError::DescriptionTooLong);
+    }
+    if settings.quirks.same_port_only {
+        return add_same_port_mapping(gateway, protocol, local_addr, lease_duration, description).await;
+    }
+    let external_port =
+        common::random_port(&options.ports, &HashSet::new()).ok_or(AddAnyPortError::NoPortsAvailable)?;
+
+    if settings.control_schema.contains_key("AddAnyPortMapping") {
+        let port = add_any_port_mapping(
+            gateway,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+            true,
+        )
+        .await
+        .map_err(parsing::convert_add_any_port_error)?;
+        if options.ports.contains(&port) {
+            return Ok(port);
+        }
+        debug!("{} reserved port {} outside of {:?}", gateway, port, options.ports);
+        if let Err(e) = remove_port(gateway, protocol, port).await {
+            debug!("removing the mapping of port {} failed: {}", port, e);
+        }
+    }
+    retry_add_random_port_mapping(gateway, protocol, local_addr, lease_duration, description, options).await
+}
+
+async fn add_any_port_mapping<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    enabled: bool,
+) -> Result<u16, RequestError> {
+    let settings = gateway.settings();
+    parsing::parse_reserved_port(
+        perform_request(
+            gateway,
+            messages::ADD_ANY_PORT_MAPPING_HEADER,
+            &messages::format_add_any_port_mapping_message(
+                messages::WAN_IP_CONNECTION_SERVICE,
+                &schema(&settings, "AddAnyPortMapping")?,
+                protocol,
+                external_port,
+                local_addr,
+                settings.quirks.lease_duration(lease_duration),
+                settings.quirks.description(description),
+                enabled,
+            ),
+            "AddAnyPortMappingResponse",
+        )
+        .await,
+    )
+}
+
+async fn retry_add_random_port_mapping<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    options: &AnyPortOptions,
+) -> Result<u16, AddAnyPortError> {
+    let mut taken = if options.skip_mapped {
+        mapped_ports(gateway, protocol).await
+    } else {
+        HashSet::new()
+    };
+    for _ in 0..options.attempts {
+        let external_port = common::random_port(&options.ports, &taken).ok_or(AddAnyPortError::NoPortsAvailable)?;
+        match add_random_port_mapping(
+            gateway,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        )
+        .await
+        {
+            Ok(port) => return Ok(port),
+            Err(AddAnyPortError::NoPortsAvailable) => {
+                taken.insert(external_port);
+            }
+            e => return e,
+        }
+    }
+
+    Err(AddAnyPortError::NoPortsAvailable)
+}
+
+/// The external ports the gateway has mapped for `protocol`, none if they can't be listed.
+async fn mapped_ports<C: Control>(gateway: &C, protocol: PortMappingProtocol) -> HashSet<u16> {
+    match get_port_mappings(gateway).await {
+        Ok(entries) => entries
+            .into_iter()
+            .filter(|entry| entry.protocol == protocol)
+            .map(|entry| entry.external_port)
+            .collect(),
+        Err(e) => {
+            debug!("listing the mappings of {} failed: {}", gateway, e);
+            HashSet::new()
+        }
+    }
+}
+
+async fn add_random_port_mapping<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+) -> Result<u16, AddAnyPortError> {
+    let result = add_port_mapping(
+        gateway,
+        protocol,
+        external_port,
+        local_addr,
+        lease_duration,
+        description,
+        true,
+    )
+    .await;
+    match result {
+        Ok(()) => Ok(external_port),
+        Err(err) => match parsing::convert_add_random_port_mapping_error(err) {
+            Some(err) => Err(err),
+            None => add_same_port_mapping(gateway, protocol, local_addr, lease_duration, description).await,
+        },
+    }
+}
+
+async fn add_same_port_mapping<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+) -> Result<u16, AddAnyPortError> {
+    match add_port_mapping(
+        gateway,
+        protocol,
+        local_addr.port(),
+        local_addr,
+        lease_duration,
+        description,
+        true,
+    )
+    .await
+    {
+        Ok(_) => Ok(local_addr.port()),
+        Err(e) => Err(parsing::convert_add_same_port_mapping_error(e)),
+    }
+}
+
+async fn add_port_mapping<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    enabled: bool,
+) -> Result<(), RequestError> {
+    let settings = gateway.settings();
+    perform_request(
+        gateway,
+        messages::ADD_PORT_MAPPING_HEADER,
+        &messages::format_add_port_mapping_message(
+            messages::WAN_IP_CONNECTION_SERVICE,
+            &schema(&settings, "AddPortMapping")?,
+            protocol,
+            external_port,
+            local_addr,
+            settings.quirks.lease_duration(lease_duration),
+            settings.quirks.description(description),
+            enabled,
+        ),
+        "AddPortMappingResponse",
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn set_port_enabled<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    enabled: bool,
+) -> Result<(), RequestError> {
+    let entry = get_specific_port_mapping_entry(gateway, protocol, external_port).await?;
+    if entry.enabled == enabled {
+        return Ok(());
+    }
+    add_port_mapping(
+        gateway,
+        protocol,
+        external_port,
+        entry.internal_addr()?,
+        entry.lease_duration,
+        &entry.port_mapping_description,
+        enabled,
+    )
+    .await
+}
+
+pub async fn update_port<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+) -> Result<MappedPort, AddPortError> {
+    let old = get_specific_port_mapping_entry(gateway, protocol, external_port)
+        .await
+        .map_err(parsing::convert_add_port_error)?;
+    let update = || {
+        map_port_enabled(
+            gateway,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+            old.enabled,
+        )
+    };
+    match update().await {
+        // The gateway doesn't overwrite mappings of another internal client.
+        Err(AddPortError::PortInUse) => {}
+        result => return result,
+    }
+    match remove_port(gateway, protocol, external_port).await {
+        // Gone meanwhile, e.g. expired, so adding it is all that's left.
+        Ok(()) | Err(RemovePortError::NoSuchPortMapping) => {}
+        Err(RemovePortError::ActionNotAuthorized) => return Err(AddPortError::ActionNotAuthorized),
+        Err(RemovePortError::RequestError(e)) => return Err(AddPortError::RequestError(e)),
+    }
+    let e = match update().await {
+        Ok(mapped) => return Ok(mapped),
+        Err(e) => e,
+    };
+    let restored = match old.internal_addr() {
+        Ok(old_addr) => {
+            add_port_mapping(
+                gateway,
+                protocol,
+                external_port,
+                old_addr,
+                old.lease_duration,
+                &old.port_mapping_description,
+                old.enabled,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(restore_error) = restored {
+        debug!(
+            "restoring the mapping of {} port {} failed: {}",
+            protocol, external_port, restore_error
+        );
+    }
+    Err(e)
+}
+
+pub async fn map_port_enabled<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    enabled: bool,
+) -> Result<MappedPort, AddPortError> {
+    let settings = gateway.settings();
+    if external_port == 0 && !settings.control_schema.contains_key("AddAnyPortMapping") {
+        return Err(AddPortError::ExternalPortZeroInvalid);
+    }
+    if local_addr.port() == 0 {
+        return Err(AddPortError::InternalPortZeroInvalid);
+    }
+    if !settings.allow_third_party && !common::is_local_address(*local_addr.ip()) {
+        return Err(AddPortError::InternalClientNotLocal);
+    }
+    if settings.quirks.description(description).len() > common::MAX_DESCRIPTION_LEN {
+        return Err(AddPortError::DescriptionTooLong);
+    }
+
+    let lease_duration = settings.quirks.lease_duration(lease_duration);
+    match add_mapping(
+        gateway,
+        protocol,
+        external_port,
+        local_addr,
+        lease_duration,
+        description,
+        enabled,
+    )
+    .await
+    {
+        Ok(external_port) => Ok(MappedPort {
+            external_port,
+            lease_duration,
+        }),
+        Err(ref e)
+            if matches!(e.inner(), RequestError::OnlyPermanentLeasesSupported)
+                && settings.permanent_lease_fallback
+                && lease_duration != 0 =>
+        {
+            debug!(
+                "{} only supports permanent leases, retrying with lease duration 0",
+                gateway
+            );
+            let external_port = add_mapping(gateway, protocol, external_port, local_addr, 0, description, enabled)
+                .await
+                .map_err(parsing::convert_add_port_error)?;
+            Ok(MappedPort {
+                external_port,
+                lease_duration: 0,
+            })
+        }
+        Err(e) => Err(parsing::convert_add_port_error(e)),
+    }
+}
+
+/// Add a mapping for `external_port`, or for a port chosen by the gateway if it is 0.
+async fn add_mapping<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    enabled: bool,
+) -> Result<u16, RequestError> {
+    if external_port == 0 {
+        return add_any_port_mapping(gateway, protocol, 0, local_addr, lease_duration, description, enabled).await;
+    }
+    add_port_mapping(
+        gateway,
+        protocol,
+        external_port,
+        local_addr,
+        lease_duration,
+        description,
+        enabled,
+    )
+    .await?;
+    Ok(external_port)
+}
+
+pub async fn remove_port<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+) -> Result<(), RemovePortError> {
+    let schema = schema(&gateway.settings(), "DeletePortMapping").map_err(RemovePortError::RequestError)?;
+    parsing::parse_delete_port_mapping_response(
+        perform_request(
+            gateway,
+            messages::DELETE_PORT_MAPPING_HEADER,
+            &messages::format_delete_port_message(
+                messages::WAN_IP_CONNECTION_SERVICE,
+                &schema,
+                protocol,
+                external_port,
+            ),
+            "DeletePortMappingResponse",
+        )
+        .await,
+    )
+}
+
+pub async fn add_port_range_with<C: Control, F: FnMut(usize, usize)>(
+    gateway: &C,
+    external_ports: RangeInclusive<u16>,
+    internal_start: SocketAddrV4,
+    protocol: PortMappingProtocol,
+    lease_duration: u32,
+    description: &str,
+    mut progress: F,
+) -> Result<Vec<MappedPort>, AddPortError> {
+    let requests = common::port_range_requests(&external_ports, internal_start, protocol, lease_duration, description)?;
+    let mut mapped = Vec::with_capacity(requests.len());
+    for batch in requests.chunks(common::BATCH_CONCURRENCY) {
+        let mut failed = None;
+        for result in gateway.map_batch(batch).await {
+            match result {
+                Ok(port) => mapped.push(port),
+                Err(e) => failed = failed.or(Some(e)),
+            }
+        }
+        if let Some(e) = failed {
+            let added: Vec<_> = mapped.iter().map(|port| port.external_port).collect();
+            roll_back_port_range(gateway, protocol, &added).await;
+            return Err(e);
+        }
+        progress(mapped.len(), requests.len());
+    }
+    Ok(mapped)
+}
+
+/// Remove the mappings of `added`, the ports of a range that failed to be mapped.
+async fn roll_back_port_range<C: Control>(gateway: &C, protocol: PortMappingProtocol, added: &[u16]) {
+    let results = match (added.first(), added.last()) {
+        (Some(&first), Some(&last)) if gateway.settings().control_schema.contains_key("DeletePortMappingRange") => {
+            vec![remove_port_range(gateway, protocol, first..=last).await]
+        }
+        _ => {
+            let mappings: Vec<_> = added.iter().map(|&port| (protocol, port)).collect();
+            gateway.remove_batch(&mappings).await
+        }
+    };
+    for e in results.into_iter().filter_map(Result::err) {
+        debug!("removing a mapping of the failed port range failed: {}", e);
+    }
+}
+
+pub async fn remove_port_range<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_ports: RangeInclusive<u16>,
+) -> Result<(), RemovePortError> {
+    if gateway.settings().control_schema.contains_key("DeletePortMappingRange") {
+        return parsing::parse_delete_port_mapping_range_response(
+            perform_request(
+                gateway,
+                messages::DELETE_PORT_MAPPING_RANGE_HEADER,
+                &messages::format_delete_port_mapping_range_message(
+                    messages::WAN_IP_CONNECTION_SERVICE,
+                    protocol,
+                    &external_ports,
+                ),
+                "DeletePortMappingRangeResponse",
+            )
+            .await,
+        );
+    }
+    let mappings: Vec<_> = external_ports.map(|port| (protocol, port)).collect();
+    gateway
+        .remove_batch(&mappings)
+        .await
+        .into_iter()
+        .filter(|result| !matches!(result, Err(RemovePortError::NoSuchPortMapping)))
+        .collect()
+}
+
+pub async fn get_generic_port_mapping_entry<C: Control>(
+    gateway: &C,
+    index: u32,
+) -> Result<PortMappingEntry, GetGenericPortMappingEntryError> {
+    parsing::parse_get_generic_port_mapping_entry(
+        perform_request(
+            gateway,
+            messages::GET_GENERIC_PORT_MAPPING_ENTRY,
+            &messages::formate_get_generic_port_mapping_entry_message(messages::WAN_IP_CONNECTION_SERVICE, index),
+            "GetGenericPortMappingEntryResponse",
+        )
+        .await,
+    )
+}
+
+pub async fn get_specific_port_mapping_entry<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+) -> Result<PortMappingEntry, RequestError> {
+    let action = "GetSpecificPortMappingEntry";
+    let service_type = messages::WAN_IP_CONNECTION_SERVICE;
+    let arguments = [
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", external_port.to_string()),
+        ("NewProtocol", protocol.to_string()),
+    ];
+    parsing::parse_get_specific_port_mapping_entry(
+        perform_request(
+            gateway,
+            &messages::format_action_header(service_type, action),
+            &messages::format_action_message(service_type, action, &arguments),
+            "GetSpecificPortMappingEntryResponse",
+        )
+        .await,
+        protocol,
+        external_port,
+    )
+}
+
+pub async fn get_port_mappings<C: Control>(
+    gateway: &C,
+) -> Result<Vec<PortMappingEntry>, GetGenericPortMappingEntryError> {
+    let mut entries = Vec::new();
+    loop {
+        match get_generic_port_mapping_entry(gateway, entries.len() as u32).await {
+            Ok(entry) => entries.push(entry),
+            Err(e) if parsing::is_end_of_port_mappings(&e) => return Ok(entries),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub async fn cleanup_matching<C: Control, F: MappingFilter>(
+    gateway: &C,
+    mut filter: F,
+) -> Result<Vec<PortMappingEntry>, GetGenericPortMappingEntryError> {
+    let mut removed = Vec::new();
+    for entry in get_port_mappings(gateway).await? {
+        if !filter.matches(&entry) {
+            continue;
+        }
+        match remove_port(gateway, entry.protocol, entry.external_port).await {
+            Ok(()) => removed.push(entry),
+            Err(e) => debug!(
+                "removing the mapping {:?} failed: {}",
+                entry.port_mapping_description, e
+            ),
+        }
+    }
+    Ok(removed)
+}
+
+pub async fn add_pinhole<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    internal: SocketAddrV6,
+    lease_time: u32,
+) -> Result<u16, RequestError> {
+    parsing::parse_field(
+        perform_firewall_request(
+            gateway,
+            "AddPinhole",
+            &messages::format_add_pinhole_message(protocol, internal, lease_time),
+        )
+        .await,
+        "UniqueID",
+    )
+}
+
+pub async fn update_pinhole<C: Control>(gateway: &C, unique_id: u16, lease_time: u32) -> Result<(), RequestError> {
+    perform_firewall_request(
+        gateway,
+        "UpdatePinhole",
+        &messages::format_update_pinhole_message(unique_id, lease_time),
+    )
+    .await
+    .map(|_| ())
+}
+
+pub async fn delete_pinhole<C: Control>(gateway: &C, unique_id: u16) -> Result<(), RequestError> {
+    perform_firewall_request(
+        gateway,
+        "DeletePinhole",
+        &messages::format_delete_pinhole_message(unique_id),
+    )
+    .await
+    .map(|_| ())
+}
+
+pub async fn get_outbound_pinhole_timeout<C: Control>(
+    gateway: &C,
+    protocol: PortMappingProtocol,
+    internal: SocketAddrV6,
+) -> Result<Duration, RequestError> {
+    parsing::parse_field(
+        perform_firewall_request(
+            gateway,
+            "GetOutboundPinholeTimeout",
+            &messages::format_get_outbound_pinhole_timeout_message(protocol, internal),
+        )
+        .await,
+        "OutboundPinholeTimeout",
+    )
+    .map(Duration::from_secs)
+}
+
+pub async fn check_pinhole_working<C: Control>(gateway: &C, unique_id: u16) -> Result<bool, RequestError> {
+    parsing::parse_bool_field(
+        perform_firewall_request(
+            gateway,
+            "CheckPinholeWorking",
+            &messages::format_check_pinhole_working_message(unique_id),
+        )
+        .await,
+        "IsWorking",
+    )
+}
+
+pub async fn get_pinhole_packets<C: Control>(gateway: &C, unique_id: u16) -> Result<u32, RequestError> {
+    parsing::parse_field(
+        perform_firewall_request(
+            gateway,
+            "GetPinholePackets",
+            &messages::format_get_pinhole_packets_message(unique_id),
+        )
+        .await,
+        "PinholePackets",
+    )
+}
+
+async fn perform_firewall_request<C: Control>(gateway: &C, action: &str, body: &str) -> RequestResult {
+    let control_url = gateway
+        .settings()
+        .firewall_control_url
+        .ok_or_else(|| RequestError::UnsupportedAction(action.to_string()))?;
+    gateway
+        .request_at(
+            control_url,
+            &messages::format_action_header(messages::WAN_IPV6_FIREWALL_CONTROL_SERVICE, action),
+            body,
+            &format!("{}Response", action),
+        )
+        .await
+}
+
+#[test]
+fn test_block_on() {
+    assert_eq!(block_on(async { 42 }), 42);
+
+    // Woken from another thread.
+    let (sender, receiver) = std::sync::mpsc::channel::<Waker>();
+    let waker = thread::spawn(move || receiver.recv().unwrap().wake());
+    let mut sender = Some(sender);
+    let output = block_on(std::future::poll_fn(|context| match sender.take() {
+        Some(sender) => {
+            sender.send(context.waker().clone()).unwrap();
+            Poll::Pending
+        }
+        None => Poll::Ready("woken"),
+    }));
+    assert_eq!(output, "woken");
+    waker.join().unwrap();
+}
@@ -0,0 +1,202 @@
+//! The description and SCPD documents of the devices, kept for as long as the search response or
+//! announcement that led to them allows (`CACHE-CONTROL: max-age`), so searching again doesn't
+//! fetch them again from the small HTTP server of the router.
+//!
+//! The parsed SCPDs are also kept by device model, which doesn't change them, so finding the
+//! same router again or several identical access points parses each one once, and with a
+//! directory set by `set_schema_dir` they are written there to outlive the process.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::common::parsing::DeviceInfo;
+
+/// Parsed SCPD, the arguments of each action by name.
+pub type Schemas = HashMap<String, Vec<String>>;
+
+/// Documents by url, with when they expire.
+static DOCUMENTS: Mutex<Vec<(String, Instant, Vec<u8>)>> = Mutex::new(Vec::new());
+
+/// The cached document at `url`, unless it expired.
+pub fn get(url: &str) -> Option<Vec<u8>> {
+    let now = Instant::now();
+    let documents = DOCUMENTS.lock().unwrap_or_else(|e| e.into_inner());
+    documents
+        .iter()
+        .find(|(cached, expires, _)| cached == url && *expires > now)
+        .map(|(_, _, document)| document.clone())
+}
+
+/// Cache the document at `url` for `max_age`, dropping the expired ones.
+pub fn insert(url: String, document: Vec<u8>, max_age: Duration) {
+    let now = Instant::now();
+    let mut documents = DOCUMENTS.lock().unwrap_or_else(|e| e.into_inner());
+    documents.retain(|(cached, expires, _)| *cached != url && *expires > now);
+    if !max_age.is_zero() {
+        documents.push((url, now + max_age, document));
+    }
+}
+
+/// Drop the cached document at `url`, so it is fetched again.
+pub fn remove(url: &str) {
+    DOCUMENTS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|(cached, _, _)| cached != url);
+}
+
+/// Parsed SCPDs by `schema_key`.
+static SCHEMAS: Mutex<Vec<(String, Arc<Schemas>)>> = Mutex::new(Vec::new());
+
+/// Directory the parsed SCPDs of known models are written to, if any.
+static SCHEMA_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Drop all cached documents and parsed SCPDs, keeping the files of the SCPD directory.
+pub fn clear() {
+    DOCUMENTS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    SCHEMAS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+/// Keep the parsed SCPDs in `dir`, or only in memory with `None`.
+pub fn set_schema_dir(dir: Option<PathBuf>) {
+    *SCHEMA_DIR.lock().unwrap_or_else(|e| e.into_inner()) = dir;
+}
+
+/// Key of the SCPD at `path` of the device at `addr` described by `device_info`.
+///
+/// Devices of the same model, including the `SERVER` header which usually names the firmware,
+/// share the key. Without a model name, only the device at `addr` has it.
+pub fn schema_key(device_info: &DeviceInfo, addr: SocketAddr, path: &str) -> String {
+    if device_info.model_name.is_empty() {
+        return format!("http://{}{}", addr, path);
+    }
+    format!(
+        "{}|{}|{}|{}|{}",
+        device_info.manufacturer, device_info.model_name, device_info.model_number, device_info.server, path
+    )
+}
+
+/// The parsed SCPD with `key`, from memory or from the SCPD directory.
+pub fn get_schemas(key: &str) -> Option<Arc<Schemas>> {
+    let cached = SCHEMAS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|(cached, _)| cached == key)
+        .map(|(_, schemas)| schemas.clone());
+    if cached.is_some() {
+        return cached;
+    }
+    let schemas = Arc::new(read_schemas(key)?);
+    SCHEMAS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((key.to_string(), schemas.clone()));
+    Some(schemas)
+}
+
+/// Keep the parsed SCPD with `key`, writing it to the SCPD directory if the key is a model's.
+pub fn insert_schemas(key: String, schemas: Schemas) {
+    if !key.starts_with("http://") {
+        write_schemas(&key, &schemas);
+    }
+    let mut cached = SCHEMAS.lock().unwrap_or_else(|e| e.into_inner());
+    cached.retain(|(cached, _)| *cached != key);
+    cached.push((key, Arc::new(schemas)));
+}
+
+/// File of the SCPD with `key` in the SCPD directory, named by the FNV-1a hash of the key.
+fn schema_file(key: &str) -> Option<PathBuf> {
+    let dir = SCHEMA_DIR.lock().unwrap_or_else(|e| e.into_inner()).clone()?;
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    Some(dir.join(format!("{:016x}.scpd", hash)))
+}
+
+/// Read the SCPD with `key` from its file, the key on the first line and then an action with its
+/// arguments on each line.
+fn read_schemas(key: &str) -> Option<Schemas> {
+    let text = fs::read_to_string(schema_file(key)?).ok()?;
+    let mut lines = text.lines();
+    if lines.next() != Some(key) {
+        return None;
+    }
+    let schemas = lines
+        .filter_map(|line| {
+            let mut names = line.split_whitespace().map(str::to_string);
+            Some((names.next()?, names.collect()))
+        })
+        .collect();
+    Some(schemas)
+}
+
+fn write_schemas(key: &str, schemas: &Schemas) {
+    let path = match schema_file(key) {
+        Some(path) => path,
+        None => return,
+    };
+    let mut text = format!("{}\n", key);
+    for (action, arguments) in schemas {
+        let _ = writeln!(text, "{} {}", action, arguments.join(" "));
+    }
+    if let Err(e) = fs::write(&path, text) {
+        debug!("writing the SCPD to {} failed: {}", path.display(), e);
+    }
+}
+
+#[test]
+fn test_cache() {
+    let url = "http://192.0.2.1:1900/test_cache.xml";
+    assert_eq!(get(url), None);
+    insert(url.to_string(), b"<root/>".to_vec(), Duration::from_secs(60));
+    assert_eq!(get(url), Some(b"<root/>".to_vec()));
+    insert(url.to_string(), b"<root/>".to_vec(), Duration::ZERO);
+    assert_eq!(get(url), None);
+    insert(url.to_string(), b"<root/>".to_vec(), Duration::from_secs(60));
+    remove(url);
+    assert_eq!(get(url), None);
+}
+
+#[test]
+fn test_schemas() {
+    let device_info = DeviceInfo {
+        manufacturer: "Example".to_string(),
+        model_name: "test_schemas".to_string(),
+        server: "Linux UPnP/1.0 MiniUPnPd/2.1".to_string(),
+        ..Default::default()
+    };
+    let addr = "192.0.2.1:5000".parse().unwrap();
+    let key = schema_key(&device_info, addr, "/WANIPCn.xml");
+    assert_eq!(
+        schema_key(&device_info, "192.0.2.2:5000".parse().unwrap(), "/WANIPCn.xml"),
+        key
+    );
+    assert_eq!(
+        schema_key(&DeviceInfo::default(), addr, "/WANIPCn.xml"),
+        "http://192.0.2.1:5000/WANIPCn.xml"
+    );
+
+    let dir = std::env::temp_dir().join(format!("igd-test-schemas-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    set_schema_dir(Some(dir.clone()));
+    let mut schemas = Schemas::new();
+    schemas.insert("GetExternalIPAddress".to_string(), Vec::new());
+    schemas.insert(
+        "DeletePortMapping".to_string(),
+        vec!["NewRemoteHost".to_string(), "NewExternalPort".to_string()],
+    );
+    assert_eq!(get_schemas(&key), None);
+    insert_schemas(key.clone(), schemas.clone());
+    assert_eq!(get_schemas(&key).as_deref(), Some(&schemas));
+    // Only in the directory.
+    SCHEMAS.lock().unwrap().clear();
+    assert_eq!(get_schemas(&key).as_deref(), Some(&schemas));
+    set_schema_dir(None);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -1,13 +1,12 @@
 use crate::PortMappingProtocol;
-use std::net::SocketAddrV4;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::net::{SocketAddrV4, SocketAddrV6};
+use core::ops::RangeInclusive;
 
 // Content of the request.
-pub const SEARCH_REQUEST: &str = "M-SEARCH * HTTP/1.1\r
-Host:239.255.255.250:1900\r
-ST:urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
-Man:\"ssdp:discover\"\r
-MX:3\r\n\r\n";
-
 pub const GET_EXTERNAL_IP_HEADER: &str = r#""urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress""#;
 
 pub const ADD_ANY_PORT_MAPPING_HEADER: &str = r#""urn:schemas-upnp-org:service:WANIPConnection:1#AddAnyPortMapping""#;
@@ -16,9 +15,31 @@ pub const ADD_PORT_MAPPING_HEADER: &str = r#""urn:schemas-upnp-org:service:WANIP
 
 pub const DELETE_PORT_MAPPING_HEADER: &str = r#""urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMapping""#;
 
+pub const DELETE_PORT_MAPPING_RANGE_HEADER: &str =
+    r#""urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMappingRange""#;
+
 pub const GET_GENERIC_PORT_MAPPING_ENTRY: &str =
     r#""urn:schemas-upnp-org:service:WANIPConnection:1#GetGenericPortMappingEntry""#;
 
+pub const WAN_IP_CONNECTION_SERVICE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+pub const WAN_COMMON_INTERFACE_CONFIG_SERVICE: &str = "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1";
+
+pub const WAN_IPV6_FIREWALL_CONTROL_SERVICE: &str = "urn:schemas-upnp-org:service:WANIPv6FirewallControl:1";
+
+pub const LAYER3_FORWARDING_SERVICE: &str = "urn:schemas-upnp-org:service:Layer3Forwarding:1";
+
+/// Types of the DeviceConfig service, of TR-064 and of UPnP.
+pub const DEVICE_CONFIG_SERVICES: &[&str] = &[
+    "urn:dslforum-org:service:DeviceConfig:1",
+    "urn:schemas-upnp-org:service:DeviceConfig:1",
+];
+
+/// Format the SOAPAction header value for an action of the given service.
+pub fn format_action_header(service_type: &str, action: &str) -> String {
+    format!(r#""{}#{}""#, service_type, action)
+}
+
 const MESSAGE_HEAD: &str = r#"<?xml version="1.0"?>
 <s:Envelope s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/" xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
 <s:Body>"#;
@@ -26,140 +47,349 @@ const MESSAGE_HEAD: &str = r#"<?xml version="1.0"?>
 const MESSAGE_TAIL: &str = r#"</s:Body>
 </s:Envelope>"#;
 
-fn format_message(body: String) -> String {
-    format!("{}{}{}", MESSAGE_HEAD, body, MESSAGE_TAIL)
+/// Start a message with the envelope and the start tag of `action`. The arguments are written
+/// right into it, and `finish_message` closes it, so it is built in a single buffer.
+fn start_message(prefix: &str, service_type: &str, action: &str) -> String {
+    let mut message = String::with_capacity(MESSAGE_HEAD.len() + MESSAGE_TAIL.len() + service_type.len() + 512);
+    message.push_str(MESSAGE_HEAD);
+    let _ = writeln!(
+        message,
+        r#"<{}:{} xmlns:{}="{}">"#,
+        prefix, action, prefix, service_type
+    );
+    message
 }
 
-pub fn format_get_external_ip_message() -> String {
-    r#"<?xml version="1.0"?>
-<s:Envelope s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/" xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
-    <s:Body>
-        <m:GetExternalIPAddress xmlns:m="urn:schemas-upnp-org:service:WANIPConnection:1">
-        </m:GetExternalIPAddress>
-    </s:Body>
-</s:Envelope>"#
-    .into()
+fn finish_message(mut message: String, prefix: &str, action: &str) -> String {
+    let _ = write!(message, "</{}:{}>", prefix, action);
+    message.push_str(MESSAGE_TAIL);
+    message
+}
+
+/// Write an argument element, escaping the value.
+fn push_argument(message: &mut String, argument: &str, value: &str) {
+    let _ = write!(message, "<{}>", argument);
+    escape_into(message, value);
+    let _ = writeln!(message, "</{}>", argument);
+}
+
+/// Escape text for an XML element or attribute.
+///
+/// Characters XML 1.0 doesn't allow at all, i.e. control characters other than tab and line
+/// breaks, are dropped, since no escape can carry them.
+#[cfg(test)]
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    escape_into(&mut escaped, text);
+    escaped
+}
+
+/// Escape text like `escape`, appending it to `out`.
+fn escape_into(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c.is_control() || c == '\u{FFFE}' || c == '\u{FFFF}' => {}
+            c => out.push(c),
+        }
+    }
+}
+
+pub fn format_get_external_ip_message(service_type: &str) -> String {
+    finish_message(
+        start_message("m", service_type, "GetExternalIPAddress"),
+        "m",
+        "GetExternalIPAddress",
+    )
+}
+
+pub fn format_get_status_info_message(service_type: &str) -> String {
+    format_no_arguments_message(service_type, "GetStatusInfo")
+}
+
+/// Format the message of an action that takes no arguments.
+pub fn format_no_arguments_message(service_type: &str, action: &str) -> String {
+    finish_message(start_message("u", service_type, action), "u", action)
+}
+
+/// Format the message of an action with the given arguments, in order.
+pub fn format_action_message(service_type: &str, action: &str, arguments: &[(&str, String)]) -> String {
+    let mut message = start_message("u", service_type, action);
+    for (argument, value) in arguments {
+        push_argument(&mut message, argument, value);
+    }
+    finish_message(message, "u", action)
+}
+
+/// Format the message of an action with the arguments of `schema`, in its order. `value` writes
+/// the value of an argument, and fails for the arguments it doesn't know, which are left out.
+fn format_schema_message<F>(service_type: &str, action: &str, schema: &[String], mut value: F) -> String
+where
+    F: FnMut(&mut String, &str) -> fmt::Result,
+{
+    let mut message = start_message("u", service_type, action);
+    for argument in schema {
+        let start = message.len();
+        let _ = write!(message, "<{}>", argument);
+        if value(&mut message, argument).is_err() {
+            warn!("Unknown argument: {}", argument);
+            message.truncate(start);
+            continue;
+        }
+        let _ = writeln!(message, "</{}>", argument);
+    }
+    finish_message(message, "u", action)
+}
+
+/// Write the value of an argument of `AddPortMapping` or `AddAnyPortMapping`.
+#[allow(clippy::too_many_arguments)]
+fn write_mapping_argument(
+    message: &mut String,
+    argument: &str,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    enabled: bool,
+) -> fmt::Result {
+    match argument {
+        "NewEnabled" => write!(message, "{}", enabled as u8),
+        "NewExternalPort" => write!(message, "{}", external_port),
+        "NewInternalClient" => write!(message, "{}", local_addr.ip()),
+        "NewInternalPort" => write!(message, "{}", local_addr.port()),
+        "NewLeaseDuration" => write!(message, "{}", lease_duration),
+        "NewPortMappingDescription" => {
+            escape_into(message, description);
+            Ok(())
+        }
+        "NewProtocol" => write!(message, "{}", protocol),
+        "NewRemoteHost" => Ok(()),
+        _ => Err(fmt::Error),
+    }
+}
+
+/// Arguments of `action` in the order of the UPnP specification, to send it without an SCPD.
+pub fn standard_arguments(action: &str) -> Vec<String> {
+    let arguments: &[&str] = match action {
+        "AddPortMapping" | "AddAnyPortMapping" => &[
+            "NewRemoteHost",
+            "NewExternalPort",
+            "NewProtocol",
+            "NewInternalPort",
+            "NewInternalClient",
+            "NewEnabled",
+            "NewPortMappingDescription",
+            "NewLeaseDuration",
+        ],
+        "DeletePortMapping" => &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+        _ => &[],
+    };
+    arguments.iter().map(|argument| argument.to_string()).collect()
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn format_add_any_port_mapping_message(
+    service_type: &str,
     schema: &[String],
     protocol: PortMappingProtocol,
     external_port: u16,
     local_addr: SocketAddrV4,
     lease_duration: u32,
     description: &str,
+    enabled: bool,
 ) -> String {
-    let args = schema
-        .iter()
-        .filter_map(|argument| {
-            let value = match argument.as_str() {
-                "NewEnabled" => 1.to_string(),
-                "NewExternalPort" => external_port.to_string(),
-                "NewInternalClient" => local_addr.ip().to_string(),
-                "NewInternalPort" => local_addr.port().to_string(),
-                "NewLeaseDuration" => lease_duration.to_string(),
-                "NewPortMappingDescription" => description.to_string(),
-                "NewProtocol" => protocol.to_string(),
-                "NewRemoteHost" => "".to_string(),
-                unknown => {
-                    warn!("Unknown argument: {}", unknown);
-                    return None;
-                }
-            };
-            Some(format!(
-                "<{argument}>{value}</{argument}>",
-                argument = argument,
-                value = value
-            ))
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    format_message(format!(
-        r#"<u:AddAnyPortMapping xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
-        {}
-        </u:AddAnyPortMapping>"#,
-        args,
-    ))
+    format_schema_message(service_type, "AddAnyPortMapping", schema, |message, argument| {
+        write_mapping_argument(
+            message,
+            argument,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+            enabled,
+        )
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn format_add_port_mapping_message(
+    service_type: &str,
     schema: &[String],
     protocol: PortMappingProtocol,
     external_port: u16,
     local_addr: SocketAddrV4,
     lease_duration: u32,
     description: &str,
+    enabled: bool,
+) -> String {
+    format_schema_message(service_type, "AddPortMapping", schema, |message, argument| {
+        write_mapping_argument(
+            message,
+            argument,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+            enabled,
+        )
+    })
+}
+
+pub fn format_delete_port_message(
+    service_type: &str,
+    schema: &[String],
+    protocol: PortMappingProtocol,
+    external_port: u16,
+) -> String {
+    format_schema_message(
+        service_type,
+        "DeletePortMapping",
+        schema,
+        |message, argument| match argument {
+            "NewExternalPort" => write!(message, "{}", external_port),
+            "NewProtocol" => write!(message, "{}", protocol),
+            "NewRemoteHost" => Ok(()),
+            _ => Err(fmt::Error),
+        },
+    )
+}
+
+/// Format a `DeletePortMappingRange` request, for the mappings of this control point only.
+pub fn format_delete_port_mapping_range_message(
+    service_type: &str,
+    protocol: PortMappingProtocol,
+    external_ports: &RangeInclusive<u16>,
 ) -> String {
-    let args = schema
-        .iter()
-        .filter_map(|argument| {
-            let value = match argument.as_str() {
-                "NewEnabled" => 1.to_string(),
-                "NewExternalPort" => external_port.to_string(),
-                "NewInternalClient" => local_addr.ip().to_string(),
-                "NewInternalPort" => local_addr.port().to_string(),
-                "NewLeaseDuration" => lease_duration.to_string(),
-                "NewPortMappingDescription" => description.to_string(),
-                "NewProtocol" => protocol.to_string(),
-                "NewRemoteHost" => "".to_string(),
-                unknown => {
-                    warn!("Unknown argument: {}", unknown);
-                    return None;
-                }
-            };
-            Some(format!(
-                "<{argument}>{value}</{argument}>",
-                argument = argument,
-                value = value
-            ))
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    format_message(format!(
-        r#"<u:AddPortMapping xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
-        {}
-        </u:AddPortMapping>"#,
-        args,
-    ))
-}
-
-pub fn format_delete_port_message(schema: &[String], protocol: PortMappingProtocol, external_port: u16) -> String {
-    let args = schema
-        .iter()
-        .filter_map(|argument| {
-            let value = match argument.as_str() {
-                "NewExternalPort" => external_port.to_string(),
-                "NewProtocol" => protocol.to_string(),
-                "NewRemoteHost" => "".to_string(),
-                unknown => {
-                    warn!("Unknown argument: {}", unknown);
-                    return None;
-                }
-            };
-            Some(format!(
-                "<{argument}>{value}</{argument}>",
-                argument = argument,
-                value = value
-            ))
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    format_message(format!(
-        r#"<u:DeletePortMapping xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
-        {}
-        </u:DeletePortMapping>"#,
-        args,
-    ))
-}
-
-pub fn formate_get_generic_port_mapping_entry_message(port_mapping_index: u32) -> String {
-    format_message(format!(
-        r#"<u:GetGenericPortMappingEntry xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
-        <NewPortMappingIndex>{}</NewPortMappingIndex>
-        </u:GetGenericPortMappingEntry>"#,
+    format_action_message(
+        service_type,
+        "DeletePortMappingRange",
+        &[
+            ("NewStartPort", external_ports.start().to_string()),
+            ("NewEndPort", external_ports.end().to_string()),
+            ("NewProtocol", protocol.to_string()),
+            ("NewManage", "0".to_string()),
+        ],
+    )
+}
+
+pub fn formate_get_generic_port_mapping_entry_message(service_type: &str, port_mapping_index: u32) -> String {
+    let mut message = start_message("u", service_type, "GetGenericPortMappingEntry");
+    let _ = writeln!(
+        message,
+        "<NewPortMappingIndex>{}</NewPortMappingIndex>",
         port_mapping_index
-    ))
+    );
+    finish_message(message, "u", "GetGenericPortMappingEntry")
+}
+
+/// The IANA protocol number the firewall control service uses instead of the protocol name.
+fn pinhole_protocol(protocol: PortMappingProtocol) -> u16 {
+    match protocol {
+        PortMappingProtocol::TCP => 6,
+        PortMappingProtocol::UDP => 17,
+    }
+}
+
+/// Format an `AddPinhole` message opening `internal` to any remote host and port.
+pub fn format_add_pinhole_message(protocol: PortMappingProtocol, internal: SocketAddrV6, lease_time: u32) -> String {
+    format_action_message(
+        WAN_IPV6_FIREWALL_CONTROL_SERVICE,
+        "AddPinhole",
+        &[
+            ("RemoteHost", "".to_string()),
+            ("RemotePort", 0.to_string()),
+            ("InternalClient", internal.ip().to_string()),
+            ("InternalPort", internal.port().to_string()),
+            ("Protocol", pinhole_protocol(protocol).to_string()),
+            ("LeaseTime", lease_time.to_string()),
+        ],
+    )
+}
+
+pub fn format_update_pinhole_message(unique_id: u16, lease_time: u32) -> String {
+    format_action_message(
+        WAN_IPV6_FIREWALL_CONTROL_SERVICE,
+        "UpdatePinhole",
+        &[
+            ("UniqueID", unique_id.to_string()),
+            ("NewLeaseTime", lease_time.to_string()),
+        ],
+    )
+}
+
+pub fn format_delete_pinhole_message(unique_id: u16) -> String {
+    format_action_message(
+        WAN_IPV6_FIREWALL_CONTROL_SERVICE,
+        "DeletePinhole",
+        &[("UniqueID", unique_id.to_string())],
+    )
+}
+
+/// Format a `GetOutboundPinholeTimeout` message for traffic from `internal` to any remote host.
+pub fn format_get_outbound_pinhole_timeout_message(protocol: PortMappingProtocol, internal: SocketAddrV6) -> String {
+    format_action_message(
+        WAN_IPV6_FIREWALL_CONTROL_SERVICE,
+        "GetOutboundPinholeTimeout",
+        &[
+            ("RemoteHost", "".to_string()),
+            ("RemotePort", 0.to_string()),
+            ("InternalClient", internal.ip().to_string()),
+            ("InternalPort", internal.port().to_string()),
+            ("Protocol", pinhole_protocol(protocol).to_string()),
+        ],
+    )
+}
+
+pub fn format_check_pinhole_working_message(unique_id: u16) -> String {
+    format_action_message(
+        WAN_IPV6_FIREWALL_CONTROL_SERVICE,
+        "CheckPinholeWorking",
+        &[("UniqueID", unique_id.to_string())],
+    )
+}
+
+pub fn format_get_pinhole_packets_message(unique_id: u16) -> String {
+    format_action_message(
+        WAN_IPV6_FIREWALL_CONTROL_SERVICE,
+        "GetPinholePackets",
+        &[("UniqueID", unique_id.to_string())],
+    )
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_escape() {
+    assert_eq!(
+        escape("a & <b> \"c\" 'd'"),
+        "a &amp; &lt;b&gt; &quot;c&quot; &apos;d&apos;"
+    );
+    assert_eq!(escape("tab\tbell\u{7}"), "tab\tbell");
+
+    let description = "</NewPortMappingDescription><NewInternalClient>10.0.0.1";
+    let message = format_add_port_mapping_message(
+        WAN_IP_CONNECTION_SERVICE,
+        &["NewInternalClient".to_string(), "NewPortMappingDescription".to_string()],
+        PortMappingProtocol::TCP,
+        8080,
+        "192.168.1.2:8080".parse().unwrap(),
+        0,
+        description,
+        true,
+    );
+    let envelope = xmltree::Element::parse(message.as_bytes()).unwrap();
+    let action = envelope
+        .get_child("Body")
+        .and_then(|body| body.get_child("AddPortMapping"))
+        .unwrap();
+    let text = |name: &str| action.get_child(name).and_then(|e| e.get_text()).unwrap().into_owned();
+    assert_eq!(text("NewPortMappingDescription"), description);
+    assert_eq!(text("NewInternalClient"), "192.168.1.2");
 }
@@ -1,11 +1,607 @@
+pub mod actions;
+pub mod cache;
 pub mod messages;
 pub mod options;
 pub mod parsing;
 
-pub use self::options::SearchOptions;
+pub use self::options::{
+    AnyPortOptions, GatewayFilter, HeaderCase, RateLimit, RequestFormat, RequestTimeouts, SearchOptions, UrlPolicy,
+};
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::ops::RangeInclusive;
+#[cfg(not(target_os = "wasi"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(not(target_os = "wasi"))]
+use std::thread;
+use std::time::{Duration, Instant};
 
 use rand::{self, Rng};
 
-pub fn random_port() -> u16 {
-    rand::thread_rng().gen_range(32_768_u16..65_535_u16)
+use crate::backoff::Backoff;
+use crate::common::parsing::{PortMappingEntry, PortMappingRequest, RequestResult, StatusInfo};
+use crate::errors::{AddPortError, RequestError};
+use crate::PortMappingProtocol;
+
+/// Log the outcome of the SOAP action in `header`, sent to `url`.
+pub fn log_response(url: &str, header: &str, status: u16, result: &RequestResult) {
+    match result.as_ref().err().and_then(|e| e.error_code()) {
+        Some(code) => debug!(
+            "{} at {} failed with status {} and error code {}",
+            header, url, status, code
+        ),
+        None => trace!("{} at {} answered with status {}", header, url, status),
+    }
+}
+
+/// The delay before retry `retry` of a request that failed with `error`, if it is retried.
+///
+/// Only errors that may go away are retried, and only if the retry can start before `deadline`.
+pub fn retry_delay(
+    backoff: Option<&dyn Backoff>,
+    retry: u32,
+    error: &RequestError,
+    deadline: Option<Instant>,
+) -> Option<Duration> {
+    if !error.is_retryable() {
+        return None;
+    }
+    let delay = backoff?.delay(retry)?;
+    match deadline {
+        Some(deadline) if Instant::now() + delay >= deadline => None,
+        _ => Some(delay),
+    }
+}
+
+/// Fail if a response was compressed, as the XML parser can't read compressed documents.
+///
+/// The requests ask for the `identity` encoding, but a proxy or a firmware may compress anyway.
+pub fn check_content_encoding(encoding: Option<&str>) -> io::Result<()> {
+    match encoding.map(str::trim) {
+        None | Some("") => Ok(()),
+        Some(encoding) if encoding.eq_ignore_ascii_case("identity") => Ok(()),
+        Some(encoding) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported content encoding {}", encoding),
+        )),
+    }
+}
+
+/// Ports below this one are privileged, only root may listen on them on most systems.
+pub const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+/// Pick a random port of `ports` that isn't `taken`, never 0. Returns `None` if there is none.
+///
+/// Privileged ports are only picked from ranges that have no other, so that a wide range never
+/// yields e.g. port 22 by accident.
+pub fn random_port(ports: &RangeInclusive<u16>, taken: &HashSet<u16>) -> Option<u16> {
+    let end = *ports.end();
+    let start = if end >= FIRST_UNPRIVILEGED_PORT {
+        (*ports.start()).max(FIRST_UNPRIVILEGED_PORT)
+    } else {
+        (*ports.start()).max(1)
+    };
+    if start > end {
+        return None;
+    }
+    let first = rand::thread_rng().gen_range(start..=end);
+    (first..=end).chain(start..first).find(|port| !taken.contains(port))
+}
+
+/// Check whether `ip` is an address of this host, by binding a socket to it.
+pub fn is_local_address(ip: Ipv4Addr) -> bool {
+    UdpSocket::bind((ip, 0)).is_ok()
+}
+
+/// The local address the host uses to reach `addr`, found by connecting a UDP socket to it.
+pub fn local_ip_towards(addr: SocketAddrV4) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(addr)?;
+    match socket.local_addr()? {
+        SocketAddr::V4(local_addr) => Ok(*local_addr.ip()),
+        SocketAddr::V6(_) => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no local IPv4 address")),
+    }
+}
+
+/// The global IPv6 address the host would use to reach the internet.
+///
+/// A UDP socket is connected to a documentation address, which only looks up the route, no
+/// packet is sent. Fails if the host has no IPv6 connectivity.
+pub fn local_ipv6() -> io::Result<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?;
+    socket.connect((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 9))?;
+    match socket.local_addr()? {
+        SocketAddr::V6(local_addr) if !local_addr.ip().is_loopback() => Ok(*local_addr.ip()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no global IPv6 address",
+        )),
+    }
+}
+
+/// Longest port mapping description sent, in bytes, after the quirks cut it. The UPnP specs
+/// set no limit, but gateways keep the descriptions in fixed buffers.
+pub const MAX_DESCRIPTION_LEN: usize = 256;
+
+/// Longest lease of a pinhole, in seconds.
+pub const MAX_PINHOLE_LEASE_TIME: u32 = 86400;
+
+/// The lease time of a pinhole for the lease duration of a port mapping.
+///
+/// Pinholes can't be permanent, so 0 and longer leases become the longest lease allowed.
+pub fn pinhole_lease_time(lease_duration: u32) -> u32 {
+    match lease_duration {
+        0 => MAX_PINHOLE_LEASE_TIME,
+        lease_duration => lease_duration.min(MAX_PINHOLE_LEASE_TIME),
+    }
+}
+
+/// The address to map for a socket bound to `local_addr`.
+///
+/// A socket bound to `0.0.0.0` is reached through the address facing the gateway.
+pub fn mapping_addr(local_addr: SocketAddr, gateway: SocketAddrV4) -> io::Result<SocketAddrV4> {
+    match local_addr {
+        SocketAddr::V4(addr) if addr.ip().is_unspecified() => {
+            Ok(SocketAddrV4::new(local_ip_towards(gateway)?, addr.port()))
+        }
+        SocketAddr::V4(addr) => Ok(addr),
+        SocketAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the socket is not bound to an IPv4 address",
+        )),
+    }
+}
+
+/// Bind the socket of a search to the address of `options`, marked with their TOS byte.
+pub fn bind_search_socket(options: &SearchOptions) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(options.bind_addr)?;
+    if let Some(tos) = options.tos {
+        set_tos(&socket, options.bind_addr.is_ipv6(), tos)?;
+    }
+    Ok(socket)
+}
+
+/// Set the TOS byte, or the traffic class of IPv6, of the datagrams sent from `socket`.
+#[cfg(unix)]
+pub fn set_tos<S: std::os::unix::io::AsRawFd>(socket: &S, ipv6: bool, tos: u8) -> io::Result<()> {
+    let (level, name) = match ipv6 {
+        true => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+        false => (libc::IPPROTO_IP, libc::IP_TOS),
+    };
+    let value = libc::c_int::from(tos);
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the TOS byte, or the traffic class of IPv6, of the datagrams sent from `socket`.
+#[cfg(not(unix))]
+pub fn set_tos<S>(_socket: &S, _ipv6: bool, _tos: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "setting the TOS byte is not supported on this platform",
+    ))
+}
+
+/// Number of requests `add_ports` and `remove_ports` have in flight at once.
+pub const BATCH_CONCURRENCY: usize = 4;
+
+/// Call `f` on every item from up to `BATCH_CONCURRENCY` threads, returning the results in order.
+#[cfg(not(target_os = "wasi"))]
+pub fn run_batch<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(items.iter().map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| {
+        for _ in 0..BATCH_CONCURRENCY.min(items.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let item = match items.get(index) {
+                    Some(item) => item,
+                    None => return,
+                };
+                let result = f(item);
+                results.lock().unwrap_or_else(|e| e.into_inner())[index] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .map(|result| result.expect("every item is processed"))
+        .collect()
+}
+
+/// Call `f` on every item in turn, WASI has no threads.
+#[cfg(target_os = "wasi")]
+pub fn run_batch<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    F: Fn(&T) -> R,
+{
+    items.iter().map(f).collect()
+}
+
+/// The requests of `Gateway::add_port_range`, mapping `external_ports` to the consecutive ports
+/// of `internal_start`.
+pub fn port_range_requests(
+    external_ports: &RangeInclusive<u16>,
+    internal_start: SocketAddrV4,
+    protocol: PortMappingProtocol,
+    lease_duration: u32,
+    description: &str,
+) -> Result<Vec<PortMappingRequest>, AddPortError> {
+    let last_offset = external_ports.end().checked_sub(*external_ports.start());
+    let internal_end = match last_offset.and_then(|offset| internal_start.port().checked_add(offset)) {
+        Some(internal_end) if *external_ports.start() != 0 => internal_end,
+        _ => return Err(AddPortError::InvalidPortRange),
+    };
+    Ok(external_ports
+        .clone()
+        .zip(internal_start.port()..=internal_end)
+        .map(|(external_port, internal_port)| PortMappingRequest {
+            protocol,
+            external_port,
+            local_addr: SocketAddrV4::new(*internal_start.ip(), internal_port),
+            lease_duration,
+            description: description.to_string(),
+        })
+        .collect())
+}
+
+/// Selects port mappings by their entry, e.g. for `Gateway::cleanup_matching`.
+///
+/// It is implemented for strings, matching the descriptions starting with them, and for
+/// closures taking the entry.
+pub trait MappingFilter {
+    /// Check whether the mapping is selected.
+    fn matches(&mut self, entry: &PortMappingEntry) -> bool;
+}
+
+impl<F: FnMut(&PortMappingEntry) -> bool> MappingFilter for F {
+    fn matches(&mut self, entry: &PortMappingEntry) -> bool {
+        self(entry)
+    }
+}
+
+impl MappingFilter for &str {
+    fn matches(&mut self, entry: &PortMappingEntry) -> bool {
+        entry.port_mapping_description.starts_with(*self)
+    }
+}
+
+impl MappingFilter for String {
+    fn matches(&mut self, entry: &PortMappingEntry) -> bool {
+        entry.port_mapping_description.starts_with(self.as_str())
+    }
+}
+
+/// Description of the mapping made by `supports_hairpinning`.
+pub const HAIRPIN_CHECK_DESCRIPTION: &str = "igd hairpin check";
+
+/// Lease of the mapping made by `supports_hairpinning`, in case removing it fails.
+pub const HAIRPIN_CHECK_LEASE: u32 = 60;
+
+/// Time between two status requests of `wait_for_connected`.
+pub const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The error of `wait_for_connected` when the connection isn't up in time.
+pub fn not_connected(last: Result<StatusInfo, RequestError>) -> RequestError {
+    match last {
+        Ok(status) => RequestError::IoError(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("WAN connection is still {}", status.connection_status),
+        )),
+        Err(e) => e,
+    }
+}
+
+/// How long the discovery of a gateway took, from sending the search request.
+///
+/// The closest gateway answers first, a large difference between the two means a slow HTTP
+/// server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiscoveryTiming {
+    /// Until the search response of the gateway was received
+    pub response: Duration,
+    /// Until its description was fetched, so it could be used
+    pub description: Duration,
+}
+
+/// The last external IP address returned by a gateway, shared by the clones of the gateway.
+#[derive(Clone, Debug, Default)]
+pub struct IpCache(Arc<Mutex<Option<(Instant, Ipv4Addr)>>>);
+
+impl IpCache {
+    /// Get the cached address if it is younger than `ttl`.
+    pub fn get(&self, ttl: Duration) -> Option<Ipv4Addr> {
+        match *self.0.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some((fetched, ip)) if fetched.elapsed() < ttl => Some(ip),
+            _ => None,
+        }
+    }
+
+    pub fn set(&self, ip: Ipv4Addr) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), ip));
+    }
+
+    pub fn clear(&self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Keeps the requests to a gateway within its `RateLimit`, shared by the clones of the gateway.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter(Arc<(Mutex<RateState>, Condvar)>);
+
+#[derive(Debug, Default)]
+struct RateState {
+    limit: Option<RateLimit>,
+    in_flight: usize,
+    last_start: Option<Instant>,
+}
+
+impl RateState {
+    /// Whether a request may start at `now`, or else how long to wait, if known.
+    fn ready(&self, now: Instant) -> Result<(), Option<Duration>> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.in_flight >= limit.max_in_flight.max(1) {
+            return Err(None);
+        }
+        match self.last_start {
+            Some(last_start) if now < last_start + limit.min_interval => {
+                Err(Some(last_start + limit.min_interval - now))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Counts a request as in flight until it is dropped.
+#[derive(Debug)]
+pub struct RatePermit(RateLimiter);
+
+impl Drop for RatePermit {
+    fn drop(&mut self) {
+        self.0.lock().in_flight -= 1;
+        (self.0).0 .1.notify_all();
+    }
+}
+
+/// How often `acquire_async` checks whether a request in flight finished.
+#[cfg(feature = "aio")]
+const RATE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl RateLimiter {
+    pub fn limit(&self) -> Option<RateLimit> {
+        self.lock().limit
+    }
+
+    pub fn set_limit(&self, limit: Option<RateLimit>) {
+        self.lock().limit = limit;
+        self.0 .1.notify_all();
+    }
+
+    /// Wait until a request may start, failing with `TimedOut` if that is after `deadline`.
+    pub fn acquire(&self, deadline: Option<Instant>) -> io::Result<RatePermit> {
+        let mut state = self.lock();
+        loop {
+            let now = Instant::now();
+            let wait = match state.ready(now) {
+                Ok(()) => return Ok(self.start(&mut state, now)),
+                Err(wait) => wait,
+            };
+            let left = deadline.map(|deadline| deadline.saturating_duration_since(now));
+            if left == Some(Duration::from_secs(0)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the deadline passed while waiting for the rate limit",
+                ));
+            }
+            state = match wait.into_iter().chain(left).min() {
+                Some(timeout) => {
+                    self.0
+                         .1
+                        .wait_timeout(state, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self.0 .1.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    /// Wait until a request may start, without blocking the runtime.
+    #[cfg(feature = "aio")]
+    pub async fn acquire_async(&self) -> RatePermit {
+        loop {
+            let wait = {
+                let mut state = self.lock();
+                let now = Instant::now();
+                match state.ready(now) {
+                    Ok(()) => return self.start(&mut state, now),
+                    Err(wait) => wait.unwrap_or(RATE_POLL_INTERVAL),
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn start(&self, state: &mut RateState, now: Instant) -> RatePermit {
+        state.in_flight += 1;
+        state.last_start = Some(now);
+        RatePermit(self.clone())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RateState> {
+        self.0 .0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[test]
+fn test_ip_cache() {
+    let cache = IpCache::default();
+    let ttl = Duration::from_secs(60);
+    assert_eq!(cache.get(ttl), None);
+
+    cache.set(Ipv4Addr::new(203, 0, 113, 1));
+    assert_eq!(cache.clone().get(ttl), Some(Ipv4Addr::new(203, 0, 113, 1)));
+    assert_eq!(cache.get(Duration::from_secs(0)), None);
+
+    cache.clear();
+    assert_eq!(cache.get(ttl), None);
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::default();
+    let unlimited: Vec<_> = (0..3).map(|_| limiter.acquire(None).unwrap()).collect();
+    drop(unlimited);
+
+    limiter.set_limit(Some(RateLimit {
+        max_in_flight: 1,
+        min_interval: Duration::from_millis(100),
+    }));
+    let started = Instant::now();
+    let permit = limiter.clone().acquire(None).unwrap();
+    let deadline = Some(Instant::now() + Duration::from_millis(50));
+    assert_eq!(limiter.acquire(deadline).unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+    let waiter = {
+        let limiter = limiter.clone();
+        thread::spawn(move || limiter.acquire(None).map(|_| started.elapsed()))
+    };
+    thread::sleep(Duration::from_millis(200));
+    drop(permit);
+    assert!(waiter.join().unwrap().unwrap() >= Duration::from_millis(200));
+}
+
+#[test]
+fn test_check_content_encoding() {
+    assert!(check_content_encoding(None).is_ok());
+    assert!(check_content_encoding(Some("Identity")).is_ok());
+    let e = check_content_encoding(Some("gzip")).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(e.to_string(), "unsupported content encoding gzip");
+}
+
+#[test]
+fn test_port_range_requests() {
+    let internal = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 21000);
+    let requests = port_range_requests(&(50000..=50009), internal, PortMappingProtocol::TCP, 0, "ftp").unwrap();
+    assert_eq!(requests.len(), 10);
+    assert_eq!(requests[9].external_port, 50009);
+    assert_eq!(requests[9].local_addr, "192.168.1.2:21009".parse().unwrap());
+
+    let range = |external_ports: RangeInclusive<u16>, internal_port| {
+        let internal = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), internal_port);
+        port_range_requests(&external_ports, internal, PortMappingProtocol::UDP, 0, "game")
+    };
+    assert!(range(65535..=65535, 65535).is_ok());
+    assert!(matches!(
+        range(RangeInclusive::new(10, 9), 10),
+        Err(AddPortError::InvalidPortRange)
+    ));
+    assert!(matches!(range(0..=9, 10), Err(AddPortError::InvalidPortRange)));
+    assert!(matches!(range(1000..=1001, 65535), Err(AddPortError::InvalidPortRange)));
+}
+
+#[test]
+fn test_is_local_address() {
+    assert!(is_local_address(Ipv4Addr::LOCALHOST));
+    assert!(!is_local_address(Ipv4Addr::new(192, 0, 2, 1)));
+}
+
+#[test]
+fn test_mapping_addr() {
+    let gateway = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1900);
+    let bound = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 8080);
+    assert_eq!(mapping_addr(bound.into(), gateway).unwrap(), bound);
+    assert_eq!(
+        mapping_addr((Ipv4Addr::UNSPECIFIED, 8080).into(), gateway).unwrap(),
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080)
+    );
+    assert!(mapping_addr("[::1]:8080".parse().unwrap(), gateway).is_err());
+}
+
+#[test]
+fn test_random_port() {
+    let none = HashSet::new();
+    assert_eq!(random_port(&(0..=1), &none), Some(1));
+    assert_eq!(random_port(&(0..=0), &none), None);
+    let port = random_port(&AnyPortOptions::default().ports, &none).unwrap();
+    assert!((49_152..=65_535).contains(&port));
+
+    let port = random_port(&(1..=1100), &none).unwrap();
+    assert!(port >= FIRST_UNPRIVILEGED_PORT);
+    assert_eq!(random_port(&(1..=1024), &none), Some(1024));
+    assert!(random_port(&(80..=90), &none).unwrap() < FIRST_UNPRIVILEGED_PORT);
+
+    let taken: HashSet<u16> = (50_000..=50_009).filter(|&port| port != 50_004).collect();
+    assert_eq!(random_port(&(50_000..=50_009), &taken), Some(50_004));
+    let taken: HashSet<u16> = (50_000..=50_009).collect();
+    assert_eq!(random_port(&(50_000..=50_009), &taken), None);
+}
+
+#[test]
+fn test_run_batch() {
+    let items: Vec<u32> = (0..10).collect();
+    assert_eq!(
+        run_batch(&items, |item| item * 2),
+        (0..20).step_by(2).collect::<Vec<_>>()
+    );
+    assert!(run_batch(&[] as &[u32], |item| *item).is_empty());
+}
+
+#[test]
+fn test_pinhole_lease_time() {
+    assert_eq!(pinhole_lease_time(0), MAX_PINHOLE_LEASE_TIME);
+    assert_eq!(pinhole_lease_time(3600), 3600);
+    assert_eq!(pinhole_lease_time(u32::MAX), MAX_PINHOLE_LEASE_TIME);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bind_search_socket() {
+    use std::os::unix::io::AsRawFd;
+
+    let options = SearchOptions {
+        bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+        tos: Some(0xb8),
+        ..Default::default()
+    };
+    let socket = bind_search_socket(&options).unwrap();
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_TOS,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(result, 0);
+    assert_eq!(value, 0xb8);
 }
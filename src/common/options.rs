@@ -1,6 +1,18 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 use std::time::Duration;
 
+use crate::backoff::Backoff;
+use crate::common::parsing::{DeviceInfo, RequestResult};
+use crate::errors::RequestError;
+use crate::ssdp::SearchRequest;
+
+/// The default `SearchOptions::fetch_timeout`, which also bounds the fetches of the SCPD when
+/// selecting another WAN connection.
+pub(crate) const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Gateway search configuration
 ///
 /// SearchOptions::default() should suffice for most situations.
@@ -16,6 +28,7 @@ use std::time::Duration;
 ///     ..Default::default()
 /// };
 /// ```
+#[derive(Clone)]
 pub struct SearchOptions {
     /// Bind address for UDP socket (defaults to all `0.0.0.0`)
     pub bind_addr: SocketAddr,
@@ -23,6 +36,30 @@ pub struct SearchOptions {
     pub broadcast_address: SocketAddr,
     /// Timeout for a search iteration (defaults to 10s)
     pub timeout: Option<Duration>,
+    /// M-SEARCH request sent (defaults to a search of Internet Gateway Devices)
+    pub request: SearchRequest,
+    /// Gateways the search functions return (defaults to any)
+    pub filter: GatewayFilter,
+    /// Delays after which the search request is sent again while no gateway answered, as
+    /// multicast datagrams get lost (defaults to `None`, sent once). With retransmissions,
+    /// `timeout` bounds the whole search rather than each wait for a response.
+    pub retransmission: Option<Arc<dyn Backoff>>,
+    /// Addresses the description of a gateway may be fetched from (defaults to
+    /// `UrlPolicy::Local`)
+    pub url_policy: UrlPolicy,
+    /// Fetch the SCPD of the gateways found to check the actions against it (defaults to
+    /// `true`), see `Gateway::validate_arguments`. Without it, finding a gateway and mapping a
+    /// port is one request for the description and one for the mapping after the search.
+    pub validate_arguments: bool,
+    /// TOS byte of the search requests, with the DSCP in its upper 6 bits, or the traffic class
+    /// when searching over IPv6 (defaults to `None`, left to the system). Some managed networks
+    /// drop unmarked multicast. Only the SSDP socket is marked, the HTTP requests to the gateway
+    /// are unicast. Setting it fails the search on platforms other than Unix.
+    pub tos: Option<u8>,
+    /// Longest time the description or the SCPD of a gateway that answered is fetched for
+    /// (defaults to 5s), so a device that accepts the connection but never answers costs the
+    /// search this long rather than its whole timeout. The fetches end with the search anyway.
+    pub fetch_timeout: Option<Duration>,
 }
 
 impl Default for SearchOptions {
@@ -31,6 +68,321 @@ impl Default for SearchOptions {
             bind_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
             broadcast_address: "239.255.255.250:1900".parse().unwrap(),
             timeout: Some(Duration::from_secs(10)),
+            request: SearchRequest::default(),
+            filter: GatewayFilter::Any,
+            retransmission: None,
+            url_policy: UrlPolicy::default(),
+            validate_arguments: true,
+            tos: None,
+            fetch_timeout: Some(DEFAULT_FETCH_TIMEOUT),
+        }
+    }
+}
+
+/// Selects the gateways returned by the search functions, by their device description.
+///
+/// On networks with several gateways, this finds the one the user configured rather than the
+/// one answering first. The other gateways are skipped as if they didn't answer.
+///
+/// # Example
+/// ```
+/// # use igd::{GatewayFilter, SearchOptions};
+/// let opts = SearchOptions {
+///     filter: GatewayFilter::FriendlyName("FRITZ!Box".into()),
+///     ..Default::default()
+/// };
+/// let opts = SearchOptions {
+///     filter: GatewayFilter::predicate(|info| info.manufacturer == "AVM Berlin"),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Default)]
+pub enum GatewayFilter {
+    /// Any gateway
+    #[default]
+    Any,
+    /// The gateway with this unique device name, e.g. `uuid:00000000-0000-0000-0000-000000000000`
+    Udn(String),
+    /// The gateways whose friendly name contains this, ignoring case
+    FriendlyName(String),
+    /// The gateways the function returns `true` for
+    Predicate(Arc<dyn Fn(&DeviceInfo) -> bool + Send + Sync>),
+}
+
+impl GatewayFilter {
+    /// Select the gateways `predicate` returns `true` for.
+    pub fn predicate<F>(predicate: F) -> GatewayFilter
+    where
+        F: Fn(&DeviceInfo) -> bool + Send + Sync + 'static,
+    {
+        GatewayFilter::Predicate(Arc::new(predicate))
+    }
+
+    /// Check whether the gateway with the given description is selected.
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        match *self {
+            GatewayFilter::Any => true,
+            GatewayFilter::Udn(ref udn) => info.udn.eq_ignore_ascii_case(udn),
+            GatewayFilter::FriendlyName(ref name) => info.friendly_name.to_lowercase().contains(&name.to_lowercase()),
+            GatewayFilter::Predicate(ref predicate) => predicate(info),
+        }
+    }
+}
+
+impl fmt::Debug for GatewayFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GatewayFilter::Any => write!(f, "Any"),
+            GatewayFilter::Udn(ref udn) => f.debug_tuple("Udn").field(udn).finish(),
+            GatewayFilter::FriendlyName(ref name) => f.debug_tuple("FriendlyName").field(name).finish(),
+            GatewayFilter::Predicate(_) => write!(f, "Predicate(..)"),
+        }
+    }
+}
+
+/// Addresses a device answering a search may point to with the `LOCATION` of its description.
+///
+/// A rogue device of the LAN could otherwise make the search fetch any url, e.g. of a service
+/// only reachable from this host. Redirects aren't followed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum UrlPolicy {
+    /// Only the address of the device
+    SameHost,
+    /// The address of the device, or any private or link-local address. Loopback addresses are
+    /// only allowed when the device is this host.
+    #[default]
+    Local,
+    /// Any address, e.g. when the description is served by another host on purpose
+    Any,
+}
+
+impl UrlPolicy {
+    /// Check whether a device at `from` may point to a description at `location`.
+    pub fn allows(&self, from: IpAddr, location: IpAddr) -> bool {
+        match *self {
+            UrlPolicy::SameHost => location == from,
+            UrlPolicy::Local => location == from || is_local(location),
+            UrlPolicy::Any => true,
+        }
+    }
+}
+
+/// Whether `ip` can only be reached on the LAN. Loopback addresses aren't, they would reach
+/// the services of this host.
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            segment & 0xfe00 == 0xfc00 || segment & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// Configuration of the external port chosen by `add_any_port_with` and `get_any_address_with`.
+///
+/// # Example
+/// ```
+/// # use igd::AnyPortOptions;
+/// let opts = AnyPortOptions {
+///     ports: 10_000..=19_999,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnyPortOptions {
+    /// External ports to choose from (defaults to the dynamic ports, `49152..=65535`)
+    ///
+    /// Port 0 is never chosen, nor ports below 1024 unless the range has no other. When the
+    /// gateway requires the internal and external port to be the same, the internal port is
+    /// used even if it is outside of the range.
+    pub ports: RangeInclusive<u16>,
+    /// Number of random ports tried before giving up with `NoPortsAvailable`, when the gateway
+    /// doesn't choose the port itself (defaults to 20)
+    pub attempts: usize,
+    /// When the gateway doesn't choose the port itself, list its mappings first and only try
+    /// ports it hasn't mapped yet (defaults to `true`). Listing takes a request per mapping.
+    pub skip_mapped: bool,
+}
+
+impl Default for AnyPortOptions {
+    fn default() -> Self {
+        Self {
+            ports: 49_152..=65_535,
+            attempts: 20,
+            skip_mapped: true,
         }
     }
 }
+
+/// Letter case used for the names of SOAP request headers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HeaderCase {
+    /// `SOAPAction` and `Content-Type`
+    Standard,
+    /// `soapaction` and `content-type`
+    Lower,
+    /// `SOAPACTION` and `CONTENT-TYPE`
+    Upper,
+}
+
+/// Formatting of the SOAP requests sent to a gateway.
+///
+/// The defaults work with most gateways. Some firmwares only accept the `SOAPAction` value
+/// without quotes, or reject a `charset` parameter on the content type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestFormat {
+    /// Surround the `SOAPAction` value with double quotes (defaults to `true`)
+    pub quote_action: bool,
+    /// Send `text/xml; charset="utf-8"` instead of `text/xml` (defaults to `false`)
+    pub charset: bool,
+    /// Letter case of the header names (defaults to `HeaderCase::Standard`)
+    pub header_case: HeaderCase,
+    /// Retry once with the alternate quote style and charset when the gateway
+    /// answers with a 400 or a 500 that is not a UPnP error (defaults to `true`)
+    pub retry_alternate: bool,
+    /// Send HTTP/1.0 requests, for embedded HTTP servers that mishandle HTTP/1.1 (defaults to `false`)
+    ///
+    /// Requests always carry an explicit `Content-Length` and `Connection: close`.
+    pub http_1_0: bool,
+}
+
+impl Default for RequestFormat {
+    fn default() -> Self {
+        Self {
+            quote_action: true,
+            charset: false,
+            header_case: HeaderCase::Standard,
+            retry_alternate: true,
+            http_1_0: false,
+        }
+    }
+}
+
+impl RequestFormat {
+    /// Name of the `SOAPAction` header.
+    pub fn action_name(&self) -> &'static str {
+        match self.header_case {
+            HeaderCase::Standard => "SOAPAction",
+            HeaderCase::Lower => "soapaction",
+            HeaderCase::Upper => "SOAPACTION",
+        }
+    }
+
+    /// Name of the `Content-Type` header.
+    pub fn content_type_name(&self) -> &'static str {
+        match self.header_case {
+            HeaderCase::Standard => "Content-Type",
+            HeaderCase::Lower => "content-type",
+            HeaderCase::Upper => "CONTENT-TYPE",
+        }
+    }
+
+    /// Value of the `SOAPAction` header for an action, given with or without quotes.
+    pub fn action_value(&self, action: &str) -> String {
+        let action = action.trim_matches('"');
+        if self.quote_action {
+            format!("\"{}\"", action)
+        } else {
+            action.to_string()
+        }
+    }
+
+    /// HTTP version written in the request line.
+    pub fn http_version(&self) -> &'static str {
+        if self.http_1_0 {
+            "HTTP/1.0"
+        } else {
+            "HTTP/1.1"
+        }
+    }
+
+    /// Value of the `Content-Type` header.
+    pub fn content_type(&self) -> &'static str {
+        if self.charset {
+            "text/xml; charset=\"utf-8\""
+        } else {
+            "text/xml"
+        }
+    }
+
+    /// The format to retry with after a request was rejected with the given status.
+    pub(crate) fn alternate(&self, status: u16, result: &RequestResult) -> Option<RequestFormat> {
+        let rejected = matches!((status, result), (400, _) | (500, Err(RequestError::HttpStatus { .. })));
+        if self.retry_alternate && rejected {
+            Some(RequestFormat {
+                quote_action: !self.quote_action,
+                charset: !self.charset,
+                retry_alternate: false,
+                ..*self
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Timeouts of the requests sent to a gateway.
+///
+/// A gateway that accepts the connection and then never answers would otherwise block the
+/// caller forever. Set them on `Gateway::timeouts`, or for a single call with
+/// `Gateway::with_timeouts`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestTimeouts {
+    /// How long connecting to the gateway may take (defaults to 5 seconds)
+    pub connect: Option<Duration>,
+    /// How long to wait for each part of the response (defaults to 10 seconds)
+    pub read: Option<Duration>,
+    /// How long a whole action may take, including the retry with the alternate request format
+    /// (defaults to 30 seconds)
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(Duration::from_secs(5)),
+            read: Some(Duration::from_secs(10)),
+            deadline: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Limits of the rate of the requests sent to a gateway, see `Gateway::set_rate_limit`.
+///
+/// Some cheap gateways crash, or drop requests, when they get several at once or in quick
+/// succession.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RateLimit {
+    /// Most requests waiting for a response at once, at least 1 (defaults to 1)
+    pub max_in_flight: usize,
+    /// Least time between the starts of two requests (defaults to 100 milliseconds)
+    pub min_interval: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 1,
+            min_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+#[test]
+fn test_url_policy() {
+    let from: IpAddr = "192.168.1.1".parse().unwrap();
+    let lan: IpAddr = "192.168.1.20".parse().unwrap();
+    let internet: IpAddr = "203.0.113.7".parse().unwrap();
+    assert!(UrlPolicy::SameHost.allows(from, from));
+    assert!(!UrlPolicy::SameHost.allows(from, lan));
+    assert!(UrlPolicy::Local.allows(from, lan));
+    assert!(UrlPolicy::Local.allows(from, "fe80::1".parse().unwrap()));
+    assert!(UrlPolicy::Local.allows(internet, internet));
+    assert!(!UrlPolicy::Local.allows(from, internet));
+    assert!(!UrlPolicy::Local.allows(from, "127.0.0.1".parse().unwrap()));
+    assert!(!UrlPolicy::Local.allows(from, "::1".parse().unwrap()));
+    let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+    assert!(UrlPolicy::Local.allows(localhost, localhost));
+    assert!(UrlPolicy::Any.allows(from, internet));
+}
@@ -1,49 +1,96 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::Duration;
 
 use url::Url;
 use xmltree::{self, Element};
 
+use crate::common::messages::{WAN_COMMON_INTERFACE_CONFIG_SERVICE, WAN_IPV6_FIREWALL_CONTROL_SERVICE};
 use crate::errors::{
-    AddAnyPortError, AddPortError, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError, RequestError,
-    SearchError,
+    raw_excerpt, AddAnyPortError, AddPortError, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError,
+    RequestError, SearchError,
 };
+use crate::proto::{self, Headers};
 use crate::PortMappingProtocol;
 
-// Parse the result.
-pub fn parse_search_result(text: &str) -> Result<(SocketAddrV4, String), SearchError> {
+/// Parse the description location of a search response: the address of the gateway and the
+/// path of its device description.
+pub fn parse_search_result(headers: &Headers) -> Result<(SocketAddrV4, String), SearchError> {
+    parse_location(headers.get("location").ok_or(SearchError::InvalidResponse)?)
+}
+
+/// Parse a description location, e.g. the `LOCATION` of a response or an announcement.
+pub fn parse_location(location: &str) -> Result<(SocketAddrV4, String), SearchError> {
     use SearchError::InvalidResponse;
 
-    for line in text.lines() {
-        let line = line.trim();
-        if line.to_ascii_lowercase().starts_with("location:") {
-            if let Some(colon) = line.find(':') {
-                let url_text = &line[colon + 1..].trim();
-                let url = Url::parse(url_text).map_err(|_| InvalidResponse)?;
-                let addr: Ipv4Addr = url
-                    .host_str()
-                    .ok_or(InvalidResponse)
-                    .and_then(|s| s.parse().map_err(|_| InvalidResponse))?;
-                let port: u16 = url.port_or_known_default().ok_or(InvalidResponse)?;
-
-                return Ok((SocketAddrV4::new(addr, port), url.path().to_string()));
+    let url = Url::parse(location).map_err(|_| InvalidResponse)?;
+    let addr: Ipv4Addr = url
+        .host_str()
+        .ok_or(InvalidResponse)
+        .and_then(|s| s.parse().map_err(|_| InvalidResponse))?;
+    let port: u16 = url.port_or_known_default().ok_or(InvalidResponse)?;
+
+    Ok((SocketAddrV4::new(addr, port), url.path().to_string()))
+}
+
+/// Parse how long a response or announcement is valid from its `CACHE-CONTROL` header value.
+pub fn parse_max_age(cache_control: &str) -> Option<Duration> {
+    cache_control
+        .split(',')
+        .find_map(|directive| {
+            let (name, value) = directive.split_once('=')?;
+            if name.trim().eq_ignore_ascii_case("max-age") {
+                value.trim().parse().ok()
+            } else {
+                None
             }
-        }
-    }
-    Err(InvalidResponse)
+        })
+        .map(Duration::from_secs)
+}
+
+/// Service types that can be used for port mapping on a regular IGD.
+pub const WAN_CONNECTION_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+];
+
+// Some devices send whitespace before the XML declaration, which the parser rejects.
+pub fn parse_xml<R>(mut resp: R) -> Result<Element, SearchError>
+where
+    R: io::Read,
+{
+    let mut buf = Vec::new();
+    resp.read_to_end(&mut buf)?;
+    let start = buf.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(buf.len());
+    Ok(Element::parse(&buf[start..])?)
 }
 
 pub fn parse_control_urls<R>(resp: R) -> Result<(String, String), SearchError>
 where
     R: io::Read,
 {
-    let root = Element::parse(resp)?;
+    parse_service_urls(resp, WAN_CONNECTION_SERVICES).map(|(scpd_url, control_url, _)| (scpd_url, control_url))
+}
+
+/// Find the first service matching one of `service_types` in a device description.
+///
+/// Returns the SCPD url, the control url and the service type that was matched.
+pub fn parse_service_urls<R>(resp: R, service_types: &[&str]) -> Result<(String, String, String), SearchError>
+where
+    R: io::Read,
+{
+    let root = parse_xml(resp)?;
 
     let mut urls = root.children.iter().filter_map(|child| {
         let child = child.as_element()?;
         if child.name == "device" {
-            Some(parse_device(child)?)
+            Some(parse_device(child, service_types)?)
         } else {
             None
         }
@@ -52,36 +99,35 @@ where
     urls.next().ok_or(SearchError::InvalidResponse)
 }
 
-fn parse_device(device: &Element) -> Option<(String, String)> {
-    let services = device
-        .get_child("serviceList")
-        .map(|service_list| {
-            service_list
-                .children
-                .iter()
-                .filter_map(|child| {
-                    let child = child.as_element()?;
-                    if child.name == "service" {
-                        parse_service(child)
-                    } else {
-                        None
-                    }
-                })
-                .next()
-        })
-        .flatten();
-    let devices = device.get_child("deviceList").map(parse_device_list).flatten();
+fn parse_device(device: &Element, service_types: &[&str]) -> Option<(String, String, String)> {
+    let services = device.get_child("serviceList").and_then(|service_list| {
+        service_list
+            .children
+            .iter()
+            .filter_map(|child| {
+                let child = child.as_element()?;
+                if child.name == "service" {
+                    parse_service(child, service_types)
+                } else {
+                    None
+                }
+            })
+            .next()
+    });
+    let devices = device
+        .get_child("deviceList")
+        .and_then(|device_list| parse_device_list(device_list, service_types));
     services.or(devices)
 }
 
-fn parse_device_list(device_list: &Element) -> Option<(String, String)> {
+fn parse_device_list(device_list: &Element, service_types: &[&str]) -> Option<(String, String, String)> {
     device_list
         .children
         .iter()
         .filter_map(|child| {
             let child = child.as_element()?;
             if child.name == "device" {
-                parse_device(child)
+                parse_device(child, service_types)
             } else {
                 None
             }
@@ -89,19 +135,13 @@ fn parse_device_list(device_list: &Element) -> Option<(String, String)> {
         .next()
 }
 
-fn parse_service(service: &Element) -> Option<(String, String)> {
+fn parse_service(service: &Element, service_types: &[&str]) -> Option<(String, String, String)> {
     let service_type = service.get_child("serviceType")?;
     let service_type = service_type
         .get_text()
         .map(|s| s.into_owned())
         .unwrap_or_else(|| "".into());
-    if [
-        "urn:schemas-upnp-org:service:WANPPPConnection:1",
-        "urn:schemas-upnp-org:service:WANIPConnection:1",
-        "urn:schemas-upnp-org:service:WANIPConnection:2",
-    ]
-    .contains(&service_type.as_str())
-    {
+    if service_types.contains(&service_type.as_str()) {
         let scpd_url = service.get_child("SCPDURL");
         let control_url = service.get_child("controlURL");
         if let (Some(scpd_url), Some(control_url)) = (scpd_url, control_url) {
@@ -111,6 +151,7 @@ fn parse_service(service: &Element) -> Option<(String, String)> {
                     .get_text()
                     .map(|s| s.into_owned())
                     .unwrap_or_else(|| "".into()),
+                service_type,
             ))
         } else {
             None
@@ -124,7 +165,7 @@ pub fn parse_schemas<R>(resp: R) -> Result<HashMap<String, Vec<String>>, SearchE
 where
     R: io::Read,
 {
-    let root = Element::parse(resp)?;
+    let root = parse_xml(resp)?;
 
     let mut schema = root.children.iter().filter_map(|child| {
         let child = child.as_element()?;
@@ -187,132 +228,437 @@ fn parse_argument(action: &Element) -> Option<String> {
     }
 }
 
+/// A successful response to a SOAP request.
 pub struct RequestReponse {
-    text: String,
-    xml: xmltree::Element,
+    pub text: String,
+    /// Where the content of the response element is in `text`
+    content: Range<usize>,
+}
+
+impl RequestReponse {
+    /// The unescaped text of the output argument `name`, empty if it is, `None` if it is missing.
+    pub fn argument(&self, name: &str) -> Option<Cow<'_, str>> {
+        proto::child(&self.text[self.content.clone()], name).map(proto::unescape)
+    }
+
+    /// The output arguments by name, in the order of the response.
+    pub fn arguments(&self) -> impl Iterator<Item = (&str, Cow<'_, str>)> {
+        proto::children(&self.text[self.content.clone()]).map(|(name, content)| (name, proto::unescape(content)))
+    }
 }
 
 pub type RequestResult = Result<RequestReponse, RequestError>;
 
+/// Parse the response to a SOAP request, whose response element is named `ok`.
+///
+/// The response is scanned where it is rather than parsed into a tree, so only the values that
+/// are asked for are copied.
 pub fn parse_response(text: String, ok: &str) -> RequestResult {
-    let mut xml = match xmltree::Element::parse(text.as_bytes()) {
-        Ok(xml) => xml,
-        Err(..) => return Err(RequestError::InvalidResponse(text)),
-    };
-    let body = match xml.get_mut_child("Body") {
+    let body = match proto::children(&text)
+        .next()
+        .and_then(|(_, envelope)| proto::child(envelope, "Body"))
+    {
         Some(body) => body,
-        None => return Err(RequestError::InvalidResponse(text)),
+        None => return Err(RequestError::invalid_response(&text)),
     };
-    if let Some(ok) = body.take_child(ok) {
-        return Ok(RequestReponse { text, xml: ok });
+    if let Some(content) = proto::child(body, ok) {
+        let start = content.as_ptr() as usize - text.as_ptr() as usize;
+        let content = start..start + content.len();
+        return Ok(RequestReponse { text, content });
     }
-    let upnp_error = match body
-        .get_child("Fault")
-        .and_then(|e| e.get_child("detail"))
-        .and_then(|e| e.get_child("UPnPError"))
+    let upnp_error = match proto::child(body, "Fault")
+        .and_then(|e| proto::child(e, "detail"))
+        .and_then(|e| proto::child(e, "UPnPError"))
     {
         Some(upnp_error) => upnp_error,
-        None => return Err(RequestError::InvalidResponse(text)),
+        None => return Err(RequestError::invalid_response(&text)),
     };
 
-    match (
-        upnp_error.get_child("errorCode"),
-        upnp_error.get_child("errorDescription"),
-    ) {
-        (Some(e), Some(d)) => match (e.get_text().as_ref(), d.get_text().as_ref()) {
-            (Some(et), Some(dt)) => match et.parse::<u16>() {
-                Ok(en) => Err(RequestError::ErrorCode(en, From::from(&dt[..]))),
-                Err(..) => Err(RequestError::InvalidResponse(text)),
-            },
-            _ => Err(RequestError::InvalidResponse(text)),
+    let field = |name| {
+        proto::child(upnp_error, name)
+            .map(proto::unescape)
+            .filter(|text| !text.is_empty())
+    };
+    match (field("errorCode"), field("errorDescription")) {
+        (Some(et), Some(dt)) => match et.parse::<u16>() {
+            Ok(en) => Err(RequestError::from_error_code(en, dt.into_owned())),
+            Err(..) => Err(RequestError::invalid_response(&text)),
         },
-        _ => Err(RequestError::InvalidResponse(text)),
+        _ => Err(RequestError::invalid_response(&text)),
+    }
+}
+
+/// Parse the response to a SOAP request, which came with the HTTP `status` and `headers`.
+///
+/// A response that is neither the expected one nor a UPnP error fails with `HttpStatus` if the
+/// status is an error, e.g. the 404 page of a control url that moved.
+pub fn parse_response_with_status(
+    status: u16,
+    headers: Vec<(String, String)>,
+    text: String,
+    ok: &str,
+) -> RequestResult {
+    if (200..300).contains(&status) {
+        return parse_response(text, ok);
+    }
+    let body = raw_excerpt(text.as_bytes());
+    match parse_response(text, ok) {
+        Err(RequestError::InvalidResponse(_)) => Err(RequestError::HttpStatus { status, headers, body }),
+        result => result,
     }
 }
 
 pub fn parse_get_external_ip_response(result: RequestResult) -> Result<Ipv4Addr, GetExternalIpError> {
     match result {
         Ok(resp) => match resp
-            .xml
-            .get_child("NewExternalIPAddress")
-            .and_then(|e| e.get_text())
+            .argument("NewExternalIPAddress")
             .and_then(|t| t.parse::<Ipv4Addr>().ok())
         {
             Some(ipv4_addr) => Ok(ipv4_addr),
-            None => Err(GetExternalIpError::RequestError(RequestError::InvalidResponse(
-                resp.text,
+            None => Err(GetExternalIpError::RequestError(RequestError::invalid_response(
+                &resp.text,
             ))),
         },
-        Err(RequestError::ErrorCode(606, _)) => Err(GetExternalIpError::ActionNotAuthorized),
+        Err(ref e) if matches!(e.inner(), RequestError::ActionNotAuthorized) => {
+            Err(GetExternalIpError::ActionNotAuthorized)
+        }
         Err(e) => Err(GetExternalIpError::RequestError(e)),
     }
 }
 
-pub fn parse_add_any_port_mapping_response(result: RequestResult) -> Result<u16, AddAnyPortError> {
-    match result {
-        Ok(resp) => {
-            match resp
-                .xml
-                .get_child("NewReservedPort")
-                .and_then(|e| e.get_text())
-                .and_then(|t| t.parse::<u16>().ok())
-            {
-                Some(port) => Ok(port),
-                None => Err(AddAnyPortError::RequestError(RequestError::InvalidResponse(resp.text))),
-            }
-        }
-        Err(err) => Err(match err {
-            RequestError::ErrorCode(605, _) => AddAnyPortError::DescriptionTooLong,
-            RequestError::ErrorCode(606, _) => AddAnyPortError::ActionNotAuthorized,
-            RequestError::ErrorCode(728, _) => AddAnyPortError::NoPortsAvailable,
-            e => AddAnyPortError::RequestError(e),
-        }),
+/// Parse the external port reserved by `AddAnyPortMapping`.
+pub fn parse_reserved_port(result: RequestResult) -> Result<u16, RequestError> {
+    let resp = result?;
+    match resp.argument("NewReservedPort").and_then(|t| t.parse::<u16>().ok()) {
+        Some(port) => Ok(port),
+        None => Err(RequestError::invalid_response(&resp.text)),
+    }
+}
+
+pub fn convert_add_any_port_error(err: RequestError) -> AddAnyPortError {
+    match *err.inner() {
+        RequestError::ErrorCode(605, _) => AddAnyPortError::DescriptionTooLong,
+        RequestError::ActionNotAuthorized => AddAnyPortError::ActionNotAuthorized,
+        RequestError::NoPortMapsAvailable => AddAnyPortError::NoPortsAvailable,
+        RequestError::OnlyPermanentLeasesSupported => AddAnyPortError::OnlyPermanentLeasesSupported,
+        _ => AddAnyPortError::RequestError(err),
     }
 }
 
 pub fn convert_add_random_port_mapping_error(error: RequestError) -> Option<AddAnyPortError> {
-    match error {
-        RequestError::ErrorCode(724, _) => None,
+    match *error.inner() {
+        RequestError::SamePortValuesRequired => None,
         RequestError::ErrorCode(605, _) => Some(AddAnyPortError::DescriptionTooLong),
-        RequestError::ErrorCode(606, _) => Some(AddAnyPortError::ActionNotAuthorized),
-        RequestError::ErrorCode(718, _) => Some(AddAnyPortError::NoPortsAvailable),
-        RequestError::ErrorCode(725, _) => Some(AddAnyPortError::OnlyPermanentLeasesSupported),
-        e => Some(AddAnyPortError::RequestError(e)),
+        RequestError::ActionNotAuthorized => Some(AddAnyPortError::ActionNotAuthorized),
+        RequestError::ConflictInMappingEntry => Some(AddAnyPortError::NoPortsAvailable),
+        RequestError::NoPortMapsAvailable => Some(AddAnyPortError::NoPortsAvailable),
+        RequestError::OnlyPermanentLeasesSupported => Some(AddAnyPortError::OnlyPermanentLeasesSupported),
+        _ => Some(AddAnyPortError::RequestError(error)),
     }
 }
 
 pub fn convert_add_same_port_mapping_error(error: RequestError) -> AddAnyPortError {
-    match error {
-        RequestError::ErrorCode(606, _) => AddAnyPortError::ActionNotAuthorized,
-        RequestError::ErrorCode(718, _) => AddAnyPortError::ExternalPortInUse,
-        RequestError::ErrorCode(725, _) => AddAnyPortError::OnlyPermanentLeasesSupported,
-        e => AddAnyPortError::RequestError(e),
+    match *error.inner() {
+        RequestError::ActionNotAuthorized => AddAnyPortError::ActionNotAuthorized,
+        RequestError::ConflictInMappingEntry => AddAnyPortError::ExternalPortInUse,
+        RequestError::OnlyPermanentLeasesSupported => AddAnyPortError::OnlyPermanentLeasesSupported,
+        _ => AddAnyPortError::RequestError(error),
     }
 }
 
 pub fn convert_add_port_error(err: RequestError) -> AddPortError {
-    match err {
+    match *err.inner() {
         RequestError::ErrorCode(605, _) => AddPortError::DescriptionTooLong,
-        RequestError::ErrorCode(606, _) => AddPortError::ActionNotAuthorized,
-        RequestError::ErrorCode(718, _) => AddPortError::PortInUse,
-        RequestError::ErrorCode(724, _) => AddPortError::SamePortValuesRequired,
-        RequestError::ErrorCode(725, _) => AddPortError::OnlyPermanentLeasesSupported,
-        e => AddPortError::RequestError(e),
+        RequestError::ActionNotAuthorized => AddPortError::ActionNotAuthorized,
+        RequestError::WildCardNotPermittedInSrcIp => AddPortError::RemoteHostWildcardNotPermitted,
+        RequestError::WildCardNotPermittedInExtPort => AddPortError::ExternalPortZeroInvalid,
+        RequestError::ConflictInMappingEntry => AddPortError::PortInUse,
+        RequestError::SamePortValuesRequired => AddPortError::SamePortValuesRequired,
+        RequestError::OnlyPermanentLeasesSupported => AddPortError::OnlyPermanentLeasesSupported,
+        RequestError::RemoteHostOnlySupportsWildcard => AddPortError::RemoteHostOnlySupportsWildcard,
+        RequestError::ExternalPortOnlySupportsWildcard => AddPortError::ExternalPortOnlySupportsWildcard,
+        RequestError::NoPortMapsAvailable => AddPortError::NoPortsAvailable,
+        RequestError::ConflictWithOtherMechanisms => AddPortError::ConflictWithOtherMechanisms,
+        RequestError::WildCardNotPermittedInIntPort => AddPortError::InternalPortZeroInvalid,
+        _ => AddPortError::RequestError(err),
     }
 }
 
 pub fn parse_delete_port_mapping_response(result: RequestResult) -> Result<(), RemovePortError> {
     match result {
         Ok(_) => Ok(()),
-        Err(err) => Err(match err {
-            RequestError::ErrorCode(606, _) => RemovePortError::ActionNotAuthorized,
-            RequestError::ErrorCode(714, _) => RemovePortError::NoSuchPortMapping,
-            e => RemovePortError::RequestError(e),
+        Err(err) => Err(match *err.inner() {
+            RequestError::ActionNotAuthorized => RemovePortError::ActionNotAuthorized,
+            RequestError::NoSuchEntryInArray => RemovePortError::NoSuchPortMapping,
+            _ => RemovePortError::RequestError(err),
         }),
     }
 }
 
+/// Like `parse_delete_port_mapping_response`, but a range without mappings
+/// (`PortMappingNotFound`, 730) is not an error.
+pub fn parse_delete_port_mapping_range_response(result: RequestResult) -> Result<(), RemovePortError> {
+    match result {
+        Err(ref err) if err.error_code() == Some(730) => Ok(()),
+        result => parse_delete_port_mapping_response(result),
+    }
+}
+
+/// Check whether `get_generic_port_mapping_entry` failed because the index is past the end.
+///
+/// Some gateways answer `NoSuchEntryInArray` instead of `SpecifiedArrayIndexInvalid`.
+pub fn is_end_of_port_mappings(error: &GetGenericPortMappingEntryError) -> bool {
+    match *error {
+        GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid => true,
+        GetGenericPortMappingEntryError::RequestError(ref e) => matches!(e.inner(), RequestError::NoSuchEntryInArray),
+        _ => false,
+    }
+}
+
+/// State of the WAN connection.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectionStatus {
+    /// The connection is not configured
+    Unconfigured,
+    /// The connection is being established
+    Connecting,
+    /// The connection is up
+    Connected,
+    /// The connection will be torn down soon
+    PendingDisconnect,
+    /// The connection is being torn down
+    Disconnecting,
+    /// The connection is down
+    Disconnected,
+    /// A status not defined by the specification
+    Other(String),
+}
+
+impl ConnectionStatus {
+    /// Parse a status as returned by `GetStatusInfo`.
+    pub fn parse(status: &str) -> ConnectionStatus {
+        match status {
+            "Unconfigured" => ConnectionStatus::Unconfigured,
+            "Connecting" => ConnectionStatus::Connecting,
+            "Connected" => ConnectionStatus::Connected,
+            "PendingDisconnect" => ConnectionStatus::PendingDisconnect,
+            "Disconnecting" => ConnectionStatus::Disconnecting,
+            "Disconnected" => ConnectionStatus::Disconnected,
+            other => ConnectionStatus::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ConnectionStatus::Unconfigured => "Unconfigured",
+            ConnectionStatus::Connecting => "Connecting",
+            ConnectionStatus::Connected => "Connected",
+            ConnectionStatus::PendingDisconnect => "PendingDisconnect",
+            ConnectionStatus::Disconnecting => "Disconnecting",
+            ConnectionStatus::Disconnected => "Disconnected",
+            ConnectionStatus::Other(ref other) => other,
+        })
+    }
+}
+
+/// Status of the WAN connection as returned by `GetStatusInfo`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatusInfo {
+    /// Connection status
+    pub connection_status: ConnectionStatus,
+    /// Last connection error, e.g. `ERROR_NONE`
+    pub last_connection_error: String,
+    /// Uptime of the connection in seconds
+    pub uptime: u32,
+}
+
+pub fn parse_get_status_info_response(result: RequestResult) -> Result<StatusInfo, RequestError> {
+    let response = result?;
+    let text = |field: &str| response.argument(field).map(Cow::into_owned).unwrap_or_default();
+    Ok(StatusInfo {
+        connection_status: ConnectionStatus::parse(&text("NewConnectionStatus")),
+        last_connection_error: text("NewLastConnectionError"),
+        uptime: text("NewUptime").parse().unwrap_or(0),
+    })
+}
+
+/// Traffic counters of the WAN interface, from the `WANCommonInterfaceConfig` service.
+///
+/// The counters are 32 bits wide on most gateways and wrap around.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    /// Total number of bytes sent
+    pub bytes_sent: u64,
+    /// Total number of bytes received
+    pub bytes_received: u64,
+    /// Total number of packets sent
+    pub packets_sent: u64,
+    /// Total number of packets received
+    pub packets_received: u64,
+}
+
+/// A port mapping to add with `add_ports`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortMappingRequest {
+    /// Protocol of the mapping
+    pub protocol: PortMappingProtocol,
+    /// External port, 0 to let the gateway choose as in `map_port`
+    pub external_port: u16,
+    /// Address the traffic is sent to
+    pub local_addr: SocketAddrV4,
+    /// Lease duration in seconds, 0 for a permanent mapping
+    pub lease_duration: u32,
+    /// Description of the mapping
+    pub description: String,
+}
+
+/// A port mapping as it was created by the gateway.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MappedPort {
+    /// External port of the mapping
+    pub external_port: u16,
+    /// Lease duration of the mapping in seconds, 0 if it is permanent
+    pub lease_duration: u32,
+}
+
+/// Parse the output arguments of a response by name, e.g. for `Gateway::call_action`.
+pub fn parse_action_response(result: RequestResult) -> Result<HashMap<String, String>, RequestError> {
+    Ok(result?
+        .arguments()
+        .map(|(name, value)| (name.to_string(), value.into_owned()))
+        .collect())
+}
+
+/// Parse a response carrying a single counter, e.g. `NewTotalBytesSent`.
+pub fn parse_counter_response(result: RequestResult, field: &str) -> Result<u64, RequestError> {
+    parse_field(result, field)
+}
+
+/// Parse a single output argument of a response.
+pub fn parse_field<T: FromStr>(result: RequestResult, field: &str) -> Result<T, RequestError> {
+    let response = result?;
+    match response.argument(field).and_then(|t| t.parse::<T>().ok()) {
+        Some(value) => Ok(value),
+        None => Err(RequestError::invalid_response(&response.text)),
+    }
+}
+
+/// Parse a boolean output argument of a response, which UPnP allows as `0`/`1`, `false`/`true`
+/// or `no`/`yes`.
+pub fn parse_bool_field(result: RequestResult, field: &str) -> Result<bool, RequestError> {
+    let response = result?;
+    let value = response.argument(field).and_then(|t| {
+        if ["1", "true", "yes"].iter().any(|value| t.eq_ignore_ascii_case(value)) {
+            Some(true)
+        } else if ["0", "false", "no"].iter().any(|value| t.eq_ignore_ascii_case(value)) {
+            Some(false)
+        } else {
+            None
+        }
+    });
+    value.ok_or(RequestError::invalid_response(&response.text))
+}
+
+/// Information about the root device, taken from its description.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Short user-friendly title, e.g. `FRITZ!Box 7430`
+    pub friendly_name: String,
+    /// Manufacturer name, e.g. `AVM Berlin`
+    pub manufacturer: String,
+    /// Model name
+    pub model_name: String,
+    /// Model number
+    pub model_number: String,
+    /// Unique device name, e.g. `uuid:00000000-0000-0000-0000-000000000000`
+    pub udn: String,
+    /// Value of the `SERVER` header of the search response
+    pub server: String,
+    /// Url of the web interface of the device, e.g. to let the user change its settings when
+    /// mapping a port fails
+    pub presentation_url: Option<String>,
+}
+
+/// Everything the search functions need from a device description.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Description {
+    /// Url of the SCPD of the WAN connection service
+    pub control_schema_url: String,
+    /// Control url of the WAN connection service
+    pub control_url: String,
+    /// Control url of the WANCommonInterfaceConfig service, if the device has one
+    pub common_interface_control_url: Option<String>,
+    /// Control url of the WANIPv6FirewallControl service, if the device has one
+    pub firewall_control_url: Option<String>,
+    /// Information about the device
+    pub device_info: DeviceInfo,
+}
+
+pub fn parse_description(description: &[u8]) -> Result<Description, SearchError> {
+    let (control_schema_url, control_url) = parse_control_urls(description)?;
+    let common_interface_control_url = parse_service_urls(description, &[WAN_COMMON_INTERFACE_CONFIG_SERVICE])
+        .ok()
+        .map(|(_, control_url, _)| control_url);
+    let firewall_control_url = parse_service_urls(description, &[WAN_IPV6_FIREWALL_CONTROL_SERVICE])
+        .ok()
+        .map(|(_, control_url, _)| control_url);
+    let device_info = parse_device_info(description)?;
+    Ok(Description {
+        control_schema_url,
+        control_url,
+        common_interface_control_url,
+        firewall_control_url,
+        device_info,
+    })
+}
+
+pub fn parse_device_info<R>(resp: R) -> Result<DeviceInfo, SearchError>
+where
+    R: io::Read,
+{
+    let root = parse_xml(resp)?;
+    let device = root.get_child("device").ok_or(SearchError::InvalidResponse)?;
+    let url_base = root
+        .get_child("URLBase")
+        .and_then(|e| e.get_text())
+        .map(|t| t.trim().to_string());
+    let text = |name: &str| {
+        device
+            .get_child(name)
+            .and_then(|e| e.get_text())
+            .map(|t| t.trim().to_string())
+            .unwrap_or_default()
+    };
+
+    Ok(DeviceInfo {
+        friendly_name: text("friendlyName"),
+        manufacturer: text("manufacturer"),
+        model_name: text("modelName"),
+        model_number: text("modelNumber"),
+        udn: text("UDN"),
+        server: String::new(),
+        presentation_url: Some(text("presentationURL"))
+            .filter(|url| !url.is_empty())
+            .map(|url| match url_base {
+                Some(ref base) => resolve_url(base, &url),
+                None => url,
+            }),
+    })
+}
+
+/// Resolve `url` against `base`, keeping it as it is if either is invalid.
+pub fn resolve_url(base: &str, url: &str) -> String {
+    Url::parse(base)
+        .and_then(|base| base.join(url))
+        .map(String::from)
+        .unwrap_or_else(|_| url.to_string())
+}
+
 /// One port mapping entry as returned by GetGenericPortMappingEntry
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PortMappingEntry {
     /// The remote host for which the mapping is valid
     /// Can be an IP address or a host name
@@ -334,61 +680,75 @@ pub struct PortMappingEntry {
     pub lease_duration: u32,
 }
 
+impl PortMappingEntry {
+    /// The address the mapping forwards to, if its internal client is an IPv4 address.
+    pub(crate) fn internal_addr(&self) -> Result<SocketAddrV4, RequestError> {
+        let ip = self
+            .internal_client
+            .parse()
+            .map_err(|_| RequestError::InvalidResponse(format!("Invalid internal client {}", self.internal_client)))?;
+        Ok(SocketAddrV4::new(ip, self.internal_port))
+    }
+}
+
 pub fn parse_get_generic_port_mapping_entry(
     result: RequestResult,
 ) -> Result<PortMappingEntry, GetGenericPortMappingEntryError> {
     let response = result?;
-    let xml = response.xml;
-    let make_err = |msg: String| || GetGenericPortMappingEntryError::RequestError(RequestError::InvalidResponse(msg));
+    let text = raw_excerpt(response.text.as_bytes());
+    let make_err = |msg: String| {
+        let text = &text;
+        move || {
+            GetGenericPortMappingEntryError::RequestError(RequestError::InvalidResponse(format!(
+                "{} in {:?}",
+                msg, text
+            )))
+        }
+    };
     let extract_field = |field: &str| {
-        xml.get_child(field)
+        response
+            .argument(field)
             .ok_or_else(make_err(format!("{} is missing", field)))
     };
-    let remote_host = extract_field("NewRemoteHost")?
-        .get_text()
-        .map(|c| c.into_owned())
-        .unwrap_or_else(|| "".into());
+    let remote_host = extract_field("NewRemoteHost")?.into_owned();
     let external_port = extract_field("NewExternalPort")?
-        .get_text()
-        .and_then(|t| t.parse::<u16>().ok())
+        .parse::<u16>()
+        .ok()
         .ok_or_else(make_err("Field NewExternalPort is invalid".into()))?;
-    let protocol = match extract_field("NewProtocol")?.get_text() {
-        Some(std::borrow::Cow::Borrowed("UDP")) => PortMappingProtocol::UDP,
-        Some(std::borrow::Cow::Borrowed("TCP")) => PortMappingProtocol::TCP,
+    let protocol = match &*extract_field("NewProtocol")? {
+        "UDP" => PortMappingProtocol::UDP,
+        "TCP" => PortMappingProtocol::TCP,
         _ => {
             return Err(GetGenericPortMappingEntryError::RequestError(
-                RequestError::InvalidResponse("Field NewProtocol is invalid".into()),
+                RequestError::InvalidResponse(format!("Field NewProtocol is invalid in {:?}", text)),
             ))
         }
     };
     let internal_port = extract_field("NewInternalPort")?
-        .get_text()
-        .and_then(|t| t.parse::<u16>().ok())
+        .parse::<u16>()
+        .ok()
         .ok_or_else(make_err("Field NewInternalPort is invalid".into()))?;
-    let internal_client = extract_field("NewInternalClient")?
-        .get_text()
-        .map(|c| c.into_owned())
-        .ok_or_else(make_err("Field NewInternalClient is empty".into()))?;
+    let internal_client = Some(extract_field("NewInternalClient")?)
+        .filter(|client| !client.is_empty())
+        .ok_or_else(make_err("Field NewInternalClient is empty".into()))?
+        .into_owned();
     let enabled = match extract_field("NewEnabled")?
-        .get_text()
-        .and_then(|t| t.parse::<u16>().ok())
+        .parse::<u16>()
+        .ok()
         .ok_or_else(make_err("Field Enabled is invalid".into()))?
     {
         0 => false,
         1 => true,
         _ => {
             return Err(GetGenericPortMappingEntryError::RequestError(
-                RequestError::InvalidResponse("Field NewEnabled is invalid".into()),
+                RequestError::InvalidResponse(format!("Field NewEnabled is invalid in {:?}", text)),
             ))
         }
     };
-    let port_mapping_description = extract_field("NewPortMappingDescription")?
-        .get_text()
-        .map(|c| c.into_owned())
-        .unwrap_or_else(|| "".into());
+    let port_mapping_description = extract_field("NewPortMappingDescription")?.into_owned();
     let lease_duration = extract_field("NewLeaseDuration")?
-        .get_text()
-        .and_then(|t| t.parse::<u32>().ok())
+        .parse::<u32>()
+        .ok()
         .ok_or_else(make_err("Field NewLeaseDuration is invalid".into()))?;
     Ok(PortMappingEntry {
         remote_host,
@@ -402,15 +762,51 @@ pub fn parse_get_generic_port_mapping_entry(
     })
 }
 
+/// Parse a response to `GetSpecificPortMappingEntry`, which leaves out the protocol and the
+/// external port that were asked for.
+pub fn parse_get_specific_port_mapping_entry(
+    result: RequestResult,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+) -> Result<PortMappingEntry, RequestError> {
+    let response = result?;
+    let text = |field: &str| response.argument(field);
+    let invalid = || RequestError::invalid_response(&response.text);
+    Ok(PortMappingEntry {
+        remote_host: String::new(),
+        external_port,
+        protocol,
+        internal_port: text("NewInternalPort")
+            .and_then(|t| t.parse().ok())
+            .ok_or_else(invalid)?,
+        internal_client: text("NewInternalClient").map(Cow::into_owned).ok_or_else(invalid)?,
+        enabled: match text("NewEnabled").as_deref() {
+            Some("1") => true,
+            Some("0") => false,
+            _ => return Err(invalid()),
+        },
+        port_mapping_description: text("NewPortMappingDescription")
+            .map(Cow::into_owned)
+            .unwrap_or_default(),
+        lease_duration: text("NewLeaseDuration")
+            .and_then(|t| t.parse().ok())
+            .ok_or_else(invalid)?,
+    })
+}
+
 #[test]
 fn test_parse_search_result_case_insensitivity() {
-    assert!(parse_search_result("location:http://0.0.0.0:0/control_url").is_ok());
-    assert!(parse_search_result("LOCATION:http://0.0.0.0:0/control_url").is_ok());
+    let result = |text: &str| parse_search_result(&Headers::parse(text.as_bytes()));
+    assert!(result("HTTP/1.1 200 OK\r\nlocation:http://0.0.0.0:0/control_url").is_ok());
+    assert!(result("HTTP/1.1 200 OK\r\nLOCATION:http://0.0.0.0:0/control_url").is_ok());
 }
 
 #[test]
 fn test_parse_search_result_ok() {
-    let result = parse_search_result("location:http://0.0.0.0:0/control_url").unwrap();
+    let result = parse_search_result(&Headers::parse(
+        b"HTTP/1.1 200 OK\r\nlocation:http://0.0.0.0:0/control_url",
+    ))
+    .unwrap();
     assert_eq!(result.0.ip(), &Ipv4Addr::new(0, 0, 0, 0));
     assert_eq!(result.0.port(), 0);
     assert_eq!(&result.1[..], "/control_url");
@@ -418,7 +814,19 @@ fn test_parse_search_result_ok() {
 
 #[test]
 fn test_parse_search_result_fail() {
-    assert!(parse_search_result("content-type:http://0.0.0.0:0/control_url").is_err());
+    assert!(parse_search_result(&Headers::parse(
+        b"HTTP/1.1 200 OK\r\ncontent-type:http://0.0.0.0:0/control_url"
+    ))
+    .is_err());
+    assert!(parse_location("http://router.local/rootDesc.xml").is_err());
+}
+
+#[test]
+fn test_parse_max_age() {
+    assert_eq!(parse_max_age("max-age=1800"), Some(Duration::from_secs(1800)));
+    assert_eq!(parse_max_age("no-cache, MAX-AGE = 120"), Some(Duration::from_secs(120)));
+    assert_eq!(parse_max_age("no-cache"), None);
+    assert_eq!(parse_max_age("max-age=forever"), None);
 }
 
 #[test]
@@ -611,6 +1019,51 @@ fn test_parse_device2() {
     let (control_schema_url, control_url) = result.unwrap();
     assert_eq!(control_url, "/igdupnp/control/WANIPConn1");
     assert_eq!(control_schema_url, "/igdconnSCPD.xml");
+
+    let description = parse_description(text.as_bytes()).unwrap();
+    assert_eq!(
+        description.common_interface_control_url.as_deref(),
+        Some("/igdupnp/control/WANCommonIFC1")
+    );
+    assert_eq!(
+        description.firewall_control_url.as_deref(),
+        Some("/igd2upnp/control/WANIPv6Firewall1")
+    );
+
+    let info = parse_device_info(text.as_bytes()).unwrap();
+    assert_eq!(info.friendly_name, "FRITZ!Box 7430");
+    assert_eq!(info.manufacturer, "AVM Berlin");
+    assert_eq!(info.model_name, "FRITZ!Box 7430");
+    assert_eq!(info.udn, "uuid:00000000-0000-0000-0000-000000000000");
+    assert_eq!(info.presentation_url.as_deref(), Some("http://fritz.box"));
+}
+
+#[test]
+fn test_parse_presentation_url() {
+    let text = r#"<?xml version="1.0"?>
+    <root xmlns="urn:schemas-upnp-org:device-1-0">
+        <URLBase>http://192.168.1.1:49000/</URLBase>
+        <device>
+            <friendlyName>Router</friendlyName>
+            <presentationURL>/admin/index.html</presentationURL>
+        </device>
+    </root>
+    "#;
+    let info = parse_device_info(text.as_bytes()).unwrap();
+    assert_eq!(
+        info.presentation_url.as_deref(),
+        Some("http://192.168.1.1:49000/admin/index.html")
+    );
+
+    assert_eq!(
+        resolve_url("http://192.168.1.1:5000/rootDesc.xml", "admin"),
+        "http://192.168.1.1:5000/admin"
+    );
+    assert_eq!(
+        resolve_url("http://192.168.1.1:5000/rootDesc.xml", "http://router/"),
+        "http://router/"
+    );
+    assert_eq!(resolve_url("not a url", "/admin"), "/admin");
 }
 
 #[test]
@@ -699,3 +1152,142 @@ fn test_parse_device3() {
     assert_eq!(control_url, "/upnp/control/WANIPConn1");
     assert_eq!(control_schema_url, "/332b484d/wanipconnSCPD.xml");
 }
+
+#[test]
+fn test_connection_status() {
+    assert_eq!(ConnectionStatus::parse("Connected"), ConnectionStatus::Connected);
+    assert_eq!(
+        ConnectionStatus::parse("PendingDisconnect").to_string(),
+        "PendingDisconnect"
+    );
+    assert_eq!(
+        ConnectionStatus::parse("Authenticating"),
+        ConnectionStatus::Other("Authenticating".into())
+    );
+}
+
+#[test]
+fn test_parse_fault() {
+    let fault = |code: u16| {
+        format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+<s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>
+<detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+<errorCode>{}</errorCode><errorDescription>Some description</errorDescription>
+</UPnPError></detail></s:Fault></s:Body></s:Envelope>"#,
+            code
+        )
+    };
+
+    let error = parse_response(fault(729), "AddPortMappingResponse").err().unwrap();
+    assert_eq!(error.error_code(), Some(729));
+    match convert_add_port_error(error) {
+        AddPortError::ConflictWithOtherMechanisms => {}
+        e => panic!("unexpected error {:?}", e),
+    }
+
+    match parse_response(fault(714), "DeletePortMappingResponse").map(|_| ()) {
+        Err(RequestError::NoSuchEntryInArray) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    match parse_response(fault(899), "DeletePortMappingResponse").map(|_| ()) {
+        Err(RequestError::ErrorCode(899, ref text)) if text == "Some description" => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_parse_action_response() {
+    let text = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+<s:Body><u:GetInfoResponse xmlns:u="urn:dslforum-org:service:DeviceInfo:1">
+<NewUpTime> 3600 </NewUpTime><NewDescription/>
+</u:GetInfoResponse></s:Body></s:Envelope>"#;
+    let arguments = parse_action_response(parse_response(text.to_string(), "GetInfoResponse")).unwrap();
+    assert_eq!(arguments.len(), 2);
+    assert_eq!(arguments["NewUpTime"], "3600");
+    assert_eq!(arguments["NewDescription"], "");
+}
+
+#[test]
+fn test_parse_response_with_status() {
+    let headers = vec![("WWW-Authenticate".to_string(), "Digest realm=\"gateway\"".to_string())];
+    match parse_response_with_status(401, headers, "Unauthorized".to_string(), "GetInfoResponse") {
+        Err(e @ RequestError::HttpStatus { status: 401, .. }) => {
+            assert!(e.is_permanent());
+            assert_eq!(
+                e.to_string(),
+                "Gateway answered with HTTP status 401, WWW-Authenticate: Digest realm=\"gateway\": Unauthorized"
+            );
+        }
+        r => panic!("unexpected {:?}", r.err()),
+    }
+    match parse_response_with_status(503, Vec::new(), String::new(), "GetInfoResponse") {
+        Err(ref e @ RequestError::HttpStatus { status: 503, .. }) => assert!(e.is_retryable()),
+        r => panic!("unexpected {:?}", r.err()),
+    }
+
+    let fault = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+<s:Body><s:Fault><detail><UPnPError><errorCode>606</errorCode><errorDescription>Action not authorized</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>"#;
+    match parse_response_with_status(500, Vec::new(), fault.to_string(), "GetInfoResponse") {
+        Err(RequestError::ActionNotAuthorized) => {}
+        r => panic!("unexpected {:?}", r.err()),
+    }
+    match parse_response_with_status(200, Vec::new(), "not xml".to_string(), "GetInfoResponse") {
+        Err(RequestError::InvalidResponse(_)) => {}
+        r => panic!("unexpected {:?}", r.err()),
+    }
+}
+
+#[test]
+fn test_invalid_response_data() {
+    let long = format!("<s:Envelope>{}</s:Envelope>", "x".repeat(4000));
+    match parse_response(long.clone(), "AddPortMappingResponse") {
+        Err(RequestError::InvalidResponse(text)) => {
+            assert!(text.starts_with("<s:Envelope>xxx"));
+            assert!(text.ends_with(&format!("... ({} more bytes)", long.len() - 2048)));
+        }
+        Err(e) => panic!("unexpected {:?}", e),
+        Ok(..) => panic!("unexpected success"),
+    }
+}
+
+#[test]
+fn test_parse_get_generic_port_mapping_entry() {
+    let response = |description: &str| {
+        format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+<s:Body><u:GetGenericPortMappingEntryResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewRemoteHost></NewRemoteHost><NewExternalPort>8080</NewExternalPort><NewProtocol>TCP</NewProtocol>
+<NewInternalPort>80</NewInternalPort><NewInternalClient>192.168.1.2</NewInternalClient><NewEnabled>1</NewEnabled>
+<NewPortMappingDescription>{}</NewPortMappingDescription><NewLeaseDuration>3600</NewLeaseDuration>
+</u:GetGenericPortMappingEntryResponse></s:Body></s:Envelope>"#,
+            description
+        )
+    };
+    let parse =
+        |text: String| parse_get_generic_port_mapping_entry(parse_response(text, "GetGenericPortMappingEntryResponse"));
+
+    let entry = parse(response("web &amp; &lt;ssh&gt;")).unwrap();
+    assert_eq!(entry.remote_host, "");
+    assert_eq!(entry.external_port, 8080);
+    assert_eq!(entry.protocol, PortMappingProtocol::TCP);
+    assert_eq!(entry.internal_client, "192.168.1.2");
+    assert!(entry.enabled);
+    assert_eq!(entry.port_mapping_description, "web & <ssh>");
+    assert_eq!(entry.lease_duration, 3600);
+    let entry = parse(response("<![CDATA[a <b>]]>")).unwrap();
+    assert_eq!(entry.port_mapping_description, "a <b>");
+
+    let missing = response("").replace("<NewLeaseDuration>3600</NewLeaseDuration>", "");
+    match parse(missing) {
+        Err(GetGenericPortMappingEntryError::RequestError(RequestError::InvalidResponse(text))) => {
+            assert!(text.starts_with("NewLeaseDuration is missing"))
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+}
@@ -0,0 +1,223 @@
+//! Port mappings declared in a TOML or JSON file, kept on a gateway by `PortMappingManager`.
+//!
+//! The file names the tag of the mappings and lists them, so the forwarding table of a router
+//! can be kept under version control and applied with `MappingConfig::apply`, e.g. by
+//! `igd-cli apply` or a small daemon calling it periodically. Tagged mappings missing from the
+//! file are removed, others are never touched.
+//!
+//! ```toml
+//! tag = "homelab:"
+//!
+//! [[mappings]]
+//! protocol = "TCP"
+//! external_port = 443
+//! internal_client = "192.168.1.10"
+//! local_port = 8443
+//! description = "web"
+//!
+//! [[mappings]]
+//! protocol = "UDP"
+//! external_port = 51820
+//! local_port = 51820
+//! lease_duration = 3600
+//! description = "wireguard"
+//! ```
+//!
+//! # Example
+//! ```no_run
+//! use igd::config::MappingConfig;
+//!
+//! let config = MappingConfig::load("mappings.toml").unwrap();
+//! let gateway = igd::search_gateway(Default::default()).unwrap();
+//! let mut manager = config.manager(gateway);
+//! let report = config.apply(&mut manager).unwrap();
+//! println!("added {}, removed {}", report.added.len(), report.removed.len());
+//! ```
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{Error, RequestError};
+use crate::{Gateway, PortMappingManager, PortMappingProtocol, PortMappingRequest, SyncReport};
+
+/// The mappings of a config file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappingConfig {
+    /// Put in front of the descriptions of the mappings, see `PortMappingManager::new`
+    pub tag: String,
+    /// The mappings to keep on the gateway
+    #[serde(default)]
+    pub mappings: Vec<MappingSpec>,
+}
+
+/// One mapping of a `MappingConfig`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappingSpec {
+    /// Protocol of the mapping, `TCP` or `UDP`
+    pub protocol: PortMappingProtocol,
+    /// External port, 0 to let the gateway choose
+    pub external_port: u16,
+    /// Host the traffic is sent to, this host if not given. Other hosts need
+    /// `Gateway::allow_third_party`.
+    #[serde(default)]
+    pub internal_client: Option<Ipv4Addr>,
+    /// Port the traffic is sent to
+    pub local_port: u16,
+    /// Lease duration in seconds, 0 for a permanent mapping (the default)
+    #[serde(default)]
+    pub lease_duration: u32,
+    /// Description of the mapping, which tells it apart from the others with the same tag
+    pub description: String,
+}
+
+/// Errors of loading a `MappingConfig`.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read
+    IoError(io::Error),
+    /// The file is not valid TOML or doesn't describe mappings
+    Toml(toml::de::Error),
+    /// The file is not valid JSON or doesn't describe mappings
+    Json(serde_json::Error),
+    /// The extension of the file is neither `.toml` nor `.json`
+    UnknownFormat(String),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> ConfigError {
+        ConfigError::IoError(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> ConfigError {
+        ConfigError::Toml(err)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(err: serde_json::Error) -> ConfigError {
+        ConfigError::Json(err)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::IoError(ref e) => write!(f, "IO error: {}", e),
+            ConfigError::Toml(ref e) => write!(f, "Invalid TOML mapping config: {}", e),
+            ConfigError::Json(ref e) => write!(f, "Invalid JSON mapping config: {}", e),
+            ConfigError::UnknownFormat(ref path) => {
+                write!(f, "Mapping config {} is neither .toml nor .json", path)
+            }
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ConfigError::IoError(ref e) => Some(e),
+            ConfigError::Toml(ref e) => Some(e),
+            ConfigError::Json(ref e) => Some(e),
+            ConfigError::UnknownFormat(_) => None,
+        }
+    }
+}
+
+impl MappingConfig {
+    /// Read a config file, in TOML or JSON depending on its extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<MappingConfig, ConfigError> {
+        let path = path.as_ref();
+        let parse = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => MappingConfig::from_toml,
+            Some("json") => MappingConfig::from_json,
+            _ => return Err(ConfigError::UnknownFormat(path.display().to_string())),
+        };
+        parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse a config in TOML.
+    pub fn from_toml(text: &str) -> Result<MappingConfig, ConfigError> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Parse a config in JSON.
+    pub fn from_json(text: &str) -> Result<MappingConfig, ConfigError> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    /// A manager of the mappings of `gateway` with the tag of the config.
+    pub fn manager(&self, gateway: Gateway) -> PortMappingManager {
+        PortMappingManager::new(gateway, self.tag.clone())
+    }
+
+    /// The requests to sync, with `local_ip` as the internal client where none is given.
+    pub fn requests(&self, local_ip: Ipv4Addr) -> Vec<PortMappingRequest> {
+        self.mappings
+            .iter()
+            .map(|spec| PortMappingRequest {
+                protocol: spec.protocol,
+                external_port: spec.external_port,
+                local_addr: SocketAddrV4::new(spec.internal_client.unwrap_or(local_ip), spec.local_port),
+                lease_duration: spec.lease_duration,
+                description: spec.description.clone(),
+            })
+            .collect()
+    }
+
+    /// Make the mappings of `manager` match the config, see `PortMappingManager::sync`.
+    ///
+    /// Mappings without an internal client go to the address of this host facing the gateway.
+    pub fn apply(&self, manager: &mut PortMappingManager) -> Result<SyncReport, Error> {
+        let local_ip = manager.gateway().local_addr_hint().map_err(RequestError::from)?;
+        Ok(manager.sync(&self.requests(local_ip))?)
+    }
+}
+
+#[test]
+fn test_parse() {
+    let toml = r#"
+        tag = "homelab:"
+
+        [[mappings]]
+        protocol = "TCP"
+        external_port = 443
+        internal_client = "192.168.1.10"
+        local_port = 8443
+        description = "web"
+
+        [[mappings]]
+        protocol = "UDP"
+        external_port = 51820
+        local_port = 51820
+        lease_duration = 3600
+        description = "wireguard"
+    "#;
+    let config = MappingConfig::from_toml(toml).unwrap();
+    assert_eq!(config.tag, "homelab:");
+    assert_eq!(config.mappings.len(), 2);
+    assert_eq!(config.mappings[0].internal_client, Some(Ipv4Addr::new(192, 168, 1, 10)));
+    assert_eq!(config.mappings[0].lease_duration, 0);
+
+    let json = serde_json::to_string(&config).unwrap();
+    assert_eq!(MappingConfig::from_json(&json).unwrap(), config);
+
+    let requests = config.requests(Ipv4Addr::new(192, 168, 1, 2));
+    assert_eq!(requests[0].local_addr, "192.168.1.10:8443".parse().unwrap());
+    assert_eq!(requests[1].protocol, PortMappingProtocol::UDP);
+    assert_eq!(requests[1].local_addr, "192.168.1.2:51820".parse().unwrap());
+
+    assert!(MappingConfig::from_toml("tag = 1").is_err());
+    assert!(MappingConfig::from_json(r#"{"tag": "a:", "mappings": [{"protocol": "SCTP"}]}"#).is_err());
+    match MappingConfig::load("mappings.yaml") {
+        Err(ConfigError::UnknownFormat(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
@@ -0,0 +1,112 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+/// A point in time by which a whole operation has to be done, e.g. finding the gateway and
+/// mapping a port, or never.
+///
+/// The timeouts of the search, of fetching the descriptions and of every request otherwise each
+/// hold on their own, so together they may take far longer than the caller meant. Every phase
+/// run under a deadline waits at most until it, see `search_gateway_within` and
+/// `Gateway::with_deadline`.
+///
+/// # Example
+/// ```no_run
+/// use std::net::SocketAddrV4;
+/// use std::time::Duration;
+/// use igd::{Deadline, PortMappingProtocol};
+///
+/// // A mapping within 5 seconds, or an error.
+/// let deadline = Deadline::after(Duration::from_secs(5));
+/// let gateway = igd::search_gateway_within(Default::default(), deadline).unwrap();
+/// let local_addr = "192.168.1.2:8080".parse::<SocketAddrV4>().unwrap();
+/// gateway.add_any_port(PortMappingProtocol::TCP, local_addr, 60, "example").unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Deadline {
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    /// No deadline, the timeouts alone bound the operation.
+    pub fn never() -> Deadline {
+        Deadline { at: None }
+    }
+
+    /// The deadline `budget` from now.
+    pub fn after(budget: Duration) -> Deadline {
+        Deadline::at(Instant::now() + budget)
+    }
+
+    /// The deadline at `instant`.
+    pub fn at(instant: Instant) -> Deadline {
+        Deadline { at: Some(instant) }
+    }
+
+    /// The deadline `timeout` after `start`, or none without a timeout.
+    pub(crate) fn from_timeout(start: Instant, timeout: Option<Duration>) -> Deadline {
+        Deadline {
+            at: timeout.map(|timeout| start + timeout),
+        }
+    }
+
+    /// The instant of the deadline, `None` if there is none.
+    pub fn instant(&self) -> Option<Instant> {
+        self.at
+    }
+
+    /// Time left until the deadline, zero once it passed, or `None` if there is none.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at.map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Whether the deadline passed.
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// The shorter of `timeout` and the time left, e.g. to bound one phase of an operation.
+    pub fn limit(&self, timeout: Option<Duration>) -> Option<Duration> {
+        match (timeout, self.remaining()) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        }
+    }
+
+    /// The earlier of the two deadlines.
+    pub fn min(self, other: Deadline) -> Deadline {
+        match (self.at, other.at) {
+            (Some(a), Some(b)) => Deadline::at(a.min(b)),
+            (a, b) => Deadline { at: a.or(b) },
+        }
+    }
+
+    /// Fail with a `TimedOut` error if the deadline passed.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.is_expired() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the deadline passed"));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_deadline() {
+    let never = Deadline::never();
+    assert_eq!(never.remaining(), None);
+    assert!(!never.is_expired());
+    assert_eq!(never.limit(None), None);
+    assert_eq!(never.limit(Some(Duration::from_secs(1))), Some(Duration::from_secs(1)));
+
+    let soon = Deadline::after(Duration::from_secs(60));
+    assert!(soon.remaining().unwrap() <= Duration::from_secs(60));
+    assert_eq!(soon.limit(Some(Duration::from_secs(1))), Some(Duration::from_secs(1)));
+    assert!(soon.limit(None).unwrap() > Duration::from_secs(1));
+    assert_eq!(soon.min(never), soon);
+    assert_eq!(never.min(soon), soon);
+
+    let passed = Deadline::at(Instant::now() - Duration::from_secs(1));
+    assert!(passed.is_expired());
+    assert_eq!(passed.limit(Some(Duration::from_secs(1))), Some(Duration::ZERO));
+    assert_eq!(soon.min(passed), passed);
+    assert_eq!(passed.check().unwrap_err().kind(), io::ErrorKind::TimedOut);
+}
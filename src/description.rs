@@ -0,0 +1,324 @@
+use xmltree::Element;
+
+use crate::common::parsing;
+use crate::errors::SearchError;
+
+/// The root device description of a gateway, see `Gateway::description`.
+///
+/// It holds the whole device tree, for what the crate doesn't use itself, e.g. the icons or
+/// the services of other devices. Missing elements are left empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RootDescription {
+    /// The XML document, as fetched
+    pub xml: String,
+    /// `URLBase` the relative urls are resolved against, if given. Without it, they are relative
+    /// to the url of the description.
+    pub url_base: Option<String>,
+    /// The root device
+    pub device: DeviceDescription,
+}
+
+impl RootDescription {
+    /// The WAN connection services of all devices, in the order of the description.
+    pub fn wan_connections(&self) -> Vec<WanConnection> {
+        self.device
+            .all_devices()
+            .into_iter()
+            .flat_map(|device| {
+                device
+                    .services
+                    .iter()
+                    .filter(|service| parsing::WAN_CONNECTION_SERVICES.contains(&service.service_type.as_str()))
+                    .map(move |service| WanConnection {
+                        device_udn: device.udn.clone(),
+                        service: service.clone(),
+                    })
+            })
+            .collect()
+    }
+}
+
+/// A device of a `RootDescription`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceDescription {
+    /// Type, e.g. `urn:schemas-upnp-org:device:InternetGatewayDevice:1`
+    pub device_type: String,
+    /// Short user-friendly title
+    pub friendly_name: String,
+    /// Manufacturer name
+    pub manufacturer: String,
+    /// Web site of the manufacturer, if given
+    pub manufacturer_url: Option<String>,
+    /// Long user-friendly title, if given
+    pub model_description: Option<String>,
+    /// Model name
+    pub model_name: String,
+    /// Model number, if given
+    pub model_number: Option<String>,
+    /// Web site of the model, if given
+    pub model_url: Option<String>,
+    /// Serial number, if given
+    pub serial_number: Option<String>,
+    /// Unique device name
+    pub udn: String,
+    /// Web interface, if given
+    pub presentation_url: Option<String>,
+    /// Icons
+    pub icons: Vec<IconDescription>,
+    /// Services
+    pub services: Vec<ServiceDescription>,
+    /// Embedded devices
+    pub devices: Vec<DeviceDescription>,
+}
+
+impl DeviceDescription {
+    /// Iterate over this device and all devices embedded in it, depth first.
+    pub fn all_devices(&self) -> Vec<&DeviceDescription> {
+        let mut devices = vec![self];
+        for device in &self.devices {
+            devices.extend(device.all_devices());
+        }
+        devices
+    }
+
+    /// Find the first service of this device or the devices embedded in it with the given type.
+    pub fn find_service(&self, service_type: &str) -> Option<&ServiceDescription> {
+        self.all_devices()
+            .into_iter()
+            .flat_map(|device| device.services.iter())
+            .find(|service| service.service_type == service_type)
+    }
+}
+
+/// An icon of a `DeviceDescription`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IconDescription {
+    /// MIME type, e.g. `image/png`
+    pub mime_type: String,
+    /// Width in pixels, 0 if not given
+    pub width: u32,
+    /// Height in pixels, 0 if not given
+    pub height: u32,
+    /// Color depth in bits, 0 if not given
+    pub depth: u32,
+    /// Url of the image
+    pub url: String,
+}
+
+/// A service of a `DeviceDescription`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServiceDescription {
+    /// Type, e.g. `urn:schemas-upnp-org:service:WANIPConnection:1`
+    pub service_type: String,
+    /// Identifier, e.g. `urn:upnp-org:serviceId:WANIPConn1`
+    pub service_id: String,
+    /// Url of the service description (SCPD)
+    pub scpd_url: String,
+    /// Url to send the actions to
+    pub control_url: String,
+    /// Url to subscribe to the events at
+    pub event_sub_url: String,
+}
+
+/// A WAN connection service, see `Gateway::wan_connections`.
+///
+/// Routers with several WAN links, e.g. a DSL line and a cellular backup, have one for each.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WanConnection {
+    /// UDN of the WANConnectionDevice the service belongs to
+    pub device_udn: String,
+    /// The WANIPConnection or WANPPPConnection service
+    pub service: ServiceDescription,
+}
+
+impl WanConnection {
+    /// Whether this is the connection named `name` by the Layer3Forwarding service, e.g.
+    /// `uuid:<UUID>:WANConnectionDevice:1,urn:upnp-org:serviceId:WANIPConn1`.
+    pub fn is_named(&self, name: &str) -> bool {
+        match name.trim().rsplit_once(',') {
+            Some((device, service_id)) => {
+                service_id.trim() == self.service.service_id
+                    && (self.device_udn.is_empty() || device.starts_with(&self.device_udn))
+            }
+            None => false,
+        }
+    }
+}
+
+/// Parse a root device description.
+pub fn parse(document: &[u8]) -> Result<RootDescription, SearchError> {
+    let root = parsing::parse_xml(document)?;
+    let device = root.get_child("device").ok_or(SearchError::InvalidResponse)?;
+    Ok(RootDescription {
+        xml: String::from_utf8_lossy(document).into_owned(),
+        url_base: optional_text(&root, "URLBase"),
+        device: parse_device(device),
+    })
+}
+
+fn parse_device(device: &Element) -> DeviceDescription {
+    DeviceDescription {
+        device_type: text(device, "deviceType"),
+        friendly_name: text(device, "friendlyName"),
+        manufacturer: text(device, "manufacturer"),
+        manufacturer_url: optional_text(device, "manufacturerURL"),
+        model_description: optional_text(device, "modelDescription"),
+        model_name: text(device, "modelName"),
+        model_number: optional_text(device, "modelNumber"),
+        model_url: optional_text(device, "modelURL"),
+        serial_number: optional_text(device, "serialNumber"),
+        udn: text(device, "UDN"),
+        presentation_url: optional_text(device, "presentationURL"),
+        icons: list(device, "iconList", "icon")
+            .map(|icon| IconDescription {
+                mime_type: text(icon, "mimetype"),
+                width: text(icon, "width").parse().unwrap_or_default(),
+                height: text(icon, "height").parse().unwrap_or_default(),
+                depth: text(icon, "depth").parse().unwrap_or_default(),
+                url: text(icon, "url"),
+            })
+            .collect(),
+        services: list(device, "serviceList", "service")
+            .map(|service| ServiceDescription {
+                service_type: text(service, "serviceType"),
+                service_id: text(service, "serviceId"),
+                scpd_url: text(service, "SCPDURL"),
+                control_url: text(service, "controlURL"),
+                event_sub_url: text(service, "eventSubURL"),
+            })
+            .collect(),
+        devices: list(device, "deviceList", "device").map(parse_device).collect(),
+    }
+}
+
+/// The children named `item` of the child `list` of `element`.
+fn list<'a>(element: &'a Element, list: &str, item: &'a str) -> impl Iterator<Item = &'a Element> {
+    element
+        .get_child(list)
+        .into_iter()
+        .flat_map(|list| list.children.iter())
+        .filter_map(|child| child.as_element())
+        .filter(move |child| child.name == item)
+}
+
+fn text(element: &Element, name: &str) -> String {
+    optional_text(element, name).unwrap_or_default()
+}
+
+fn optional_text(element: &Element, name: &str) -> Option<String> {
+    element
+        .get_child(name)
+        .and_then(|e| e.get_text())
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+#[test]
+fn test_parse() {
+    let text = r#"<?xml version="1.0"?>
+    <root xmlns="urn:schemas-upnp-org:device-1-0">
+        <URLBase>http://192.168.1.1:5000</URLBase>
+        <device>
+            <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+            <friendlyName>Router</friendlyName>
+            <manufacturer>ACME</manufacturer>
+            <modelName>R1</modelName>
+            <serialNumber> 1234 </serialNumber>
+            <UDN>uuid:1</UDN>
+            <iconList>
+                <icon><mimetype>image/png</mimetype><width>48</width><height>48</height><depth>24</depth><url>/icon.png</url></icon>
+            </iconList>
+            <serviceList>
+                <service>
+                    <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+                    <serviceId>urn:upnp-org:serviceId:L3Forwarding1</serviceId>
+                    <SCPDURL>/L3F.xml</SCPDURL>
+                    <controlURL>/ctl/L3F</controlURL>
+                    <eventSubURL>/evt/L3F</eventSubURL>
+                </service>
+            </serviceList>
+            <deviceList>
+                <device>
+                    <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+                    <UDN>uuid:2</UDN>
+                    <serviceList>
+                        <service>
+                            <serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType>
+                            <controlURL>/ctl/CmnIfCfg</controlURL>
+                        </service>
+                    </serviceList>
+                </device>
+            </deviceList>
+        </device>
+    </root>
+    "#;
+    let description = parse(text.as_bytes()).unwrap();
+    assert_eq!(description.xml, text);
+    assert_eq!(description.url_base.as_deref(), Some("http://192.168.1.1:5000"));
+
+    let device = &description.device;
+    assert_eq!(device.friendly_name, "Router");
+    assert_eq!(device.serial_number.as_deref(), Some("1234"));
+    assert_eq!(device.model_number, None);
+    assert_eq!(device.icons[0].width, 48);
+    assert_eq!(device.icons[0].url, "/icon.png");
+    assert_eq!(device.services[0].event_sub_url, "/evt/L3F");
+    assert_eq!(device.all_devices().len(), 2);
+    assert_eq!(device.devices[0].udn, "uuid:2");
+
+    let service = device
+        .find_service("urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1")
+        .unwrap();
+    assert_eq!(service.control_url, "/ctl/CmnIfCfg");
+    assert_eq!(service.scpd_url, "");
+    assert!(device
+        .find_service("urn:schemas-upnp-org:service:WANIPConnection:1")
+        .is_none());
+    assert!(description.wan_connections().is_empty());
+}
+
+#[test]
+fn test_wan_connections() {
+    let connection_device = |udn: &str, service_type: &str, service_id: &str| {
+        format!(
+            r#"<device>
+                <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+                <UDN>{}</UDN>
+                <serviceList>
+                    <service>
+                        <serviceType>urn:schemas-upnp-org:service:{}</serviceType>
+                        <serviceId>urn:upnp-org:serviceId:{}</serviceId>
+                        <controlURL>/ctl/{}</controlURL>
+                    </service>
+                </serviceList>
+            </device>"#,
+            udn, service_type, service_id, service_id
+        )
+    };
+    let text = format!(
+        r#"<?xml version="1.0"?>
+        <root xmlns="urn:schemas-upnp-org:device-1-0">
+            <device>
+                <UDN>uuid:1</UDN>
+                <deviceList>
+                    <device>
+                        <UDN>uuid:2</UDN>
+                        <deviceList>{}{}</deviceList>
+                    </device>
+                </deviceList>
+            </device>
+        </root>"#,
+        connection_device("uuid:3", "WANIPConnection:1", "WANIPConn1"),
+        connection_device("uuid:4", "WANPPPConnection:1", "WANPPPConn1"),
+    );
+    let connections = parse(text.as_bytes()).unwrap().wan_connections();
+    assert_eq!(connections.len(), 2);
+    assert_eq!(connections[0].device_udn, "uuid:3");
+    assert_eq!(connections[1].service.control_url, "/ctl/WANPPPConn1");
+
+    let default = "uuid:4:WANConnectionDevice:1,urn:upnp-org:serviceId:WANPPPConn1";
+    assert!(!connections[0].is_named(default));
+    assert!(connections[1].is_named(default));
+    assert!(!connections[1].is_named("uuid:3:WANConnectionDevice:1,urn:upnp-org:serviceId:WANPPPConn1"));
+    assert!(!connections[1].is_named(""));
+}
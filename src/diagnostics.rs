@@ -0,0 +1,354 @@
+//! A report of what the gateways on the network say about themselves, to attach to bug reports.
+//!
+//! `collect` searches the gateways, keeping the raw search responses, then fetches the device
+//! description and the SCPD of every service of each gateway found, and sends a few actions
+//! that change nothing, like `GetExternalIPAddress` and `GetStatusInfo`. Everything is put in
+//! one `Report`, with the serial numbers, UDNs and external addresses redacted, which is
+//! written as JSON with `Report::to_json`. SCPDs at urls the `url_policy` of the search options
+//! doesn't allow, e.g. through the `URLBase` of a description, aren't fetched.
+//!
+//! # Example
+//! ```no_run
+//! let report = igd::diagnostics::collect(Default::default());
+//! std::fs::write("igd-diagnostics.json", report.to_json()).unwrap();
+//! ```
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::common::parsing;
+use crate::common::{self, messages, SearchOptions, UrlPolicy};
+use crate::errors::SearchError;
+use crate::search::{self, SearchTransport};
+use crate::Gateway;
+
+/// What replaces the identifying values in a `Report`.
+pub const REDACTED: &str = "REDACTED";
+
+/// Actions sent to the WAN connection service, none of which change anything.
+const CONNECTION_ACTIONS: &[&str] = &[
+    "GetExternalIPAddress",
+    "GetStatusInfo",
+    "GetConnectionTypeInfo",
+    "GetNATRSIPStatus",
+];
+
+/// Actions sent to the WANCommonInterfaceConfig service, none of which change anything.
+const COMMON_INTERFACE_ACTIONS: &[&str] = &["GetCommonLinkProperties", "GetTotalBytesReceived"];
+
+/// Everything `collect` gathered.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Report {
+    /// Version of the crate
+    pub version: String,
+    /// The search responses received, in order
+    pub search_responses: Vec<SearchResponse>,
+    /// Why the search failed, if it did
+    pub search_error: Option<String>,
+    /// The gateways found
+    pub gateways: Vec<GatewayReport>,
+}
+
+/// A datagram received in answer to the search.
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchResponse {
+    /// Address the datagram came from
+    pub from: String,
+    /// The datagram, decoded as UTF-8 with invalid sequences replaced
+    pub text: String,
+}
+
+/// What a gateway said about itself.
+#[derive(Clone, Debug, Serialize)]
+pub struct GatewayReport {
+    /// Control url of the gateway, see `Gateway`'s `Display`
+    pub gateway: String,
+    /// Url of the device description
+    pub description_url: String,
+    /// The device description, as fetched
+    pub description: String,
+    /// The service descriptions (SCPD) of all services
+    pub service_descriptions: Vec<Document>,
+    /// What the gateway supports, see `Capabilities`
+    pub capabilities: String,
+    /// The results of the actions sent
+    pub actions: Vec<ActionResult>,
+}
+
+/// A document fetched from a gateway.
+#[derive(Clone, Debug, Serialize)]
+pub struct Document {
+    /// Url of the document
+    pub url: String,
+    /// The document, if it could be fetched
+    pub text: Option<String>,
+    /// Why it couldn't be fetched
+    pub error: Option<String>,
+}
+
+/// The result of an action sent to a gateway.
+#[derive(Clone, Debug, Serialize)]
+pub struct ActionResult {
+    /// Type of the service the action was sent to
+    pub service_type: String,
+    /// Name of the action
+    pub action: String,
+    /// Output arguments by name, if the action succeeded
+    pub output: Option<BTreeMap<String, String>>,
+    /// Why the action failed
+    pub error: Option<String>,
+}
+
+impl Report {
+    /// The report as pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("reports always serialize to JSON")
+    }
+
+    /// Replace each of `secrets` in every text of the report with `REDACTED`.
+    fn redact(&mut self, secrets: &[String]) {
+        let redact = |text: &mut String| {
+            for secret in secrets {
+                if text.contains(secret.as_str()) {
+                    *text = text.replace(secret.as_str(), REDACTED);
+                }
+            }
+        };
+        for response in &mut self.search_responses {
+            redact(&mut response.text);
+        }
+        for gateway in &mut self.gateways {
+            redact(&mut gateway.description);
+            for document in &mut gateway.service_descriptions {
+                document.text.iter_mut().for_each(redact);
+            }
+            for output in gateway.actions.iter_mut().filter_map(|action| action.output.as_mut()) {
+                output.values_mut().for_each(redact);
+            }
+        }
+    }
+}
+
+/// Search the gateways with `options` and report what they say about themselves.
+///
+/// This never fails, errors are part of the report. It takes the search timeout, plus the
+/// time to fetch the documents and send the actions.
+pub fn collect(options: SearchOptions) -> Report {
+    let mut report = Report {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ..Default::default()
+    };
+    let url_policy = options.url_policy;
+    let gateways = match common::bind_search_socket(&options) {
+        Ok(socket) => {
+            let recorder = Recorder {
+                socket: &socket,
+                responses: RefCell::new(Vec::new()),
+            };
+            let result = search::search_multi_gateways_with(&recorder, options);
+            report.search_responses = recorder.responses.into_inner();
+            result
+        }
+        Err(e) => Err(e.into()),
+    };
+    let gateways = match gateways {
+        Ok(gateways) => gateways,
+        Err(e) => {
+            report.search_error = Some(e.to_string());
+            Vec::new()
+        }
+    };
+
+    let mut secrets = Vec::new();
+    for gateway in &gateways {
+        let gateway_report = report_gateway(gateway, url_policy);
+        for device in gateway.description().device.all_devices() {
+            secrets.push(device.udn.clone());
+            secrets.extend(device.serial_number.clone());
+        }
+        for action in &gateway_report.actions {
+            if let Some(ip) = action
+                .output
+                .as_ref()
+                .and_then(|output| output.get("NewExternalIPAddress"))
+            {
+                secrets.push(ip.clone());
+            }
+        }
+        report.gateways.push(gateway_report);
+    }
+    secrets.retain(|secret| !secret.is_empty());
+    report.redact(&secrets);
+    report
+}
+
+fn report_gateway(gateway: &Gateway, url_policy: UrlPolicy) -> GatewayReport {
+    let description_url = format!("http://{}{}", gateway.addr, gateway.root_url);
+    let description = gateway.description();
+    let base = description.url_base.as_deref().unwrap_or(&description_url);
+    let services = gateway.services();
+
+    let service_descriptions = services
+        .iter()
+        .filter(|service| !service.scpd_url.is_empty())
+        .map(|service| {
+            let url = parsing::resolve_url(base, &service.scpd_url);
+            if let Err(e) = check_url(url_policy, (*gateway.addr.ip()).into(), &url) {
+                return Document {
+                    url,
+                    text: None,
+                    error: Some(e.to_string()),
+                };
+            }
+            match search::get_with_status(&url, gateway.deadline) {
+                Ok((200, document)) => Document {
+                    url,
+                    text: Some(String::from_utf8_lossy(&document).into_owned()),
+                    error: None,
+                },
+                Ok((status, _)) => Document {
+                    url,
+                    text: None,
+                    error: Some(format!("HTTP status {}", status)),
+                },
+                Err(e) => Document {
+                    url,
+                    text: None,
+                    error: Some(e.to_string()),
+                },
+            }
+        })
+        .collect();
+
+    let mut actions = Vec::new();
+    for service in &services {
+        let names = if service.control_url == gateway.control_url.trim() {
+            CONNECTION_ACTIONS
+        } else if service.service_type == messages::WAN_COMMON_INTERFACE_CONFIG_SERVICE {
+            COMMON_INTERFACE_ACTIONS
+        } else {
+            continue;
+        };
+        for action in names {
+            let result = gateway.call_action(service, action, &[]);
+            actions.push(ActionResult {
+                service_type: service.service_type.clone(),
+                action: action.to_string(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                output: result.ok().map(|output| output.into_iter().collect()),
+            });
+        }
+    }
+
+    GatewayReport {
+        gateway: gateway.to_string(),
+        description_url,
+        description: description.xml.clone(),
+        service_descriptions,
+        capabilities: gateway.capabilities().to_string(),
+        actions,
+    }
+}
+
+/// Fail if a device at `from` pointed to `url`, e.g. through the `URLBase` of its description,
+/// and `policy` doesn't allow it. Host names are only allowed by `UrlPolicy::Any`, since they
+/// may resolve to any address.
+fn check_url(policy: UrlPolicy, from: IpAddr, url: &str) -> Result<(), SearchError> {
+    let host = url::Url::parse(url).ok().and_then(|url| match url.host() {
+        Some(url::Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+        _ => None,
+    });
+    match host {
+        Some(host) if policy.allows(from, host) => Ok(()),
+        None if policy == UrlPolicy::Any => Ok(()),
+        _ => Err(SearchError::UrlNotAllowed(url.to_string())),
+    }
+}
+
+/// A transport keeping the datagrams received.
+struct Recorder<'a> {
+    socket: &'a UdpSocket,
+    responses: RefCell<Vec<SearchResponse>>,
+}
+
+impl SearchTransport for Recorder<'_> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (read, from) = self.socket.recv_from(buf)?;
+        self.responses.borrow_mut().push(SearchResponse {
+            from: from.to_string(),
+            text: String::from_utf8_lossy(&buf[..read]).into_owned(),
+        });
+        Ok((read, from))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+#[test]
+fn test_check_url() {
+    let from = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 1));
+    assert!(check_url(UrlPolicy::SameHost, from, "http://192.168.1.1:5000/scpd.xml").is_ok());
+    assert!(check_url(UrlPolicy::SameHost, from, "http://192.168.1.2:5000/scpd.xml").is_err());
+    assert!(check_url(UrlPolicy::Local, from, "http://192.168.1.2:5000/scpd.xml").is_ok());
+    assert!(check_url(UrlPolicy::Local, from, "http://203.0.113.1/scpd.xml").is_err());
+    assert!(check_url(UrlPolicy::Local, from, "http://router.example/scpd.xml").is_err());
+    assert!(check_url(UrlPolicy::Local, from, "http://127.0.0.1:5000/scpd.xml").is_err());
+    assert!(check_url(UrlPolicy::Local, from, "http://[::1]:5000/scpd.xml").is_err());
+    assert!(check_url(UrlPolicy::Any, from, "http://router.example/scpd.xml").is_ok());
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_collect() {
+    let mock = crate::test::MockGateway::start().unwrap();
+    let report = collect(SearchOptions {
+        timeout: Some(Duration::from_millis(500)),
+        ..mock.search_options()
+    });
+    assert_eq!(report.search_error, None);
+    assert_eq!(report.search_responses.len(), 1);
+    assert_eq!(report.gateways.len(), 1);
+
+    let gateway = &report.gateways[0];
+    assert!(gateway.description.contains("<UDN>REDACTED</UDN>"));
+    assert!(!report.search_responses[0]
+        .text
+        .contains("00000000-0000-0000-0000-000000000001"));
+    let scpd = gateway
+        .service_descriptions
+        .iter()
+        .find(|document| document.url.ends_with("/WANIPCn.xml"))
+        .unwrap();
+    assert!(scpd.text.as_ref().unwrap().contains("GetExternalIPAddress"));
+    let missing = gateway
+        .service_descriptions
+        .iter()
+        .find(|document| document.url.ends_with("/WANCfg.xml"))
+        .unwrap();
+    assert_eq!(missing.error.as_deref(), Some("HTTP status 404"));
+
+    let external_ip = gateway
+        .actions
+        .iter()
+        .find(|action| action.action == "GetExternalIPAddress")
+        .unwrap();
+    assert_eq!(external_ip.output.as_ref().unwrap()["NewExternalIPAddress"], REDACTED);
+    assert!(report.to_json().contains("\"GetStatusInfo\""));
+    assert!(!report.to_json().contains(&mock.external_ip().to_string()));
+}
@@ -0,0 +1,194 @@
+use std::net::{Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use crate::common::{self, parsing::MappedPort};
+use crate::errors::{Error, RequestError};
+use crate::{Gateway, PortMappingProtocol};
+
+/// A port forwarded over IPv4 and IPv6, made by `Gateway::open_dual_stack`.
+///
+/// The IPv4 side is a port mapping, the IPv6 side a pinhole in the firewall of the gateway, if
+/// the gateway and the host support IPv6. Both expire with their leases unless they are renewed,
+/// nothing is removed when the handle is dropped.
+///
+/// # Example
+/// ```no_run
+/// use igd::PortMappingProtocol;
+///
+/// let gateway = igd::search_gateway(Default::default()).unwrap();
+/// let mut forward = gateway
+///     .open_dual_stack(8080, PortMappingProtocol::TCP, 3600, "web")
+///     .unwrap();
+/// println!("IPv4 port {}, IPv6 address {:?}", forward.external_port(), forward.pinhole_addr());
+/// forward.renew().unwrap();
+/// forward.remove().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct DualStackMapping {
+    gateway: Gateway,
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    mapped: MappedPort,
+    description: String,
+    lease_duration: u32,
+    pinhole: Option<(SocketAddrV6, u16)>,
+}
+
+impl DualStackMapping {
+    pub(crate) fn open(
+        gateway: Gateway,
+        port: u16,
+        protocol: PortMappingProtocol,
+        lease_duration: u32,
+        description: &str,
+        local_ipv6: Option<Ipv6Addr>,
+    ) -> Result<DualStackMapping, Error> {
+        let local_addr = SocketAddrV4::new(gateway.local_addr_hint().map_err(RequestError::from)?, port);
+        let mapped = gateway.map_port(protocol, port, local_addr, lease_duration, description)?;
+
+        let pinhole = match (&gateway.firewall_control_url, local_ipv6) {
+            (Some(_), Some(ip)) => {
+                let internal = SocketAddrV6::new(ip, port, 0, 0);
+                match gateway.add_pinhole(protocol, internal, common::pinhole_lease_time(lease_duration)) {
+                    Ok(unique_id) => Some((internal, unique_id)),
+                    Err(e) => {
+                        if let Err(e) = gateway.remove_port(protocol, mapped.external_port) {
+                            debug!("removing the mapping of {} port {} failed: {}", protocol, port, e);
+                        }
+                        return Err(e.into());
+                    }
+                }
+            }
+            (Some(_), None) => {
+                debug!("no global IPv6 address, not opening a pinhole for port {}", port);
+                None
+            }
+            (None, _) => None,
+        };
+
+        Ok(DualStackMapping {
+            gateway,
+            protocol,
+            local_addr,
+            mapped,
+            description: description.to_string(),
+            lease_duration,
+            pinhole,
+        })
+    }
+
+    /// The gateway the port is forwarded on.
+    pub fn gateway(&self) -> &Gateway {
+        &self.gateway
+    }
+
+    /// The protocol of the forwarded port.
+    pub fn protocol(&self) -> PortMappingProtocol {
+        self.protocol
+    }
+
+    /// The local IPv4 address the external port is mapped to.
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.local_addr
+    }
+
+    /// The external IPv4 port.
+    pub fn external_port(&self) -> u16 {
+        self.mapped.external_port
+    }
+
+    /// The IPv6 address the pinhole lets traffic through to, if one was opened.
+    pub fn pinhole_addr(&self) -> Option<SocketAddrV6> {
+        self.pinhole.map(|(internal, _)| internal)
+    }
+
+    /// The id of the pinhole given by the gateway, if one was opened.
+    pub fn pinhole_id(&self) -> Option<u16> {
+        self.pinhole.map(|(_, unique_id)| unique_id)
+    }
+
+    /// Renew the leases of the mapping and the pinhole.
+    ///
+    /// The mapping is added again with the lease granted the first time, and the pinhole lease
+    /// is set anew. Call this before the shorter of both leases expires.
+    pub fn renew(&mut self) -> Result<(), Error> {
+        self.mapped = self.gateway.map_port(
+            self.protocol,
+            self.mapped.external_port,
+            self.local_addr,
+            self.mapped.lease_duration,
+            &self.description,
+        )?;
+        if let Some((_, unique_id)) = self.pinhole {
+            self.gateway
+                .update_pinhole(unique_id, common::pinhole_lease_time(self.lease_duration))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the mapping and close the pinhole.
+    ///
+    /// Both are tried, the first error is returned.
+    pub fn remove(self) -> Result<(), Error> {
+        let mapping = self
+            .gateway
+            .remove_port(self.protocol, self.mapped.external_port)
+            .map_err(Error::from);
+        let pinhole = match self.pinhole {
+            Some((_, unique_id)) => self.gateway.delete_pinhole(unique_id).map_err(Error::from),
+            None => Ok(()),
+        };
+        mapping.and(pinhole)
+    }
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_dual_stack_mapping() {
+    let mock = crate::test::MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+
+    let mut forward = DualStackMapping::open(
+        gateway.clone(),
+        5000,
+        PortMappingProtocol::TCP,
+        0,
+        "dual",
+        Some(Ipv6Addr::LOCALHOST),
+    )
+    .unwrap();
+    assert_eq!(forward.external_port(), 5000);
+    assert_eq!(
+        forward.pinhole_addr(),
+        Some(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 5000, 0, 0))
+    );
+    let pinholes = mock.pinholes();
+    assert_eq!(pinholes.len(), 1);
+    assert_eq!(pinholes[0].protocol, 6);
+    assert_eq!(pinholes[0].lease_time, common::MAX_PINHOLE_LEASE_TIME);
+
+    forward.renew().unwrap();
+    forward.remove().unwrap();
+    assert!(mock.mappings().is_empty());
+    assert!(mock.pinholes().is_empty());
+
+    // Without an IPv6 address only the port is mapped.
+    let forward = DualStackMapping::open(gateway, 5001, PortMappingProtocol::UDP, 60, "dual", None).unwrap();
+    assert_eq!(forward.pinhole_id(), None);
+    forward.remove().unwrap();
+
+    mock.respond(
+        "AddPinhole",
+        crate::test::MockResponse::Fault(606, "Action not authorized".into()),
+    );
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    let result = DualStackMapping::open(
+        gateway,
+        5002,
+        PortMappingProtocol::TCP,
+        0,
+        "dual",
+        Some(Ipv6Addr::LOCALHOST),
+    );
+    assert!(result.is_err());
+    assert!(mock.mappings().is_empty());
+}
@@ -1,13 +1,72 @@
 use std::error;
 use std::fmt;
 use std::io;
+use std::net::SocketAddrV4;
 use std::str;
 #[cfg(feature = "aio")]
 use std::string::FromUtf8Error;
+use std::time::Duration;
 
 #[cfg(feature = "aio")]
 use tokio::time::error::Elapsed;
 
+/// Longest raw data kept in an error, so a huge response doesn't bloat it.
+const MAX_RAW_DATA_LEN: usize = 2048;
+
+/// The raw data that couldn't be parsed, as text cut to `MAX_RAW_DATA_LEN` bytes.
+pub(crate) fn raw_excerpt(data: &[u8]) -> String {
+    if data.len() <= MAX_RAW_DATA_LEN {
+        return String::from_utf8_lossy(data).into_owned();
+    }
+    format!(
+        "{}... ({} more bytes)",
+        String::from_utf8_lossy(&data[..MAX_RAW_DATA_LEN]),
+        data.len() - MAX_RAW_DATA_LEN
+    )
+}
+
+/// Headers of an HTTP error response kept in `RequestError::HttpStatus`.
+pub(crate) const HTTP_ERROR_HEADERS: &[&str] =
+    &["WWW-Authenticate", "Retry-After", "Location", "Server", "Content-Type"];
+
+/// Whether the header `name` is one of `HTTP_ERROR_HEADERS`.
+pub(crate) fn is_http_error_header(name: &str) -> bool {
+    HTTP_ERROR_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name))
+}
+
+/// The action a failed request asked a gateway to perform.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestContext {
+    /// Address of the gateway
+    pub gateway: SocketAddrV4,
+    /// Url of the control point the request was sent to
+    pub url: String,
+    /// Type of the service, e.g. `urn:schemas-upnp-org:service:WANIPConnection:1`
+    pub service: String,
+    /// Name of the action, e.g. `AddPortMapping`
+    pub action: String,
+    /// Time from sending the request until it failed
+    pub elapsed: Duration,
+}
+
+impl RequestContext {
+    /// The context of a request with the given `SOAPAction` header, e.g.
+    /// `"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping"`.
+    pub(crate) fn new(gateway: SocketAddrV4, url: &str, header: &str, elapsed: Duration) -> RequestContext {
+        let header = header.trim_matches('"');
+        let (service, action) = header.split_once('#').unwrap_or(("", header));
+        RequestContext {
+            gateway,
+            url: url.to_string(),
+            service: service.to_string(),
+            action: action.to_string(),
+            elapsed,
+        }
+    }
+}
+
 /// Errors that can occur when sending the request to the gateway.
 #[derive(Debug)]
 pub enum RequestError {
@@ -15,12 +74,63 @@ pub enum RequestError {
     AttoHttpError(attohttpc::Error),
     /// IO Error
     IoError(io::Error),
-    /// The response from the gateway could not be parsed.
+    /// The response from the gateway could not be parsed. Holds the reason, or the response cut
+    /// to 2 KiB.
     InvalidResponse(String),
+    /// The arguments of the action were invalid (error code 402).
+    InvalidArgs,
+    /// The gateway failed to perform the action (error code 501).
+    ActionFailed,
+    /// The client is not authorized to perform the action (error code 606).
+    ActionNotAuthorized,
+    /// The port mapping index is out of bounds (error code 713).
+    SpecifiedArrayIndexInvalid,
+    /// There is no such port mapping (error code 714).
+    NoSuchEntryInArray,
+    /// The remote host can not be a wildcard (error code 715).
+    WildCardNotPermittedInSrcIp,
+    /// The external port can not be a wildcard (error code 716).
+    WildCardNotPermittedInExtPort,
+    /// The mapping conflicts with a mapping assigned to another client (error code 718).
+    ConflictInMappingEntry,
+    /// The internal and external ports have to be the same (error code 724).
+    SamePortValuesRequired,
+    /// The gateway only supports permanent leases (error code 725).
+    OnlyPermanentLeasesSupported,
+    /// The remote host has to be a wildcard (error code 726).
+    RemoteHostOnlySupportsWildcard,
+    /// The external port has to be a wildcard (error code 727).
+    ExternalPortOnlySupportsWildcard,
+    /// The gateway has no free ports left (error code 728).
+    NoPortMapsAvailable,
+    /// The mapping conflicts with one made by another mechanism, e.g. NAT-PMP (error code 729).
+    ConflictWithOtherMechanisms,
+    /// The internal port can not be a wildcard (error code 732).
+    WildCardNotPermittedInIntPort,
     /// The gateway returned an unhandled error code and description.
     ErrorCode(u16, String),
     /// Action is not supported by the gateway
     UnsupportedAction(String),
+    /// The gateway answered with an HTTP error status instead of a SOAP response or fault, e.g.
+    /// 404 when the control url moved with a firmware update, 503 while it is busy, or 401 when
+    /// it requires authentication.
+    HttpStatus {
+        /// The status code
+        status: u16,
+        /// The headers telling more, among `WWW-Authenticate`, `Retry-After`, `Location`,
+        /// `Server` and `Content-Type`
+        headers: Vec<(String, String)>,
+        /// The body, cut to 2 KiB
+        body: String,
+    },
+    /// An action of a gateway failed. Every error of a request to a control point is wrapped in
+    /// this, see `context` and `inner`.
+    RequestFailed {
+        /// The gateway and the action
+        context: Box<RequestContext>,
+        /// Why it failed
+        error: Box<RequestError>,
+    },
     /// When using the aio feature.
     #[cfg(feature = "aio")]
     HyperError(hyper::Error),
@@ -34,6 +144,195 @@ pub enum RequestError {
     Utf8Error(FromUtf8Error),
 }
 
+impl RequestError {
+    /// Build the error for a UPnP error code and description returned by the gateway.
+    pub(crate) fn from_error_code(code: u16, description: String) -> RequestError {
+        match code {
+            402 => RequestError::InvalidArgs,
+            501 => RequestError::ActionFailed,
+            606 => RequestError::ActionNotAuthorized,
+            713 => RequestError::SpecifiedArrayIndexInvalid,
+            714 => RequestError::NoSuchEntryInArray,
+            715 => RequestError::WildCardNotPermittedInSrcIp,
+            716 => RequestError::WildCardNotPermittedInExtPort,
+            718 => RequestError::ConflictInMappingEntry,
+            724 => RequestError::SamePortValuesRequired,
+            725 => RequestError::OnlyPermanentLeasesSupported,
+            726 => RequestError::RemoteHostOnlySupportsWildcard,
+            727 => RequestError::ExternalPortOnlySupportsWildcard,
+            728 => RequestError::NoPortMapsAvailable,
+            729 => RequestError::ConflictWithOtherMechanisms,
+            732 => RequestError::WildCardNotPermittedInIntPort,
+            _ => RequestError::ErrorCode(code, description),
+        }
+    }
+
+    /// The UPnP error code returned by the gateway, if the request failed with one.
+    pub fn error_code(&self) -> Option<u16> {
+        match *self {
+            RequestError::InvalidArgs => Some(402),
+            RequestError::ActionFailed => Some(501),
+            RequestError::ActionNotAuthorized => Some(606),
+            RequestError::SpecifiedArrayIndexInvalid => Some(713),
+            RequestError::NoSuchEntryInArray => Some(714),
+            RequestError::WildCardNotPermittedInSrcIp => Some(715),
+            RequestError::WildCardNotPermittedInExtPort => Some(716),
+            RequestError::ConflictInMappingEntry => Some(718),
+            RequestError::SamePortValuesRequired => Some(724),
+            RequestError::OnlyPermanentLeasesSupported => Some(725),
+            RequestError::RemoteHostOnlySupportsWildcard => Some(726),
+            RequestError::ExternalPortOnlySupportsWildcard => Some(727),
+            RequestError::NoPortMapsAvailable => Some(728),
+            RequestError::ConflictWithOtherMechanisms => Some(729),
+            RequestError::WildCardNotPermittedInIntPort => Some(732),
+            RequestError::ErrorCode(n, _) => Some(n),
+            RequestError::RequestFailed { ref error, .. } => error.error_code(),
+            _ => None,
+        }
+    }
+}
+
+impl RequestError {
+    /// The error of a response that couldn't be parsed, holding it cut to 2 KiB.
+    pub(crate) fn invalid_response(text: &str) -> RequestError {
+        RequestError::InvalidResponse(raw_excerpt(text.as_bytes()))
+    }
+
+    /// Attach the gateway and action of the failed request.
+    pub(crate) fn with_context(self, context: RequestContext) -> RequestError {
+        RequestError::RequestFailed {
+            context: Box::new(context),
+            error: Box::new(self),
+        }
+    }
+
+    /// The gateway and action of the failed request, if the error came from a gateway.
+    pub fn context(&self) -> Option<&RequestContext> {
+        match *self {
+            RequestError::RequestFailed { ref context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The error without its context, e.g. `ConflictInMappingEntry`, to match on.
+    pub fn inner(&self) -> &RequestError {
+        match *self {
+            RequestError::RequestFailed { ref error, .. } => error.inner(),
+            _ => self,
+        }
+    }
+
+    /// Whether the request may succeed if it is sent again later.
+    ///
+    /// That is the case after timeouts and dropped connections, and when the gateway failed to
+    /// perform the action (error code 501), which it does while it is busy or booting.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            RequestError::AttoHttpError(ref e) => is_transient_http(e),
+            RequestError::IoError(ref e) => is_transient(e),
+            RequestError::ActionFailed => true,
+            RequestError::ErrorCode(code, _) => (500..600).contains(&code),
+            RequestError::HttpStatus { status, .. } => matches!(status, 429 | 500 | 502 | 503 | 504),
+            RequestError::RequestFailed { ref error, .. } => error.is_retryable(),
+            #[cfg(feature = "aio")]
+            RequestError::HyperError(ref e) => is_transient_hyper(e),
+            _ => false,
+        }
+    }
+
+    /// Whether the gateway will refuse the same request every time, e.g. because the client is
+    /// not authorized or the gateway doesn't accept a wildcard.
+    ///
+    /// An error can be neither retryable nor permanent, e.g. a conflict with another mapping,
+    /// which lasts until the other mapping is removed.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            RequestError::InvalidArgs
+            | RequestError::ActionNotAuthorized
+            | RequestError::SpecifiedArrayIndexInvalid
+            | RequestError::NoSuchEntryInArray
+            | RequestError::WildCardNotPermittedInSrcIp
+            | RequestError::WildCardNotPermittedInExtPort
+            | RequestError::WildCardNotPermittedInIntPort
+            | RequestError::SamePortValuesRequired
+            | RequestError::OnlyPermanentLeasesSupported
+            | RequestError::RemoteHostOnlySupportsWildcard
+            | RequestError::ExternalPortOnlySupportsWildcard
+            | RequestError::UnsupportedAction(..) => true,
+            RequestError::HttpStatus { status, .. } => matches!(status, 401 | 403 | 404 | 405 | 501),
+            RequestError::RequestFailed { ref error, .. } => error.is_permanent(),
+            _ => false,
+        }
+    }
+
+    /// The kind of `io::Error` this converts to.
+    fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            RequestError::IoError(ref e) => timed_out_kind(e.kind()),
+            RequestError::RequestFailed { ref error, .. } => error.io_kind(),
+            RequestError::InvalidResponse(..) => io::ErrorKind::InvalidData,
+            RequestError::ActionNotAuthorized => io::ErrorKind::PermissionDenied,
+            RequestError::ConflictInMappingEntry | RequestError::ConflictWithOtherMechanisms => {
+                io::ErrorKind::AddrInUse
+            }
+            RequestError::NoPortMapsAvailable => io::ErrorKind::AddrNotAvailable,
+            RequestError::SpecifiedArrayIndexInvalid | RequestError::NoSuchEntryInArray => io::ErrorKind::NotFound,
+            RequestError::InvalidArgs
+            | RequestError::WildCardNotPermittedInSrcIp
+            | RequestError::WildCardNotPermittedInExtPort
+            | RequestError::WildCardNotPermittedInIntPort
+            | RequestError::SamePortValuesRequired
+            | RequestError::OnlyPermanentLeasesSupported
+            | RequestError::RemoteHostOnlySupportsWildcard
+            | RequestError::ExternalPortOnlySupportsWildcard => io::ErrorKind::InvalidInput,
+            RequestError::UnsupportedAction(..) => io::ErrorKind::Unsupported,
+            RequestError::HttpStatus { status: 401, .. } | RequestError::HttpStatus { status: 403, .. } => {
+                io::ErrorKind::PermissionDenied
+            }
+            RequestError::HttpStatus { status: 404, .. } => io::ErrorKind::NotFound,
+            #[cfg(feature = "aio")]
+            RequestError::Utf8Error(..) => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+/// A socket whose read timeout expired fails with `WouldBlock` on some platforms, which
+/// callers of a blocking request see as a timeout.
+fn timed_out_kind(kind: io::ErrorKind) -> io::ErrorKind {
+    match kind {
+        io::ErrorKind::WouldBlock => io::ErrorKind::TimedOut,
+        kind => kind,
+    }
+}
+
+/// Whether an I/O error may go away by itself, e.g. a timeout or a gateway that is rebooting.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+fn is_transient_http(e: &attohttpc::Error) -> bool {
+    match *e.kind() {
+        attohttpc::ErrorKind::Io(ref e) => is_transient(e),
+        _ => false,
+    }
+}
+
+#[cfg(feature = "aio")]
+fn is_transient_hyper(e: &hyper::Error) -> bool {
+    e.is_timeout() || e.is_connect() || e.is_incomplete_message() || e.is_closed()
+}
+
 impl From<attohttpc::Error> for RequestError {
     fn from(err: attohttpc::Error) -> RequestError {
         RequestError::AttoHttpError(err)
@@ -70,24 +369,74 @@ impl From<FromUtf8Error> for RequestError {
 #[cfg(feature = "aio")]
 impl From<Elapsed> for RequestError {
     fn from(_err: Elapsed) -> RequestError {
-        RequestError::IoError(io::Error::new(io::ErrorKind::TimedOut, "timer failed"))
+        RequestError::IoError(io::Error::new(io::ErrorKind::TimedOut, "the request timed out"))
     }
 }
 
 impl fmt::Display for RequestError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            RequestError::AttoHttpError(ref e) => write!(f, "HTTP error {}", e),
+            RequestError::AttoHttpError(ref e) => write!(f, "HTTP error: {}", e),
             RequestError::InvalidResponse(ref e) => write!(f, "Invalid response from gateway: {}", e),
-            RequestError::IoError(ref e) => write!(f, "IO error. {}", e),
+            RequestError::IoError(ref e) => write!(f, "IO error: {}", e),
+            RequestError::InvalidArgs => write!(f, "Gateway response error 402: Invalid Args"),
+            RequestError::ActionFailed => write!(f, "Gateway response error 501: Action Failed"),
+            RequestError::ActionNotAuthorized => write!(f, "Gateway response error 606: Action not authorized"),
+            RequestError::SpecifiedArrayIndexInvalid => {
+                write!(f, "Gateway response error 713: Specified array index invalid")
+            }
+            RequestError::NoSuchEntryInArray => write!(f, "Gateway response error 714: No such entry in array"),
+            RequestError::WildCardNotPermittedInSrcIp => {
+                write!(f, "Gateway response error 715: Wildcard not permitted in remote host")
+            }
+            RequestError::WildCardNotPermittedInExtPort => {
+                write!(f, "Gateway response error 716: Wildcard not permitted in external port")
+            }
+            RequestError::ConflictInMappingEntry => write!(f, "Gateway response error 718: Conflict in mapping entry"),
+            RequestError::SamePortValuesRequired => write!(f, "Gateway response error 724: Same port values required"),
+            RequestError::OnlyPermanentLeasesSupported => {
+                write!(f, "Gateway response error 725: Only permanent leases supported")
+            }
+            RequestError::RemoteHostOnlySupportsWildcard => {
+                write!(f, "Gateway response error 726: Remote host only supports wildcard")
+            }
+            RequestError::ExternalPortOnlySupportsWildcard => {
+                write!(f, "Gateway response error 727: External port only supports wildcard")
+            }
+            RequestError::NoPortMapsAvailable => write!(f, "Gateway response error 728: No port maps available"),
+            RequestError::ConflictWithOtherMechanisms => {
+                write!(f, "Gateway response error 729: Conflict with other mechanisms")
+            }
+            RequestError::WildCardNotPermittedInIntPort => {
+                write!(f, "Gateway response error 732: Wildcard not permitted in internal port")
+            }
             RequestError::ErrorCode(n, ref e) => write!(f, "Gateway response error {}: {}", n, e),
             RequestError::UnsupportedAction(ref e) => write!(f, "Gateway does not support action: {}", e),
+            RequestError::HttpStatus {
+                status,
+                ref headers,
+                ref body,
+            } => {
+                write!(f, "Gateway answered with HTTP status {}", status)?;
+                for (name, value) in headers {
+                    write!(f, ", {}: {}", name, value)?;
+                }
+                if !body.is_empty() {
+                    write!(f, ": {}", body)?;
+                }
+                Ok(())
+            }
+            RequestError::RequestFailed { ref context, ref error } => write!(
+                f,
+                "{} request to {} failed after {:?}: {}",
+                context.action, context.url, context.elapsed, error
+            ),
             #[cfg(feature = "aio")]
-            RequestError::HyperError(ref e) => write!(f, "Hyper Error: {}", e),
+            RequestError::HyperError(ref e) => write!(f, "Hyper error: {}", e),
             #[cfg(feature = "aio")]
-            RequestError::HttpError(ref e) => write!(f, "Http  Error: {}", e),
+            RequestError::HttpError(ref e) => write!(f, "HTTP error: {}", e),
             #[cfg(feature = "aio")]
-            RequestError::Utf8Error(ref e) => write!(f, "Utf8Error Error: {}", e),
+            RequestError::Utf8Error(ref e) => write!(f, "UTF-8 error in the response body: {}", e),
         }
     }
 }
@@ -100,12 +449,14 @@ impl std::error::Error for RequestError {
             RequestError::IoError(ref e) => Some(e),
             RequestError::ErrorCode(..) => None,
             RequestError::UnsupportedAction(..) => None,
+            RequestError::RequestFailed { ref error, .. } => Some(&**error),
             #[cfg(feature = "aio")]
             RequestError::HyperError(ref e) => Some(e),
             #[cfg(feature = "aio")]
             RequestError::HttpError(ref e) => Some(e),
             #[cfg(feature = "aio")]
             RequestError::Utf8Error(ref e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -137,6 +488,8 @@ pub enum AddAnyPortError {
     ActionNotAuthorized,
     /// Can not add a mapping for local port 0.
     InternalPortZeroInvalid,
+    /// The local address is not an address of this host and `allow_third_party` is not set.
+    InternalClientNotLocal,
     /// The gateway does not have any free ports.
     NoPortsAvailable,
     /// The gateway can only map internal ports to same-numbered external ports
@@ -172,16 +525,30 @@ pub enum AddPortError {
     ActionNotAuthorized,
     /// Can not add a mapping for local port 0.
     InternalPortZeroInvalid,
+    /// The local address is not an address of this host and `allow_third_party` is not set.
+    InternalClientNotLocal,
     /// External port number 0 (any port) is considered invalid by the gateway.
     ExternalPortZeroInvalid,
     /// The requested mapping conflicts with a mapping assigned to another client.
     PortInUse,
+    /// The gateway does not accept a wildcard remote host.
+    RemoteHostWildcardNotPermitted,
+    /// The gateway only supports a wildcard remote host.
+    RemoteHostOnlySupportsWildcard,
+    /// The gateway only supports a wildcard external port.
+    ExternalPortOnlySupportsWildcard,
+    /// The gateway does not have any free ports.
+    NoPortsAvailable,
+    /// The requested mapping conflicts with one made by another mechanism, e.g. NAT-PMP.
+    ConflictWithOtherMechanisms,
     /// The gateway requires that the requested internal and external ports are the same.
     SamePortValuesRequired,
     /// The gateway only supports permanent leases (ie. a `lease_duration` of 0).
     OnlyPermanentLeasesSupported,
     /// The description was too long for the gateway to handle.
     DescriptionTooLong,
+    /// The port range is empty, starts at port 0, or its internal ports go past 65535.
+    InvalidPortRange,
     /// Some other error occured performing the request.
     RequestError(RequestError),
 }
@@ -189,8 +556,37 @@ pub enum AddPortError {
 impl fmt::Display for GetExternalIpError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            GetExternalIpError::ActionNotAuthorized => write!(f, "The client is not authorized to remove the port"),
-            GetExternalIpError::RequestError(ref e) => write!(f, "Request Error. {}", e),
+            GetExternalIpError::ActionNotAuthorized => {
+                write!(f, "The client is not authorized to get the external IP address")
+            }
+            GetExternalIpError::RequestError(ref e) => write!(f, "Getting the external IP address failed: {}", e),
+        }
+    }
+}
+
+impl GetExternalIpError {
+    /// The gateway and action of the failed request, see `RequestError::context`.
+    pub fn context(&self) -> Option<&RequestContext> {
+        match *self {
+            GetExternalIpError::RequestError(ref e) => e.context(),
+            _ => None,
+        }
+    }
+
+    /// Whether the request may succeed if it is sent again later, see `RequestError::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            GetExternalIpError::ActionNotAuthorized => false,
+            GetExternalIpError::RequestError(ref e) => e.is_retryable(),
+        }
+    }
+
+    /// Whether the gateway will refuse the same request every time, see
+    /// `RequestError::is_permanent`.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            GetExternalIpError::ActionNotAuthorized => true,
+            GetExternalIpError::RequestError(ref e) => e.is_permanent(),
         }
     }
 }
@@ -203,7 +599,37 @@ impl From<io::Error> for GetExternalIpError {
 
 impl std::error::Error for GetExternalIpError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match *self {
+            GetExternalIpError::RequestError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl RemovePortError {
+    /// The gateway and action of the failed request, see `RequestError::context`.
+    pub fn context(&self) -> Option<&RequestContext> {
+        match *self {
+            RemovePortError::RequestError(ref e) => e.context(),
+            _ => None,
+        }
+    }
+
+    /// Whether the request may succeed if it is sent again later, see `RequestError::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            RemovePortError::RequestError(ref e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether the gateway will refuse the same request every time, see
+    /// `RequestError::is_permanent`.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            RemovePortError::ActionNotAuthorized | RemovePortError::NoSuchPortMapping => true,
+            RemovePortError::RequestError(ref e) => e.is_permanent(),
+        }
     }
 }
 
@@ -212,14 +638,49 @@ impl fmt::Display for RemovePortError {
         match *self {
             RemovePortError::ActionNotAuthorized => write!(f, "The client is not authorized to remove the port"),
             RemovePortError::NoSuchPortMapping => write!(f, "The port was not mapped"),
-            RemovePortError::RequestError(ref e) => write!(f, "Request error. {}", e),
+            RemovePortError::RequestError(ref e) => write!(f, "Removing the port mapping failed: {}", e),
         }
     }
 }
 
 impl std::error::Error for RemovePortError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match *self {
+            RemovePortError::RequestError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl AddAnyPortError {
+    /// The gateway and action of the failed request, see `RequestError::context`.
+    pub fn context(&self) -> Option<&RequestContext> {
+        match *self {
+            AddAnyPortError::RequestError(ref e) => e.context(),
+            _ => None,
+        }
+    }
+
+    /// Whether the request may succeed if it is sent again later, see `RequestError::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            AddAnyPortError::RequestError(ref e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether the gateway will refuse the same request every time, see
+    /// `RequestError::is_permanent`.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            AddAnyPortError::ActionNotAuthorized
+            | AddAnyPortError::InternalPortZeroInvalid
+            | AddAnyPortError::InternalClientNotLocal
+            | AddAnyPortError::OnlyPermanentLeasesSupported
+            | AddAnyPortError::DescriptionTooLong => true,
+            AddAnyPortError::NoPortsAvailable | AddAnyPortError::ExternalPortInUse => false,
+            AddAnyPortError::RequestError(ref e) => e.is_permanent(),
+        }
     }
 }
 
@@ -227,11 +688,14 @@ impl fmt::Display for AddAnyPortError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             AddAnyPortError::ActionNotAuthorized => {
-                write!(f, "The client is not authorized to remove the port")
+                write!(f, "The client is not authorized to map a port")
             }
             AddAnyPortError::InternalPortZeroInvalid => {
                 write!(f, "Can not add a mapping for local port 0")
             }
+            AddAnyPortError::InternalClientNotLocal => {
+                write!(f, "The local address is not an address of this host")
+            }
             AddAnyPortError::NoPortsAvailable => {
                 write!(f, "The gateway does not have any free ports")
             }
@@ -250,14 +714,50 @@ impl fmt::Display for AddAnyPortError {
             AddAnyPortError::DescriptionTooLong => {
                 write!(f, "The description was too long for the gateway to handle.")
             }
-            AddAnyPortError::RequestError(ref e) => write!(f, "Request error. {}", e),
+            AddAnyPortError::RequestError(ref e) => write!(f, "Adding a port mapping failed: {}", e),
         }
     }
 }
 
 impl std::error::Error for AddAnyPortError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match *self {
+            AddAnyPortError::RequestError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl AddPortError {
+    /// The gateway and action of the failed request, see `RequestError::context`.
+    pub fn context(&self) -> Option<&RequestContext> {
+        match *self {
+            AddPortError::RequestError(ref e) => e.context(),
+            _ => None,
+        }
+    }
+
+    /// Whether the request may succeed if it is sent again later, see `RequestError::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            AddPortError::RequestError(ref e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether the gateway will refuse the same request every time, see
+    /// `RequestError::is_permanent`.
+    ///
+    /// Conflicts with other mappings and running out of ports aren't, they last until other
+    /// mappings are removed.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            AddPortError::PortInUse | AddPortError::NoPortsAvailable | AddPortError::ConflictWithOtherMechanisms => {
+                false
+            }
+            AddPortError::RequestError(ref e) => e.is_permanent(),
+            _ => true,
+        }
     }
 }
 
@@ -266,6 +766,7 @@ impl fmt::Display for AddPortError {
         match *self {
             AddPortError::ActionNotAuthorized => write!(f, "The client is not authorized to map this port."),
             AddPortError::InternalPortZeroInvalid => write!(f, "Can not add a mapping for local port 0"),
+            AddPortError::InternalClientNotLocal => write!(f, "The local address is not an address of this host."),
             AddPortError::ExternalPortZeroInvalid => write!(
                 f,
                 "External port number 0 (any port) is considered invalid by the gateway."
@@ -274,6 +775,20 @@ impl fmt::Display for AddPortError {
                 f,
                 "The requested mapping conflicts with a mapping assigned to another client."
             ),
+            AddPortError::RemoteHostWildcardNotPermitted => {
+                write!(f, "The gateway does not accept a wildcard remote host.")
+            }
+            AddPortError::RemoteHostOnlySupportsWildcard => {
+                write!(f, "The gateway only supports a wildcard remote host.")
+            }
+            AddPortError::ExternalPortOnlySupportsWildcard => {
+                write!(f, "The gateway only supports a wildcard external port.")
+            }
+            AddPortError::NoPortsAvailable => write!(f, "The gateway does not have any free ports."),
+            AddPortError::ConflictWithOtherMechanisms => write!(
+                f,
+                "The requested mapping conflicts with a mapping made by another mechanism."
+            ),
             AddPortError::SamePortValuesRequired => write!(
                 f,
                 "The gateway requires that the requested internal and external ports are the same."
@@ -283,14 +798,36 @@ impl fmt::Display for AddPortError {
                 "The gateway only supports permanent leases (ie. a `lease_duration` of 0),"
             ),
             AddPortError::DescriptionTooLong => write!(f, "The description was too long for the gateway to handle."),
-            AddPortError::RequestError(ref e) => write!(f, "Request error. {}", e),
+            AddPortError::InvalidPortRange => write!(
+                f,
+                "The port range is empty, starts at port 0, or its internal ports go past 65535."
+            ),
+            AddPortError::RequestError(ref e) => write!(f, "Adding the port mapping failed: {}", e),
         }
     }
 }
 
 impl std::error::Error for AddPortError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match *self {
+            AddPortError::RequestError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Mappings conflicting with another one fail with `AddrInUse`, and unauthorized ones with
+/// `PermissionDenied`. The `AddPortError` is kept as the inner error.
+impl From<AddPortError> for io::Error {
+    fn from(err: AddPortError) -> io::Error {
+        let kind = match err {
+            AddPortError::ActionNotAuthorized => io::ErrorKind::PermissionDenied,
+            AddPortError::PortInUse | AddPortError::ConflictWithOtherMechanisms => io::ErrorKind::AddrInUse,
+            AddPortError::NoPortsAvailable => io::ErrorKind::AddrNotAvailable,
+            AddPortError::RequestError(ref e) => e.io_kind(),
+            _ => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, err)
     }
 }
 
@@ -313,6 +850,33 @@ pub enum SearchError {
     /// Error parsing URI
     #[cfg(feature = "aio")]
     InvalidUri(hyper::http::uri::InvalidUri),
+    /// No device answered the search within the timeout
+    NoResponse(Duration),
+    /// Devices answered the search, but none of the responses could be parsed
+    InvalidResponses {
+        /// Number of responses received
+        received: usize,
+        /// Why the last one couldn't be parsed
+        last_error: Box<SearchError>,
+    },
+    /// Gateways answered the search, but none of their descriptions could be fetched and parsed.
+    /// Holds the url of each description with its error.
+    DescriptionsFailed(Vec<(String, SearchError)>),
+    /// The gateway was skipped, the filter of the search options doesn't select it
+    NotSelected,
+    /// Data received from a device couldn't be parsed. Holds the error, and the raw data cut to
+    /// 2 KiB, to include in bug reports.
+    InvalidData(Box<SearchError>, String),
+    /// A device pointed to a description at this url, which `SearchOptions::url_policy` doesn't
+    /// allow
+    UrlNotAllowed(String),
+    /// Fetching a document of the gateway, e.g. its description, failed
+    FetchFailed {
+        /// Url of the document
+        url: String,
+        /// Why it failed
+        error: Box<SearchError>,
+    },
 }
 
 impl From<attohttpc::Error> for SearchError {
@@ -359,18 +923,121 @@ impl From<Elapsed> for SearchError {
     }
 }
 
+impl SearchError {
+    /// Attach the raw data that couldn't be parsed.
+    pub(crate) fn with_data(self, data: &[u8]) -> SearchError {
+        SearchError::InvalidData(Box::new(self), raw_excerpt(data))
+    }
+
+    /// Attach the url of the document that couldn't be fetched.
+    pub(crate) fn at_url(self, url: &str) -> SearchError {
+        SearchError::FetchFailed {
+            url: url.to_string(),
+            error: Box::new(self),
+        }
+    }
+
+    /// Whether this is the error of a socket whose read timeout expired.
+    pub(crate) fn is_timeout(&self) -> bool {
+        match *self {
+            SearchError::IoError(ref e) => e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut,
+            _ => false,
+        }
+    }
+
+    /// Whether searching again may succeed.
+    ///
+    /// That is the case after timeouts, when no gateway answered, and when a description
+    /// couldn't be fetched because of a timeout or a dropped connection.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            SearchError::HttpError(ref e) => is_transient_http(e),
+            SearchError::IoError(ref e) => is_transient(e),
+            #[cfg(feature = "aio")]
+            SearchError::HyperError(ref e) => is_transient_hyper(e),
+            SearchError::NoResponse(..) => true,
+            SearchError::DescriptionsFailed(ref failures) => failures.iter().any(|(_, e)| e.is_retryable()),
+            SearchError::FetchFailed { ref error, .. } => error.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether searching again with the same options will fail the same way, e.g. because the
+    /// filter doesn't select the gateway.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            SearchError::NotSelected | SearchError::UrlNotAllowed(..) => true,
+            #[cfg(feature = "aio")]
+            SearchError::InvalidUri(..) => true,
+            SearchError::DescriptionsFailed(ref failures) => failures.iter().all(|(_, e)| e.is_permanent()),
+            SearchError::FetchFailed { ref error, .. } => error.is_permanent(),
+            _ => false,
+        }
+    }
+
+    /// The kind of `io::Error` this converts to.
+    fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            SearchError::IoError(ref e) => timed_out_kind(e.kind()),
+            SearchError::NoResponse(..) => io::ErrorKind::TimedOut,
+            SearchError::InvalidResponse
+            | SearchError::InvalidResponses { .. }
+            | SearchError::InvalidData(..)
+            | SearchError::Utf8Error(..)
+            | SearchError::XmlError(..) => io::ErrorKind::InvalidData,
+            SearchError::DescriptionsFailed(ref failures) => {
+                failures.last().map_or(io::ErrorKind::Other, |(_, e)| e.io_kind())
+            }
+            SearchError::FetchFailed { ref error, .. } => error.io_kind(),
+            SearchError::NotSelected => io::ErrorKind::NotFound,
+            SearchError::UrlNotAllowed(..) => io::ErrorKind::PermissionDenied,
+            #[cfg(feature = "aio")]
+            SearchError::InvalidUri(..) => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+/// A search without answer fails with `TimedOut`, and one with only unparseable answers with
+/// `InvalidData`. The `SearchError` is kept as the inner error.
+impl From<SearchError> for io::Error {
+    fn from(err: SearchError) -> io::Error {
+        io::Error::new(err.io_kind(), err)
+    }
+}
+
 impl fmt::Display for SearchError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            SearchError::HttpError(ref e) => write!(f, "HTTP error {}", e),
+            SearchError::HttpError(ref e) => write!(f, "HTTP error: {}", e),
             SearchError::InvalidResponse => write!(f, "Invalid response"),
             SearchError::IoError(ref e) => write!(f, "IO error: {}", e),
             SearchError::Utf8Error(ref e) => write!(f, "UTF-8 error: {}", e),
             SearchError::XmlError(ref e) => write!(f, "XML error: {}", e),
             #[cfg(feature = "aio")]
-            SearchError::HyperError(ref e) => write!(f, "Hyper Error: {}", e),
+            SearchError::HyperError(ref e) => write!(f, "Hyper error: {}", e),
             #[cfg(feature = "aio")]
-            SearchError::InvalidUri(ref e) => write!(f, "InvalidUri Error: {}", e),
+            SearchError::InvalidUri(ref e) => write!(f, "Invalid URI: {}", e),
+            SearchError::NoResponse(timeout) => write!(f, "No response to the search within {:?}", timeout),
+            SearchError::InvalidResponses {
+                received,
+                ref last_error,
+            } => write!(
+                f,
+                "None of the {} responses to the search could be parsed, the last one: {}",
+                received, last_error
+            ),
+            SearchError::DescriptionsFailed(ref failures) => {
+                write!(f, "No gateway description could be fetched")?;
+                for (url, e) in failures {
+                    write!(f, ", {}: {}", url, e)?;
+                }
+                Ok(())
+            }
+            SearchError::NotSelected => write!(f, "Gateway not selected by the search filter"),
+            SearchError::UrlNotAllowed(ref url) => write!(f, "{} is not allowed by the url policy", url),
+            SearchError::InvalidData(ref e, ref data) => write!(f, "{} in {:?}", e, data),
+            SearchError::FetchFailed { ref url, ref error } => write!(f, "Fetching {} failed: {}", url, error),
         }
     }
 }
@@ -387,6 +1054,15 @@ impl error::Error for SearchError {
             SearchError::HyperError(ref e) => Some(e),
             #[cfg(feature = "aio")]
             SearchError::InvalidUri(ref e) => Some(e),
+            SearchError::NoResponse(..) => None,
+            SearchError::InvalidResponses { ref last_error, .. } => Some(&**last_error),
+            SearchError::DescriptionsFailed(ref failures) => {
+                failures.last().map(|(_, e)| e as &(dyn error::Error + 'static))
+            }
+            SearchError::NotSelected => None,
+            SearchError::UrlNotAllowed(..) => None,
+            SearchError::InvalidData(ref e, _) => Some(&**e),
+            SearchError::FetchFailed { ref error, .. } => Some(&**error),
         }
     }
 }
@@ -404,12 +1080,38 @@ pub enum GetGenericPortMappingEntryError {
 
 impl From<RequestError> for GetGenericPortMappingEntryError {
     fn from(err: RequestError) -> GetGenericPortMappingEntryError {
-        match err {
-            RequestError::ErrorCode(code, _) if code == 606 => GetGenericPortMappingEntryError::ActionNotAuthorized,
-            RequestError::ErrorCode(code, _) if code == 713 => {
-                GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid
-            }
-            other => GetGenericPortMappingEntryError::RequestError(other),
+        match *err.inner() {
+            RequestError::ActionNotAuthorized => GetGenericPortMappingEntryError::ActionNotAuthorized,
+            RequestError::SpecifiedArrayIndexInvalid => GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid,
+            _ => GetGenericPortMappingEntryError::RequestError(err),
+        }
+    }
+}
+
+impl GetGenericPortMappingEntryError {
+    /// The gateway and action of the failed request, see `RequestError::context`.
+    pub fn context(&self) -> Option<&RequestContext> {
+        match *self {
+            GetGenericPortMappingEntryError::RequestError(ref e) => e.context(),
+            _ => None,
+        }
+    }
+
+    /// Whether the request may succeed if it is sent again later, see `RequestError::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            GetGenericPortMappingEntryError::RequestError(ref e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether the gateway will refuse the same request every time, see
+    /// `RequestError::is_permanent`.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            GetGenericPortMappingEntryError::ActionNotAuthorized
+            | GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid => true,
+            GetGenericPortMappingEntryError::RequestError(ref e) => e.is_permanent(),
         }
     }
 }
@@ -423,12 +1125,21 @@ impl fmt::Display for GetGenericPortMappingEntryError {
             GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid => {
                 write!(f, "The provided index into the port mapping list is invalid.")
             }
-            GetGenericPortMappingEntryError::RequestError(ref e) => e.fmt(f),
+            GetGenericPortMappingEntryError::RequestError(ref e) => {
+                write!(f, "Getting the port mapping entry failed: {}", e)
+            }
         }
     }
 }
 
-impl std::error::Error for GetGenericPortMappingEntryError {}
+impl std::error::Error for GetGenericPortMappingEntryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            GetGenericPortMappingEntryError::RequestError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 /// An error type that emcompasses all possible errors.
 #[derive(Debug)]
@@ -439,6 +1150,8 @@ pub enum Error {
     AddPortError(AddPortError),
     /// `GetExternalIpError`
     GetExternalIpError(GetExternalIpError),
+    /// `GetGenericPortMappingEntryError`
+    GetGenericPortMappingEntryError(GetGenericPortMappingEntryError),
     /// `RemovePortError`
     RemovePortError(RemovePortError),
     /// `RequestError`
@@ -450,12 +1163,56 @@ pub enum Error {
 /// A result type where the error is `igd::Error`.
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
+impl Error {
+    /// The gateway and action of the failed request, if the error came from a request to a
+    /// gateway. Only the errors of requests whose failure has no variant of its own, e.g.
+    /// `AddPortError::PortInUse`, keep the context.
+    pub fn context(&self) -> Option<&RequestContext> {
+        match *self {
+            Error::AddAnyPortError(ref e) => e.context(),
+            Error::AddPortError(ref e) => e.context(),
+            Error::GetExternalIpError(ref e) => e.context(),
+            Error::GetGenericPortMappingEntryError(ref e) => e.context(),
+            Error::RemovePortError(ref e) => e.context(),
+            Error::RequestError(ref e) => e.context(),
+            Error::SearchError(..) => None,
+        }
+    }
+
+    /// Whether the operation may succeed if it is tried again later.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            Error::AddAnyPortError(ref e) => e.is_retryable(),
+            Error::AddPortError(ref e) => e.is_retryable(),
+            Error::GetExternalIpError(ref e) => e.is_retryable(),
+            Error::GetGenericPortMappingEntryError(ref e) => e.is_retryable(),
+            Error::RemovePortError(ref e) => e.is_retryable(),
+            Error::RequestError(ref e) => e.is_retryable(),
+            Error::SearchError(ref e) => e.is_retryable(),
+        }
+    }
+
+    /// Whether the operation will fail the same way every time it is tried.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            Error::AddAnyPortError(ref e) => e.is_permanent(),
+            Error::AddPortError(ref e) => e.is_permanent(),
+            Error::GetExternalIpError(ref e) => e.is_permanent(),
+            Error::GetGenericPortMappingEntryError(ref e) => e.is_permanent(),
+            Error::RemovePortError(ref e) => e.is_permanent(),
+            Error::RequestError(ref e) => e.is_permanent(),
+            Error::SearchError(ref e) => e.is_permanent(),
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::AddAnyPortError(ref e) => e.fmt(f),
             Error::AddPortError(ref e) => e.fmt(f),
             Error::GetExternalIpError(ref e) => e.fmt(f),
+            Error::GetGenericPortMappingEntryError(ref e) => e.fmt(f),
             Error::RemovePortError(ref e) => e.fmt(f),
             Error::RequestError(ref e) => e.fmt(f),
             Error::SearchError(ref e) => e.fmt(f),
@@ -469,6 +1226,7 @@ impl error::Error for Error {
             Error::AddAnyPortError(ref e) => Some(e),
             Error::AddPortError(ref e) => Some(e),
             Error::GetExternalIpError(ref e) => Some(e),
+            Error::GetGenericPortMappingEntryError(ref e) => Some(e),
             Error::RemovePortError(ref e) => Some(e),
             Error::RequestError(ref e) => Some(e),
             Error::SearchError(ref e) => Some(e),
@@ -494,6 +1252,12 @@ impl From<GetExternalIpError> for Error {
     }
 }
 
+impl From<GetGenericPortMappingEntryError> for Error {
+    fn from(err: GetGenericPortMappingEntryError) -> Error {
+        Error::GetGenericPortMappingEntryError(err)
+    }
+}
+
 impl From<RemovePortError> for Error {
     fn from(err: RemovePortError) -> Error {
         Error::RemovePortError(err)
@@ -511,3 +1275,143 @@ impl From<SearchError> for Error {
         Error::SearchError(err)
     }
 }
+
+#[test]
+fn test_source_chain() {
+    use std::error::Error as _;
+
+    let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused");
+    let context = RequestContext::new(
+        "192.168.1.1:5000".parse().unwrap(),
+        "http://192.168.1.1:5000/ctl/IPConn",
+        r#""urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping""#,
+        Duration::from_millis(20),
+    );
+    let err = Error::from(AddPortError::RequestError(
+        RequestError::from(refused).with_context(context),
+    ));
+    assert_eq!(
+        err.to_string(),
+        "Adding the port mapping failed: AddPortMapping request to http://192.168.1.1:5000/ctl/IPConn failed \
+         after 20ms: IO error: connection refused"
+    );
+
+    let mut chain = vec![];
+    let mut source = err.source();
+    while let Some(e) = source {
+        chain.push(e);
+        source = e.source();
+    }
+    assert_eq!(chain.len(), 4);
+    assert!(chain[0].is::<AddPortError>());
+    assert!(chain[1].is::<RequestError>());
+    assert!(chain[2].is::<RequestError>());
+    let cause = chain[3].downcast_ref::<io::Error>().unwrap();
+    assert_eq!(cause.kind(), io::ErrorKind::ConnectionRefused);
+
+    let err =
+        SearchError::from(io::Error::new(io::ErrorKind::TimedOut, "timed out")).at_url("http://10.0.0.1/desc.xml");
+    assert_eq!(
+        err.to_string(),
+        "Fetching http://10.0.0.1/desc.xml failed: IO error: timed out"
+    );
+    assert!(err.source().unwrap().source().unwrap().is::<io::Error>());
+}
+
+#[test]
+fn test_into_io_error() {
+    let err = io::Error::from(SearchError::NoResponse(Duration::from_secs(3)));
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(matches!(
+        err.get_ref().unwrap().downcast_ref::<SearchError>(),
+        Some(SearchError::NoResponse(..))
+    ));
+    let timed_out = SearchError::from(io::Error::from(io::ErrorKind::WouldBlock)).at_url("http://10.0.0.1/desc.xml");
+    assert_eq!(io::Error::from(timed_out).kind(), io::ErrorKind::TimedOut);
+    let invalid = SearchError::InvalidResponses {
+        received: 2,
+        last_error: Box::new(SearchError::InvalidResponse),
+    };
+    assert_eq!(io::Error::from(invalid).kind(), io::ErrorKind::InvalidData);
+
+    assert_eq!(
+        io::Error::from(AddPortError::PortInUse).kind(),
+        io::ErrorKind::AddrInUse
+    );
+    assert_eq!(
+        io::Error::from(AddPortError::ActionNotAuthorized).kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        io::Error::from(AddPortError::SamePortValuesRequired).kind(),
+        io::ErrorKind::InvalidInput
+    );
+    let refused = RequestError::from(io::Error::from(io::ErrorKind::ConnectionRefused)).with_context(status_context());
+    assert_eq!(
+        io::Error::from(AddPortError::RequestError(refused)).kind(),
+        io::ErrorKind::ConnectionRefused
+    );
+    let conflict = AddPortError::RequestError(RequestError::from_error_code(718, String::new()));
+    assert_eq!(io::Error::from(conflict).kind(), io::ErrorKind::AddrInUse);
+}
+
+#[test]
+fn test_retryable() {
+    let timed_out = RequestError::from(io::Error::from(io::ErrorKind::WouldBlock)).with_context(status_context());
+    assert!(timed_out.is_retryable());
+    assert!(!timed_out.is_permanent());
+    assert!(RequestError::ActionFailed.is_retryable());
+    assert!(RequestError::ErrorCode(503, String::new()).is_retryable());
+    assert!(RequestError::ActionNotAuthorized.is_permanent());
+    assert!(!RequestError::ActionNotAuthorized.is_retryable());
+    assert!(RequestError::WildCardNotPermittedInSrcIp.is_permanent());
+
+    let conflict = AddPortError::PortInUse;
+    assert!(!conflict.is_retryable() && !conflict.is_permanent());
+    assert!(AddPortError::RemoteHostWildcardNotPermitted.is_permanent());
+    assert!(AddPortError::RequestError(RequestError::ActionFailed).is_retryable());
+    assert!(RemovePortError::NoSuchPortMapping.is_permanent());
+    assert!(Error::from(GetExternalIpError::ActionNotAuthorized).is_permanent());
+
+    assert!(SearchError::NoResponse(Duration::from_secs(3)).is_retryable());
+    assert!(SearchError::NotSelected.is_permanent());
+    let failed = SearchError::DescriptionsFailed(vec![
+        ("http://10.0.0.1/desc.xml".to_string(), SearchError::NotSelected),
+        (
+            "http://10.0.0.2/desc.xml".to_string(),
+            SearchError::from(io::Error::from(io::ErrorKind::ConnectionReset)),
+        ),
+    ]);
+    assert!(failed.is_retryable());
+    assert!(!failed.is_permanent());
+}
+
+#[cfg(test)]
+fn status_context() -> RequestContext {
+    RequestContext::new(
+        "10.0.0.1:80".parse().unwrap(),
+        "http://10.0.0.1/ctl",
+        r#""urn:schemas-upnp-org:service:WANIPConnection:1#GetStatusInfo""#,
+        Duration::from_secs(1),
+    )
+}
+
+#[test]
+fn test_request_context() {
+    let err = RequestError::from_error_code(718, String::new()).with_context(status_context());
+    let context = err.context().unwrap();
+    assert_eq!(context.gateway, "10.0.0.1:80".parse().unwrap());
+    assert_eq!(context.service, "urn:schemas-upnp-org:service:WANIPConnection:1");
+    assert_eq!(context.action, "GetStatusInfo");
+    assert!(matches!(err.inner(), RequestError::ConflictInMappingEntry));
+    assert_eq!(err.error_code(), Some(718));
+
+    let err = RequestError::ErrorCode(899, "Unknown".to_string()).with_context(status_context());
+    let err = GetGenericPortMappingEntryError::from(err);
+    assert_eq!(err.context().unwrap().action, "GetStatusInfo");
+    let err = RequestError::SpecifiedArrayIndexInvalid.with_context(status_context());
+    assert!(matches!(
+        GetGenericPortMappingEntryError::from(err),
+        GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid
+    ));
+}
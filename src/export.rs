@@ -0,0 +1,94 @@
+use std::fmt::Write;
+
+use crate::PortMappingEntry;
+
+/// Format of `Gateway::export_port_mappings`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExportFormat {
+    /// A JSON array of objects with the fields of `PortMappingEntry`, e.g.
+    /// `{"remote_host": "", "external_port": 8080, "protocol": "TCP", ...}`
+    Json,
+    /// CSV with a header line naming the fields of `PortMappingEntry`, quoted as in RFC 4180
+    Csv,
+}
+
+const CSV_HEADER: &str = "remote_host,external_port,protocol,internal_port,internal_client,enabled,\
+                          port_mapping_description,lease_duration";
+
+/// Write `entries` in `format`.
+pub(crate) fn export(entries: &[PortMappingEntry], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_string_pretty(entries).expect("port mapping entries always serialize to JSON")
+        }
+        ExportFormat::Csv => {
+            let mut csv = String::from(CSV_HEADER);
+            csv.push_str("\r\n");
+            for entry in entries {
+                let _ = write!(
+                    csv,
+                    "{},{},{},{},{},{},{},{}\r\n",
+                    csv_field(&entry.remote_host),
+                    entry.external_port,
+                    entry.protocol,
+                    entry.internal_port,
+                    csv_field(&entry.internal_client),
+                    entry.enabled,
+                    csv_field(&entry.port_mapping_description),
+                    entry.lease_duration
+                );
+            }
+            csv
+        }
+    }
+}
+
+/// Quote a field if it contains a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[test]
+fn test_export() {
+    use crate::PortMappingProtocol;
+
+    let entries = vec![
+        PortMappingEntry {
+            remote_host: String::new(),
+            external_port: 8080,
+            protocol: PortMappingProtocol::TCP,
+            internal_port: 80,
+            internal_client: "192.168.1.2".to_string(),
+            enabled: true,
+            port_mapping_description: "web, \"main\"".to_string(),
+            lease_duration: 0,
+        },
+        PortMappingEntry {
+            remote_host: "203.0.113.7".to_string(),
+            external_port: 5353,
+            protocol: PortMappingProtocol::UDP,
+            internal_port: 53,
+            internal_client: "192.168.1.3".to_string(),
+            enabled: false,
+            port_mapping_description: "dns".to_string(),
+            lease_duration: 3600,
+        },
+    ];
+
+    let csv = export(&entries, ExportFormat::Csv);
+    let lines: Vec<_> = csv.split("\r\n").collect();
+    assert_eq!(lines[0], CSV_HEADER);
+    assert_eq!(lines[1], r#",8080,TCP,80,192.168.1.2,true,"web, ""main""",0"#);
+    assert_eq!(lines[2], "203.0.113.7,5353,UDP,53,192.168.1.3,false,dns,3600");
+    assert_eq!(lines[3], "");
+
+    let json = export(&entries, ExportFormat::Json);
+    let parsed: Vec<PortMappingEntry> = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed, entries);
+    assert!(json.contains(r#""protocol": "UDP""#));
+    assert_eq!(export(&[], ExportFormat::Json), "[]");
+}
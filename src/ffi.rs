@@ -0,0 +1,442 @@
+//! A flat C interface to the blocking API.
+//!
+//! The matching header is `include/igd.h`, generated with
+//! `cbindgen --config cbindgen.toml --output include/igd.h`. Build a shared library with
+//! `cargo rustc --release --features ffi --crate-type cdylib`.
+//!
+//! Every function returns `IGD_OK` or a negative error code. The message of the last error of
+//! the calling thread is available from `igd_last_error_message`. Panics don't unwind into the
+//! caller, they are returned as `IGD_ERROR_PANIC`.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::time::Duration;
+
+use crate::errors::{
+    AddAnyPortError, AddPortError, Error, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError,
+};
+use crate::{Gateway, PortMappingProtocol, SearchOptions};
+
+/// Success.
+pub const IGD_OK: c_int = 0;
+/// A pointer was null, a string was not valid, or a value was out of range.
+pub const IGD_ERROR_INVALID_ARGUMENT: c_int = -1;
+/// No gateway was found.
+pub const IGD_ERROR_SEARCH: c_int = -2;
+/// The request failed or the gateway sent an invalid response.
+pub const IGD_ERROR_REQUEST: c_int = -3;
+/// The gateway refused the action.
+pub const IGD_ERROR_NOT_AUTHORIZED: c_int = -4;
+/// The external port is already mapped to another client.
+pub const IGD_ERROR_PORT_IN_USE: c_int = -5;
+/// There is no such port mapping.
+pub const IGD_ERROR_NO_SUCH_MAPPING: c_int = -6;
+/// The port mapping index is out of bounds, i.e. there are no more mappings.
+pub const IGD_ERROR_INDEX_INVALID: c_int = -7;
+/// The gateway has no free external port left.
+pub const IGD_ERROR_NO_PORTS_AVAILABLE: c_int = -8;
+/// The gateway only supports permanent leases, i.e. a lease duration of 0.
+pub const IGD_ERROR_ONLY_PERMANENT_LEASES: c_int = -9;
+/// The gateway requires the external port to be the same as the internal port.
+pub const IGD_ERROR_SAME_PORT_VALUES_REQUIRED: c_int = -10;
+/// The library panicked. This is a bug, please report it with the error message.
+pub const IGD_ERROR_PANIC: c_int = -11;
+
+/// Protocol value for TCP.
+pub const IGD_TCP: c_int = 0;
+/// Protocol value for UDP.
+pub const IGD_UDP: c_int = 1;
+
+/// A gateway found by `igd_search_gateway`, freed with `igd_gateway_free`.
+pub struct IgdGateway {
+    gateway: Gateway,
+}
+
+/// A port mapping, as returned by `igd_get_port_mapping`.
+///
+/// Strings are NUL terminated and truncated to the size of their buffer.
+#[repr(C)]
+pub struct IgdPortMapping {
+    /// `IGD_TCP` or `IGD_UDP`
+    pub protocol: c_int,
+    /// External port of the mapping
+    pub external_port: u16,
+    /// Internal (local) port of the mapping
+    pub internal_port: u16,
+    /// Internal client of the mapping
+    pub internal_client: [c_char; 64],
+    /// Remote host the mapping is restricted to, empty for any
+    pub remote_host: [c_char; 64],
+    /// Description of the mapping
+    pub description: [c_char; 256],
+    /// Lease duration in seconds, 0 for permanent
+    pub lease_duration: u32,
+    /// 1 if the mapping is enabled, 0 otherwise
+    pub enabled: c_int,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn fail<E: Into<Error>>(error: E) -> c_int {
+    let error = error.into();
+    let code = match error {
+        Error::SearchError(..) => IGD_ERROR_SEARCH,
+        Error::GetExternalIpError(GetExternalIpError::ActionNotAuthorized)
+        | Error::RemovePortError(RemovePortError::ActionNotAuthorized)
+        | Error::AddAnyPortError(AddAnyPortError::ActionNotAuthorized)
+        | Error::AddPortError(AddPortError::ActionNotAuthorized)
+        | Error::GetGenericPortMappingEntryError(GetGenericPortMappingEntryError::ActionNotAuthorized) => {
+            IGD_ERROR_NOT_AUTHORIZED
+        }
+        Error::AddAnyPortError(AddAnyPortError::InternalPortZeroInvalid)
+        | Error::AddAnyPortError(AddAnyPortError::InternalClientNotLocal)
+        | Error::AddPortError(AddPortError::InternalClientNotLocal)
+        | Error::AddAnyPortError(AddAnyPortError::DescriptionTooLong)
+        | Error::AddPortError(AddPortError::InternalPortZeroInvalid)
+        | Error::AddPortError(AddPortError::ExternalPortZeroInvalid)
+        | Error::AddPortError(AddPortError::DescriptionTooLong)
+        | Error::AddPortError(AddPortError::InvalidPortRange)
+        | Error::AddPortError(AddPortError::RemoteHostWildcardNotPermitted)
+        | Error::AddPortError(AddPortError::RemoteHostOnlySupportsWildcard)
+        | Error::AddPortError(AddPortError::ExternalPortOnlySupportsWildcard) => IGD_ERROR_INVALID_ARGUMENT,
+        Error::AddAnyPortError(AddAnyPortError::ExternalPortInUse)
+        | Error::AddPortError(AddPortError::PortInUse)
+        | Error::AddPortError(AddPortError::ConflictWithOtherMechanisms) => IGD_ERROR_PORT_IN_USE,
+        Error::AddAnyPortError(AddAnyPortError::NoPortsAvailable)
+        | Error::AddPortError(AddPortError::NoPortsAvailable) => IGD_ERROR_NO_PORTS_AVAILABLE,
+        Error::AddAnyPortError(AddAnyPortError::OnlyPermanentLeasesSupported)
+        | Error::AddPortError(AddPortError::OnlyPermanentLeasesSupported) => IGD_ERROR_ONLY_PERMANENT_LEASES,
+        Error::AddPortError(AddPortError::SamePortValuesRequired) => IGD_ERROR_SAME_PORT_VALUES_REQUIRED,
+        Error::RemovePortError(RemovePortError::NoSuchPortMapping) => IGD_ERROR_NO_SUCH_MAPPING,
+        Error::GetGenericPortMappingEntryError(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => {
+            IGD_ERROR_INDEX_INVALID
+        }
+        _ => IGD_ERROR_REQUEST,
+    };
+    set_last_error(&error.to_string());
+    code
+}
+
+fn invalid_argument(message: &str) -> c_int {
+    set_last_error(message);
+    IGD_ERROR_INVALID_ARGUMENT
+}
+
+fn set_last_error(message: &str) {
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    let _ = LAST_ERROR.try_with(|last_error| *last_error.borrow_mut() = message);
+}
+
+/// Run the body of an exported function, turning a panic into `IGD_ERROR_PANIC` instead of
+/// unwinding into the caller.
+fn catch<F: FnOnce() -> c_int>(f: F) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(code) => code,
+        Err(panic) => {
+            let message = match panic.downcast_ref::<&str>() {
+                Some(message) => message,
+                None => panic.downcast_ref::<String>().map_or("unknown panic", String::as_str),
+            };
+            set_last_error(&format!("panicked: {}", message));
+            IGD_ERROR_PANIC
+        }
+    }
+}
+
+fn protocol(protocol: c_int) -> Option<PortMappingProtocol> {
+    match protocol {
+        IGD_TCP => Some(PortMappingProtocol::TCP),
+        IGD_UDP => Some(PortMappingProtocol::UDP),
+        _ => None,
+    }
+}
+
+unsafe fn string<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+fn copy_string(dst: &mut [c_char], src: &str) {
+    let mut len = src.len().min(dst.len() - 1);
+    while !src.is_char_boundary(len) {
+        len -= 1;
+    }
+    for (d, s) in dst.iter_mut().zip(src.bytes().take(len)) {
+        *d = s as c_char;
+    }
+    dst[len] = 0;
+}
+
+/// Message of the last error that occurred on the calling thread.
+///
+/// The string stays valid until the next call on the same thread. Null if it is not available,
+/// e.g. while the thread exits.
+#[no_mangle]
+pub extern "C" fn igd_last_error_message() -> *const c_char {
+    catch_unwind(|| LAST_ERROR.with(|last_error| last_error.borrow().as_ptr())).unwrap_or(ptr::null())
+}
+
+/// Search a gateway, waiting at most `timeout_ms` milliseconds.
+///
+/// # Safety
+/// `gateway` must be a valid pointer. On success it receives a gateway to free with
+/// `igd_gateway_free`.
+#[no_mangle]
+pub unsafe extern "C" fn igd_search_gateway(timeout_ms: u32, gateway: *mut *mut IgdGateway) -> c_int {
+    catch(|| {
+        if gateway.is_null() {
+            return invalid_argument("gateway is null");
+        }
+        let options = SearchOptions {
+            timeout: Some(Duration::from_millis(timeout_ms.into())),
+            ..Default::default()
+        };
+        match crate::search_gateway(options) {
+            Ok(found) => {
+                *gateway = Box::into_raw(Box::new(IgdGateway { gateway: found }));
+                IGD_OK
+            }
+            Err(e) => fail(e),
+        }
+    })
+}
+
+/// Free a gateway returned by `igd_search_gateway`.
+///
+/// # Safety
+/// `gateway` must be null or a gateway returned by `igd_search_gateway` that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn igd_gateway_free(gateway: *mut IgdGateway) {
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        if !gateway.is_null() {
+            drop(Box::from_raw(gateway));
+        }
+    }));
+}
+
+/// Allow port mappings to other hosts of the LAN when `allow` is not 0. By default, `local_ip`
+/// has to be an address of this host, or the mapping fails with `IGD_ERROR_INVALID_ARGUMENT`.
+///
+/// # Safety
+/// `gateway` must be a valid gateway.
+#[no_mangle]
+pub unsafe extern "C" fn igd_gateway_set_allow_third_party(gateway: *mut IgdGateway, allow: c_int) -> c_int {
+    catch(|| match gateway.as_mut() {
+        Some(gateway) => {
+            gateway.gateway.allow_third_party = allow != 0;
+            IGD_OK
+        }
+        None => invalid_argument("gateway is null"),
+    })
+}
+
+/// Write the external IP address of the gateway to `buf`, as a NUL terminated string.
+///
+/// A buffer of 16 bytes is large enough for any address.
+///
+/// # Safety
+/// `gateway` must be a valid gateway and `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn igd_get_external_ip(gateway: *const IgdGateway, buf: *mut c_char, len: usize) -> c_int {
+    catch(|| {
+        let gateway = match gateway.as_ref() {
+            Some(gateway) => &gateway.gateway,
+            None => return invalid_argument("gateway is null"),
+        };
+        if buf.is_null() || len < 16 {
+            return invalid_argument("buffer is null or shorter than 16 bytes");
+        }
+        match gateway.get_external_ip() {
+            Ok(ip) => {
+                copy_string(std::slice::from_raw_parts_mut(buf, len), &ip.to_string());
+                IGD_OK
+            }
+            Err(e) => fail(e),
+        }
+    })
+}
+
+/// Add a port mapping from `external_port` to `local_ip:local_port`.
+///
+/// The lease duration is in seconds, 0 is infinite. `description` may be null. `local_ip` has to
+/// be an address of this host, see `igd_gateway_set_allow_third_party`.
+///
+/// # Safety
+/// `gateway` must be a valid gateway, `local_ip` and `description` must be null or NUL
+/// terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn igd_add_port(
+    gateway: *const IgdGateway,
+    protocol: c_int,
+    external_port: u16,
+    local_ip: *const c_char,
+    local_port: u16,
+    lease_duration: u32,
+    description: *const c_char,
+) -> c_int {
+    catch(|| {
+        let (gateway, protocol, local_addr) = match arguments(gateway, protocol, local_ip, local_port) {
+            Ok(arguments) => arguments,
+            Err(code) => return code,
+        };
+        let description = string(description).unwrap_or("");
+        match gateway.add_port(protocol, external_port, local_addr, lease_duration, description) {
+            Ok(()) => IGD_OK,
+            Err(e) => fail(e),
+        }
+    })
+}
+
+/// Add a port mapping to `local_ip:local_port` with any external port.
+///
+/// On success, the external port is written to `external_port`.
+///
+/// # Safety
+/// Same as `igd_add_port`, and `external_port` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn igd_add_any_port(
+    gateway: *const IgdGateway,
+    protocol: c_int,
+    local_ip: *const c_char,
+    local_port: u16,
+    lease_duration: u32,
+    description: *const c_char,
+    external_port: *mut u16,
+) -> c_int {
+    catch(|| {
+        let (gateway, protocol, local_addr) = match arguments(gateway, protocol, local_ip, local_port) {
+            Ok(arguments) => arguments,
+            Err(code) => return code,
+        };
+        if external_port.is_null() {
+            return invalid_argument("external_port is null");
+        }
+        let description = string(description).unwrap_or("");
+        match gateway.add_any_port(protocol, local_addr, lease_duration, description) {
+            Ok(port) => {
+                *external_port = port;
+                IGD_OK
+            }
+            Err(e) => fail(e),
+        }
+    })
+}
+
+unsafe fn arguments<'a>(
+    gateway: *const IgdGateway,
+    protocol: c_int,
+    local_ip: *const c_char,
+    local_port: u16,
+) -> Result<(&'a Gateway, PortMappingProtocol, SocketAddrV4), c_int> {
+    let gateway = gateway.as_ref().ok_or_else(|| invalid_argument("gateway is null"))?;
+    let protocol = self::protocol(protocol).ok_or_else(|| invalid_argument("invalid protocol"))?;
+    let local_ip = string(local_ip)
+        .and_then(|ip| ip.parse::<Ipv4Addr>().ok())
+        .ok_or_else(|| invalid_argument("local_ip is not a valid IPv4 address"))?;
+    Ok((&gateway.gateway, protocol, SocketAddrV4::new(local_ip, local_port)))
+}
+
+/// Remove the port mapping of `external_port`.
+///
+/// # Safety
+/// `gateway` must be a valid gateway.
+#[no_mangle]
+pub unsafe extern "C" fn igd_remove_port(gateway: *const IgdGateway, protocol: c_int, external_port: u16) -> c_int {
+    catch(|| {
+        let gateway = match gateway.as_ref() {
+            Some(gateway) => &gateway.gateway,
+            None => return invalid_argument("gateway is null"),
+        };
+        let protocol = match self::protocol(protocol) {
+            Some(protocol) => protocol,
+            None => return invalid_argument("invalid protocol"),
+        };
+        match gateway.remove_port(protocol, external_port) {
+            Ok(()) => IGD_OK,
+            Err(e) => fail(e),
+        }
+    })
+}
+
+/// Get the port mapping at `index`.
+///
+/// Mappings are listed by calling this with increasing indexes until it returns
+/// `IGD_ERROR_INDEX_INVALID`.
+///
+/// # Safety
+/// `gateway` must be a valid gateway and `mapping` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn igd_get_port_mapping(
+    gateway: *const IgdGateway,
+    index: u32,
+    mapping: *mut IgdPortMapping,
+) -> c_int {
+    catch(|| {
+        let gateway = match gateway.as_ref() {
+            Some(gateway) => &gateway.gateway,
+            None => return invalid_argument("gateway is null"),
+        };
+        let mapping = match mapping.as_mut() {
+            Some(mapping) => mapping,
+            None => return invalid_argument("mapping is null"),
+        };
+        match gateway.get_generic_port_mapping_entry(index) {
+            Ok(entry) => {
+                mapping.protocol = match entry.protocol {
+                    PortMappingProtocol::TCP => IGD_TCP,
+                    PortMappingProtocol::UDP => IGD_UDP,
+                };
+                mapping.external_port = entry.external_port;
+                mapping.internal_port = entry.internal_port;
+                copy_string(&mut mapping.internal_client, &entry.internal_client);
+                copy_string(&mut mapping.remote_host, &entry.remote_host);
+                copy_string(&mut mapping.description, &entry.port_mapping_description);
+                mapping.lease_duration = entry.lease_duration;
+                mapping.enabled = entry.enabled as c_int;
+                IGD_OK
+            }
+            Err(e) => fail(e),
+        }
+    })
+}
+
+#[test]
+fn test_errors() {
+    assert_eq!(fail(RemovePortError::NoSuchPortMapping), IGD_ERROR_NO_SUCH_MAPPING);
+    let message = unsafe { CStr::from_ptr(igd_last_error_message()) };
+    assert_eq!(
+        message.to_str().unwrap(),
+        RemovePortError::NoSuchPortMapping.to_string()
+    );
+
+    let mut buf = [0 as c_char; 16];
+    assert_eq!(
+        unsafe { igd_get_external_ip(std::ptr::null(), buf.as_mut_ptr(), buf.len()) },
+        IGD_ERROR_INVALID_ARGUMENT
+    );
+    assert_eq!(
+        unsafe { igd_gateway_set_allow_third_party(std::ptr::null_mut(), 1) },
+        IGD_ERROR_INVALID_ARGUMENT
+    );
+    copy_string(&mut buf[..4], "192.168.1.1");
+    assert_eq!(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().unwrap(), "192");
+    copy_string(&mut buf[..4], "äöü");
+    assert_eq!(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().unwrap(), "ä");
+}
+
+#[test]
+fn test_panic() {
+    assert_eq!(catch(|| panic!("invalid state")), IGD_ERROR_PANIC);
+    let message = unsafe { CStr::from_ptr(igd_last_error_message()) };
+    assert_eq!(message.to_str().unwrap(), "panicked: invalid state");
+    assert_eq!(catch(|| IGD_OK), IGD_OK);
+}
@@ -0,0 +1,107 @@
+use std::net::SocketAddrV4;
+
+use crate::errors::{RemovePortError, RequestError, Result};
+use crate::{search_gateway, Gateway, PortMappingProtocol, PortMappingRequest, Renewer, SearchOptions};
+
+/// Lease duration of the mappings made by `forward`, renewed halfway through.
+pub const FORWARD_LEASE_DURATION: u32 = 3600;
+
+/// Forward `port` of the gateway to the same port of this host, keeping the mapping until the
+/// returned handle is dropped.
+///
+/// This searches the gateway with the default options, maps `port` to the address of this host
+/// facing it with a lease of `FORWARD_LEASE_DURATION`, and renews the mapping with a `Renewer`.
+/// Use `Gateway` directly for anything else, e.g. another external port.
+///
+/// # Example
+/// ```no_run
+/// use igd::PortMappingProtocol;
+///
+/// let forward = igd::forward(8080, PortMappingProtocol::TCP, "my server").unwrap();
+/// println!("reachable on port {} of the gateway", forward.external_port());
+/// // The mapping is removed here.
+/// drop(forward);
+/// ```
+pub fn forward(port: u16, protocol: PortMappingProtocol, description: &str) -> Result<ForwardHandle> {
+    forward_with(Default::default(), port, protocol, description)
+}
+
+/// Forward `port` like `forward`, searching the gateway with `options`.
+pub fn forward_with(
+    options: SearchOptions,
+    port: u16,
+    protocol: PortMappingProtocol,
+    description: &str,
+) -> Result<ForwardHandle> {
+    let gateway = search_gateway(options)?;
+    let local_ip = gateway.local_addr_hint().map_err(RequestError::from)?;
+    let local_addr = SocketAddrV4::new(local_ip, port);
+    let renewer = Renewer::new(gateway);
+    let mapped = renewer.add(PortMappingRequest {
+        protocol,
+        external_port: port,
+        local_addr,
+        lease_duration: FORWARD_LEASE_DURATION,
+        description: description.to_string(),
+    })?;
+    Ok(ForwardHandle {
+        renewer,
+        protocol,
+        external_port: mapped.external_port,
+        local_addr,
+    })
+}
+
+/// A port forwarded by `forward`, renewed until the handle is dropped or removed.
+#[derive(Debug)]
+pub struct ForwardHandle {
+    renewer: Renewer,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+}
+
+impl ForwardHandle {
+    /// The gateway the port is forwarded on.
+    pub fn gateway(&self) -> &Gateway {
+        self.renewer.gateway()
+    }
+
+    /// Protocol of the mapping.
+    pub fn protocol(&self) -> PortMappingProtocol {
+        self.protocol
+    }
+
+    /// External port of the mapping.
+    pub fn external_port(&self) -> u16 {
+        self.external_port
+    }
+
+    /// Address of this host the traffic is sent to.
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.local_addr
+    }
+
+    /// Stop renewing the mapping and remove it, as dropping the handle does, but with the error.
+    pub fn remove(self) -> std::result::Result<(), RemovePortError> {
+        self.renewer.stop()
+    }
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_forward() {
+    let mock = crate::test::MockGateway::start().unwrap();
+    let forward = forward_with(mock.search_options(), 8080, PortMappingProtocol::TCP, "igd test").unwrap();
+    assert_eq!(forward.external_port(), 8080);
+    let mappings = mock.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].internal_client, forward.local_addr().ip().to_string());
+    assert_eq!(mappings[0].lease_duration, FORWARD_LEASE_DURATION);
+    drop(forward);
+    assert!(mock.mappings().is_empty());
+
+    let forward = forward_with(mock.search_options(), 8081, PortMappingProtocol::UDP, "igd test").unwrap();
+    forward.remove().unwrap();
+    assert!(mock.mappings().is_empty());
+}
@@ -0,0 +1,219 @@
+//! The addresses of the network interfaces of this host that a gateway may be searched from.
+//!
+//! A host with several interfaces, e.g. Ethernet, Wi-Fi and a VPN, may have to search from each
+//! of them to find its gateway, or pick the right one as `SearchOptions::bind_addr`. The
+//! addresses are listed with `getifaddrs`, so only Unix platforms are supported. Elsewhere
+//! `candidate_addrs` fails with an error of kind `Unsupported`.
+//!
+//! # Example
+//! ```no_run
+//! use igd::interfaces;
+//! use igd::SearchOptions;
+//!
+//! for candidate in interfaces::candidate_addrs().unwrap() {
+//!     let options = SearchOptions {
+//!         bind_addr: candidate.bind_addr(),
+//!         ..Default::default()
+//!     };
+//!     if let Ok(gateway) = igd::search_gateway(options) {
+//!         println!("found {} from {}", gateway, candidate.name);
+//!         break;
+//!     }
+//! }
+//! ```
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+/// An address of a network interface.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterfaceAddr {
+    /// Name of the interface, e.g. `eth0`
+    pub name: String,
+    /// Index of the interface, the scope of its link-local IPv6 addresses
+    pub index: u32,
+    /// Address of the interface
+    pub addr: IpAddr,
+}
+
+impl InterfaceAddr {
+    /// The address to bind a socket to, with any port, e.g. as `SearchOptions::bind_addr`.
+    ///
+    /// Link-local IPv6 addresses are scoped to the interface.
+    pub fn bind_addr(&self) -> SocketAddr {
+        match self.addr {
+            IpAddr::V4(addr) => SocketAddr::new(IpAddr::V4(addr), 0),
+            IpAddr::V6(addr) => {
+                let scope = if is_link_local(addr) { self.index } else { 0 };
+                SocketAddr::V6(SocketAddrV6::new(addr, 0, 0, scope))
+            }
+        }
+    }
+}
+
+/// List the addresses a gateway may be searched from.
+///
+/// Those are the private IPv4 addresses, and the unique local and link-local IPv6 addresses,
+/// of the interfaces that are up, not loopback, and can broadcast (IPv4) or multicast (IPv6).
+pub fn candidate_addrs() -> io::Result<Vec<InterfaceAddr>> {
+    Ok(platform::interface_addrs()?
+        .into_iter()
+        .filter(|(flags, interface)| is_candidate(*flags, interface.addr))
+        .map(|(_, interface)| interface)
+        .collect())
+}
+
+/// The flags of an interface that matter to `is_candidate`.
+#[derive(Clone, Copy, Debug, Default)]
+struct Flags {
+    up: bool,
+    loopback: bool,
+    broadcast: bool,
+    multicast: bool,
+}
+
+fn is_candidate(flags: Flags, addr: IpAddr) -> bool {
+    if !flags.up || flags.loopback {
+        return false;
+    }
+    match addr {
+        IpAddr::V4(addr) => flags.broadcast && is_private(addr),
+        IpAddr::V6(addr) => flags.multicast && (is_unique_local(addr) || is_link_local(addr)),
+    }
+}
+
+/// Whether the address is private (RFC 1918) or shared by a carrier-grade NAT (RFC 6598).
+fn is_private(addr: Ipv4Addr) -> bool {
+    let octets = addr.octets();
+    addr.is_private() || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+}
+
+fn is_unique_local(addr: Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xfe00 == 0xfc00
+}
+
+fn is_link_local(addr: Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::ffi::CStr;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::ptr;
+
+    use super::{Flags, InterfaceAddr};
+
+    pub fn interface_addrs() -> io::Result<Vec<(Flags, InterfaceAddr)>> {
+        let mut list: *mut libc::ifaddrs = ptr::null_mut();
+        if unsafe { libc::getifaddrs(&mut list) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut addrs = vec![];
+        let mut entry = list;
+        while !entry.is_null() {
+            let ifa = unsafe { &*entry };
+            entry = ifa.ifa_next;
+            if ifa.ifa_addr.is_null() {
+                continue;
+            }
+            let addr = match unsafe { address(ifa.ifa_addr) } {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let name = unsafe { CStr::from_ptr(ifa.ifa_name) };
+            let flags = ifa.ifa_flags as libc::c_int;
+            addrs.push((
+                Flags {
+                    up: flags & libc::IFF_UP != 0,
+                    loopback: flags & libc::IFF_LOOPBACK != 0,
+                    broadcast: flags & libc::IFF_BROADCAST != 0,
+                    multicast: flags & libc::IFF_MULTICAST != 0,
+                },
+                InterfaceAddr {
+                    name: name.to_string_lossy().into_owned(),
+                    index: unsafe { libc::if_nametoindex(ifa.ifa_name) },
+                    addr,
+                },
+            ));
+        }
+        unsafe { libc::freeifaddrs(list) };
+        Ok(addrs)
+    }
+
+    /// Read an IPv4 or IPv6 address, other families are skipped.
+    unsafe fn address(addr: *const libc::sockaddr) -> Option<IpAddr> {
+        match (*addr).sa_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = &*(addr as *const libc::sockaddr_in);
+                Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))))
+            }
+            libc::AF_INET6 => {
+                let addr = &*(addr as *const libc::sockaddr_in6);
+                Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod platform {
+    use std::io;
+
+    use super::{Flags, InterfaceAddr};
+
+    pub fn interface_addrs() -> io::Result<Vec<(Flags, InterfaceAddr)>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "listing the interface addresses is not supported on this platform",
+        ))
+    }
+}
+
+#[test]
+fn test_is_candidate() {
+    let lan = Flags {
+        up: true,
+        broadcast: true,
+        multicast: true,
+        ..Default::default()
+    };
+    assert!(is_candidate(lan, "192.168.1.10".parse().unwrap()));
+    assert!(is_candidate(lan, "10.0.0.2".parse().unwrap()));
+    assert!(is_candidate(lan, "100.64.3.4".parse().unwrap()));
+    assert!(is_candidate(lan, "fd00::10".parse().unwrap()));
+    assert!(is_candidate(lan, "fe80::1".parse().unwrap()));
+    assert!(!is_candidate(lan, "8.8.8.8".parse().unwrap()));
+    assert!(!is_candidate(lan, "100.128.0.1".parse().unwrap()));
+    assert!(!is_candidate(lan, "2001:db8::1".parse().unwrap()));
+
+    let down = Flags { up: false, ..lan };
+    assert!(!is_candidate(down, "192.168.1.10".parse().unwrap()));
+    let loopback = Flags { loopback: true, ..lan };
+    assert!(!is_candidate(loopback, "10.0.0.1".parse().unwrap()));
+    // Point-to-point links, e.g. of a VPN, don't broadcast.
+    let tunnel = Flags {
+        broadcast: false,
+        multicast: false,
+        ..lan
+    };
+    assert!(!is_candidate(tunnel, "10.8.0.2".parse().unwrap()));
+
+    let link_local = InterfaceAddr {
+        name: "eth0".to_string(),
+        index: 2,
+        addr: "fe80::1".parse().unwrap(),
+    };
+    assert_eq!(link_local.bind_addr(), "[fe80::1%2]:0".parse().unwrap());
+}
+
+#[test]
+fn test_candidate_addrs() {
+    for candidate in candidate_addrs().unwrap() {
+        assert!(!candidate.addr.is_loopback());
+        std::net::UdpSocket::bind(candidate.bind_addr()).unwrap();
+    }
+}
@@ -1,17 +1,53 @@
 #![deny(missing_docs)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
 //! This library allows you to communicate with an IGD enabled device.
 //! Use one of the `search_gateway` functions to obtain a `Gateway` object.
 //! You can then communicate with the device via this object.
+//!
+//! # WASI
+//!
+//! The crate builds for `wasm32-wasip2`, whose sockets are enough to search the gateway and
+//! send it requests. WASI has no threads, so what runs on background threads, i.e. `Renewer`,
+//! `forward`, `Keepalive`, `ExternalIpWatcher`, `GatewayRegistry` and
+//! `search_gateway_and_collect`, is left out there, and batches of requests, e.g. of
+//! `Gateway::add_ports`, are sent one after the other. The `aio` feature isn't available, tokio
+//! has no sockets on WASI. Hosts with sockets of their own can search the gateway over them with
+//! `search_gateway_with`.
+//!
+//! # `no_std`
+//!
+//! Without the default `std` feature, the crate only needs `alloc`, and all that is left is
+//! `proto`: the SSDP and SOAP messages of the protocol, formatted and parsed without any
+//! networking, so firmware can talk to the gateway over its own TCP/IP stack. Every other feature
+//! of the crate but `log` turns `std` back on, optional dependencies enabled on their own, e.g.
+//! `serde`, don't. The `smoltcp` example maps a port over smoltcp this way.
+//!
+//! Before 0.13, `default-features = false` only turned `log` off. Such dependents now need
+//! `features = ["std"]` to keep the rest of the crate.
 
+extern crate alloc;
+#[cfg(feature = "std")]
 extern crate attohttpc;
+#[cfg(feature = "log")]
 #[macro_use]
 extern crate log;
 #[cfg(feature = "aio")]
 extern crate bytes;
 
+#[cfg(feature = "prometheus")]
+extern crate prometheus;
+#[cfg(feature = "std")]
 extern crate rand;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(any(feature = "config", feature = "diagnostics", feature = "export"))]
+extern crate serde_json;
+#[cfg(feature = "config")]
+extern crate toml;
+#[cfg(feature = "std")]
 extern crate url;
+#[cfg(feature = "std")]
 extern crate xmltree;
 
 #[cfg(feature = "aio")]
@@ -23,31 +59,191 @@ extern crate hyper;
 #[cfg(feature = "aio")]
 extern crate tokio;
 
+// Without the `log` feature, the records are type checked, so their arguments count as used, but
+// never formatted.
+#[cfg(not(feature = "log"))]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
+#[cfg(not(feature = "log"))]
+#[allow(unused_macros)]
+macro_rules! debug {
+    ($($arg:tt)+) => { trace!($($arg)+) };
+}
+#[cfg(not(feature = "log"))]
+#[allow(unused_macros)]
+macro_rules! info {
+    ($($arg:tt)+) => { trace!($($arg)+) };
+}
+#[cfg(not(feature = "log"))]
+macro_rules! warn {
+    ($($arg:tt)+) => { trace!($($arg)+) };
+}
+
 // data structures
-pub use self::common::parsing::PortMappingEntry;
-pub use self::common::SearchOptions;
+#[cfg(feature = "std")]
+pub use self::availability::{GatewayEvent, GatewayTracker};
+#[cfg(feature = "std")]
+pub use self::backoff::{Backoff, ExponentialBackoff, FixedBackoff};
+#[cfg(feature = "std")]
+pub use self::capabilities::Capabilities;
+#[cfg(feature = "std")]
+pub use self::common::parsing::{
+    ConnectionStatus, DeviceInfo, MappedPort, PortMappingEntry, PortMappingRequest, StatusInfo, TrafficStats,
+};
+#[cfg(feature = "std")]
+pub use self::common::{
+    AnyPortOptions, DiscoveryTiming, GatewayFilter, HeaderCase, MappingFilter, RateLimit, RequestFormat,
+    RequestTimeouts, SearchOptions, UrlPolicy,
+};
+#[cfg(feature = "std")]
+pub use self::deadline::Deadline;
+#[cfg(feature = "std")]
+pub use self::description::{DeviceDescription, IconDescription, RootDescription, ServiceDescription, WanConnection};
+#[cfg(feature = "std")]
+pub use self::dual_stack::DualStackMapping;
+#[cfg(feature = "std")]
 pub use self::errors::{
-    AddAnyPortError, AddPortError, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError, RequestError,
-    SearchError,
+    AddAnyPortError, AddPortError, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError,
+    RequestContext, RequestError, SearchError,
 };
+#[cfg(feature = "std")]
 pub use self::errors::{Error, Result};
+#[cfg(feature = "export")]
+pub use self::export::ExportFormat;
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+pub use self::forward::{forward, forward_with, ForwardHandle, FORWARD_LEASE_DURATION};
+#[cfg(feature = "std")]
 pub use self::gateway::Gateway;
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+pub use self::manager::Keepalive;
+#[cfg(feature = "std")]
+pub use self::manager::{Failover, LeaseEvent, PortMappingManager, SyncReport};
+#[cfg(feature = "std")]
+pub use self::monitor::{TrafficMonitor, TrafficRate};
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+pub use self::registry::{GatewayRegistry, RegisteredGateway};
+#[cfg(feature = "std")]
+pub use self::renewal::{FractionScheduler, RenewalScheduler};
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+pub use self::renewer::{RenewalStatus, Renewer};
+#[cfg(feature = "std")]
+pub use self::session::MappingSession;
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+pub use self::watcher::ExternalIpWatcher;
 
 // search of gateway
+#[cfg(feature = "std")]
+pub use self::search::clear_description_cache;
+#[cfg(feature = "route")]
+pub use self::search::search_default_gateway;
+#[cfg(feature = "std")]
 pub use self::search::search_gateway;
+#[cfg(feature = "std")]
+pub use self::search::search_gateway_within;
+#[cfg(feature = "std")]
 pub use self::search::search_multi_gateways;
+#[cfg(feature = "std")]
+pub use self::search::set_schema_cache_dir;
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+pub use self::search::{search_gateway_and_collect, LateGateways};
+#[cfg(feature = "std")]
+pub use self::search::{search_gateway_with, search_multi_gateways_with, SearchTransport};
 
 #[cfg(feature = "aio")]
 pub mod aio;
+#[cfg(feature = "auto")]
+pub mod auto;
+#[cfg(feature = "std")]
+mod availability;
+#[cfg(feature = "std")]
+mod backoff;
+#[cfg(feature = "std")]
+mod capabilities;
+#[cfg(feature = "cassette")]
+pub mod cassette;
+#[cfg(feature = "std")]
 mod common;
+// Without std, only the formatting of the messages, used by `proto`, is built.
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "std")]
+mod deadline;
+#[cfg(feature = "std")]
+mod description;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
+#[cfg(feature = "std")]
+mod dual_stack;
+#[cfg(feature = "std")]
 mod errors;
+#[cfg(feature = "export")]
+mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+mod forward;
+#[cfg(feature = "std")]
 mod gateway;
+#[cfg(feature = "interfaces")]
+pub mod interfaces;
+#[cfg(feature = "std")]
+mod manager;
+#[cfg(not(feature = "std"))]
+#[allow(dead_code)]
+#[path = "common/messages.rs"]
+mod messages;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+#[cfg(feature = "std")]
+mod monitor;
+#[cfg(feature = "stun")]
+pub mod nat_probe;
+#[cfg(feature = "natpmp")]
+pub mod natpmp;
+#[cfg(feature = "netwatch")]
+pub mod netwatch;
+#[cfg(feature = "std")]
+pub mod parsing;
+#[cfg(feature = "pcp")]
+pub mod pcp;
+pub mod proto;
+#[cfg(feature = "std")]
+pub mod quirks;
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+mod registry;
+#[cfg(feature = "std")]
+mod renewal;
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+mod renewer;
+#[cfg(feature = "route")]
+pub mod route;
+#[cfg(feature = "std")]
 mod search;
+#[cfg(feature = "std")]
+mod session;
+#[cfg(feature = "std")]
+mod soap;
+#[cfg(feature = "std")]
+pub mod ssdp;
+#[cfg(feature = "stun")]
+pub mod stun;
+#[cfg(feature = "mock")]
+pub mod test;
+#[cfg(feature = "tr064")]
+pub mod tr064;
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
+mod watcher;
 
-use std::fmt;
+use core::fmt;
 
 /// Represents the protocols available for port mapping.
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PortMappingProtocol {
     /// TCP protocol
     TCP,
@@ -0,0 +1,912 @@
+use std::collections::HashMap;
+use std::mem;
+use std::net::SocketAddrV4;
+#[cfg(not(target_os = "wasi"))]
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+#[cfg(not(target_os = "wasi"))]
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::common::parsing::{ConnectionStatus, MappedPort, PortMappingEntry, PortMappingRequest, StatusInfo};
+use crate::common::{self, SearchOptions};
+use crate::errors::{AddPortError, Error, GetGenericPortMappingEntryError, RemovePortError, RequestError};
+use crate::{search_gateway, Gateway, PortMappingProtocol};
+
+/// Keeps the port mappings of an application on a gateway.
+///
+/// The mappings of the manager are told apart from others by a tag, which is put in front of
+/// their descriptions. Mappings with other descriptions are never touched.
+///
+/// # Example
+/// ```no_run
+/// use std::net::SocketAddrV4;
+/// use igd::{PortMappingManager, PortMappingProtocol, PortMappingRequest};
+///
+/// let gateway = igd::search_gateway(Default::default()).unwrap();
+/// let local_addr = SocketAddrV4::new(gateway.local_addr_hint().unwrap(), 8080);
+/// let mut manager = PortMappingManager::new(gateway, "myapp:");
+/// let report = manager
+///     .sync(&[PortMappingRequest {
+///         protocol: PortMappingProtocol::TCP,
+///         external_port: 8080,
+///         local_addr,
+///         lease_duration: 3600,
+///         description: "web".to_string(),
+///     }])
+///     .unwrap();
+/// println!("added {}, removed {}", report.added.len(), report.removed.len());
+/// ```
+#[derive(Debug)]
+pub struct PortMappingManager {
+    gateway: Gateway,
+    tag: String,
+    mappings: Vec<(PortMappingRequest, MappedPort)>,
+    /// Leases of the mappings that aren't permanent, by protocol and external port
+    leases: HashMap<(PortMappingProtocol, u16), Lease>,
+    expiry_warning: Duration,
+    backups: Vec<Gateway>,
+    /// The status of the WAN connection at the last `verify`, and when it was checked, to notice
+    /// restarts
+    status: Option<(StatusInfo, Instant)>,
+}
+
+/// How long before its lease ends a mapping is reported as expiring by default.
+const DEFAULT_EXPIRY_WARNING: Duration = Duration::from_secs(60);
+
+/// How far, in seconds, the uptime of the connection may lag behind the time since the last
+/// check before a restart is assumed. Gateways round the uptime, and the requests take time.
+const UPTIME_SLACK: u64 = 5;
+
+/// The end of the lease of a mapping, and what `poll_leases` reported about it.
+#[derive(Clone, Copy, Debug)]
+struct Lease {
+    expires: Instant,
+    expiring: bool,
+    expired: bool,
+}
+
+impl Lease {
+    fn new(mapped: MappedPort) -> Option<Lease> {
+        if mapped.lease_duration == 0 {
+            return None;
+        }
+        Some(Lease {
+            expires: Instant::now() + Duration::from_secs(mapped.lease_duration.into()),
+            expiring: false,
+            expired: false,
+        })
+    }
+}
+
+/// A change of the lease of a mapping of a `PortMappingManager`, see `poll_leases`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaseEvent {
+    /// The lease ends soon, unless the mapping is made again, e.g. by `sync`
+    Expiring {
+        /// Protocol of the mapping
+        protocol: PortMappingProtocol,
+        /// External port of the mapping
+        external_port: u16,
+        /// Time left until the lease ends
+        remaining: Duration,
+    },
+    /// The lease ended, the gateway most likely removed the mapping
+    Expired {
+        /// Protocol of the mapping
+        protocol: PortMappingProtocol,
+        /// External port of the mapping
+        external_port: u16,
+    },
+}
+
+/// The changes made by `PortMappingManager::sync`.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Mappings that didn't exist and were added
+    pub added: Vec<MappedPort>,
+    /// Mappings that existed with other ports or another local address and were replaced
+    pub updated: Vec<MappedPort>,
+    /// Mappings that are no longer wanted and were removed, by protocol and external port
+    pub removed: Vec<(PortMappingProtocol, u16)>,
+    /// Mappings that could not be changed, by description, with the error of the gateway
+    pub failed: Vec<(String, Error)>,
+    /// The switch to a backup gateway, if the gateway stopped responding, see
+    /// `PortMappingManager::set_backup_gateways`
+    pub failover: Option<Failover>,
+    /// The gateway restarted, or its WAN connection was re-established, since the last
+    /// `PortMappingManager::verify`, so all mappings were made again
+    pub restarted: bool,
+}
+
+/// A switch of a `PortMappingManager` from a gateway that stopped responding to a backup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failover {
+    /// Address of the gateway that stopped responding, now the last backup
+    pub from: SocketAddrV4,
+    /// Address of the backup the mappings were moved to
+    pub to: SocketAddrV4,
+}
+
+impl PortMappingManager {
+    /// Manage the mappings of `gateway` whose descriptions start with `tag`.
+    pub fn new<S: Into<String>>(gateway: Gateway, tag: S) -> PortMappingManager {
+        PortMappingManager {
+            gateway,
+            tag: tag.into(),
+            mappings: Vec::new(),
+            leases: HashMap::new(),
+            expiry_warning: DEFAULT_EXPIRY_WARNING,
+            backups: Vec::new(),
+            status: None,
+        }
+    }
+
+    /// The gateway the mappings are made on.
+    pub fn gateway(&self) -> &Gateway {
+        &self.gateway
+    }
+
+    /// The mappings made by `sync` and `add`, with the ports and leases the gateway granted.
+    pub fn mappings(&self) -> &[(PortMappingRequest, MappedPort)] {
+        &self.mappings
+    }
+
+    /// Gateways to move the mappings to, in order, when the gateway stops responding.
+    ///
+    /// This is meant for networks with several routers, e.g. a primary line and a backup. When
+    /// `verify` can't reach the gateway, the mappings are made on the first backup that answers,
+    /// as `rediscover` does, which then becomes the gateway. The old gateway becomes the last
+    /// backup, so the mappings move back when the new one fails in turn.
+    pub fn set_backup_gateways(&mut self, backups: Vec<Gateway>) {
+        self.backups = backups;
+    }
+
+    /// The gateways the mappings are moved to when the gateway stops responding.
+    pub fn backup_gateways(&self) -> &[Gateway] {
+        &self.backups
+    }
+
+    /// Report mappings as expiring when their lease ends within `warning` (defaults to 60
+    /// seconds), see `poll_leases`.
+    pub fn set_expiry_warning(&mut self, warning: Duration) {
+        self.expiry_warning = warning;
+    }
+
+    /// When the lease of a mapping of the manager ends, `None` if it is permanent or unknown.
+    ///
+    /// It is computed from the lease the gateway granted when the mapping was made or found.
+    pub fn expires(&self, protocol: PortMappingProtocol, external_port: u16) -> Option<Instant> {
+        self.leases.get(&(protocol, external_port)).map(|lease| lease.expires)
+    }
+
+    /// The time left on the lease of a mapping of the manager, zero once it ended, `None` if
+    /// it is permanent or unknown.
+    pub fn remaining(&self, protocol: PortMappingProtocol, external_port: u16) -> Option<Duration> {
+        self.expires(protocol, external_port)
+            .map(|expires| expires.saturating_duration_since(Instant::now()))
+    }
+
+    /// Report the leases that end soon or ended since the last call, in the order of `mappings`.
+    ///
+    /// The manager doesn't renew the leases itself, call this periodically, e.g. every few
+    /// seconds, to warn before a mapping stops working, and `sync` or `add` to make it again.
+    /// Each lease is reported as expiring and as expired once, until it is granted again.
+    pub fn poll_leases(&mut self) -> Vec<LeaseEvent> {
+        self.poll_leases_at(Instant::now())
+    }
+
+    fn poll_leases_at(&mut self, now: Instant) -> Vec<LeaseEvent> {
+        let mut events = Vec::new();
+        for (request, mapped) in &self.mappings {
+            let (protocol, external_port) = (request.protocol, mapped.external_port);
+            let lease = match self.leases.get_mut(&(protocol, external_port)) {
+                Some(lease) => lease,
+                None => continue,
+            };
+            if lease.expires <= now {
+                if !lease.expired {
+                    lease.expired = true;
+                    events.push(LeaseEvent::Expired {
+                        protocol,
+                        external_port,
+                    });
+                }
+            } else if !lease.expiring && lease.expires - now <= self.expiry_warning {
+                lease.expiring = true;
+                events.push(LeaseEvent::Expiring {
+                    protocol,
+                    external_port,
+                    remaining: lease.expires - now,
+                });
+            }
+        }
+        events
+    }
+
+    /// Make the mappings on the gateway match `desired`.
+    ///
+    /// The mappings of the gateway are listed and matched to the desired ones by description.
+    /// Missing mappings are added, those with a different protocol, port or local address are
+    /// replaced, and tagged mappings that aren't desired anymore are removed. Matching mappings
+    /// are left alone, so calling this repeatedly with the same requests changes nothing.
+    ///
+    /// Only failing to list the mappings is an error, failures of single mappings are reported.
+    pub fn sync(&mut self, desired: &[PortMappingRequest]) -> Result<SyncReport, GetGenericPortMappingEntryError> {
+        let mut current: Vec<PortMappingEntry> = self
+            .gateway
+            .get_port_mappings()?
+            .into_iter()
+            .filter(|entry| entry.port_mapping_description.starts_with(&self.tag))
+            .collect();
+
+        let mut report = SyncReport::default();
+        let mut mappings = Vec::new();
+        for request in desired {
+            let description = self.description(request);
+            let existing = current
+                .iter()
+                .position(|entry| entry.port_mapping_description == description)
+                .map(|index| current.remove(index));
+
+            let replaced = match existing {
+                Some(ref entry) if matches(entry, request) => {
+                    let mapped = MappedPort {
+                        external_port: entry.external_port,
+                        lease_duration: entry.lease_duration,
+                    };
+                    mappings.push((request.clone(), mapped));
+                    continue;
+                }
+                Some(entry) => {
+                    if let Err(e) = self.gateway.remove_port(entry.protocol, entry.external_port) {
+                        report.failed.push((description, e.into()));
+                        continue;
+                    }
+                    true
+                }
+                None => false,
+            };
+
+            match self.gateway.map_port(
+                request.protocol,
+                request.external_port,
+                request.local_addr,
+                request.lease_duration,
+                &description,
+            ) {
+                Ok(mapped) => {
+                    if replaced {
+                        report.updated.push(mapped);
+                    } else {
+                        report.added.push(mapped);
+                    }
+                    mappings.push((request.clone(), mapped));
+                }
+                Err(e) => report.failed.push((description, e.into())),
+            }
+        }
+
+        for entry in current {
+            match self.gateway.remove_port(entry.protocol, entry.external_port) {
+                Ok(()) => report.removed.push((entry.protocol, entry.external_port)),
+                Err(e) => report.failed.push((entry.port_mapping_description, e.into())),
+            }
+        }
+
+        self.leases = mappings
+            .iter()
+            .filter_map(|(request, mapped)| Some(((request.protocol, mapped.external_port), Lease::new(*mapped)?)))
+            .collect();
+        self.mappings = mappings;
+        #[cfg(feature = "prometheus")]
+        self.record(&report);
+        Ok(report)
+    }
+
+    /// Check that the gateway still has each mapping of the manager, making those it dropped
+    /// again.
+    ///
+    /// Many gateways drop their mappings when the WAN connection is re-established, long before
+    /// the leases end. Each mapping is looked up with `GetSpecificPortMappingEntry`, and made
+    /// again on the same external port if it is missing or points elsewhere. Those are reported
+    /// as added, see `Keepalive` to do this periodically.
+    ///
+    /// Gateways that don't announce their restarts may lose the mappings without a trace in
+    /// the list, so the uptime of the WAN connection is checked first with `GetStatusInfo`. If it
+    /// went backwards, or the connection came back after being down, all mappings are made again
+    /// without looking them up, and the report is marked `restarted`.
+    ///
+    /// If the gateway can't be reached, the mappings are moved to a backup gateway, see
+    /// `set_backup_gateways`, and the report is the one of the move.
+    pub fn verify(&mut self) -> SyncReport {
+        let report = if self.restarted() {
+            debug!("{} restarted, making the mappings again", self.gateway);
+            let mut report = SyncReport {
+                restarted: true,
+                ..Default::default()
+            };
+            for index in 0..self.mappings.len() {
+                self.remap(index, &mut report);
+            }
+            #[cfg(feature = "prometheus")]
+            self.record(&report);
+            report
+        } else {
+            self.verify_mappings()
+        };
+        let unreachable = report.failed.iter().any(|(_, e)| match *e {
+            Error::RequestError(ref e) => is_unreachable(e),
+            _ => false,
+        });
+        if !unreachable || self.backups.is_empty() {
+            return report;
+        }
+        debug!("{} stopped responding, failing over", self.gateway);
+        self.fail_over().unwrap_or(report)
+    }
+
+    /// Move the mappings to the first backup that takes them.
+    fn fail_over(&mut self) -> Option<SyncReport> {
+        for index in 0..self.backups.len() {
+            let from = self.gateway.addr;
+            let mut gateway = self.backups[index].clone();
+            let to = gateway.addr;
+            match self.move_to(&mut gateway) {
+                Ok(mut report) => {
+                    self.backups.remove(index);
+                    self.backups.push(gateway);
+                    report.failover = Some(Failover { from, to });
+                    return Some(report);
+                }
+                Err(e) => debug!("failing over to {} failed: {}", gateway, e),
+            }
+        }
+        None
+    }
+
+    /// Whether the gateway restarted, or its connection was re-established, since the status was
+    /// last checked, as far as the status of its connection tells.
+    fn restarted(&mut self) -> bool {
+        let status = match self.gateway.get_status_info() {
+            Ok(status) => status,
+            Err(e) => {
+                debug!("getting the status of {} failed: {}", self.gateway, e);
+                return false;
+            }
+        };
+        let connected = status.connection_status == ConnectionStatus::Connected;
+        let uptime = u64::from(status.uptime);
+        match self.status.replace((status, Instant::now())) {
+            Some((ref last, _)) if last.connection_status != ConnectionStatus::Connected => connected,
+            // The uptime should have grown by the time since the last check, it didn't if the
+            // connection came back meanwhile, even when it has been up longer than it was then.
+            Some((ref last, checked)) => {
+                connected && uptime + UPTIME_SLACK < u64::from(last.uptime) + checked.elapsed().as_secs()
+            }
+            None => false,
+        }
+    }
+
+    fn verify_mappings(&mut self) -> SyncReport {
+        let mut report = SyncReport::default();
+        for index in 0..self.mappings.len() {
+            let (request, mapped) = self.mappings[index].clone();
+            let description = self.description(&request);
+            match self
+                .gateway
+                .get_specific_port_mapping_entry(request.protocol, mapped.external_port)
+            {
+                Ok(ref entry) if matches(entry, &request) => continue,
+                Ok(_) => {}
+                Err(ref e) if matches!(e.inner(), RequestError::NoSuchEntryInArray) => {}
+                Err(e) => {
+                    report.failed.push((description, e.into()));
+                    continue;
+                }
+            }
+
+            debug!("the mapping {:?} is gone, making it again", description);
+            self.remap(index, &mut report);
+        }
+        #[cfg(feature = "prometheus")]
+        self.record(&report);
+        report
+    }
+
+    /// Make the mapping at `index` again on the same external port, reporting it as added.
+    fn remap(&mut self, index: usize, report: &mut SyncReport) {
+        let (request, mapped) = self.mappings[index].clone();
+        let description = self.description(&request);
+        match self.gateway.map_port(
+            request.protocol,
+            mapped.external_port,
+            request.local_addr,
+            request.lease_duration,
+            &description,
+        ) {
+            Ok(remapped) => {
+                self.leases.remove(&(request.protocol, mapped.external_port));
+                if let Some(lease) = Lease::new(remapped) {
+                    self.leases.insert((request.protocol, remapped.external_port), lease);
+                }
+                self.mappings[index].1 = remapped;
+                report.added.push(remapped);
+            }
+            Err(e) => report.failed.push((description, e.into())),
+        }
+    }
+
+    /// Update the metrics after the mappings were made again.
+    #[cfg(feature = "prometheus")]
+    fn record(&self, report: &SyncReport) {
+        crate::metrics::record_renewals(report.added.len() + report.updated.len(), report.failed.len());
+        crate::metrics::record_managed_mappings(&self.tag, self.mappings.len());
+    }
+
+    /// Add a single mapping, with the tag in front of its description.
+    ///
+    /// The mapping is kept until the next `sync` that doesn't ask for it.
+    pub fn add(&mut self, request: PortMappingRequest) -> Result<MappedPort, AddPortError> {
+        let mapped = self.gateway.map_port(
+            request.protocol,
+            request.external_port,
+            request.local_addr,
+            request.lease_duration,
+            &self.description(&request),
+        )?;
+        self.mappings.retain(|&(ref other, other_mapped)| {
+            (other.protocol, other_mapped.external_port) != (request.protocol, mapped.external_port)
+        });
+        self.leases.remove(&(request.protocol, mapped.external_port));
+        if let Some(lease) = Lease::new(mapped) {
+            self.leases.insert((request.protocol, mapped.external_port), lease);
+        }
+        self.mappings.push((request, mapped));
+        #[cfg(feature = "prometheus")]
+        crate::metrics::record_managed_mappings(&self.tag, self.mappings.len());
+        Ok(mapped)
+    }
+
+    /// Remove a single mapping of the manager.
+    pub fn remove(&mut self, protocol: PortMappingProtocol, external_port: u16) -> Result<(), RemovePortError> {
+        self.gateway.remove_port(protocol, external_port)?;
+        self.mappings
+            .retain(|&(ref request, mapped)| (request.protocol, mapped.external_port) != (protocol, external_port));
+        self.leases.remove(&(protocol, external_port));
+        #[cfg(feature = "prometheus")]
+        crate::metrics::record_managed_mappings(&self.tag, self.mappings.len());
+        Ok(())
+    }
+
+    /// Search the gateway again and make the mappings of the manager on it.
+    ///
+    /// This is meant for when the network changed, e.g. after a Wi-Fi roam or a DHCP renewal.
+    /// The settings of the old gateway carry over. Unless `allow_third_party` is set, mappings
+    /// to an address this host no longer has are moved to the address facing the new gateway.
+    /// The mappings are then made on the new gateway as `sync` does.
+    pub fn rediscover(&mut self, options: SearchOptions) -> Result<SyncReport, Error> {
+        let mut gateway = search_gateway(options)?;
+        self.move_to(&mut gateway)
+    }
+
+    /// Make the mappings on `gateway` and manage them there, leaving the previous gateway in
+    /// `gateway`. The manager is left as it was if the mappings couldn't be listed.
+    fn move_to(&mut self, gateway: &mut Gateway) -> Result<SyncReport, Error> {
+        gateway.allow_third_party = self.gateway.allow_third_party;
+        gateway.permanent_lease_fallback = self.gateway.permanent_lease_fallback;
+        let local_ip = gateway.local_addr_hint().map_err(RequestError::from)?;
+
+        let desired: Vec<PortMappingRequest> = self
+            .mappings
+            .iter()
+            .map(|(request, _)| {
+                let mut request = request.clone();
+                if !gateway.allow_third_party && !common::is_local_address(*request.local_addr.ip()) {
+                    debug!("moving the mapping {:?} to {}", request.description, local_ip);
+                    request.local_addr.set_ip(local_ip);
+                }
+                request
+            })
+            .collect();
+        mem::swap(&mut self.gateway, gateway);
+        let status = self.status.take();
+        self.sync(&desired).map_err(|e| {
+            mem::swap(&mut self.gateway, gateway);
+            self.status = status;
+            e.into()
+        })
+    }
+
+    /// Remove all mappings of the manager, waiting at most `timeout` for the gateway.
+    ///
+    /// This is meant to be called when the application exits, e.g. from a signal handler. The
+    /// mappings are removed on a background thread, which is left behind if the timeout expires,
+    /// except on WASI, where they are removed by the calling thread. Returns the mappings, by
+    /// protocol and external port, that could not be removed in time.
+    pub fn shutdown(&mut self, timeout: Duration) -> Vec<(PortMappingProtocol, u16)> {
+        self.leases.clear();
+        let mappings: Vec<_> = mem::take(&mut self.mappings)
+            .into_iter()
+            .map(|(request, mapped)| (request.protocol, mapped.external_port))
+            .collect();
+        #[cfg(feature = "prometheus")]
+        crate::metrics::record_managed_mappings(&self.tag, 0);
+        if mappings.is_empty() {
+            return mappings;
+        }
+
+        // WASI has no threads, the timeouts of the requests bound the removal there.
+        #[cfg(target_os = "wasi")]
+        {
+            let _ = timeout;
+            remove_on_shutdown(&self.gateway, mappings)
+        }
+        #[cfg(not(target_os = "wasi"))]
+        {
+            let (sender, receiver) = mpsc::channel();
+            let gateway = self.gateway.clone();
+            let pending = mappings.clone();
+            thread::spawn(move || {
+                let _ = sender.send(remove_on_shutdown(&gateway, pending));
+            });
+            receiver.recv_timeout(timeout).unwrap_or_else(|_| {
+                debug!("removing the mappings of {} timed out", self.gateway);
+                mappings
+            })
+        }
+    }
+
+    /// The description sent to the gateway for a request, as the gateway will store it.
+    fn description(&self, request: &PortMappingRequest) -> String {
+        let description = format!("{}{}", self.tag, request.description);
+        self.gateway.quirks.description(&description).to_string()
+    }
+}
+
+/// Runs `PortMappingManager::verify` periodically on a background thread.
+///
+/// It is independent of the leases, so mappings dropped by the gateway are noticed even when
+/// they are permanent. The thread stops when the keepalive is stopped or dropped.
+///
+/// # Example
+/// ```no_run
+/// use std::sync::{Arc, Mutex};
+/// use std::time::Duration;
+/// use igd::{Keepalive, PortMappingManager};
+///
+/// let gateway = igd::search_gateway(Default::default()).unwrap();
+/// let manager = Arc::new(Mutex::new(PortMappingManager::new(gateway, "myapp:")));
+/// let _keepalive = Keepalive::spawn(manager.clone(), Duration::from_secs(60), |report| {
+///     println!("made {} mappings again", report.added.len());
+/// });
+/// ```
+#[cfg(not(target_os = "wasi"))]
+pub struct Keepalive {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl Keepalive {
+    /// Verify the mappings of `manager` every `interval`, calling `callback` with the report
+    /// when a mapping was made again or couldn't be checked, or the manager failed over.
+    pub fn spawn<F>(manager: Arc<Mutex<PortMappingManager>>, interval: Duration, mut callback: F) -> Keepalive
+    where
+        F: FnMut(SyncReport) + Send + 'static,
+    {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || loop {
+                let (ref lock, ref condvar) = *stopped;
+                let guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+                let (guard, _) = condvar
+                    .wait_timeout_while(guard, interval, |stopped| !*stopped)
+                    .unwrap_or_else(|e| e.into_inner());
+                if *guard {
+                    return;
+                }
+                drop(guard);
+
+                let report = manager.lock().unwrap_or_else(|e| e.into_inner()).verify();
+                if !report.added.is_empty() || !report.failed.is_empty() || report.failover.is_some() {
+                    callback(report);
+                }
+            })
+        };
+        Keepalive {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop verifying and wait for the background thread to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let (ref lock, ref condvar) = *self.stopped;
+        *lock.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Remove `mappings` from `gateway`, returning those that are left.
+fn remove_on_shutdown(gateway: &Gateway, mappings: Vec<(PortMappingProtocol, u16)>) -> Vec<(PortMappingProtocol, u16)> {
+    let results = gateway.remove_ports(&mappings);
+    mappings
+        .into_iter()
+        .zip(results)
+        .filter(|(_, result)| match *result {
+            Ok(()) | Err(RemovePortError::NoSuchPortMapping) => false,
+            Err(ref e) => {
+                debug!("removing a mapping on shutdown failed: {}", e);
+                true
+            }
+        })
+        .map(|(mapping, _)| mapping)
+        .collect()
+}
+
+/// Whether a request failed because the gateway couldn't be reached, rather than refused it.
+fn is_unreachable(e: &RequestError) -> bool {
+    matches!(e.inner(), RequestError::AttoHttpError(..) | RequestError::IoError(..))
+}
+
+/// Check whether an existing mapping is the one requested.
+fn matches(entry: &PortMappingEntry, request: &PortMappingRequest) -> bool {
+    entry.protocol == request.protocol
+        && (request.external_port == 0 || entry.external_port == request.external_port)
+        && entry.internal_port == request.local_addr.port()
+        && entry.internal_client == request.local_addr.ip().to_string()
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_sync() {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080);
+    gateway
+        .add_port(PortMappingProtocol::TCP, 2000, local_addr, 0, "other")
+        .unwrap();
+    gateway
+        .add_port(PortMappingProtocol::TCP, 2001, local_addr, 0, "test:stale")
+        .unwrap();
+
+    let request = |external_port, description: &str| PortMappingRequest {
+        protocol: PortMappingProtocol::TCP,
+        external_port,
+        local_addr,
+        lease_duration: 0,
+        description: description.to_string(),
+    };
+    let mut manager = PortMappingManager::new(gateway, "test:");
+    let report = manager.sync(&[request(3000, "a"), request(3001, "b")]).unwrap();
+    assert_eq!(report.added.len(), 2);
+    assert_eq!(report.removed, vec![(PortMappingProtocol::TCP, 2001)]);
+    assert_eq!(mock.mappings().len(), 3);
+
+    let report = manager.sync(&[request(3000, "a"), request(3002, "b")]).unwrap();
+    assert!(report.added.is_empty() && report.removed.is_empty() && report.failed.is_empty());
+    assert_eq!(report.updated[0].external_port, 3002);
+    assert_eq!(manager.mappings().len(), 2);
+
+    manager.sync(&[]).unwrap();
+    assert_eq!(mock.mappings()[0].port_mapping_description, "other");
+
+    // The gateway lost its mappings, e.g. because it was replaced.
+    manager.add(request(3005, "e")).unwrap();
+    manager.gateway().remove_port(PortMappingProtocol::TCP, 3005).unwrap();
+    let report = manager.rediscover(mock.search_options()).unwrap();
+    assert_eq!(report.added.len(), 1);
+    assert_eq!(mock.mappings().len(), 2);
+    manager.sync(&[]).unwrap();
+
+    manager.add(request(3003, "c")).unwrap();
+    manager.add(request(3004, "d")).unwrap();
+    assert_eq!(mock.mappings().len(), 3);
+    assert!(manager.shutdown(Duration::from_secs(5)).is_empty());
+    assert!(manager.mappings().is_empty());
+    assert_eq!(mock.mappings().len(), 1);
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_lease_events() {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    let request = |external_port, lease_duration| PortMappingRequest {
+        protocol: PortMappingProtocol::UDP,
+        external_port,
+        local_addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9000),
+        lease_duration,
+        description: external_port.to_string(),
+    };
+    let mut manager = PortMappingManager::new(gateway, "test:");
+    manager.set_expiry_warning(Duration::from_secs(30));
+    manager.add(request(4000, 0)).unwrap();
+    manager.add(request(4001, 60)).unwrap();
+    assert_eq!(manager.remaining(PortMappingProtocol::UDP, 4000), None);
+    let remaining = manager.remaining(PortMappingProtocol::UDP, 4001).unwrap();
+    assert!(remaining > Duration::from_secs(50) && remaining <= Duration::from_secs(60));
+    assert!(manager.poll_leases().is_empty());
+
+    let now = Instant::now();
+    match manager.poll_leases_at(now + Duration::from_secs(45))[..] {
+        [LeaseEvent::Expiring {
+            external_port: 4001,
+            remaining,
+            ..
+        }] => assert!(remaining <= Duration::from_secs(15)),
+        ref events => panic!("unexpected events {:?}", events),
+    }
+    assert!(manager.poll_leases_at(now + Duration::from_secs(50)).is_empty());
+    assert_eq!(
+        manager.poll_leases_at(now + Duration::from_secs(61)),
+        [LeaseEvent::Expired {
+            protocol: PortMappingProtocol::UDP,
+            external_port: 4001,
+        }]
+    );
+    assert!(manager.poll_leases_at(now + Duration::from_secs(62)).is_empty());
+
+    // Making the mapping again grants a new lease.
+    manager.add(request(4001, 60)).unwrap();
+    assert!(manager.poll_leases_at(now + Duration::from_secs(1)).is_empty());
+    assert!(manager.shutdown(Duration::from_secs(5)).is_empty());
+    assert_eq!(manager.remaining(PortMappingProtocol::UDP, 4001), None);
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_keepalive() {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9000);
+    let mut manager = PortMappingManager::new(gateway, "test:");
+    manager
+        .add(PortMappingRequest {
+            protocol: PortMappingProtocol::TCP,
+            external_port: 5000,
+            local_addr,
+            lease_duration: 0,
+            description: "a".to_string(),
+        })
+        .unwrap();
+    let report = manager.verify();
+    assert!(report.added.is_empty() && report.failed.is_empty());
+
+    // The gateway dropped the mapping, e.g. when its WAN connection came back.
+    manager.gateway().remove_port(PortMappingProtocol::TCP, 5000).unwrap();
+    let manager = Arc::new(Mutex::new(manager));
+    let (sender, receiver) = mpsc::channel();
+    let keepalive = Keepalive::spawn(manager.clone(), Duration::from_millis(10), move |report| {
+        let _ = sender.send(report);
+    });
+    let report = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    keepalive.stop();
+    assert_eq!(report.added[0].external_port, 5000);
+    assert_eq!(mock.mappings()[0].port_mapping_description, "test:a");
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_restart_detection() {
+    use std::net::Ipv4Addr;
+
+    use crate::test::MockResponse;
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    let status = |status: &str, uptime: u32| {
+        MockResponse::Ok(vec![
+            ("NewConnectionStatus".to_string(), status.to_string()),
+            ("NewLastConnectionError".to_string(), "ERROR_NONE".to_string()),
+            ("NewUptime".to_string(), uptime.to_string()),
+        ])
+    };
+    let mut manager = PortMappingManager::new(crate::search_gateway(mock.search_options()).unwrap(), "test:");
+    manager
+        .add(PortMappingRequest {
+            protocol: PortMappingProtocol::TCP,
+            external_port: 7000,
+            local_addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9000),
+            lease_duration: 0,
+            description: "a".to_string(),
+        })
+        .unwrap();
+    mock.respond("GetStatusInfo", status("Connected", 1000));
+    assert!(!manager.verify().restarted);
+    mock.respond("GetStatusInfo", status("Connected", 1060));
+    let report = manager.verify();
+    assert!(!report.restarted && report.added.is_empty());
+
+    // The uptime went backwards, the mapping is made again though it is still listed.
+    mock.respond("GetStatusInfo", status("Connected", 5));
+    let report = manager.verify();
+    assert!(report.restarted);
+    assert_eq!(report.added[0].external_port, 7000);
+
+    // The connection went down and came back.
+    mock.respond("GetStatusInfo", status("Disconnected", 0));
+    assert!(!manager.verify().restarted);
+    mock.respond("GetStatusInfo", status("Connected", 100));
+    assert!(manager.verify().restarted);
+    assert_eq!(mock.mappings().len(), 1);
+
+    // Ten minutes after an uptime of 120s, the connection came back five minutes ago.
+    let ten_minutes_ago = |manager: &mut PortMappingManager| {
+        manager.status.as_mut().unwrap().1 = Instant::now().checked_sub(Duration::from_secs(600)).unwrap();
+    };
+    mock.respond("GetStatusInfo", status("Connected", 120));
+    assert!(!manager.verify().restarted);
+    ten_minutes_ago(&mut manager);
+    mock.respond("GetStatusInfo", status("Connected", 300));
+    assert!(manager.verify().restarted);
+    // It stayed up meanwhile.
+    ten_minutes_ago(&mut manager);
+    mock.respond("GetStatusInfo", status("Connected", 900));
+    assert!(!manager.verify().restarted);
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_failover() {
+    use std::net::Ipv4Addr;
+
+    let primary = crate::test::MockGateway::start().unwrap();
+    let backup = crate::test::MockGateway::start().unwrap();
+    let mut manager = PortMappingManager::new(crate::search_gateway(primary.search_options()).unwrap(), "test:");
+    let backup_gateway = crate::search_gateway(backup.search_options()).unwrap();
+    let backup_addr = backup_gateway.addr;
+    manager.set_backup_gateways(vec![backup_gateway]);
+    manager
+        .add(PortMappingRequest {
+            protocol: PortMappingProtocol::UDP,
+            external_port: 6000,
+            local_addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9000),
+            lease_duration: 0,
+            description: "a".to_string(),
+        })
+        .unwrap();
+    assert!(manager.verify().failover.is_none());
+
+    let primary_addr = manager.gateway().addr;
+    drop(primary);
+    let report = manager.verify();
+    assert_eq!(
+        report.failover,
+        Some(Failover {
+            from: primary_addr,
+            to: backup_addr,
+        })
+    );
+    assert_eq!(report.added.len(), 1);
+    assert_eq!(manager.gateway().addr, backup_addr);
+    assert_eq!(manager.backup_gateways()[0].addr, primary_addr);
+    assert_eq!(backup.mappings()[0].external_port, 6000);
+
+    // Neither gateway answers, the mappings stay where they are.
+    drop(backup);
+    let report = manager.verify();
+    assert!(report.failover.is_none());
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(manager.gateway().addr, backup_addr);
+}
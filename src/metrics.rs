@@ -0,0 +1,224 @@
+//! Prometheus metrics of the gateways the crate talks to and the mappings it keeps.
+//!
+//! The crate records into one set of metrics for the whole process, which is registered like
+//! any other collector, so alerts can fire when NAT traversal breaks, e.g. when the gateway
+//! stops answering or renewals start failing:
+//!
+//! | Metric | Labels | |
+//! |---|---|---|
+//! | `igd_gateway_up` | `gateway` | 1 if the last request reached the gateway, 0 otherwise |
+//! | `igd_external_ip_changes_total` | `gateway` | Changes of the external address seen |
+//! | `igd_managed_port_mappings` | `tag` | Mappings kept by each `PortMappingManager` |
+//! | `igd_renewals_total` | `result` | Mappings made or renewed by `PortMappingManager::sync` and `verify` and `auto::AutoMapping`, by `success` or `failure` |
+//! | `igd_requests_total` | `action` | SOAP requests sent, counting the retries of a request as one |
+//! | `igd_request_errors_total` | `action`, `error` | Failed SOAP requests, by UPnP error code, or `unreachable`, `invalid_response`, `http_<status>` or `other` |
+//!
+//! # Example
+//! ```no_run
+//! use prometheus::{Encoder, TextEncoder};
+//!
+//! let registry = prometheus::Registry::new();
+//! igd::metrics::register(&registry).unwrap();
+//!
+//! let gateway = igd::search_gateway(Default::default()).unwrap();
+//! let _ = gateway.get_external_ip();
+//!
+//! let mut text = Vec::new();
+//! TextEncoder::new().encode(&registry.gather(), &mut text).unwrap();
+//! println!("{}", String::from_utf8(text).unwrap());
+//! ```
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry};
+
+use crate::errors::RequestError;
+
+/// The metrics of the crate, see the module documentation.
+///
+/// Clones share the same values.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    gateway_up: IntGaugeVec,
+    external_ip_changes: IntCounterVec,
+    managed_mappings: IntGaugeVec,
+    renewals: IntCounterVec,
+    requests: IntCounterVec,
+    request_errors: IntCounterVec,
+    /// Last external address of each gateway, to count the changes
+    external_ips: Arc<Mutex<HashMap<SocketAddrV4, Ipv4Addr>>>,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let gauge = |name: &str, help: &str, labels: &[&str]| {
+            IntGaugeVec::new(Opts::new(name, help), labels).expect("valid metric")
+        };
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            IntCounterVec::new(Opts::new(name, help), labels).expect("valid metric")
+        };
+        Metrics {
+            gateway_up: gauge(
+                "igd_gateway_up",
+                "Whether the last request reached the gateway",
+                &["gateway"],
+            ),
+            external_ip_changes: counter(
+                "igd_external_ip_changes_total",
+                "Changes of the external address of the gateway",
+                &["gateway"],
+            ),
+            managed_mappings: gauge("igd_managed_port_mappings", "Port mappings kept by a manager", &["tag"]),
+            renewals: counter("igd_renewals_total", "Port mappings made or renewed", &["result"]),
+            requests: counter("igd_requests_total", "SOAP requests sent to gateways", &["action"]),
+            request_errors: counter(
+                "igd_request_errors_total",
+                "SOAP requests that failed",
+                &["action", "error"],
+            ),
+            external_ips: Arc::default(),
+        }
+    }
+}
+
+impl Collector for Metrics {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.gateway_up.desc();
+        descs.extend(self.external_ip_changes.desc());
+        descs.extend(self.managed_mappings.desc());
+        descs.extend(self.renewals.desc());
+        descs.extend(self.requests.desc());
+        descs.extend(self.request_errors.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.gateway_up.collect();
+        families.extend(self.external_ip_changes.collect());
+        families.extend(self.managed_mappings.collect());
+        families.extend(self.renewals.collect());
+        families.extend(self.requests.collect());
+        families.extend(self.request_errors.collect());
+        families
+    }
+}
+
+/// The metrics the crate records into.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Register the metrics of the crate with `registry`.
+pub fn register(registry: &Registry) -> prometheus::Result<()> {
+    registry.register(Box::new(metrics().clone()))
+}
+
+/// Record the outcome of a request with the SOAPAction `header` to `gateway`.
+pub(crate) fn record_request<T>(gateway: SocketAddrV4, header: &str, result: &Result<T, RequestError>) {
+    let metrics = metrics();
+    let action = header.trim_matches('"').rsplit('#').next().unwrap_or_default();
+    metrics.requests.with_label_values(&[action]).inc();
+    let e = match *result {
+        Ok(_) => {
+            metrics.gateway_up.with_label_values(&[&gateway.to_string()]).set(1);
+            return;
+        }
+        Err(ref e) => e.inner(),
+    };
+    let unreachable = match *e {
+        RequestError::AttoHttpError(_) | RequestError::IoError(_) => true,
+        #[cfg(feature = "aio")]
+        RequestError::HyperError(_) => true,
+        _ => false,
+    };
+    metrics
+        .gateway_up
+        .with_label_values(&[&gateway.to_string()])
+        .set(if unreachable { 0 } else { 1 });
+    let error = match (e.error_code(), e) {
+        (Some(code), _) => code.to_string(),
+        (None, _) if unreachable => "unreachable".to_string(),
+        (None, &RequestError::InvalidResponse(_)) => "invalid_response".to_string(),
+        (None, &RequestError::HttpStatus { status, .. }) => format!("http_{}", status),
+        (None, _) => "other".to_string(),
+    };
+    metrics.request_errors.with_label_values(&[action, &error]).inc();
+}
+
+/// Record the external address `ip` of `gateway`, counting it as a change if it differs from
+/// the last one.
+pub(crate) fn record_external_ip(gateway: SocketAddrV4, ip: Ipv4Addr) {
+    let metrics = metrics();
+    let previous = metrics
+        .external_ips
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(gateway, ip);
+    if previous.is_some_and(|previous| previous != ip) {
+        metrics
+            .external_ip_changes
+            .with_label_values(&[&gateway.to_string()])
+            .inc();
+    }
+}
+
+/// Record the number of mappings kept by the manager with `tag`.
+pub(crate) fn record_managed_mappings(tag: &str, count: usize) {
+    metrics().managed_mappings.with_label_values(&[tag]).set(count as i64);
+}
+
+/// Record mappings made or renewed, `succeeded` of them successfully and `failed` not.
+pub(crate) fn record_renewals(succeeded: usize, failed: usize) {
+    let renewals = &metrics().renewals;
+    renewals.with_label_values(&["success"]).inc_by(succeeded as u64);
+    renewals.with_label_values(&["failure"]).inc_by(failed as u64);
+}
+
+#[test]
+fn test_metrics() {
+    let gateway = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 250), 5000);
+    let label = gateway.to_string();
+    let metrics = metrics();
+
+    let header = r#""urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress""#;
+    record_request(gateway, header, &Ok(()));
+    assert_eq!(metrics.gateway_up.with_label_values(&[&label]).get(), 1);
+    let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+    record_request::<()>(gateway, header, &Err(RequestError::IoError(refused)));
+    assert_eq!(metrics.gateway_up.with_label_values(&[&label]).get(), 0);
+    record_request::<()>(gateway, header, &Err(RequestError::ActionNotAuthorized));
+    assert_eq!(metrics.gateway_up.with_label_values(&[&label]).get(), 1);
+    let errors = |error: &str| {
+        metrics
+            .request_errors
+            .with_label_values(&["GetExternalIPAddress", error])
+            .get()
+    };
+    assert!(errors("unreachable") >= 1);
+    assert!(errors("606") >= 1);
+
+    let changes = || metrics.external_ip_changes.with_label_values(&[&label]).get();
+    record_external_ip(gateway, Ipv4Addr::new(203, 0, 113, 1));
+    record_external_ip(gateway, Ipv4Addr::new(203, 0, 113, 1));
+    assert_eq!(changes(), 0);
+    record_external_ip(gateway, Ipv4Addr::new(198, 51, 100, 1));
+    assert_eq!(changes(), 1);
+
+    record_managed_mappings("metrics test:", 3);
+    assert_eq!(metrics.managed_mappings.with_label_values(&["metrics test:"]).get(), 3);
+
+    let registry = Registry::new();
+    register(&registry).unwrap();
+    let names: Vec<_> = registry
+        .gather()
+        .iter()
+        .map(|family| family.name().to_string())
+        .collect();
+    assert!(names.contains(&"igd_gateway_up".to_string()));
+    assert!(names.contains(&"igd_managed_port_mappings".to_string()));
+}
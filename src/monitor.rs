@@ -0,0 +1,111 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::errors::RequestError;
+use crate::Gateway;
+
+/// Transfer rates of the WAN interface over one polling interval.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrafficRate {
+    /// Bytes sent per second
+    pub upload: f64,
+    /// Bytes received per second
+    pub download: f64,
+    /// Time between the two samples the rates were computed from
+    pub elapsed: Duration,
+}
+
+/// Computes the transfer rates of the WAN interface from the byte counters of the gateway.
+///
+/// Iterating waits for the polling interval, then yields the rates since the previous sample.
+/// The counters of most gateways are 32-bit and wrap around after 4 GiB, which is accounted for.
+///
+/// # Example
+/// ```no_run
+/// use std::time::Duration;
+/// use igd::TrafficMonitor;
+///
+/// let gateway = igd::search_gateway(Default::default()).unwrap();
+/// for rate in TrafficMonitor::new(&gateway, Duration::from_secs(5)).take(10) {
+///     let rate = rate.unwrap();
+///     println!("up {:.0} B/s, down {:.0} B/s", rate.upload, rate.download);
+/// }
+/// ```
+pub struct TrafficMonitor<'a> {
+    gateway: &'a Gateway,
+    interval: Duration,
+    last: Option<(Instant, u64, u64)>,
+}
+
+impl<'a> TrafficMonitor<'a> {
+    /// Create a monitor polling `gateway` every `interval`.
+    pub fn new(gateway: &'a Gateway, interval: Duration) -> TrafficMonitor<'a> {
+        TrafficMonitor {
+            gateway,
+            interval,
+            last: None,
+        }
+    }
+
+    fn sample(&self) -> Result<(Instant, u64, u64), RequestError> {
+        let sent = self.gateway.get_counter("GetTotalBytesSent", "NewTotalBytesSent")?;
+        let received = self
+            .gateway
+            .get_counter("GetTotalBytesReceived", "NewTotalBytesReceived")?;
+        Ok((Instant::now(), sent, received))
+    }
+}
+
+impl<'a> Iterator for TrafficMonitor<'a> {
+    type Item = Result<TrafficRate, RequestError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (then, sent_then, received_then) = match self.last {
+            Some(last) => last,
+            None => match self.sample() {
+                Ok(first) => first,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        if let Some(wait) = (then + self.interval).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+
+        let (now, sent, received) = match self.sample() {
+            Ok(sample) => sample,
+            Err(e) => {
+                self.last = None;
+                return Some(Err(e));
+            }
+        };
+        self.last = Some((now, sent, received));
+
+        let elapsed = now - then;
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        Some(Ok(TrafficRate {
+            upload: counter_delta(sent_then, sent) as f64 / seconds,
+            download: counter_delta(received_then, received) as f64 / seconds,
+            elapsed,
+        }))
+    }
+}
+
+/// Difference between two readings of a counter, which may have wrapped around in between.
+fn counter_delta(previous: u64, current: u64) -> u64 {
+    if current >= previous {
+        current - previous
+    } else if previous <= u64::from(u32::MAX) {
+        // A 32-bit counter wrapped around.
+        current + (1 << 32) - previous
+    } else {
+        // The counter was reset, e.g. by a reboot.
+        current
+    }
+}
+
+#[test]
+fn test_counter_delta() {
+    assert_eq!(counter_delta(100, 150), 50);
+    assert_eq!(counter_delta(u64::from(u32::MAX) - 9, 10), 20);
+    assert_eq!(counter_delta(1 << 40, 10), 10);
+}
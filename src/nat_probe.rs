@@ -0,0 +1,338 @@
+//! Classification of the NAT behavior (RFC 5780), to judge whether direct hole punching can work.
+//!
+//! Probing needs a STUN server that supports RFC 5780, i.e. that answers with an `OTHER-ADDRESS`
+//! and honors `CHANGE-REQUEST`. With a plain STUN server only the mapped address is reported.
+//!
+//! Port mappings created through the gateway make incoming traffic reach us whatever the NAT
+//! behavior is; probing tells whether peers can still connect directly when no mapping is possible.
+//! `probe_with_gateway` probes with such a mapping made for the probing socket, to tell whether
+//! the NAT sends its traffic from the mapped port.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use crate::common;
+use crate::stun;
+use crate::{Gateway, PortMappingProtocol};
+
+/// How the NAT treats different remote endpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Behavior {
+    /// The same for every remote address and port
+    EndpointIndependent,
+    /// Depends on the remote address, but not on the port
+    AddressDependent,
+    /// Depends on the remote address and port
+    AddressAndPortDependent,
+}
+
+/// Result of a NAT probe.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NatReport {
+    /// Local address of the probing socket
+    pub local_addr: SocketAddr,
+    /// Address the STUN server saw the probe coming from
+    pub mapped_addr: SocketAddr,
+    /// Mapping behavior, `None` if the server doesn't support RFC 5780
+    pub mapping: Option<Behavior>,
+    /// Filtering behavior, `None` if the server doesn't support RFC 5780
+    pub filtering: Option<Behavior>,
+    /// External address of the port mapping made through the gateway for the probing socket by
+    /// `probe_with_gateway`, `None` without one
+    pub port_mapping: Option<SocketAddr>,
+}
+
+impl NatReport {
+    /// Whether the address of the probing socket is translated at all.
+    pub fn is_nat(&self) -> bool {
+        self.local_addr != self.mapped_addr
+    }
+
+    /// Whether direct hole punching between peers is likely to work.
+    ///
+    /// This is the case when the NAT reuses the same external port for every remote endpoint,
+    /// so the address learned through a server is the one peers can reach, or when that address
+    /// is the port mapping made through the gateway, which lets any peer in.
+    pub fn hole_punching_likely(&self) -> bool {
+        !self.is_nat()
+            || self.mapping == Some(Behavior::EndpointIndependent)
+            || self.port_mapping == Some(self.mapped_addr)
+    }
+}
+
+/// How many times each request of a probe is sent before the server is taken not to answer, as
+/// datagrams get lost.
+pub const ATTEMPTS: u32 = 3;
+
+/// Lease duration of the port mapping made by `probe_with_gateway`, removed after the probe.
+pub const PROBE_LEASE_DURATION: u32 = 60;
+
+/// Probe the NAT through the STUN server at `server` (`host:port`).
+///
+/// The probe sends up to five requests, each up to `ATTEMPTS` times, waiting at most `timeout`
+/// for an answer each time.
+pub fn probe(server: &str, timeout: Duration) -> io::Result<NatReport> {
+    let server = resolve(server)?;
+    let socket = UdpSocket::bind(stun::unspecified_addr(&server))?;
+    socket.set_read_timeout(Some(timeout))?;
+    probe_from(&socket, server, None)
+}
+
+/// Probe the NAT like `probe`, with a port mapping for the probing socket made through `gateway`.
+///
+/// The UDP port of the socket is mapped to the same external port, if the gateway allows it, for
+/// `PROBE_LEASE_DURATION`, and the mapping is removed after the probe. Its external address is
+/// reported as `NatReport::port_mapping`, which tells whether the NAT keeps using the mapping for
+/// the traffic of the socket. If the gateway refuses the mapping, the probe goes on without.
+pub fn probe_with_gateway(gateway: &Gateway, server: &str, timeout: Duration) -> io::Result<NatReport> {
+    let server = resolve(server)?;
+    let socket = UdpSocket::bind(stun::unspecified_addr(&server))?;
+    socket.set_read_timeout(Some(timeout))?;
+    let port_mapping = map_socket(gateway, &socket);
+    let report = probe_from(&socket, server, port_mapping);
+    if let Some(port_mapping) = port_mapping {
+        if let Err(e) = gateway.remove_port(PortMappingProtocol::UDP, port_mapping.port()) {
+            debug!("removing the mapping of the probe failed: {}", e);
+        }
+    }
+    report
+}
+
+fn resolve(server: &str) -> io::Result<SocketAddr> {
+    server
+        .to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "STUN server has no IPv4 address"))
+}
+
+/// Map the port of `socket` through `gateway`, returning the external address of the mapping.
+fn map_socket(gateway: &Gateway, socket: &UdpSocket) -> Option<SocketAddr> {
+    let local_addr = match socket
+        .local_addr()
+        .and_then(|local_addr| common::mapping_addr(local_addr, gateway.addr))
+    {
+        Ok(local_addr) => local_addr,
+        Err(e) => {
+            debug!("no address of the probe to map: {}", e);
+            return None;
+        }
+    };
+    let mapped = match gateway.map_port(
+        PortMappingProtocol::UDP,
+        local_addr.port(),
+        local_addr,
+        PROBE_LEASE_DURATION,
+        "igd NAT probe",
+    ) {
+        Ok(mapped) => mapped,
+        Err(e) => {
+            debug!("mapping the port of the probe failed, probing without: {}", e);
+            return None;
+        }
+    };
+    match gateway.get_external_ip() {
+        Ok(external_ip) => Some(SocketAddr::new(external_ip.into(), mapped.external_port)),
+        Err(e) => {
+            debug!("getting the external address failed, probing without a mapping: {}", e);
+            let _ = gateway.remove_port(PortMappingProtocol::UDP, mapped.external_port);
+            None
+        }
+    }
+}
+
+fn probe_from(socket: &UdpSocket, server: SocketAddr, port_mapping: Option<SocketAddr>) -> io::Result<NatReport> {
+    // The probing socket is unbound, so find the local address facing the server separately.
+    let route = UdpSocket::bind(stun::unspecified_addr(&server))?;
+    route.connect(server)?;
+    let local_addr = SocketAddr::new(route.local_addr()?.ip(), socket.local_addr()?.port());
+
+    let first = stun::transact(socket, server, false, false, ATTEMPTS)?;
+    let mut report = NatReport {
+        local_addr,
+        mapped_addr: first.mapped,
+        mapping: None,
+        filtering: None,
+        port_mapping,
+    };
+    let other = match first.other {
+        Some(other) if other.ip() != server.ip() && other.port() != server.port() => other,
+        _ => {
+            debug!("STUN server {} does not support RFC 5780", server);
+            return Ok(report);
+        }
+    };
+
+    // Mapping: compare the mapped address toward the alternate address, then the alternate port.
+    let alternate_ip = SocketAddr::new(other.ip(), server.port());
+    let second = stun::transact(socket, alternate_ip, false, false, ATTEMPTS)?;
+    report.mapping = Some(if second.mapped == first.mapped {
+        Behavior::EndpointIndependent
+    } else if stun::transact(socket, other, false, false, ATTEMPTS)?.mapped == second.mapped {
+        Behavior::AddressDependent
+    } else {
+        Behavior::AddressAndPortDependent
+    });
+
+    // Filtering: ask the server to answer from another address, then from another port. No
+    // answer after all the attempts means the NAT filtered it.
+    let answered = |change_ip, change_port| match stun::transact(socket, server, change_ip, change_port, ATTEMPTS) {
+        Ok(_) => Ok(true),
+        Err(ref e) if stun::is_timeout(e) => Ok(false),
+        Err(e) => Err(e),
+    };
+    report.filtering = Some(if answered(true, true)? {
+        Behavior::EndpointIndependent
+    } else if answered(false, true)? {
+        Behavior::AddressDependent
+    } else {
+        Behavior::AddressAndPortDependent
+    });
+
+    Ok(report)
+}
+
+/// Start a STUN server on two ports of 127.0.0.1 and 127.0.0.2, as seen through a NAT with the
+/// given behaviors that translates to `external_ip`, returning its primary address. With `lossy`,
+/// the first copy of every request is lost.
+#[cfg(all(test, target_os = "linux"))]
+fn fake_server(external_ip: std::net::Ipv4Addr, mapping: Behavior, filtering: Behavior, lossy: bool) -> SocketAddr {
+    use std::collections::HashSet;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    let primary = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let alternate_port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let ports = [
+        primary.local_addr().unwrap().port(),
+        alternate_port.local_addr().unwrap().port(),
+    ];
+    let alternate_ip = Ipv4Addr::new(127, 0, 0, 2);
+    let sockets = Arc::new([
+        [primary, alternate_port],
+        [
+            UdpSocket::bind((alternate_ip, ports[0])).unwrap(),
+            UdpSocket::bind((alternate_ip, ports[1])).unwrap(),
+        ],
+    ]);
+    let lost = Arc::new(Mutex::new(HashSet::new()));
+
+    let address = |kind: u16, addr: SocketAddr| {
+        let mut attribute = kind.to_be_bytes().to_vec();
+        attribute.extend_from_slice(&[0, 8, 0, 1]);
+        attribute.extend_from_slice(&addr.port().to_be_bytes());
+        match addr.ip() {
+            IpAddr::V4(ip) => attribute.extend_from_slice(&ip.octets()),
+            IpAddr::V6(_) => unreachable!(),
+        }
+        attribute
+    };
+    for (ip, port) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+        let sockets = sockets.clone();
+        let lost = lost.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 576];
+            while let Ok((read, from)) = sockets[ip][port].recv_from(&mut buf) {
+                let request = &buf[..read];
+                let transaction_id = request[8..20].to_vec();
+                if lossy && lost.lock().unwrap().insert(transaction_id.clone()) {
+                    continue;
+                }
+                // CHANGE-REQUEST
+                let flags = if read >= 28 { request[27] } else { 0 };
+                let (change_ip, change_port) = (flags & 0x04 != 0, flags & 0x02 != 0);
+                let filtered = match filtering {
+                    Behavior::EndpointIndependent => false,
+                    Behavior::AddressDependent => change_ip,
+                    Behavior::AddressAndPortDependent => change_ip || change_port,
+                };
+                if filtered {
+                    continue;
+                }
+
+                let external_port = match mapping {
+                    Behavior::EndpointIndependent => from.port(),
+                    Behavior::AddressDependent => from.port().wrapping_add(100 * ip as u16),
+                    Behavior::AddressAndPortDependent => from.port().wrapping_add(100 * ip as u16 + 10 * port as u16),
+                };
+                let other = [Ipv4Addr::LOCALHOST, alternate_ip][1 - ip];
+                let mut attributes = address(0x0001, SocketAddr::new(external_ip.into(), external_port));
+                attributes.extend(address(0x802C, SocketAddr::new(other.into(), ports[1 - port])));
+                // Binding response
+                let mut response = vec![0x01, 0x01];
+                response.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+                response.extend_from_slice(&request[4..20]);
+                response.extend(attributes);
+
+                let reply = &sockets[ip ^ change_ip as usize][port ^ change_port as usize];
+                reply.send_to(&response, from).unwrap();
+            }
+        });
+    }
+    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ports[0])
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_probe() {
+    use std::net::Ipv4Addr;
+
+    let external_ip = Ipv4Addr::new(203, 0, 113, 7);
+    let timeout = Duration::from_millis(100);
+    for behavior in [
+        Behavior::EndpointIndependent,
+        Behavior::AddressDependent,
+        Behavior::AddressAndPortDependent,
+    ] {
+        let server = fake_server(external_ip, behavior, behavior, false);
+        let report = probe(&server.to_string(), timeout).unwrap();
+        assert_eq!(report.mapped_addr.ip(), external_ip);
+        assert!(report.is_nat());
+        assert_eq!(report.mapping, Some(behavior));
+        assert_eq!(report.filtering, Some(behavior));
+        assert_eq!(report.port_mapping, None);
+        assert_eq!(report.hole_punching_likely(), behavior == Behavior::EndpointIndependent);
+    }
+
+    // Lost requests are sent again rather than taken for filtering.
+    let server = fake_server(
+        external_ip,
+        Behavior::EndpointIndependent,
+        Behavior::EndpointIndependent,
+        true,
+    );
+    let report = probe(&server.to_string(), timeout).unwrap();
+    assert_eq!(report.mapping, Some(Behavior::EndpointIndependent));
+    assert_eq!(report.filtering, Some(Behavior::EndpointIndependent));
+
+    let server = fake_server(
+        external_ip,
+        Behavior::AddressDependent,
+        Behavior::EndpointIndependent,
+        false,
+    );
+    let report = probe(&server.to_string(), timeout).unwrap();
+    assert_eq!(report.mapping, Some(Behavior::AddressDependent));
+    assert_eq!(report.filtering, Some(Behavior::EndpointIndependent));
+}
+
+#[cfg(all(feature = "mock", target_os = "linux"))]
+#[test]
+fn test_probe_with_gateway() {
+    let mock = crate::test::MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    // The NAT sends the traffic of the socket from its mapping.
+    let server = fake_server(
+        mock.external_ip(),
+        Behavior::AddressAndPortDependent,
+        Behavior::AddressAndPortDependent,
+        false,
+    );
+    let report = probe_with_gateway(&gateway, &server.to_string(), Duration::from_millis(100)).unwrap();
+    assert_eq!(report.mapping, Some(Behavior::AddressAndPortDependent));
+    assert_eq!(report.port_mapping, Some(report.mapped_addr));
+    assert!(report.hole_punching_likely());
+    // The mapping is gone with the probe.
+    assert!(mock.mappings().is_empty());
+}
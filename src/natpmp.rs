@@ -0,0 +1,551 @@
+//! A minimal NAT-PMP client (RFC 6886), for gateways that speak neither UPnP nor PCP.
+//!
+//! The client remembers the mappings it made and when they were granted, so `NatPmpClient::poll`
+//! can renew each of them when half of its lifetime has passed, as the RFC recommends. Every
+//! response carries the seconds since the gateway started, and when those go back, the gateway
+//! lost its mappings, e.g. after a reboot, and the client makes them again (RFC 6886 section 3.6).
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Backoff, ExponentialBackoff, PortMappingProtocol};
+
+/// Port the NAT-PMP server listens on.
+pub const SERVER_PORT: u16 = 5351;
+
+/// Port the gateway announces its external address on.
+pub const CLIENT_PORT: u16 = 5350;
+
+/// Time to wait for an answer to a request, retransmissions included.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(4);
+
+const VERSION: u8 = 0;
+const RESPONSE_BIT: u8 = 0x80;
+const OPCODE_EXTERNAL_ADDRESS: u8 = 0;
+const MAX_PACKET_LEN: usize = 16;
+
+/// Errors of the NAT-PMP client.
+#[derive(Debug)]
+pub enum NatPmpError {
+    /// IO error, including timeouts of requests
+    IoError(io::Error),
+    /// The gateway returned an invalid response.
+    InvalidResponse,
+    /// The gateway refused the request with the given result code.
+    ResultCode(u16),
+}
+
+impl From<io::Error> for NatPmpError {
+    fn from(err: io::Error) -> NatPmpError {
+        NatPmpError::IoError(err)
+    }
+}
+
+impl fmt::Display for NatPmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NatPmpError::IoError(ref e) => write!(f, "IO error: {}", e),
+            NatPmpError::InvalidResponse => write!(f, "Invalid response from the NAT-PMP gateway"),
+            NatPmpError::ResultCode(code) => write!(f, "The NAT-PMP gateway returned {} ({})", result_name(code), code),
+        }
+    }
+}
+
+impl error::Error for NatPmpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            NatPmpError::IoError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The meaning of a result code, from RFC 6886 section 3.5.
+fn result_name(code: u16) -> &'static str {
+    match code {
+        0 => "success",
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown result",
+    }
+}
+
+/// A mapping made by `NatPmpClient::map`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NatPmpMapping {
+    /// Protocol of the mapping
+    pub protocol: PortMappingProtocol,
+    /// Port of this host the mapping forwards to
+    pub internal_port: u16,
+    /// External port assigned by the gateway
+    pub external_port: u16,
+    /// Lifetime granted by the gateway, in seconds
+    pub lifetime: u32,
+    requested_lifetime: u32,
+    granted: Instant,
+}
+
+impl NatPmpMapping {
+    /// When the mapping should be renewed, after half of its lifetime.
+    pub fn renew_at(&self) -> Instant {
+        self.granted + Duration::from_secs(u64::from(self.lifetime)) / 2
+    }
+
+    /// When the mapping expires unless it is renewed.
+    pub fn expires_at(&self) -> Instant {
+        self.granted + Duration::from_secs(u64::from(self.lifetime))
+    }
+}
+
+/// Seconds since the gateway started, as of the last response, and when it was received.
+#[derive(Clone, Copy, Debug)]
+struct Epoch {
+    seconds: u32,
+    received: Instant,
+}
+
+impl Epoch {
+    /// Whether the gateway restarted since this epoch, seeing `seconds` at `now`.
+    ///
+    /// The seconds have to go on at least 7/8 as fast as the client time, less 2 seconds of
+    /// slack, as in RFC 6886 section 3.6.
+    fn is_lost(&self, seconds: u32, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.received).as_secs();
+        let expected = u64::from(self.seconds) + elapsed * 7 / 8;
+        u64::from(seconds) + 2 < expected
+    }
+}
+
+fn opcode(protocol: PortMappingProtocol) -> u8 {
+    match protocol {
+        PortMappingProtocol::UDP => 1,
+        PortMappingProtocol::TCP => 2,
+    }
+}
+
+fn map_request(protocol: PortMappingProtocol, internal_port: u16, external_port: u16, lifetime: u32) -> Vec<u8> {
+    let mut request = Vec::with_capacity(12);
+    request.extend_from_slice(&[VERSION, opcode(protocol), 0, 0]);
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+/// Parse the header of a response to `opcode`, returning the result code and the seconds since
+/// the gateway started.
+fn parse_header(packet: &[u8], opcode: u8) -> Option<(u16, u32)> {
+    if packet.len() < 8 || packet[0] != VERSION || packet[1] != opcode | RESPONSE_BIT {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes([packet[2], packet[3]]),
+        u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+    ))
+}
+
+/// A NAT-PMP client talking to one gateway.
+///
+/// # Example
+/// ```no_run
+/// use std::time::Duration;
+/// use igd::natpmp::NatPmpClient;
+/// use igd::PortMappingProtocol;
+///
+/// let mut client = NatPmpClient::new("192.168.1.1:5351".parse().unwrap()).unwrap();
+/// let mapping = client.map(PortMappingProtocol::TCP, 8080, 8080, 7200).unwrap();
+/// println!("reachable on port {}", mapping.external_port);
+///
+/// loop {
+///     if client.poll(Duration::from_secs(60)).unwrap() {
+///         println!("the gateway restarted, now at {:?}", client.mappings());
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct NatPmpClient {
+    socket: UdpSocket,
+    announce_socket: Option<UdpSocket>,
+    gateway: SocketAddrV4,
+    timeout: Duration,
+    retransmission: Arc<dyn Backoff>,
+    epoch: Option<Epoch>,
+    mappings: Vec<NatPmpMapping>,
+}
+
+impl NatPmpClient {
+    /// Create a client for the gateway at `gateway`, usually port 5351 of the default router.
+    pub fn new(gateway: SocketAddrV4) -> io::Result<NatPmpClient> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect(gateway)?;
+        Ok(NatPmpClient {
+            socket,
+            announce_socket: None,
+            gateway,
+            timeout: DEFAULT_TIMEOUT,
+            retransmission: Arc::new(default_retransmission()),
+            epoch: None,
+            mappings: Vec::new(),
+        })
+    }
+
+    /// Set how long to wait for an answer to a request, retransmissions included.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set the delays after which a request is sent again while it isn't answered (defaults
+    /// to 250ms, doubling each time).
+    ///
+    /// When the delays run out, the request is waited for until the timeout.
+    pub fn set_retransmission(&mut self, backoff: Arc<dyn Backoff>) {
+        self.retransmission = backoff;
+    }
+
+    /// The address of the gateway.
+    pub fn gateway(&self) -> SocketAddrV4 {
+        self.gateway
+    }
+
+    /// The mappings made by this client and not removed since.
+    pub fn mappings(&self) -> &[NatPmpMapping] {
+        &self.mappings
+    }
+
+    /// Get the external IP address of the gateway.
+    pub fn external_ip(&mut self) -> Result<Ipv4Addr, NatPmpError> {
+        let (response, lost) = self.request(&[VERSION, OPCODE_EXTERNAL_ADDRESS])?;
+        if response.len() < 12 {
+            return Err(NatPmpError::InvalidResponse);
+        }
+        if lost {
+            self.recreate()?;
+        }
+        Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]))
+    }
+
+    /// Map an external port to `internal_port` of this host for `lifetime` seconds.
+    ///
+    /// The gateway picks another external port if `suggested_external_port` is taken, or any if
+    /// it is 0. Mapping the same protocol and internal port again renews the mapping.
+    pub fn map(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        suggested_external_port: u16,
+        lifetime: u32,
+    ) -> Result<NatPmpMapping, NatPmpError> {
+        let (mapping, lost) = self.send_map(protocol, internal_port, suggested_external_port, lifetime)?;
+        let index = self.position(protocol, internal_port);
+        match index {
+            Some(index) => self.mappings[index] = mapping.clone(),
+            None => self.mappings.push(mapping.clone()),
+        }
+        if lost {
+            self.recreate()?;
+            return Ok(self.mappings[index.unwrap_or(self.mappings.len() - 1)].clone());
+        }
+        Ok(mapping)
+    }
+
+    /// Remove the mapping of `internal_port`.
+    pub fn unmap(&mut self, protocol: PortMappingProtocol, internal_port: u16) -> Result<(), NatPmpError> {
+        let index = match self.position(protocol, internal_port) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        self.mappings.remove(index);
+        let (_, lost) = self.send_map(protocol, internal_port, 0, 0)?;
+        if lost {
+            self.recreate()?;
+        }
+        Ok(())
+    }
+
+    /// Listen for the external address the gateway multicasts when it starts or its address changes.
+    ///
+    /// This binds port 5350, which fails if another process on this host already did.
+    pub fn listen_for_announcements(&mut self) -> io::Result<()> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, CLIENT_PORT))?;
+        socket.join_multicast_v4(&Ipv4Addr::new(224, 0, 0, 1), &Ipv4Addr::UNSPECIFIED)?;
+        self.announce_socket = Some(socket);
+        Ok(())
+    }
+
+    /// Wait up to `timeout`, then renew the mappings that passed half of their lifetime.
+    ///
+    /// The wait ends early when a mapping has to be renewed, or when an announcement of the
+    /// gateway is received if `listen_for_announcements` was called. Returns whether the gateway
+    /// had restarted, in which case all mappings were made again.
+    pub fn poll(&mut self, timeout: Duration) -> Result<bool, NatPmpError> {
+        let now = Instant::now();
+        let wake = self
+            .mappings
+            .iter()
+            .map(NatPmpMapping::renew_at)
+            .fold(now + timeout, Instant::min);
+        let wait = wake.saturating_duration_since(now);
+
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let received = match self.announce_socket {
+            Some(ref socket) if !wait.is_zero() => {
+                socket.set_read_timeout(Some(wait))?;
+                match socket.recv_from(&mut buf) {
+                    Ok(received) => Some(received),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => None,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            _ => {
+                thread::sleep(wait);
+                None
+            }
+        };
+
+        let lost = match received {
+            Some((read, from)) => self.handle_announcement(&buf[..read], from),
+            None => false,
+        };
+        if lost {
+            self.recreate()?;
+            return Ok(true);
+        }
+        self.renew_due()
+    }
+
+    /// Renew the mappings that passed half of their lifetime, returning whether they had to be
+    /// made again because the gateway restarted.
+    fn renew_due(&mut self) -> Result<bool, NatPmpError> {
+        let now = Instant::now();
+        for index in 0..self.mappings.len() {
+            let mapping = self.mappings[index].clone();
+            if mapping.renew_at() > now {
+                continue;
+            }
+            let (renewed, lost) = self.send_map(
+                mapping.protocol,
+                mapping.internal_port,
+                mapping.external_port,
+                mapping.requested_lifetime,
+            )?;
+            self.mappings[index] = renewed;
+            if lost {
+                self.recreate()?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Make every mapping again, suggesting the external port it had.
+    ///
+    /// All mappings are tried, the first error is returned.
+    fn recreate(&mut self) -> Result<(), NatPmpError> {
+        info!(
+            "NAT-PMP gateway {} restarted, making {} mappings again",
+            self.gateway,
+            self.mappings.len()
+        );
+        let mut result = Ok(());
+        for index in 0..self.mappings.len() {
+            let mapping = self.mappings[index].clone();
+            match self.send_map(
+                mapping.protocol,
+                mapping.internal_port,
+                mapping.external_port,
+                mapping.requested_lifetime,
+            ) {
+                Ok((recreated, _)) => self.mappings[index] = recreated,
+                Err(e) => {
+                    debug!(
+                        "making the mapping of {} port {} again failed: {}",
+                        mapping.protocol, mapping.internal_port, e
+                    );
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Check an announcement received from `from`, returning whether the gateway restarted.
+    fn handle_announcement(&mut self, packet: &[u8], from: SocketAddr) -> bool {
+        if from.ip() != IpAddr::V4(*self.gateway.ip()) {
+            debug!("ignoring NAT-PMP message from {}, not the gateway", from);
+            return false;
+        }
+        match parse_header(packet, OPCODE_EXTERNAL_ADDRESS) {
+            Some((0, seconds)) if packet.len() >= 12 => self.update_epoch(seconds),
+            _ => {
+                debug!("ignoring invalid NAT-PMP announcement from {}", from);
+                false
+            }
+        }
+    }
+
+    /// Remember the epoch of a response, returning whether the gateway restarted.
+    fn update_epoch(&mut self, seconds: u32) -> bool {
+        let now = Instant::now();
+        let lost = match self.epoch {
+            Some(epoch) => epoch.is_lost(seconds, now),
+            None => false,
+        };
+        self.epoch = Some(Epoch { seconds, received: now });
+        lost
+    }
+
+    fn position(&self, protocol: PortMappingProtocol, internal_port: u16) -> Option<usize> {
+        self.mappings
+            .iter()
+            .position(|mapping| mapping.protocol == protocol && mapping.internal_port == internal_port)
+    }
+
+    fn send_map(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<(NatPmpMapping, bool), NatPmpError> {
+        let request = map_request(protocol, internal_port, external_port, lifetime);
+        let (response, lost) = self.request(&request)?;
+        if response.len() < 16 || u16::from_be_bytes([response[8], response[9]]) != internal_port {
+            return Err(NatPmpError::InvalidResponse);
+        }
+        let mapping = NatPmpMapping {
+            protocol,
+            internal_port,
+            external_port: u16::from_be_bytes([response[10], response[11]]),
+            lifetime: u32::from_be_bytes([response[12], response[13], response[14], response[15]]),
+            requested_lifetime: lifetime,
+            granted: Instant::now(),
+        };
+        Ok((mapping, lost))
+    }
+
+    /// Send `request`, retransmitting it until the response arrives.
+    ///
+    /// Returns the response and whether it showed that the gateway restarted.
+    fn request(&mut self, request: &[u8]) -> Result<(Vec<u8>, bool), NatPmpError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut retry = 0;
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        loop {
+            self.socket.send(request)?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "NAT-PMP request timed out").into());
+            }
+            let left = deadline - now;
+            let wait = self.retransmission.delay(retry).map_or(left, |wait| wait.min(left));
+            // A socket doesn't take a zero read timeout.
+            self.socket.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+            loop {
+                let read = match self.socket.recv(&mut buf) {
+                    Ok(read) => read,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                    Err(e) => return Err(e.into()),
+                };
+                let (result_code, seconds) = match parse_header(&buf[..read], request[1]) {
+                    Some(header) => header,
+                    None => {
+                        debug!("ignoring unrelated NAT-PMP response");
+                        continue;
+                    }
+                };
+                let lost = self.update_epoch(seconds);
+                if result_code != 0 {
+                    return Err(NatPmpError::ResultCode(result_code));
+                }
+                return Ok((buf[..read].to_vec(), lost));
+            }
+            retry += 1;
+        }
+    }
+}
+
+/// The retransmissions of RFC 6886 section 3.1, from 250ms doubling each time.
+fn default_retransmission() -> ExponentialBackoff {
+    ExponentialBackoff {
+        initial: Duration::from_millis(250),
+        max: Duration::from_secs(64),
+        jitter: 0.0,
+        max_retries: None,
+    }
+}
+
+#[test]
+fn test_epoch_is_lost() {
+    let received = Instant::now();
+    let epoch = Epoch {
+        seconds: 1000,
+        received,
+    };
+    assert!(!epoch.is_lost(1000, received));
+    assert!(!epoch.is_lost(998, received));
+    assert!(!epoch.is_lost(1090, received + Duration::from_secs(100)));
+    assert!(epoch.is_lost(997, received));
+    assert!(epoch.is_lost(10, received + Duration::from_secs(100)));
+}
+
+#[test]
+fn test_renew_and_recreate() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let server_addr = match server.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    let seconds = Arc::new(AtomicU32::new(1000));
+    let requests = Arc::new(AtomicU32::new(0));
+    {
+        let seconds = seconds.clone();
+        let requests = requests.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            while let Ok((read, from)) = server.recv_from(&mut buf) {
+                requests.fetch_add(1, Ordering::SeqCst);
+                let request = &buf[..read];
+                let mut response = vec![VERSION, request[1] | RESPONSE_BIT, 0, 0];
+                response.extend_from_slice(&seconds.load(Ordering::SeqCst).to_be_bytes());
+                response.extend_from_slice(&request[4..8]);
+                // Grant at most a 1 second lifetime, so the mapping is due for renewal quickly.
+                let lifetime = u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+                response.extend_from_slice(&lifetime.min(1).to_be_bytes());
+                server.send_to(&response, from).unwrap();
+            }
+        });
+    }
+
+    let mut client = NatPmpClient::new(server_addr).unwrap();
+    let mapping = client.map(PortMappingProtocol::TCP, 9000, 9000, 3600).unwrap();
+    assert_eq!(mapping.external_port, 9000);
+    assert_eq!(mapping.lifetime, 1);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // Half of the lifetime passes, the mapping is renewed.
+    assert!(!client.poll(Duration::from_secs(5)).unwrap());
+    assert!(Instant::now() >= mapping.renew_at());
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    // The gateway rebooted, the renewal shows it and the mapping is made again.
+    seconds.store(3, Ordering::SeqCst);
+    assert!(client.poll(Duration::from_secs(5)).unwrap());
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+    assert_eq!(client.mappings()[0].requested_lifetime, 3600);
+
+    client.unmap(PortMappingProtocol::TCP, 9000).unwrap();
+    assert!(client.mappings().is_empty());
+}
@@ -0,0 +1,208 @@
+//! Notification of changes of the network interfaces and addresses of this host.
+//!
+//! After a Wi-Fi roam or a DHCP renewal, the gateway may be another one, or the mappings may
+//! point to an address the host doesn't have anymore. `NetworkWatcher` tells when that may have
+//! happened, so the gateway can be searched again and the mappings made again, e.g. with
+//! `PortMappingManager::rediscover`.
+//!
+//! On Linux the watcher subscribes to the link, address and route changes of the kernel over
+//! netlink. There is no native watcher for the other platforms, Windows (`NotifyAddrChange`)
+//! and macOS (SystemConfiguration) included: there it polls the local addresses used to reach
+//! the internet every 2 seconds. It sees changes of those addresses only, up to 2 seconds late,
+//! and not e.g. a new gateway on the same subnet.
+//!
+//! # Example
+//! ```no_run
+//! use std::time::Duration;
+//! use igd::netwatch::NetworkWatcher;
+//! use igd::PortMappingManager;
+//!
+//! let gateway = igd::search_gateway(Default::default()).unwrap();
+//! let mut manager = PortMappingManager::new(gateway, "myapp:");
+//! let mut watcher = NetworkWatcher::new().unwrap();
+//! loop {
+//!     if watcher.wait(Duration::from_secs(60)).unwrap() {
+//!         let report = manager.rediscover(Default::default()).unwrap();
+//!         println!("{} mappings failed", report.failed.len());
+//!     }
+//! }
+//! ```
+
+use std::io;
+use std::time::Duration;
+
+/// How long changes have to stop before they are reported, as they come in bursts, e.g. an
+/// address removed and another one added.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Watches the network interfaces and addresses of this host, natively on Linux only, by polling
+/// elsewhere, see the module docs.
+#[derive(Debug)]
+pub struct NetworkWatcher {
+    inner: platform::Watcher,
+}
+
+impl NetworkWatcher {
+    /// Start watching.
+    pub fn new() -> io::Result<NetworkWatcher> {
+        Ok(NetworkWatcher {
+            inner: platform::Watcher::new()?,
+        })
+    }
+
+    /// Wait up to `timeout` for the interfaces or addresses to change.
+    ///
+    /// Returns whether they changed. Once a change is seen, this waits until no more changes
+    /// come for a moment, so a burst of changes is reported once.
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        if !self.inner.wait(timeout)? {
+            return Ok(false);
+        }
+        while self.inner.wait(SETTLE_TIME)? {}
+        Ok(true)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    pub struct Watcher {
+        fd: OwnedFd,
+    }
+
+    impl Watcher {
+        pub fn new() -> io::Result<Watcher> {
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                    libc::NETLINK_ROUTE,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups =
+                (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR | libc::RTMGRP_IPV4_ROUTE)
+                    as u32;
+            let result = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Watcher { fd })
+        }
+
+        /// Wait for netlink messages, returning whether any came.
+        pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+                0 => Ok(false),
+                result if result < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        Ok(false)
+                    } else {
+                        Err(e)
+                    }
+                }
+                _ => {
+                    self.drain()?;
+                    Ok(true)
+                }
+            }
+        }
+
+        /// Read all pending messages, their content doesn't matter.
+        fn drain(&mut self) -> io::Result<()> {
+            let mut buf = [0u8; 8192];
+            loop {
+                let read =
+                    unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+                if read >= 0 {
+                    continue;
+                }
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    // Messages were dropped because too many came, which is a change as well.
+                    _ if e.raw_os_error() == Some(libc::ENOBUFS) => continue,
+                    _ => return Err(e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::common;
+
+    const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+    #[derive(Debug)]
+    pub struct Watcher {
+        addrs: (Option<IpAddr>, Option<IpAddr>),
+    }
+
+    /// The local addresses used to reach the internet, which change with the interfaces.
+    fn local_addrs() -> (Option<IpAddr>, Option<IpAddr>) {
+        let ipv4 = common::local_ip_towards(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 9)).ok();
+        let ipv6 = common::local_ipv6().ok();
+        (ipv4.map(IpAddr::V4), ipv6.map(IpAddr::V6))
+    }
+
+    impl Watcher {
+        pub fn new() -> io::Result<Watcher> {
+            Ok(Watcher { addrs: local_addrs() })
+        }
+
+        /// Poll the local addresses, returning whether they changed.
+        pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+            let deadline = Instant::now() + timeout;
+            loop {
+                let addrs = local_addrs();
+                if addrs != self.addrs {
+                    self.addrs = addrs;
+                    return Ok(true);
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(false);
+                }
+                thread::sleep(POLL_INTERVAL.min(deadline - now));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_network_watcher() {
+    let mut watcher = NetworkWatcher::new().unwrap();
+    watcher.wait(Duration::from_millis(10)).unwrap();
+}
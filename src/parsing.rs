@@ -0,0 +1,55 @@
+//! The parsers used by the search functions, for use with other transports.
+//!
+//! They work on raw bytes, so they can be fed with captured packets, responses fetched with
+//! another HTTP client, or fuzzer input, and behave exactly like the search functions.
+
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+
+use crate::common::parsing;
+pub use crate::common::parsing::Description;
+use crate::description;
+use crate::errors::SearchError;
+use crate::proto::Headers;
+use crate::{DeviceInfo, RootDescription};
+
+/// Parse a response to the M-SEARCH request.
+///
+/// Returns the address of the gateway and the path of its device description.
+pub fn parse_search_result(response: &[u8]) -> Result<(SocketAddrV4, String), SearchError> {
+    parsing::parse_search_result(&Headers::parse(response))
+}
+
+/// Parse a device description, returning the SCPD url and the control url of the WAN connection.
+pub fn parse_control_urls(description: &[u8]) -> Result<(String, String), SearchError> {
+    parsing::parse_control_urls(description)
+}
+
+/// Parse everything the search functions use from a device description.
+pub fn parse_description(description: &[u8]) -> Result<Description, SearchError> {
+    parsing::parse_description(description)
+}
+
+/// Parse the whole device tree of a device description, see `Gateway::description`.
+pub fn parse_root_description(description: &[u8]) -> Result<RootDescription, SearchError> {
+    description::parse(description)
+}
+
+/// Parse the information about the root device of a device description.
+pub fn parse_device_info(description: &[u8]) -> Result<DeviceInfo, SearchError> {
+    parsing::parse_device_info(description)
+}
+
+/// Parse an SCPD, returning the input arguments of each action.
+pub fn parse_schemas(scpd: &[u8]) -> Result<HashMap<String, Vec<String>>, SearchError> {
+    parsing::parse_schemas(scpd)
+}
+
+#[test]
+fn test_parse_search_result() {
+    let response = b"HTTP/1.1 200 OK\r\nLOCATION: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+    let (addr, root_url) = parse_search_result(response).unwrap();
+    assert_eq!(addr, "192.168.1.1:5000".parse().unwrap());
+    assert_eq!(root_url, "/rootDesc.xml");
+    assert!(parse_search_result(&[0xff, 0xfe]).is_err());
+}
@@ -0,0 +1,689 @@
+//! A minimal PCP client (RFC 6887), for gateways that speak the Port Control Protocol instead of UPnP.
+//!
+//! Only the MAP opcode is implemented. The client remembers the mappings it made and the epoch
+//! of the server, and makes the mappings again when the epoch shows that the server lost its
+//! state, e.g. after a reboot (RFC 6887 section 8.5). A restarting server says so with an
+//! unsolicited ANNOUNCE message, which `PcpClient::poll` receives once
+//! `PcpClient::listen_for_announcements` has been called. Every response to a request is
+//! checked as well.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+
+use crate::{Backoff, ExponentialBackoff, PortMappingProtocol};
+
+/// Port the PCP server listens on.
+pub const SERVER_PORT: u16 = 5351;
+
+/// Port unsolicited messages of the PCP server are sent to.
+pub const CLIENT_PORT: u16 = 5350;
+
+/// Time to wait for an answer to a request, retransmissions included.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(4);
+
+const VERSION: u8 = 2;
+const RESPONSE_BIT: u8 = 0x80;
+const OPCODE_ANNOUNCE: u8 = 0;
+const OPCODE_MAP: u8 = 1;
+const OPTION_THIRD_PARTY: u8 = 1;
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = 36;
+const MAX_PACKET_LEN: usize = 1100;
+
+/// Result code of an unsupported version, also sent by NAT-PMP servers.
+const UNSUPP_VERSION: u8 = 1;
+
+/// Errors of the PCP client.
+#[derive(Debug)]
+pub enum PcpError {
+    /// IO error, including timeouts of requests
+    IoError(io::Error),
+    /// The server only speaks another version of the protocol, e.g. NAT-PMP.
+    UnsupportedVersion,
+    /// The server returned an invalid response.
+    InvalidResponse,
+    /// The server refused the request with the given result code.
+    ResultCode(u8),
+}
+
+impl From<io::Error> for PcpError {
+    fn from(err: io::Error) -> PcpError {
+        PcpError::IoError(err)
+    }
+}
+
+impl fmt::Display for PcpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PcpError::IoError(ref e) => write!(f, "IO error: {}", e),
+            PcpError::UnsupportedVersion => write!(f, "The server does not support PCP version 2"),
+            PcpError::InvalidResponse => write!(f, "Invalid response from the PCP server"),
+            PcpError::ResultCode(code) => write!(f, "The PCP server returned {} ({})", result_name(code), code),
+        }
+    }
+}
+
+impl error::Error for PcpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PcpError::IoError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The name of a result code, from RFC 6887 section 7.4.
+fn result_name(code: u8) -> &'static str {
+    match code {
+        0 => "SUCCESS",
+        1 => "UNSUPP_VERSION",
+        2 => "NOT_AUTHORIZED",
+        3 => "MALFORMED_REQUEST",
+        4 => "UNSUPP_OPCODE",
+        5 => "UNSUPP_OPTION",
+        6 => "MALFORMED_OPTION",
+        7 => "NETWORK_FAILURE",
+        8 => "NO_RESOURCES",
+        9 => "UNSUPP_PROTOCOL",
+        10 => "USER_EX_QUOTA",
+        11 => "CANNOT_PROVIDE_EXTERNAL",
+        12 => "ADDRESS_MISMATCH",
+        13 => "EXCESSIVE_REMOTE_PEERS",
+        _ => "unknown result",
+    }
+}
+
+/// A mapping made by `PcpClient::map` or `PcpClient::map_for`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PcpMapping {
+    /// Protocol of the mapping
+    pub protocol: PortMappingProtocol,
+    /// Other host of the LAN the mapping forwards to, `None` for this host
+    pub third_party: Option<IpAddr>,
+    /// Port the mapping forwards to
+    pub internal_port: u16,
+    /// External address and port assigned by the server
+    pub external_addr: SocketAddr,
+    /// Lifetime granted by the server, in seconds
+    pub lifetime: u32,
+    requested_lifetime: u32,
+    nonce: [u8; 12],
+}
+
+/// Server time of the last response and when it was received.
+#[derive(Clone, Copy, Debug)]
+struct Epoch {
+    server_time: u32,
+    received: Instant,
+}
+
+impl Epoch {
+    /// Whether the server lost its state since this epoch, seeing `server_time` at `now`.
+    ///
+    /// The server time has to go on at about the same pace as the client time, with the slack
+    /// allowed in RFC 6887 section 8.5.
+    fn is_lost(&self, server_time: u32, now: Instant) -> bool {
+        if u64::from(server_time) + 1 < u64::from(self.server_time) {
+            return true;
+        }
+        let client_delta = now.duration_since(self.received).as_secs();
+        let server_delta = u64::from(server_time.saturating_sub(self.server_time));
+        client_delta + 2 < server_delta - server_delta / 16 || server_delta + 2 < client_delta - client_delta / 16
+    }
+}
+
+/// A response of the server, its payload left unparsed.
+struct Response<'a> {
+    opcode: u8,
+    result_code: u8,
+    lifetime: u32,
+    epoch: u32,
+    payload: &'a [u8],
+}
+
+fn parse_response(packet: &[u8]) -> Result<Response<'_>, PcpError> {
+    if packet.len() < 4 || packet[1] & RESPONSE_BIT == 0 {
+        return Err(PcpError::InvalidResponse);
+    }
+    if packet[0] != VERSION {
+        return Err(PcpError::UnsupportedVersion);
+    }
+    if packet.len() < HEADER_LEN {
+        return Err(PcpError::InvalidResponse);
+    }
+    Ok(Response {
+        opcode: packet[1] & !RESPONSE_BIT,
+        result_code: packet[3],
+        lifetime: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+        epoch: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        payload: &packet[HEADER_LEN..],
+    })
+}
+
+/// An address as carried by PCP, IPv4 addresses being IPv4-mapped.
+fn address_bytes(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn parse_address(bytes: &[u8]) -> IpAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&bytes[..16]);
+    let ip = Ipv6Addr::from(octets);
+    match ip.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip),
+    }
+}
+
+fn protocol_number(protocol: PortMappingProtocol) -> u8 {
+    match protocol {
+        PortMappingProtocol::TCP => 6,
+        PortMappingProtocol::UDP => 17,
+    }
+}
+
+fn map_request(client_ip: IpAddr, mapping: &PcpMapping, lifetime: u32) -> Vec<u8> {
+    let mut request = Vec::with_capacity(HEADER_LEN + MAP_LEN);
+    request.extend_from_slice(&[VERSION, OPCODE_MAP, 0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&address_bytes(client_ip));
+    request.extend_from_slice(&mapping.nonce);
+    request.extend_from_slice(&[protocol_number(mapping.protocol), 0, 0, 0]);
+    request.extend_from_slice(&mapping.internal_port.to_be_bytes());
+    request.extend_from_slice(&mapping.external_addr.port().to_be_bytes());
+    request.extend_from_slice(&address_bytes(mapping.external_addr.ip()));
+    if let Some(third_party) = mapping.third_party {
+        request.extend_from_slice(&[OPTION_THIRD_PARTY, 0]);
+        request.extend_from_slice(&16u16.to_be_bytes());
+        request.extend_from_slice(&address_bytes(third_party));
+    }
+    request
+}
+
+/// A PCP client talking to the server of one gateway.
+///
+/// # Example
+/// ```no_run
+/// use std::time::Duration;
+/// use igd::pcp::{PcpClient, SERVER_PORT};
+/// use igd::PortMappingProtocol;
+///
+/// let mut client = PcpClient::new(([192, 168, 1, 1], SERVER_PORT).into()).unwrap();
+/// let mapping = client.map(PortMappingProtocol::TCP, 8080, 8080, 3600).unwrap();
+/// println!("reachable at {}", mapping.external_addr);
+///
+/// client.listen_for_announcements().unwrap();
+/// loop {
+///     if client.poll(Duration::from_secs(60)).unwrap() {
+///         println!("the gateway restarted, now at {:?}", client.mappings());
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct PcpClient {
+    socket: UdpSocket,
+    announce_socket: Option<UdpSocket>,
+    server: SocketAddr,
+    client_ip: IpAddr,
+    timeout: Duration,
+    retransmission: Arc<dyn Backoff>,
+    epoch: Option<Epoch>,
+    mappings: Vec<PcpMapping>,
+}
+
+impl PcpClient {
+    /// Create a client for the PCP server at `server`, usually port 5351 of the default router.
+    pub fn new(server: SocketAddr) -> io::Result<PcpClient> {
+        let socket = match server {
+            SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+            SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+        };
+        socket.connect(server)?;
+        let client_ip = socket.local_addr()?.ip();
+        Ok(PcpClient {
+            socket,
+            announce_socket: None,
+            server,
+            client_ip,
+            timeout: DEFAULT_TIMEOUT,
+            retransmission: Arc::new(default_retransmission()),
+            epoch: None,
+            mappings: Vec::new(),
+        })
+    }
+
+    /// Set how long to wait for an answer to a request, retransmissions included.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set the delays after which a request is sent again while it isn't answered (defaults
+    /// to 500ms, doubling each time).
+    ///
+    /// When the delays run out, the request is waited for until the timeout.
+    pub fn set_retransmission(&mut self, backoff: Arc<dyn Backoff>) {
+        self.retransmission = backoff;
+    }
+
+    /// The address of the server.
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// The mappings made by this client and not removed since.
+    pub fn mappings(&self) -> &[PcpMapping] {
+        &self.mappings
+    }
+
+    /// Map an external port to `internal_port` of this host for `lifetime` seconds.
+    ///
+    /// The server picks another external port if `suggested_external_port` is taken, or any if
+    /// it is 0. Mapping the same protocol and internal port again renews the mapping.
+    pub fn map(
+        &mut self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        suggested_external_port: u16,
+        lifetime: u32,
+    ) -> Result<PcpMapping, PcpError> {
+        self.map_with(None, protocol, internal_port, suggested_external_port, lifetime)
+    }
+
+    /// Map an external port to `internal_port` of another host of the LAN, e.g. a NAS or a camera.
+    ///
+    /// The request carries the THIRD_PARTY option (RFC 6887 section 13.1). Many servers only
+    /// allow it to some clients, if at all, and refuse it with `NOT_AUTHORIZED` (2) or
+    /// `UNSUPP_OPTION` (5). Otherwise this is the same as `map`.
+    pub fn map_for(
+        &mut self,
+        internal_ip: IpAddr,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        suggested_external_port: u16,
+        lifetime: u32,
+    ) -> Result<PcpMapping, PcpError> {
+        let third_party = self.third_party(internal_ip);
+        self.map_with(third_party, protocol, internal_port, suggested_external_port, lifetime)
+    }
+
+    fn map_with(
+        &mut self,
+        third_party: Option<IpAddr>,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        suggested_external_port: u16,
+        lifetime: u32,
+    ) -> Result<PcpMapping, PcpError> {
+        let unspecified = match self.server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let index = self.position(third_party, protocol, internal_port);
+        let mut mapping = match index {
+            Some(index) => self.mappings[index].clone(),
+            None => PcpMapping {
+                protocol,
+                third_party,
+                internal_port,
+                external_addr: SocketAddr::new(unspecified, 0),
+                lifetime: 0,
+                requested_lifetime: lifetime,
+                nonce: rand::thread_rng().gen(),
+            },
+        };
+        mapping.external_addr.set_port(suggested_external_port);
+        mapping.requested_lifetime = lifetime;
+
+        let lost = self.send_map(&mut mapping, lifetime)?;
+        match index {
+            Some(index) => self.mappings[index] = mapping.clone(),
+            None => self.mappings.push(mapping.clone()),
+        }
+        if lost {
+            self.refresh()?;
+            return Ok(self.mappings[index.unwrap_or(self.mappings.len() - 1)].clone());
+        }
+        Ok(mapping)
+    }
+
+    /// Remove the mapping of `internal_port`.
+    pub fn unmap(&mut self, protocol: PortMappingProtocol, internal_port: u16) -> Result<(), PcpError> {
+        self.unmap_with(None, protocol, internal_port)
+    }
+
+    /// Remove a mapping made with `map_for`.
+    pub fn unmap_for(
+        &mut self,
+        internal_ip: IpAddr,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+    ) -> Result<(), PcpError> {
+        let third_party = self.third_party(internal_ip);
+        self.unmap_with(third_party, protocol, internal_port)
+    }
+
+    fn unmap_with(
+        &mut self,
+        third_party: Option<IpAddr>,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+    ) -> Result<(), PcpError> {
+        let index = match self.position(third_party, protocol, internal_port) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let mut mapping = self.mappings.remove(index);
+        if self.send_map(&mut mapping, 0)? {
+            self.refresh()?;
+        }
+        Ok(())
+    }
+
+    /// The THIRD_PARTY address for mappings to `internal_ip`, none if it is this host.
+    fn third_party(&self, internal_ip: IpAddr) -> Option<IpAddr> {
+        Some(internal_ip).filter(|ip| *ip != self.client_ip)
+    }
+
+    fn position(
+        &self,
+        third_party: Option<IpAddr>,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+    ) -> Option<usize> {
+        self.mappings.iter().position(|mapping| {
+            mapping.third_party == third_party && mapping.protocol == protocol && mapping.internal_port == internal_port
+        })
+    }
+
+    /// Request every mapping again, with the lifetime it was first requested with.
+    ///
+    /// This renews the mappings, and makes them again if the server lost them. The external
+    /// port of each mapping is suggested again. All mappings are tried, the first error is
+    /// returned.
+    pub fn refresh(&mut self) -> Result<(), PcpError> {
+        let mut result = Ok(());
+        for index in 0..self.mappings.len() {
+            let mut mapping = self.mappings[index].clone();
+            let lifetime = mapping.requested_lifetime;
+            match self.send_map(&mut mapping, lifetime) {
+                Ok(_) => self.mappings[index] = mapping,
+                Err(e) => {
+                    debug!(
+                        "refreshing the mapping of {} port {} failed: {}",
+                        mapping.protocol, mapping.internal_port, e
+                    );
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Listen for the ANNOUNCE messages a server multicasts when it restarts.
+    ///
+    /// This binds port 5350, which fails if another process on this host already did.
+    pub fn listen_for_announcements(&mut self) -> io::Result<()> {
+        let socket = match self.server {
+            SocketAddr::V4(_) => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, CLIENT_PORT))?;
+                socket.join_multicast_v4(&Ipv4Addr::new(224, 0, 0, 1), &Ipv4Addr::UNSPECIFIED)?;
+                socket
+            }
+            SocketAddr::V6(_) => {
+                let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, CLIENT_PORT))?;
+                socket.join_multicast_v6(&Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1), 0)?;
+                socket
+            }
+        };
+        self.announce_socket = Some(socket);
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for a message of the server and handle it.
+    ///
+    /// Returns whether the server had lost its state, in which case the mappings were made
+    /// again after a random delay of up to 5 seconds, so that the clients of a restarted server
+    /// don't all answer at once. Fails if `listen_for_announcements` wasn't called.
+    pub fn poll(&mut self, timeout: Duration) -> Result<bool, PcpError> {
+        let socket = self
+            .announce_socket
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not listening for announcements"))?;
+        socket.set_read_timeout(Some(timeout))?;
+
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let (read, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        if !self.handle_announcement(&buf[..read], from) {
+            return Ok(false);
+        }
+
+        thread::sleep(Duration::from_millis(rand::thread_rng().gen_range(0..5000)));
+        self.refresh()?;
+        Ok(true)
+    }
+
+    /// Check an announcement received from `from`, returning whether the server lost its state.
+    fn handle_announcement(&mut self, packet: &[u8], from: SocketAddr) -> bool {
+        if from.ip() != self.server.ip() {
+            debug!("ignoring PCP message from {}, not the server", from);
+            return false;
+        }
+        match parse_response(packet) {
+            Ok(response) if response.opcode == OPCODE_ANNOUNCE && response.result_code == 0 => {
+                let lost = self.update_epoch(response.epoch);
+                if lost {
+                    info!(
+                        "PCP server {} lost its state, making {} mappings again",
+                        self.server,
+                        self.mappings.len()
+                    );
+                }
+                lost
+            }
+            Ok(response) => {
+                debug!("ignoring PCP message with opcode {} from {}", response.opcode, from);
+                false
+            }
+            Err(e) => {
+                debug!("ignoring invalid PCP message from {}: {}", from, e);
+                false
+            }
+        }
+    }
+
+    /// Remember the epoch of a response, returning whether the server lost its state.
+    fn update_epoch(&mut self, server_time: u32) -> bool {
+        let now = Instant::now();
+        let lost = match self.epoch {
+            Some(epoch) => epoch.is_lost(server_time, now),
+            None => false,
+        };
+        self.epoch = Some(Epoch {
+            server_time,
+            received: now,
+        });
+        lost
+    }
+
+    /// Send a MAP request for `mapping` and update it from the response.
+    ///
+    /// Returns whether the response showed that the server lost its state.
+    fn send_map(&mut self, mapping: &mut PcpMapping, lifetime: u32) -> Result<bool, PcpError> {
+        let request = map_request(self.client_ip, mapping, lifetime);
+        let deadline = Instant::now() + self.timeout;
+        let mut retry = 0;
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        loop {
+            self.socket.send(&request)?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "PCP request timed out").into());
+            }
+            let left = deadline - now;
+            let wait = self.retransmission.delay(retry).map_or(left, |wait| wait.min(left));
+            // A socket doesn't take a zero read timeout.
+            self.socket.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+            loop {
+                let read = match self.socket.recv(&mut buf) {
+                    Ok(read) => read,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                    Err(e) => return Err(e.into()),
+                };
+                let response = match parse_response(&buf[..read]) {
+                    Ok(response) => response,
+                    Err(PcpError::InvalidResponse) => {
+                        debug!("ignoring invalid PCP response");
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                if response.opcode != OPCODE_MAP
+                    || response.payload.len() < MAP_LEN
+                    || response.payload[..12] != mapping.nonce
+                {
+                    debug!("ignoring unrelated PCP response");
+                    continue;
+                }
+
+                let lost = self.update_epoch(response.epoch);
+                if response.result_code != 0 {
+                    return Err(match response.result_code {
+                        UNSUPP_VERSION => PcpError::UnsupportedVersion,
+                        code => PcpError::ResultCode(code),
+                    });
+                }
+                let payload = response.payload;
+                mapping.external_addr = SocketAddr::new(
+                    parse_address(&payload[20..36]),
+                    u16::from_be_bytes([payload[18], payload[19]]),
+                );
+                mapping.lifetime = response.lifetime;
+                return Ok(lost);
+            }
+            retry += 1;
+        }
+    }
+}
+
+/// Retransmissions from 500ms doubling each time, up to the 1024 seconds of RFC 6887.
+fn default_retransmission() -> ExponentialBackoff {
+    ExponentialBackoff {
+        initial: Duration::from_millis(500),
+        max: Duration::from_secs(1024),
+        jitter: 0.0,
+        max_retries: None,
+    }
+}
+
+#[test]
+fn test_epoch_is_lost() {
+    let received = Instant::now();
+    let epoch = Epoch {
+        server_time: 1000,
+        received,
+    };
+    assert!(!epoch.is_lost(1000, received));
+    assert!(!epoch.is_lost(1100, received + Duration::from_secs(100)));
+    assert!(!epoch.is_lost(999, received));
+    // The server restarted.
+    assert!(epoch.is_lost(10, received + Duration::from_secs(100)));
+    // The server restarted a while ago and its time caught up, or it jumped ahead.
+    assert!(epoch.is_lost(1050, received + Duration::from_secs(100)));
+    assert!(epoch.is_lost(2000, received + Duration::from_secs(100)));
+}
+
+#[test]
+fn test_reestablish_after_announcement() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let epoch = Arc::new(AtomicU32::new(1000));
+    let requests = Arc::new(AtomicU32::new(0));
+    {
+        let epoch = epoch.clone();
+        let requests = requests.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            while let Ok((read, from)) = server.recv_from(&mut buf) {
+                requests.fetch_add(1, Ordering::SeqCst);
+                let request = &buf[..read];
+                let mut response = vec![VERSION, OPCODE_MAP | RESPONSE_BIT, 0, 0];
+                response.extend_from_slice(&request[4..8]);
+                response.extend_from_slice(&epoch.load(Ordering::SeqCst).to_be_bytes());
+                response.extend_from_slice(&[0; 12]);
+                response.extend_from_slice(&request[HEADER_LEN..HEADER_LEN + 20]);
+                response[HEADER_LEN + 18..HEADER_LEN + 20].copy_from_slice(&request[HEADER_LEN + 16..HEADER_LEN + 18]);
+                response.extend_from_slice(&address_bytes(Ipv4Addr::new(203, 0, 113, 1).into()));
+                server.send_to(&response, from).unwrap();
+            }
+        });
+    }
+
+    let mut client = PcpClient::new(server_addr).unwrap();
+    let mapping = client.map(PortMappingProtocol::UDP, 9000, 0, 3600).unwrap();
+    assert_eq!(mapping.external_addr, "203.0.113.1:9000".parse().unwrap());
+    assert_eq!(mapping.lifetime, 3600);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    let mut announcement = vec![VERSION, OPCODE_ANNOUNCE | RESPONSE_BIT, 0, 0, 0, 0, 0, 0];
+    announcement.extend_from_slice(&1000u32.to_be_bytes());
+    announcement.extend_from_slice(&[0; 12]);
+    assert!(!client.handle_announcement(&announcement, server_addr));
+    assert!(!client.handle_announcement(&announcement, "192.0.2.1:5351".parse().unwrap()));
+
+    // The server rebooted, its epoch starts over.
+    epoch.store(3, Ordering::SeqCst);
+    announcement[8..12].copy_from_slice(&2u32.to_be_bytes());
+    assert!(client.handle_announcement(&announcement, server_addr));
+    client.refresh().unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    assert_eq!(client.mappings()[0].nonce, mapping.nonce);
+
+    client.unmap(PortMappingProtocol::UDP, 9000).unwrap();
+    assert!(client.mappings().is_empty());
+}
+
+#[test]
+fn test_map_request_third_party() {
+    let mut mapping = PcpMapping {
+        protocol: PortMappingProtocol::TCP,
+        third_party: None,
+        internal_port: 80,
+        external_addr: (Ipv4Addr::UNSPECIFIED, 8080).into(),
+        lifetime: 0,
+        requested_lifetime: 3600,
+        nonce: [7; 12],
+    };
+    let client_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+    let request = map_request(client_ip, &mapping, 3600);
+    assert_eq!(request.len(), HEADER_LEN + MAP_LEN);
+    assert_eq!(parse_address(&request[8..24]), client_ip);
+
+    mapping.third_party = Some(Ipv4Addr::new(192, 168, 1, 20).into());
+    let request = map_request(client_ip, &mapping, 3600);
+    assert_eq!(request.len(), HEADER_LEN + MAP_LEN + 20);
+    assert_eq!(request[HEADER_LEN + MAP_LEN..HEADER_LEN + MAP_LEN + 4], [1, 0, 0, 16]);
+    assert_eq!(parse_address(&request[8..24]), client_ip);
+    assert_eq!(
+        parse_address(&request[HEADER_LEN + MAP_LEN + 4..]),
+        mapping.third_party.unwrap()
+    );
+}
@@ -0,0 +1,673 @@
+//! The SSDP and SOAP messages of the protocol, formatted and parsed without any networking.
+//!
+//! The messages are the ones `Gateway` sends, for programs with a TCP/IP stack of their own, e.g.
+//! firmware without std. It only needs `alloc`, see the crate documentation on `no_std`.
+//! The program sends the messages and feeds the replies back:
+//!
+//! 1. Send `format_search_request` over UDP to `SSDP_ADDR`, and parse the answers with
+//!    `parse_location` until one is a gateway.
+//! 2. Fetch the description at that location with `format_description_request` over TCP, and
+//!    find the port mapping service in it with `find_connection_service`.
+//! 3. Send an `Action` to the control url of the service with `format_action_request`, and get
+//!    the values out of the reply with `parse_response` and `response_value`.
+//!
+//! The HTTP requests ask the gateway to close the connection once it answered, a response is
+//! complete at the latest then.
+//!
+//! # Example
+//! ```
+//! use igd::proto::{self, Action, Location};
+//! use igd::PortMappingProtocol;
+//!
+//! let search = proto::format_search_request(proto::INTERNET_GATEWAY_DEVICE, 3);
+//! // ... sent to proto::SSDP_ADDR, which answers:
+//! let answer = b"HTTP/1.1 200 OK\r\nLOCATION: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+//! let location = proto::parse_location(answer).unwrap();
+//!
+//! // The description fetched from the location lists the service.
+//! let service = proto::Service {
+//!     service_type: "urn:schemas-upnp-org:service:WANIPConnection:1".into(),
+//!     control_url: "/ctl/IPConn".into(),
+//! };
+//! let control = location.resolve(&service.control_url).unwrap();
+//! let action = Action::add_port_mapping(
+//!     &service.service_type,
+//!     PortMappingProtocol::TCP,
+//!     8080,
+//!     "192.168.1.2:8080".parse().unwrap(),
+//!     3600,
+//!     "web",
+//! );
+//! let request = proto::format_action_request(&control, &action);
+//! // ... sent over TCP to control.addr.
+//! assert!(request.starts_with("POST /ctl/IPConn HTTP/1.0\r\n"));
+//! ```
+
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::str;
+
+#[cfg(feature = "std")]
+use crate::common::messages;
+#[cfg(not(feature = "std"))]
+use crate::messages;
+use crate::PortMappingProtocol;
+
+/// Multicast address and port M-SEARCH requests are sent to.
+pub const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+/// Search target matching Internet Gateway Devices.
+pub const INTERNET_GATEWAY_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// Format an M-SEARCH request for the given search target.
+///
+/// Devices wait up to `mx` seconds before answering, to spread the responses.
+pub fn format_search_request(search_target: &str, mx: u8) -> String {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         Host:239.255.255.250:1900\r\n\
+         ST:{}\r\n\
+         Man:\"ssdp:discover\"\r\n\
+         MX:{}\r\n\
+         \r\n",
+        search_target, mx
+    )
+}
+
+/// Where an HTTP resource of the gateway is: the address to connect to, and the path to ask for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Location {
+    /// Address of the gateway
+    pub addr: SocketAddrV4,
+    /// Path of the resource, starting with `/`
+    pub path: String,
+}
+
+impl Location {
+    /// Parse an `http` url with an IPv4 host, the port defaulting to 80.
+    pub fn parse(url: &str) -> Option<Location> {
+        let rest = url.trim().strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, 80),
+        };
+        Some(Location {
+            addr: SocketAddrV4::new(host.parse().ok()?, port),
+            path: path.to_string(),
+        })
+    }
+
+    /// The location of `url` relative to this one, e.g. of a control url of the description found
+    /// here. Absolute urls are parsed as they are, others are paths on the same gateway.
+    pub fn resolve(&self, url: &str) -> Option<Location> {
+        let url = url.trim();
+        if url.contains("://") {
+            return Location::parse(url);
+        }
+        let path = match url.strip_prefix('/') {
+            Some(_) => url.to_string(),
+            None => format!("/{}", url),
+        };
+        Some(Location { addr: self.addr, path })
+    }
+}
+
+/// The start line and headers of an SSDP message, borrowed from the datagram it came in.
+///
+/// Nothing is copied, the lines are found again by every lookup, which is cheaper for the few
+/// headers of a message. Names are matched case-insensitively. Lines that aren't UTF-8, or
+/// aren't headers, are skipped rather than failing the whole message, since some devices send
+/// e.g. a `SERVER` in Latin-1.
+///
+/// # Example
+/// ```
+/// use igd::proto::Headers;
+///
+/// let headers = Headers::parse(b"HTTP/1.1 200 OK\r\nlocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n");
+/// assert!(headers.is_success());
+/// assert_eq!(headers.get("LOCATION"), Some("http://192.168.1.1:5000/rootDesc.xml"));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Headers<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Headers<'a> {
+    /// The headers of a message, up to the empty line that ends them.
+    pub fn parse(data: &'a [u8]) -> Headers<'a> {
+        Headers { data }
+    }
+
+    fn lines(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.data
+            .split(|b| *b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+    }
+
+    /// The first line, e.g. `HTTP/1.1 200 OK` for a search response or `NOTIFY * HTTP/1.1`.
+    pub fn start_line(&self) -> Option<&'a str> {
+        self.lines()
+            .next()
+            .and_then(|line| str::from_utf8(line).ok())
+            .map(str::trim)
+    }
+
+    /// Whether the message is a successful HTTP response, i.e. answers a search.
+    pub fn is_success(&self) -> bool {
+        let mut status = self.start_line().unwrap_or_default().split_whitespace();
+        status.next().is_some_and(|version| version.starts_with("HTTP/")) && status.next() == Some("200")
+    }
+
+    /// The headers, as their name and value, trimmed, in the order of the message.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        self.lines()
+            .skip(1)
+            .take_while(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .filter_map(|line| {
+                let (name, value) = str::from_utf8(line).ok()?.split_once(':')?;
+                Some((name.trim(), value.trim()))
+            })
+    }
+
+    /// The value of the first header named `name`, matched case-insensitively.
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+}
+
+/// Get the description location (`LOCATION`) of a response to an M-SEARCH request.
+///
+/// Returns `None` if the data is not a successful HTTP response, e.g. a search request of another
+/// control point, or has no location with an IPv4 address.
+pub fn parse_location(data: &[u8]) -> Option<Location> {
+    let headers = Headers::parse(data);
+    if !headers.is_success() {
+        return None;
+    }
+    Location::parse(headers.get("location")?)
+}
+
+/// A port mapping service of the gateway, `WANIPConnection` or `WANPPPConnection`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Service {
+    /// Type of the service, e.g. `urn:schemas-upnp-org:service:WANIPConnection:1`
+    pub service_type: String,
+    /// Url to send the actions to, usually relative to the location of the description
+    pub control_url: String,
+}
+
+/// Find the service to map ports with in the description of the gateway.
+///
+/// A `WANIPConnection` is preferred over a `WANPPPConnection`, of any version.
+pub fn find_connection_service(description: &str) -> Option<Service> {
+    let mut found = Vec::new();
+    find_elements(description, "service", &mut found);
+    let services: Vec<Service> = found
+        .iter()
+        .filter_map(|service| {
+            Some(Service {
+                service_type: unescape(child(service, "serviceType")?).into_owned(),
+                control_url: unescape(child(service, "controlURL")?).into_owned(),
+            })
+        })
+        .collect();
+    [
+        "urn:schemas-upnp-org:service:WANIPConnection:",
+        "urn:schemas-upnp-org:service:WANPPPConnection:",
+    ]
+    .iter()
+    .find_map(|prefix| {
+        services
+            .iter()
+            .find(|service| service.service_type.starts_with(prefix))
+            .cloned()
+    })
+}
+
+/// A SOAP action, sent with `format_action_request`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Action {
+    /// Value of the `SOAPAction` header
+    pub header: String,
+    /// The SOAP envelope
+    pub body: String,
+}
+
+impl Action {
+    /// An action of the given service with the given arguments, in order. The values are escaped.
+    pub fn new(service_type: &str, action: &str, arguments: &[(&str, String)]) -> Action {
+        Action {
+            header: messages::format_action_header(service_type, action),
+            body: messages::format_action_message(service_type, action, arguments),
+        }
+    }
+
+    /// Get the external IP address of the gateway, from the `NewExternalIPAddress` value.
+    pub fn get_external_ip(service_type: &str) -> Action {
+        Action {
+            header: messages::format_action_header(service_type, "GetExternalIPAddress"),
+            body: messages::format_get_external_ip_message(service_type),
+        }
+    }
+
+    /// Map `external_port` to `local_addr`, for `lease_duration` seconds or permanently if 0.
+    pub fn add_port_mapping(
+        service_type: &str,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Action {
+        Action {
+            header: messages::format_action_header(service_type, "AddPortMapping"),
+            body: messages::format_add_port_mapping_message(
+                service_type,
+                &messages::standard_arguments("AddPortMapping"),
+                protocol,
+                external_port,
+                local_addr,
+                lease_duration,
+                description,
+                true,
+            ),
+        }
+    }
+
+    /// Remove the mapping of `external_port`.
+    pub fn delete_port_mapping(service_type: &str, protocol: PortMappingProtocol, external_port: u16) -> Action {
+        Action {
+            header: messages::format_action_header(service_type, "DeletePortMapping"),
+            body: messages::format_delete_port_message(
+                service_type,
+                &messages::standard_arguments("DeletePortMapping"),
+                protocol,
+                external_port,
+            ),
+        }
+    }
+}
+
+/// Format the request of the description at `location`.
+///
+/// HTTP/1.0 is used here and by `format_action_request`, so the response isn't chunked.
+pub fn format_description_request(location: &Location) -> String {
+    format!(
+        "GET {} HTTP/1.0\r\n\
+         Host: {}\r\n\
+         Connection: close\r\n\
+         \r\n",
+        location.path, location.addr
+    )
+}
+
+/// Format the request of `action` to the control url at `location`, body included.
+pub fn format_action_request(location: &Location, action: &Action) -> String {
+    format!(
+        "POST {} HTTP/1.0\r\n\
+         Host: {}\r\n\
+         Content-Type: text/xml; charset=\"utf-8\"\r\n\
+         SOAPAction: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        location.path,
+        location.addr,
+        action.header,
+        action.body.len(),
+        action.body
+    )
+}
+
+/// Status and body of an HTTP response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Response<'a> {
+    /// HTTP status, 200 for success and 500 for SOAP faults
+    pub status: u16,
+    /// The body, the description or the SOAP envelope
+    pub body: &'a str,
+}
+
+impl Response<'_> {
+    /// The UPnP error code of a SOAP fault, e.g. 718 when the port is mapped already.
+    pub fn error_code(&self) -> Option<u16> {
+        response_value(self.body, "errorCode")?.parse().ok()
+    }
+}
+
+/// Parse the response to a request, once all of it was received.
+///
+/// Returns `None` while the headers, or the `Content-Length` bytes of body after them, are
+/// incomplete, or if the data is not an HTTP response. Without `Content-Length`, the body is what
+/// came until the gateway closed the connection, which it does after answering.
+pub fn parse_response(data: &[u8]) -> Option<Response<'_>> {
+    let end = data.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = str::from_utf8(&data[..end]).ok()?;
+    let mut body = &data[end + 4..];
+    let mut lines = head.split("\r\n");
+    let mut status = lines.next()?.split_whitespace();
+    if !status.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    let status = status.next()?.parse().ok()?;
+    let length = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("content-length").then_some(value)
+    });
+    if let Some(length) = length {
+        body = body.get(..length.trim().parse().ok()?)?;
+    }
+    Some(Response {
+        status,
+        body: str::from_utf8(body).ok()?,
+    })
+}
+
+/// The text of the first element named `name`, in any namespace, e.g. `NewExternalIPAddress` in
+/// the response to `Action::get_external_ip`.
+pub fn response_value(body: &str, name: &str) -> Option<String> {
+    find_element(body, name).map(|content| unescape(content).into_owned())
+}
+
+/// Markup other than elements, by how it starts and ends.
+const MARKUP: &[(&str, &str)] = &[("<!--", "-->"), ("<![CDATA[", "]]>"), ("<?", "?>"), ("<!", ">")];
+
+/// The child elements of `xml`, as their local name, without namespace prefix, and their raw
+/// content, in document order. Text, comments and the like between them are skipped.
+///
+/// This borrows from `xml` rather than building a tree, the rest of the crate parses the SOAP
+/// responses with it too.
+pub(crate) fn children(xml: &str) -> impl Iterator<Item = (&str, &str)> {
+    let mut rest = xml;
+    core::iter::from_fn(move || loop {
+        rest = &rest[rest.find('<')?..];
+        let end = match MARKUP.iter().find(|(open, _)| rest.starts_with(open)) {
+            Some((_, close)) => rest.find(close)? + close.len(),
+            // An end tag without start tag.
+            None if rest.starts_with("</") => rest.find('>')? + 1,
+            None => {
+                let tag_end = rest.find('>')?;
+                let tag = &rest[1..tag_end];
+                let name = tag
+                    .split(|c: char| c.is_whitespace() || c == '/')
+                    .next()
+                    .unwrap_or_default();
+                let local_name = name.rsplit(':').next().unwrap_or_default();
+                rest = &rest[tag_end + 1..];
+                if tag.ends_with('/') {
+                    return Some((local_name, ""));
+                }
+                let (content, after) = split_content(rest, name)?;
+                rest = after;
+                return Some((local_name, content));
+            }
+        };
+        rest = &rest[end..];
+    })
+}
+
+/// Split what follows the start tag of an element `name` into its content and what follows its
+/// end tag.
+fn split_content<'a>(xml: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let mut depth = 0;
+    let mut pos = 0;
+    loop {
+        let tag = pos + xml[pos..].find('<')?;
+        let after = &xml[tag + 1..];
+        let tag_end = after.find('>')?;
+        match after.strip_prefix('/') {
+            Some(closing) if closing[..tag_end - 1].trim_end() == name => {
+                if depth == 0 {
+                    return Some((&xml[..tag], &after[tag_end + 1..]));
+                }
+                depth -= 1;
+            }
+            Some(_) => {}
+            // Another element of the same name inside this one.
+            None if after.starts_with(name)
+                && after[name.len()..].starts_with(|c: char| c.is_whitespace() || c == '>')
+                && !after[..tag_end].ends_with('/') =>
+            {
+                depth += 1
+            }
+            None => {}
+        }
+        pos = tag + 1;
+    }
+}
+
+/// The content of the child element of `xml` named `name`.
+pub(crate) fn child<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    children(xml)
+        .find(|(child, _)| *child == name)
+        .map(|(_, content)| content)
+}
+
+/// The content of the first element named `name` in `xml`, at any depth.
+pub(crate) fn find_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    children(xml).find_map(|(child, content)| match child == name {
+        true => Some(content),
+        false => find_element(content, name),
+    })
+}
+
+/// The content of every element named `name` in `xml`, at any depth, in document order.
+fn find_elements<'a>(xml: &'a str, name: &str, found: &mut Vec<&'a str>) {
+    for (child, content) in children(xml) {
+        if child == name {
+            found.push(content);
+        } else {
+            find_elements(content, name, found);
+        }
+    }
+}
+
+/// The text of an element's content, trimmed, with the character references replaced and CDATA
+/// sections unwrapped. It is only copied if it has any of them.
+pub(crate) fn unescape(text: &str) -> Cow<'_, str> {
+    let text = text.trim();
+    if !text.contains('&') && !text.contains("<![CDATA[") {
+        return Cow::Borrowed(text);
+    }
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(['&', '<']) {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").unwrap_or(cdata.len());
+            unescaped.push_str(&cdata[..end]);
+            rest = cdata.get(end + 3..).unwrap_or_default();
+            continue;
+        }
+        let reference = rest
+            .strip_prefix('&')
+            .and_then(|rest| rest.find(';').map(|semicolon| &rest[..semicolon]));
+        let c = reference.and_then(|reference| match reference {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match reference.strip_prefix("#x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => reference.strip_prefix('#')?.parse().ok().and_then(char::from_u32),
+            },
+        });
+        match (c, reference) {
+            (Some(c), Some(reference)) => {
+                unescaped.push(c);
+                rest = &rest[reference.len() + 2..];
+            }
+            // Not a reference, or a stray `<`, keep it as it is.
+            _ => {
+                unescaped.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    Cow::Owned(unescaped)
+}
+
+#[test]
+fn test_search() {
+    let request = format_search_request(INTERNET_GATEWAY_DEVICE, 2);
+    assert!(request.starts_with("M-SEARCH * HTTP/1.1\r\n"));
+    assert!(
+        request.contains("ST:urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nMan:\"ssdp:discover\"\r\nMX:2\r\n")
+    );
+    assert!(request.ends_with("\r\n\r\n"));
+
+    let location = parse_location(
+        b"HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n",
+    );
+    assert_eq!(
+        location,
+        Some(Location {
+            addr: "192.168.1.1:5000".parse().unwrap(),
+            path: "/rootDesc.xml".to_string(),
+        })
+    );
+    assert_eq!(
+        parse_location(b"NOTIFY * HTTP/1.1\r\nLOCATION: http://192.168.1.1/\r\n\r\n"),
+        None
+    );
+    assert_eq!(
+        parse_location(b"HTTP/1.1 200 OK\r\nLOCATION: http://router.local/\r\n\r\n"),
+        None
+    );
+}
+
+#[test]
+fn test_headers() {
+    let headers = Headers::parse(
+        b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\nServer: caf\xe9/1.0\r\nst:upnp:rootdevice \r\nbroken\r\n\r\nLOCATION: body\r\n",
+    );
+    assert_eq!(headers.start_line(), Some("HTTP/1.1 200 OK"));
+    assert!(headers.is_success());
+    assert_eq!(headers.get("cache-control"), Some("max-age=120"));
+    assert_eq!(headers.get("ST"), Some("upnp:rootdevice"));
+    assert_eq!(headers.get("server"), None);
+    assert_eq!(headers.get("location"), None);
+    assert_eq!(headers.iter().count(), 2);
+
+    assert!(!Headers::parse(b"NOTIFY * HTTP/1.1\r\n\r\n").is_success());
+    assert!(!Headers::parse(b"HTTP/1.1 404 Not Found\r\n\r\n").is_success());
+    assert!(!Headers::parse(b"").is_success());
+}
+
+#[test]
+fn test_location() {
+    let location = Location::parse("http://192.168.1.1").unwrap();
+    assert_eq!(location.addr, "192.168.1.1:80".parse().unwrap());
+    assert_eq!(location.path, "/");
+    assert_eq!(Location::parse("https://192.168.1.1/"), None);
+
+    let location = Location::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+    assert_eq!(location.resolve("/ctl/IPConn").unwrap().path, "/ctl/IPConn");
+    assert_eq!(location.resolve("ctl/IPConn").unwrap().path, "/ctl/IPConn");
+    let absolute = location.resolve("http://192.168.1.1:5001/ctl/IPConn").unwrap();
+    assert_eq!(absolute.addr, "192.168.1.1:5001".parse().unwrap());
+}
+
+#[test]
+fn test_find_connection_service() {
+    let description = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device><serviceList>
+<service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType><controlURL>/ctl/L3F</controlURL></service>
+</serviceList>
+<deviceList><device><deviceList><device><serviceList>
+<service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType><controlURL>/ctl/PPPConn</controlURL></service>
+<service>
+  <serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>
+  <controlURL>/ctl/IPConn?a=1&amp;b=2</controlURL>
+</service>
+</serviceList></device></deviceList></device></deviceList>
+</device></root>"#;
+    assert_eq!(
+        find_connection_service(description),
+        Some(Service {
+            service_type: "urn:schemas-upnp-org:service:WANIPConnection:2".to_string(),
+            control_url: "/ctl/IPConn?a=1&b=2".to_string(),
+        })
+    );
+    assert_eq!(find_connection_service("<root/>"), None);
+}
+
+#[test]
+fn test_action_request() {
+    let location = Location::parse("http://192.168.1.1:5000/ctl/IPConn").unwrap();
+    let action = Action::delete_port_mapping(messages::WAN_IP_CONNECTION_SERVICE, PortMappingProtocol::UDP, 9000);
+    let request = format_action_request(&location, &action);
+    assert!(request.starts_with("POST /ctl/IPConn HTTP/1.0\r\nHost: 192.168.1.1:5000\r\n"));
+    assert!(request.contains("SOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMapping\"\r\n"));
+    assert!(request.contains(&format!("Content-Length: {}\r\n", action.body.len())));
+    assert!(request.ends_with(&action.body));
+    assert!(action.body.contains("<NewExternalPort>9000</NewExternalPort>"));
+
+    let request = format_description_request(&location);
+    assert_eq!(
+        request,
+        "GET /ctl/IPConn HTTP/1.0\r\nHost: 192.168.1.1:5000\r\nConnection: close\r\n\r\n"
+    );
+}
+
+#[test]
+fn test_parse_response() {
+    let body = "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                <NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>\
+                </u:GetExternalIPAddressResponse></s:Body></s:Envelope>";
+    let data = format!("HTTP/1.1 200 OK\r\nCONTENT-LENGTH: {}\r\n\r\n{}", body.len(), body);
+    assert_eq!(parse_response(&data.as_bytes()[..20]), None);
+    assert_eq!(parse_response(&data.as_bytes()[..data.len() - 1]), None);
+    let response = parse_response(data.as_bytes()).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, body);
+
+    let data = b"HTTP/1.0 200 OK\r\n\r\n<u:R><NewExternalIPAddress>203.0.113.1</NewExternalIPAddress><Empty/></u:R>";
+    let response = parse_response(data).unwrap();
+    assert_eq!(
+        response_value(response.body, "NewExternalIPAddress").as_deref(),
+        Some("203.0.113.1")
+    );
+    assert_eq!(response_value(response.body, "Empty").as_deref(), Some(""));
+    assert_eq!(response.error_code(), None);
+
+    let data = b"HTTP/1.1 500 Internal Server Error\r\n\r\n<s:Fault><detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>718</errorCode><errorDescription>Conflict&#x20;in &lt;mapping&gt; &bogus</errorDescription></UPnPError></detail></s:Fault>";
+    let response = parse_response(data).unwrap();
+    assert_eq!(response.status, 500);
+    assert_eq!(response.error_code(), Some(718));
+    assert_eq!(
+        response_value(response.body, "errorDescription").as_deref(),
+        Some("Conflict in <mapping> &bogus")
+    );
+}
+
+#[test]
+fn test_children() {
+    let xml = r#"<?xml version="1.0"?><!-- a <comment> --><s:Envelope xmlns:s="urn:x">
+<s:Body><u:R><A>1</A><B x="/"/><B><B>nested</B></B><C><![CDATA[<not an element>]]> &amp; more</C></u:R></s:Body>
+</s:Envelope>trailing"#;
+    let root: Vec<_> = children(xml).map(|(name, _)| name).collect();
+    assert_eq!(root, ["Envelope"]);
+    let response = child(child(find_element(xml, "Envelope").unwrap(), "Body").unwrap(), "R").unwrap();
+    let names: Vec<_> = children(response).map(|(name, _)| name).collect();
+    assert_eq!(names, ["A", "B", "B", "C"]);
+    assert_eq!(children(response).nth(2).unwrap().1, "<B>nested</B>");
+    assert_eq!(unescape(child(response, "A").unwrap()), Cow::Borrowed("1"));
+    assert_eq!(unescape(child(response, "C").unwrap()), "<not an element> & more");
+    assert_eq!(find_element(xml, "Missing"), None);
+    assert_eq!(children("<A>unterminated").next(), None);
+}
@@ -0,0 +1,347 @@
+//! Workarounds for known router firmware bugs.
+//!
+//! After discovery, the device description and the `SERVER` header of the search response are
+//! matched against a table of rules. The quirks of every matching rule are stored on the
+//! `Gateway` and applied to the requests it sends.
+//!
+//! Rules for devices this crate doesn't know about can be added with `register`.
+
+use std::borrow::Cow;
+use std::sync::Mutex;
+
+use crate::common::messages;
+use crate::common::parsing::DeviceInfo;
+use crate::common::RequestFormat;
+
+/// Firmware bugs that the requests sent to a gateway have to work around.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// The gateway rejects any lease duration other than 0 (permanent).
+    pub only_permanent_leases: bool,
+    /// The gateway rejects port mapping descriptions longer than this many bytes.
+    pub max_description_len: Option<usize>,
+    /// The gateway requires `NewRemoteHost` to be sent, even when its SCPD doesn't list it.
+    pub needs_empty_remote_host: bool,
+    /// The gateway rejects leases longer than this many seconds.
+    pub max_lease_duration: Option<u32>,
+    /// The gateway's HTTP server only handles HTTP/1.0 requests.
+    pub http_1_0: bool,
+    /// The gateway only maps an external port to the same internal port.
+    pub same_port_only: bool,
+    /// The gateway reads the arguments in the order of the UPnP specification, whatever order its SCPD lists.
+    pub standard_argument_order: bool,
+}
+
+impl Quirks {
+    /// Combine two sets of quirks, keeping the stricter value of each.
+    pub fn merge(self, other: Quirks) -> Quirks {
+        fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+
+        Quirks {
+            only_permanent_leases: self.only_permanent_leases || other.only_permanent_leases,
+            max_description_len: min(self.max_description_len, other.max_description_len),
+            needs_empty_remote_host: self.needs_empty_remote_host || other.needs_empty_remote_host,
+            max_lease_duration: min(self.max_lease_duration, other.max_lease_duration),
+            http_1_0: self.http_1_0 || other.http_1_0,
+            same_port_only: self.same_port_only || other.same_port_only,
+            standard_argument_order: self.standard_argument_order || other.standard_argument_order,
+        }
+    }
+
+    /// The request format to use for a gateway with these quirks.
+    pub fn request_format(&self) -> RequestFormat {
+        RequestFormat {
+            http_1_0: self.http_1_0,
+            ..Default::default()
+        }
+    }
+
+    /// Adjust a requested lease duration to what the gateway accepts.
+    pub fn lease_duration(&self, lease_duration: u32) -> u32 {
+        if self.only_permanent_leases {
+            return 0;
+        }
+        match self.max_lease_duration {
+            Some(max) if lease_duration > max => max,
+            _ => lease_duration,
+        }
+    }
+
+    /// Truncate a description to what the gateway accepts.
+    pub fn description<'a>(&self, description: &'a str) -> &'a str {
+        match self.max_description_len {
+            Some(max) if description.len() > max => {
+                let mut end = max;
+                while !description.is_char_boundary(end) {
+                    end -= 1;
+                }
+                &description[..end]
+            }
+            _ => description,
+        }
+    }
+
+    pub(crate) fn schema<'a>(&self, action: &str, schema: &'a [String]) -> Cow<'a, [String]> {
+        let mut schema = Cow::Borrowed(schema);
+        if self.needs_empty_remote_host && !schema.iter().any(|argument| argument == "NewRemoteHost") {
+            schema.to_mut().insert(0, "NewRemoteHost".to_string());
+        }
+        if self.standard_argument_order {
+            // Arguments the specification doesn't list keep their place after the standard ones.
+            let standard = messages::standard_arguments(action);
+            let position = |argument: &String| standard.iter().position(|a| a == argument).unwrap_or(standard.len());
+            if !schema.windows(2).all(|pair| position(&pair[0]) <= position(&pair[1])) {
+                schema.to_mut().sort_by_key(position);
+            }
+        }
+        schema
+    }
+}
+
+/// A rule matching devices by their description and search response.
+///
+/// Every field that is set must be contained in the corresponding device value, ignoring case.
+/// A rule without any field set matches nothing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QuirkRule {
+    /// Substring of the device manufacturer
+    pub manufacturer: Option<String>,
+    /// Substring of the device model name
+    pub model_name: Option<String>,
+    /// Substring of the `SERVER` header of the search response
+    pub server: Option<String>,
+    /// Quirks applied to matching devices
+    pub quirks: Quirks,
+}
+
+impl QuirkRule {
+    /// Check whether the rule matches the given device.
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        fn contains(value: &str, pattern: &Option<String>) -> bool {
+            match *pattern {
+                Some(ref pattern) => value.to_ascii_lowercase().contains(&pattern.to_ascii_lowercase()),
+                None => true,
+            }
+        }
+
+        (self.manufacturer.is_some() || self.model_name.is_some() || self.server.is_some())
+            && contains(&info.manufacturer, &self.manufacturer)
+            && contains(&info.model_name, &self.model_name)
+            && contains(&info.server, &self.server)
+    }
+}
+
+struct BuiltinRule {
+    manufacturer: Option<&'static str>,
+    model_name: Option<&'static str>,
+    server: Option<&'static str>,
+    quirks: Quirks,
+}
+
+const NO_QUIRKS: Quirks = Quirks {
+    only_permanent_leases: false,
+    max_description_len: None,
+    needs_empty_remote_host: false,
+    max_lease_duration: None,
+    http_1_0: false,
+    same_port_only: false,
+    standard_argument_order: false,
+};
+
+const BUILTIN_RULES: &[BuiltinRule] = &[
+    BuiltinRule {
+        manufacturer: Some("Linksys"),
+        model_name: Some("WRT54G"),
+        server: None,
+        quirks: Quirks {
+            only_permanent_leases: true,
+            ..NO_QUIRKS
+        },
+    },
+    BuiltinRule {
+        manufacturer: Some("Thomson"),
+        model_name: Some("SpeedTouch"),
+        server: None,
+        quirks: Quirks {
+            max_description_len: Some(32),
+            ..NO_QUIRKS
+        },
+    },
+    // Windows Internet Connection Sharing
+    BuiltinRule {
+        manufacturer: Some("Microsoft"),
+        model_name: None,
+        server: None,
+        quirks: Quirks {
+            needs_empty_remote_host: true,
+            ..NO_QUIRKS
+        },
+    },
+    BuiltinRule {
+        manufacturer: Some("Technicolor"),
+        model_name: None,
+        server: None,
+        quirks: Quirks {
+            max_lease_duration: Some(86400),
+            ..NO_QUIRKS
+        },
+    },
+    BuiltinRule {
+        manufacturer: Some("Huawei"),
+        model_name: Some("HG8"),
+        server: None,
+        quirks: Quirks {
+            same_port_only: true,
+            ..NO_QUIRKS
+        },
+    },
+    BuiltinRule {
+        manufacturer: None,
+        model_name: None,
+        server: Some("RomPager"),
+        quirks: Quirks {
+            http_1_0: true,
+            standard_argument_order: true,
+            ..NO_QUIRKS
+        },
+    },
+];
+
+static CUSTOM_RULES: Mutex<Vec<QuirkRule>> = Mutex::new(Vec::new());
+
+/// Register a custom rule, used for all gateways discovered from now on.
+///
+/// # Example
+/// ```
+/// use igd::quirks::{self, QuirkRule, Quirks};
+///
+/// quirks::register(QuirkRule {
+///     manufacturer: Some("ACME".into()),
+///     quirks: Quirks {
+///         max_lease_duration: Some(86400),
+///         ..Default::default()
+///     },
+///     ..Default::default()
+/// });
+/// ```
+pub fn register(rule: QuirkRule) {
+    CUSTOM_RULES.lock().unwrap_or_else(|e| e.into_inner()).push(rule);
+}
+
+/// Look up the quirks of a device in the builtin and custom rules.
+pub fn lookup(info: &DeviceInfo) -> Quirks {
+    let builtin = BUILTIN_RULES
+        .iter()
+        .map(|rule| QuirkRule {
+            manufacturer: rule.manufacturer.map(Into::into),
+            model_name: rule.model_name.map(Into::into),
+            server: rule.server.map(Into::into),
+            quirks: rule.quirks,
+        })
+        .filter(|rule| rule.matches(info))
+        .fold(Quirks::default(), |quirks, rule| quirks.merge(rule.quirks));
+
+    CUSTOM_RULES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|rule| rule.matches(info))
+        .fold(builtin, |quirks, rule| quirks.merge(rule.quirks))
+}
+
+#[test]
+fn test_lookup() {
+    let info = DeviceInfo {
+        manufacturer: "Linksys Inc.".into(),
+        model_name: "WRT54GL".into(),
+        ..Default::default()
+    };
+    assert!(lookup(&info).only_permanent_leases);
+    assert_eq!(lookup(&DeviceInfo::default()), Quirks::default());
+    assert_eq!(NO_QUIRKS, Quirks::default());
+}
+
+#[test]
+fn test_lookup_description_len() {
+    let info = DeviceInfo {
+        manufacturer: "THOMSON".into(),
+        model_name: "SpeedTouch 585".into(),
+        ..Default::default()
+    };
+    assert_eq!(lookup(&info).max_description_len, Some(32));
+}
+
+#[test]
+fn test_lookup_empty_remote_host() {
+    let info = DeviceInfo {
+        manufacturer: "Microsoft Corporation".into(),
+        model_name: "Internet Connection Sharing".into(),
+        ..Default::default()
+    };
+    assert!(lookup(&info).needs_empty_remote_host);
+}
+
+#[test]
+fn test_lookup_lease_cap() {
+    let info = DeviceInfo {
+        manufacturer: "Technicolor".into(),
+        model_name: "TG789vac".into(),
+        ..Default::default()
+    };
+    let quirks = lookup(&info);
+    assert_eq!(quirks.max_lease_duration, Some(86400));
+    assert_eq!(quirks.lease_duration(604800), 86400);
+}
+
+#[test]
+fn test_lookup_same_port() {
+    let info = DeviceInfo {
+        manufacturer: "Huawei Technologies Co., Ltd".into(),
+        model_name: "HG8245H".into(),
+        ..Default::default()
+    };
+    assert!(lookup(&info).same_port_only);
+}
+
+#[test]
+fn test_lookup_argument_order() {
+    let info = DeviceInfo {
+        server: "Allegro-Software-RomPager/4.07 UPnP/1.0".into(),
+        ..Default::default()
+    };
+    let quirks = lookup(&info);
+    assert!(quirks.http_1_0);
+    assert!(quirks.standard_argument_order);
+
+    let schema: Vec<String> = ["NewProtocol", "NewExternalPort", "NewRemoteHost", "NewVendorFlag"]
+        .iter()
+        .map(|argument| argument.to_string())
+        .collect();
+    assert_eq!(
+        quirks.schema("DeletePortMapping", &schema).as_ref(),
+        ["NewRemoteHost", "NewExternalPort", "NewProtocol", "NewVendorFlag"]
+    );
+}
+
+#[test]
+fn test_apply() {
+    let quirks = Quirks {
+        max_description_len: Some(4),
+        max_lease_duration: Some(86400),
+        needs_empty_remote_host: true,
+        ..Default::default()
+    };
+    assert_eq!(quirks.description("rust-igd"), "rust");
+    assert_eq!(quirks.description("äöü"), "äö");
+    assert_eq!(quirks.lease_duration(604800), 86400);
+    assert_eq!(quirks.lease_duration(0), 0);
+    assert_eq!(
+        quirks.schema("DeletePortMapping", &["NewProtocol".to_string()])[0],
+        "NewRemoteHost"
+    );
+}
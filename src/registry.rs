@@ -0,0 +1,377 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::common::{self, SearchOptions};
+use crate::search;
+use crate::ssdp::{Notification, NotifyListener};
+use crate::Gateway;
+
+/// How long an `ssdp:alive` announcement without `CACHE-CONTROL` is valid, the minimum the
+/// UPnP device architecture allows.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(1800);
+
+/// How often the background thread checks whether it was stopped while it listens.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A gateway held by a `GatewayRegistry`.
+#[derive(Clone, Debug)]
+pub struct RegisteredGateway {
+    /// The gateway, as of the last search that found it
+    pub gateway: Gateway,
+    /// When the gateway was first found
+    pub first_seen: Instant,
+    /// When the gateway last answered a search or announced itself
+    pub last_seen: Instant,
+    /// When the gateway is dropped unless it is seen again
+    pub expires: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    /// Live gateways by UDN
+    gateways: HashMap<String, RegisteredGateway>,
+    stopped: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Keeps the set of live gateways up to date on a background thread.
+///
+/// The gateways are searched every `interval`, and the `ssdp:alive` and `ssdp:byebye`
+/// announcements are listened to in between, so gateways joining or leaving are noticed
+/// quickly. A gateway is dropped when it says goodbye, or when it is neither found by two
+/// searches in a row nor announces itself again before its last announcement expires.
+///
+/// The registry can be shared, e.g. in an `Arc`, so the parts of an application needing a
+/// gateway get one without each searching on its own. The thread stops when the registry is
+/// stopped or dropped.
+///
+/// # Example
+/// ```no_run
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// use igd::GatewayRegistry;
+///
+/// let registry = Arc::new(GatewayRegistry::spawn(Default::default(), Duration::from_secs(300)).unwrap());
+/// let gateway = registry.wait_for_gateway(Duration::from_secs(10)).unwrap();
+/// println!("External IP address: {}", gateway.get_external_ip().unwrap());
+/// for registered in registry.gateways() {
+///     println!("{} last seen {:?} ago", registered.gateway, registered.last_seen.elapsed());
+/// }
+/// ```
+pub struct GatewayRegistry {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl GatewayRegistry {
+    /// Search the gateways with `options` every `interval`, and listen to their announcements
+    /// on port 1900 in between.
+    ///
+    /// This fails if the announcements can't be listened to, see `NotifyListener::bind`.
+    pub fn spawn(options: SearchOptions, interval: Duration) -> io::Result<GatewayRegistry> {
+        Ok(GatewayRegistry::spawn_with_listener(
+            options,
+            interval,
+            Some(NotifyListener::bind()?),
+        ))
+    }
+
+    /// Search the gateways with `options` every `interval`, and listen to their announcements
+    /// with `listener` in between, if given.
+    pub fn spawn_with_listener(
+        options: SearchOptions,
+        interval: Duration,
+        listener: Option<NotifyListener>,
+    ) -> GatewayRegistry {
+        let shared = Arc::new(Shared::default());
+        let mut worker = Worker {
+            shared: shared.clone(),
+            options,
+            interval,
+            listener,
+            rejected: HashSet::new(),
+        };
+        GatewayRegistry {
+            shared,
+            thread: Some(thread::spawn(move || worker.run())),
+        }
+    }
+
+    /// The live gateways, in the order they were found.
+    pub fn gateways(&self) -> Vec<RegisteredGateway> {
+        let mut gateways: Vec<_> = self.shared.lock().gateways.values().cloned().collect();
+        gateways.sort_by_key(|registered| registered.first_seen);
+        gateways
+    }
+
+    /// The live gateway found first, if any.
+    pub fn gateway(&self) -> Option<Gateway> {
+        first(&self.shared.lock())
+    }
+
+    /// The live gateway with the given UDN, if any.
+    pub fn get(&self, udn: &str) -> Option<Gateway> {
+        self.shared
+            .lock()
+            .gateways
+            .get(udn)
+            .map(|registered| registered.gateway.clone())
+    }
+
+    /// Wait up to `timeout` for a gateway to be live, returning the one found first.
+    pub fn wait_for_gateway(&self, timeout: Duration) -> Option<Gateway> {
+        let guard = self.shared.lock();
+        let (guard, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(guard, timeout, |state| state.gateways.is_empty() && !state.stopped)
+            .unwrap_or_else(|e| e.into_inner());
+        first(&guard)
+    }
+
+    /// Stop keeping the gateways up to date and wait for the background thread to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for GatewayRegistry {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn first(state: &State) -> Option<Gateway> {
+    state
+        .gateways
+        .values()
+        .min_by_key(|registered| registered.first_seen)
+        .map(|registered| registered.gateway.clone())
+}
+
+struct Worker {
+    shared: Arc<Shared>,
+    options: SearchOptions,
+    interval: Duration,
+    listener: Option<NotifyListener>,
+    /// Locations announced by devices that turned out not to be gateways, so their description
+    /// isn't fetched on each announcement. Gateways among them are still found by searching.
+    rejected: HashSet<String>,
+}
+
+impl Worker {
+    fn run(&mut self) {
+        let mut next_search = Instant::now();
+        loop {
+            if self.shared.lock().stopped {
+                return;
+            }
+            if Instant::now() >= next_search {
+                self.search();
+                next_search = Instant::now() + self.interval;
+            }
+
+            let now = Instant::now();
+            let wake = self.expire(now).map_or(next_search, |expires| expires.min(next_search));
+            let wait = wake.saturating_duration_since(now);
+            match self.listener {
+                Some(ref listener) => match listener.recv(Some(wait.min(STOP_CHECK_INTERVAL))) {
+                    Ok(Some(notification)) => self.handle(&notification),
+                    Ok(None) => {}
+                    Err(e) => {
+                        debug!("listening to announcements failed, only searching from now on: {}", e);
+                        self.listener = None;
+                    }
+                },
+                None => {
+                    let guard = self.shared.lock();
+                    let _ = self
+                        .shared
+                        .changed
+                        .wait_timeout_while(guard, wait, |state| !state.stopped)
+                        .unwrap_or_else(|e| e.into_inner());
+                }
+            }
+        }
+    }
+
+    fn search(&mut self) {
+        match search::search_multi_gateways(self.options.clone()) {
+            Ok(gateways) => {
+                let expires = Instant::now() + self.interval * 2 + self.options.timeout.unwrap_or_default();
+                for gateway in gateways {
+                    self.seen(gateway, expires);
+                }
+            }
+            Err(e) => debug!("searching the gateways failed: {}", e),
+        }
+    }
+
+    /// Drop the expired gateways, returning when the next one expires.
+    fn expire(&mut self, now: Instant) -> Option<Instant> {
+        let mut state = self.shared.lock();
+        let count = state.gateways.len();
+        state.gateways.retain(|_, registered| {
+            if registered.expires <= now {
+                debug!("{} expired", registered.gateway);
+            }
+            registered.expires > now
+        });
+        if state.gateways.len() != count {
+            self.shared.changed.notify_all();
+        }
+        state.gateways.values().map(|registered| registered.expires).min()
+    }
+
+    fn handle(&mut self, notification: &Notification) {
+        let udn = match notification.udn() {
+            Some(udn) => udn,
+            None => return,
+        };
+
+        if notification.is_byebye() {
+            if let Some(registered) = self.shared.lock().gateways.remove(udn) {
+                debug!("{} said goodbye", registered.gateway);
+                self.shared.changed.notify_all();
+            }
+        } else if notification.is_alive() {
+            let now = Instant::now();
+            let expires = now + notification.max_age().unwrap_or(DEFAULT_MAX_AGE);
+            if let Some(registered) = self.shared.lock().gateways.get_mut(udn) {
+                registered.last_seen = now;
+                registered.expires = registered.expires.max(expires);
+                return;
+            }
+
+            let location = match notification.location() {
+                Some(location) if !self.rejected.contains(location) => location.to_string(),
+                _ => return,
+            };
+            match search::get_announced_gateway(notification, self.options.url_policy) {
+                Ok(mut gateway) => {
+                    gateway.local_addr = common::local_ip_towards(gateway.addr)
+                        .ok()
+                        .map(|ip| SocketAddrV4::new(ip, 0));
+                    self.seen(gateway, expires);
+                }
+                Err(e) => {
+                    debug!("{} is not a gateway: {}", location, e);
+                    self.rejected.insert(location);
+                }
+            }
+        }
+    }
+
+    fn seen(&self, gateway: Gateway, expires: Instant) {
+        let now = Instant::now();
+        let mut state = self.shared.lock();
+        let udn = gateway.device_info.udn.clone();
+        match state.gateways.get_mut(&udn) {
+            Some(registered) => {
+                registered.gateway = gateway;
+                registered.last_seen = now;
+                registered.expires = registered.expires.max(expires);
+            }
+            None => {
+                debug!("found {}", gateway);
+                state.gateways.insert(
+                    udn,
+                    RegisteredGateway {
+                        gateway,
+                        first_seen: now,
+                        last_seen: now,
+                        expires,
+                    },
+                );
+            }
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_gateway_registry() {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let listener_addr = socket.local_addr().unwrap();
+    let options = SearchOptions {
+        timeout: Some(Duration::from_millis(500)),
+        ..mock.search_options()
+    };
+    let registry = GatewayRegistry::spawn_with_listener(
+        options,
+        Duration::from_secs(60),
+        Some(NotifyListener::from_socket(socket)),
+    );
+
+    let gateway = registry.wait_for_gateway(Duration::from_secs(5)).unwrap();
+    let udn = gateway.device_info.udn.clone();
+    assert_eq!(registry.gateways().len(), 1);
+    assert_eq!(registry.get(&udn).unwrap().addr, gateway.addr);
+    assert!(registry.get("uuid:00000000-0000-0000-0000-0000000000ff").is_none());
+
+    let announce = |nts: &str| {
+        let message = format!(
+            "NOTIFY * HTTP/1.1\r\n\
+             HOST: 239.255.255.250:1900\r\n\
+             CACHE-CONTROL: max-age=1800\r\n\
+             LOCATION: http://{}/rootDesc.xml\r\n\
+             NT: upnp:rootdevice\r\n\
+             NTS: {}\r\n\
+             USN: {}::upnp:rootdevice\r\n\
+             \r\n",
+            mock.http_addr(),
+            nts,
+            udn
+        );
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .send_to(message.as_bytes(), listener_addr)
+            .unwrap();
+    };
+    let wait_until = |live: bool| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while registry.gateway().is_some() != live {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    announce("ssdp:byebye");
+    wait_until(false);
+    assert!(registry.gateways().is_empty());
+
+    announce("ssdp:alive");
+    wait_until(true);
+    let registered = &registry.gateways()[0];
+    assert_eq!(registered.gateway.device_info.udn, udn);
+    assert!(registered.expires > Instant::now() + Duration::from_secs(1700));
+
+    registry.stop();
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::backoff::{Backoff, ExponentialBackoff};
+
+/// When mappings are renewed, relative to their leases, and retried after failing.
+///
+/// It is used by `auto::AutoMapping` and `forward`, so embedders with their own timers, or
+/// limits on when the network may be used, e.g. on mobile, decide when the requests are sent.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use igd::RenewalScheduler;
+///
+/// /// Renew a minute before the lease ends, and retry every 10 seconds.
+/// #[derive(Debug)]
+/// struct LastMinute;
+///
+/// impl RenewalScheduler for LastMinute {
+///     fn renew_after(&self, lease: Duration) -> Duration {
+///         lease.saturating_sub(Duration::from_secs(60))
+///     }
+///
+///     fn retry_after(&self, _failures: u32) -> Option<Duration> {
+///         Some(Duration::from_secs(10))
+///     }
+/// }
+/// ```
+pub trait RenewalScheduler: fmt::Debug + Send + Sync {
+    /// How long after a mapping with a lease of `lease` was made to renew it.
+    fn renew_after(&self, lease: Duration) -> Duration;
+
+    /// How long after the renewal failed `failures` times in a row, counting from 0, to retry
+    /// it, or `None` to give up until the next scheduled renewal.
+    fn retry_after(&self, failures: u32) -> Option<Duration>;
+}
+
+/// Renews mappings after a fraction of their lease, spread randomly, and retries failures after
+/// the delays of a `Backoff`.
+#[derive(Clone, Debug)]
+pub struct FractionScheduler {
+    /// Fraction of the lease after which the mapping is renewed, from 0 to 1 (defaults to 0.5)
+    pub fraction: f64,
+    /// Fraction of the delay it is randomly made longer or shorter by, from 0 to 1, so mappings
+    /// made together aren't renewed together (defaults to 0)
+    pub jitter: f64,
+    /// Delays between the retries of a renewal that failed (defaults to an `ExponentialBackoff`
+    /// from 1 second up to 1 minute, without limit)
+    pub failure_backoff: Arc<dyn Backoff>,
+}
+
+impl Default for FractionScheduler {
+    fn default() -> Self {
+        Self {
+            fraction: 0.5,
+            jitter: 0.0,
+            failure_backoff: Arc::new(ExponentialBackoff {
+                initial: Duration::from_secs(1),
+                max: Duration::from_secs(60),
+                max_retries: None,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+impl RenewalScheduler for FractionScheduler {
+    fn renew_after(&self, lease: Duration) -> Duration {
+        let delay = lease.mul_f64(self.fraction.clamp(0.0, 1.0));
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        delay
+            .mul_f64(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter))
+            .min(lease)
+    }
+
+    fn retry_after(&self, failures: u32) -> Option<Duration> {
+        self.failure_backoff.delay(failures)
+    }
+}
+
+#[test]
+fn test_fraction_scheduler() {
+    let scheduler = FractionScheduler::default();
+    assert_eq!(
+        scheduler.renew_after(Duration::from_secs(3600)),
+        Duration::from_secs(1800)
+    );
+    assert_eq!(scheduler.renew_after(Duration::ZERO), Duration::ZERO);
+
+    let scheduler = FractionScheduler {
+        fraction: 0.8,
+        jitter: 0.5,
+        ..scheduler
+    };
+    for _ in 0..100 {
+        let delay = scheduler.renew_after(Duration::from_secs(100));
+        assert!(delay >= Duration::from_secs(40) && delay <= Duration::from_secs(100));
+    }
+    assert!(scheduler.retry_after(100).is_some());
+}
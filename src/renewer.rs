@@ -0,0 +1,347 @@
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::common::parsing::{MappedPort, PortMappingRequest};
+use crate::errors::{AddPortError, RemovePortError};
+use crate::renewal::{FractionScheduler, RenewalScheduler};
+use crate::{Gateway, PortMappingProtocol};
+
+/// Port mappings renewed on a background thread, for programs that don't use async.
+///
+/// Mappings are made on the calling thread by `add`, then renewed by the thread when the
+/// `RenewalScheduler` says so, until they are removed. `stop`, or dropping the renewer, ends
+/// the thread and removes the mappings that are left.
+///
+/// # Example
+/// ```no_run
+/// use std::net::SocketAddrV4;
+/// use igd::{PortMappingProtocol, PortMappingRequest, Renewer};
+///
+/// let gateway = igd::search_gateway(Default::default()).unwrap();
+/// let local_ip = gateway.local_addr_hint().unwrap();
+/// let renewer = Renewer::new(gateway);
+/// for port in [8080, 8443] {
+///     renewer
+///         .add(PortMappingRequest {
+///             protocol: PortMappingProtocol::TCP,
+///             external_port: port,
+///             local_addr: SocketAddrV4::new(local_ip, port),
+///             lease_duration: 3600,
+///             description: "web".to_string(),
+///         })
+///         .unwrap();
+/// }
+/// for status in renewer.status() {
+///     println!("port {} renewed {:?}", status.mapped.external_port, status.next_renewal);
+/// }
+/// renewer.stop().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Renewer {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// The state of a mapping kept by a `Renewer`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenewalStatus {
+    /// The mapping as requested
+    pub request: PortMappingRequest,
+    /// The mapping as the gateway last made it
+    pub mapped: MappedPort,
+    /// When the mapping is renewed next, `None` if it is permanent
+    pub next_renewal: Option<Instant>,
+    /// Renewals that failed since the last one that succeeded
+    pub failures: u32,
+    /// Why the last renewal failed, if it did
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    gateway: Gateway,
+    scheduler: Arc<dyn RenewalScheduler>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    mappings: Vec<RenewalStatus>,
+    stopped: bool,
+}
+
+impl Renewer {
+    /// Renew mappings on `gateway` halfway through their leases, see `FractionScheduler`.
+    pub fn new(gateway: Gateway) -> Renewer {
+        Renewer::with_scheduler(gateway, Arc::new(FractionScheduler::default()))
+    }
+
+    /// Renew mappings on `gateway` when `scheduler` says so.
+    pub fn with_scheduler(gateway: Gateway, scheduler: Arc<dyn RenewalScheduler>) -> Renewer {
+        let shared = Arc::new(Shared {
+            gateway,
+            scheduler,
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.run())
+        };
+        Renewer {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// The gateway the mappings are made on.
+    pub fn gateway(&self) -> &Gateway {
+        &self.shared.gateway
+    }
+
+    /// Make a mapping, as `Gateway::map_port` does, and keep renewing it.
+    ///
+    /// A mapping of the renewer with the same protocol and external port is replaced.
+    pub fn add(&self, request: PortMappingRequest) -> Result<MappedPort, AddPortError> {
+        let mapped = self.shared.gateway.map_port(
+            request.protocol,
+            request.external_port,
+            request.local_addr,
+            request.lease_duration,
+            &request.description,
+        )?;
+        let mut state = self.shared.lock();
+        state.mappings.retain(|status| {
+            (status.request.protocol, status.mapped.external_port) != (request.protocol, mapped.external_port)
+        });
+        state.mappings.push(RenewalStatus {
+            next_renewal: self.shared.next_renewal(mapped),
+            request,
+            mapped,
+            failures: 0,
+            last_error: None,
+        });
+        self.shared.changed.notify_all();
+        Ok(mapped)
+    }
+
+    /// Stop renewing a mapping and remove it from the gateway.
+    pub fn remove(&self, protocol: PortMappingProtocol, external_port: u16) -> Result<(), RemovePortError> {
+        self.shared
+            .lock()
+            .mappings
+            .retain(|status| (status.request.protocol, status.mapped.external_port) != (protocol, external_port));
+        self.shared.changed.notify_all();
+        self.shared.gateway.remove_port(protocol, external_port)
+    }
+
+    /// The mappings kept by the renewer.
+    pub fn status(&self) -> Vec<RenewalStatus> {
+        self.shared.lock().mappings.clone()
+    }
+
+    /// Stop the background thread and remove the mappings.
+    ///
+    /// Every mapping is tried, the first error is returned.
+    pub fn stop(mut self) -> Result<(), RemovePortError> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), RemovePortError> {
+        let mappings = {
+            let mut state = self.shared.lock();
+            state.stopped = true;
+            mem::take(&mut state.mappings)
+        };
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let ports: Vec<_> = mappings
+            .iter()
+            .map(|status| (status.request.protocol, status.mapped.external_port))
+            .collect();
+        self.shared
+            .gateway
+            .remove_ports(&ports)
+            .into_iter()
+            .zip(ports)
+            .map(|(result, (protocol, external_port))| match result {
+                // Someone else removed it already.
+                Err(RemovePortError::NoSuchPortMapping) => Ok(()),
+                Err(e) => {
+                    debug!(
+                        "removing the mapping of {} port {} failed: {}",
+                        protocol, external_port, e
+                    );
+                    Err(e)
+                }
+                Ok(()) => Ok(()),
+            })
+            .fold(Ok(()), Result::and)
+    }
+}
+
+impl Drop for Renewer {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn next_renewal(&self, mapped: MappedPort) -> Option<Instant> {
+        match mapped.lease_duration {
+            // Permanent mappings don't need renewing.
+            0 => None,
+            lease => Some(Instant::now() + self.scheduler.renew_after(Duration::from_secs(lease.into()))),
+        }
+    }
+
+    /// Renew the mappings when they are due until stopped. The lock isn't held while the
+    /// gateway is asked, so the renewer can be used meanwhile.
+    fn run(&self) {
+        let mut state = self.lock();
+        loop {
+            if state.stopped {
+                return;
+            }
+            let now = Instant::now();
+            let due = state
+                .mappings
+                .iter()
+                .find(|status| status.next_renewal.is_some_and(|at| at <= now))
+                .map(|status| (status.request.clone(), status.mapped.external_port));
+            let (request, external_port) = match due {
+                Some(due) => due,
+                None => {
+                    let next = state.mappings.iter().filter_map(|status| status.next_renewal).min();
+                    state = match next {
+                        Some(next) => {
+                            self.changed
+                                .wait_timeout(state, next.saturating_duration_since(now))
+                                .unwrap_or_else(|e| e.into_inner())
+                                .0
+                        }
+                        None => self.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+                    };
+                    continue;
+                }
+            };
+            drop(state);
+
+            let result = self.gateway.map_port(
+                request.protocol,
+                external_port,
+                request.local_addr,
+                request.lease_duration,
+                &request.description,
+            );
+            #[cfg(feature = "prometheus")]
+            crate::metrics::record_renewals(result.is_ok() as usize, result.is_err() as usize);
+
+            state = self.lock();
+            let status = match state.mappings.iter_mut().find(|status| {
+                (status.request.protocol, status.mapped.external_port) == (request.protocol, external_port)
+            }) {
+                Some(status) => status,
+                // It was removed meanwhile, undo the renewal.
+                None => {
+                    drop(state);
+                    if result.is_ok() {
+                        let _ = self.gateway.remove_port(request.protocol, external_port);
+                    }
+                    state = self.lock();
+                    continue;
+                }
+            };
+            match result {
+                Ok(mapped) => {
+                    status.mapped = mapped;
+                    status.next_renewal = self.next_renewal(mapped);
+                    status.failures = 0;
+                    status.last_error = None;
+                }
+                Err(e) => {
+                    let delay = self.scheduler.retry_after(status.failures).unwrap_or_else(|| {
+                        self.scheduler
+                            .renew_after(Duration::from_secs(request.lease_duration.into()))
+                    });
+                    debug!(
+                        "renewing {} port {} failed, retrying in {:?}: {}",
+                        request.protocol, external_port, delay, e
+                    );
+                    status.next_renewal = Some(Instant::now() + delay);
+                    status.failures += 1;
+                    status.last_error = Some(e.to_string());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_renewer() {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[derive(Debug)]
+    struct Immediately;
+
+    impl RenewalScheduler for Immediately {
+        fn renew_after(&self, _lease: Duration) -> Duration {
+            Duration::from_millis(20)
+        }
+
+        fn retry_after(&self, _failures: u32) -> Option<Duration> {
+            Some(Duration::from_millis(20))
+        }
+    }
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    let renewer = Renewer::with_scheduler(gateway, Arc::new(Immediately));
+    let request = |external_port, lease_duration| PortMappingRequest {
+        protocol: PortMappingProtocol::TCP,
+        external_port,
+        local_addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080),
+        lease_duration,
+        description: "igd test".to_string(),
+    };
+    renewer.add(request(6000, 60)).unwrap();
+    renewer.add(request(6001, 0)).unwrap();
+    assert_eq!(mock.mappings().len(), 2);
+
+    // Renewed even though the gateway dropped it.
+    mock.clear_mappings();
+    let start = Instant::now();
+    while mock.mappings().is_empty() && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mappings = mock.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].external_port, 6000);
+
+    let status = renewer.status();
+    assert_eq!(status.len(), 2);
+    assert_eq!(
+        status
+            .iter()
+            .find(|status| status.mapped.external_port == 6001)
+            .unwrap()
+            .next_renewal,
+        None
+    );
+
+    renewer.remove(PortMappingProtocol::TCP, 6000).unwrap();
+    assert_eq!(renewer.status().len(), 1);
+    renewer.add(request(6002, 60)).unwrap();
+    renewer.stop().unwrap();
+    assert!(mock.mappings().is_empty());
+}
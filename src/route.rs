@@ -0,0 +1,100 @@
+//! The default route of this host, which leads to the gateway that is most likely wanted.
+//!
+//! Only Linux is supported, where the routing table is read from `/proc/net/route`. The routing
+//! socket or sysctl of macOS and the BSDs, and `GetBestRoute2` of Windows, aren't implemented:
+//! there `default_route` fails with an error of kind `Unsupported`.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use crate::common;
+
+/// The IPv4 default route.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DefaultRoute {
+    /// Address of the router
+    pub gateway: Ipv4Addr,
+    /// Name of the interface the router is reached through, e.g. `eth0`
+    pub interface: String,
+    /// Address of this host on that interface
+    pub local_addr: Ipv4Addr,
+}
+
+/// Find the IPv4 default route.
+///
+/// If there are several, the one with the lowest metric is returned.
+pub fn default_route() -> io::Result<DefaultRoute> {
+    let (gateway, interface) = default_gateway()?;
+    let local_addr = common::local_ip_towards(SocketAddrV4::new(gateway, 9))?;
+    Ok(DefaultRoute {
+        gateway,
+        interface,
+        local_addr,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn default_gateway() -> io::Result<(Ipv4Addr, String)> {
+    let table = std::fs::read_to_string("/proc/net/route")?;
+    parse_route_table(&table).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no IPv4 default route"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> io::Result<(Ipv4Addr, String)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading the routing table is not supported on this platform",
+    ))
+}
+
+/// Find the default gateway and its interface in the content of `/proc/net/route`.
+///
+/// Addresses are in hexadecimal, in the byte order of the host.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_route_table(table: &str) -> Option<(Ipv4Addr, String)> {
+    const RTF_UP: u32 = 0x1;
+    const RTF_GATEWAY: u32 = 0x2;
+
+    let hex = |field: &str| u32::from_str_radix(field, 16).ok();
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 {
+                return None;
+            }
+            let (destination, gateway, flags, metric, mask) = (
+                hex(fields[1])?,
+                hex(fields[2])?,
+                hex(fields[3])?,
+                fields[6].parse::<u32>().ok()?,
+                hex(fields[7])?,
+            );
+            if destination != 0 || mask != 0 || flags & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+                return None;
+            }
+            Some((metric, Ipv4Addr::from(gateway.to_ne_bytes()), fields[0].to_string()))
+        })
+        .min_by_key(|(metric, _, _)| *metric)
+        .map(|(_, gateway, interface)| (gateway, interface))
+}
+
+#[test]
+fn test_parse_route_table() {
+    let gateway = u32::from_ne_bytes([192, 168, 1, 1]);
+    let vpn = u32::from_ne_bytes([10, 8, 0, 1]);
+    let table = format!(
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+         tun0\t00000000\t{:08X}\t0003\t0\t0\t200\t00000000\t0\t0\t0\n\
+         wlan0\t00000000\t{:08X}\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+         eth0\t00000000\t{:08X}\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+         eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n",
+        vpn, gateway, gateway
+    );
+    assert_eq!(
+        parse_route_table(&table),
+        Some((Ipv4Addr::new(192, 168, 1, 1), "eth0".to_string()))
+    );
+    assert_eq!(parse_route_table(table.lines().next().unwrap()), None);
+}
@@ -1,12 +1,31 @@
-use std::collections::HashMap;
-use std::net::{SocketAddrV4, UdpSocket};
-use std::str;
-use std::time::Instant;
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::path::PathBuf;
+use std::str;
+#[cfg(not(target_os = "wasi"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(not(target_os = "wasi"))]
+use std::sync::Mutex;
+#[cfg(not(target_os = "wasi"))]
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::common::{messages, parsing, SearchOptions};
+use crate::backoff::Backoff;
+#[cfg(feature = "cassette")]
+use crate::cassette;
+use crate::common::{
+    self, cache, parsing, parsing::Description, parsing::DeviceInfo, DiscoveryTiming, GatewayFilter, SearchOptions,
+    UrlPolicy,
+};
+use crate::deadline::Deadline;
+use crate::description::{self, RootDescription};
 use crate::errors::SearchError;
 use crate::gateway::Gateway;
+use crate::proto::Headers;
+use crate::quirks;
+use crate::ssdp::Notification;
 
 /// Search gateway, using the given `SearchOptions`.
 ///
@@ -25,48 +44,857 @@ use crate::gateway::Gateway;
 /// }
 /// ```
 pub fn search_gateway(options: SearchOptions) -> Result<Gateway, SearchError> {
-    let socket = UdpSocket::bind(options.bind_addr)?;
-    socket.set_read_timeout(options.timeout)?;
+    let socket = common::bind_search_socket(&options)?;
+    #[cfg(feature = "cassette")]
+    let socket = cassette::Transport::new(&socket);
+    search_gateway_with(&socket, options)
+}
+
+/// Search gateway over the given transport, using the given `SearchOptions`.
+///
+/// The `bind_addr` of the options is not used, the transport is expected to be bound already.
+pub fn search_gateway_with<T: SearchTransport + ?Sized>(
+    transport: &T,
+    options: SearchOptions,
+) -> Result<Gateway, SearchError> {
+    search_gateway_until(transport, options, Deadline::never())
+}
+
+/// Search gateway like `search_gateway`, giving up at `deadline` whatever the timeouts.
+///
+/// The wait for a response and the fetches of the descriptions end by the deadline, and so do
+/// the requests of the gateway found, which keeps it, see `Gateway::with_deadline`.
+pub fn search_gateway_within(options: SearchOptions, deadline: Deadline) -> Result<Gateway, SearchError> {
+    let socket = common::bind_search_socket(&options)?;
+    #[cfg(feature = "cassette")]
+    let socket = cassette::Transport::new(&socket);
+    search_gateway_until(&socket, options, deadline)
+}
+
+/// How often the thread collecting late responses checks whether it was stopped.
+#[cfg(not(target_os = "wasi"))]
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Search gateway like `search_gateway`, then keep collecting the gateways that answer late for
+/// `linger` on a background thread.
+///
+/// The gateway answering first is returned as soon as it is fetched. On networks with several
+/// routers, the others would be lost with the socket; here they are fetched as they answer and
+/// kept by the returned `LateGateways`, e.g. as backups for
+/// `PortMappingManager::set_backup_gateways`, without searching again. Gateways answering more
+/// than once, e.g. on several interfaces, are collected once, and the gateway returned isn't
+/// collected again. The late responses aren't recorded by a cassette.
+///
+/// # Example
+/// ```no_run
+/// use std::time::Duration;
+/// use igd::{search_gateway_and_collect, PortMappingManager};
+///
+/// let (gateway, late) = search_gateway_and_collect(Default::default(), Duration::from_secs(3)).unwrap();
+/// let mut manager = PortMappingManager::new(gateway, "my app");
+/// manager.set_backup_gateways(late.wait());
+/// ```
+#[cfg(not(target_os = "wasi"))]
+pub fn search_gateway_and_collect(
+    options: SearchOptions,
+    linger: Duration,
+) -> Result<(Gateway, LateGateways), SearchError> {
+    let socket = common::bind_search_socket(&options)?;
+    let collect_options = options.clone();
+    let sent = Instant::now();
+    let gateway = {
+        #[cfg(feature = "cassette")]
+        let socket = cassette::Transport::new(&socket);
+        search_gateway_until(&socket, options, Deadline::never())?
+    };
+
+    let shared = Arc::new(LateShared::default());
+    let collector = Collector {
+        socket,
+        options: collect_options,
+        sent,
+        until: Instant::now() + linger,
+        seen: HashSet::from([(gateway.addr, gateway.root_url.clone())]),
+        udns: HashSet::from([gateway.device_info.udn.clone()]),
+        shared: shared.clone(),
+    };
+    let thread = thread::spawn(move || collector.run());
+    Ok((
+        gateway,
+        LateGateways {
+            shared,
+            thread: Some(thread),
+        },
+    ))
+}
+
+/// The gateways collected by `search_gateway_and_collect` after it returned.
+///
+/// The collection ends when its time is up, or when the handle is dropped.
+#[cfg(not(target_os = "wasi"))]
+#[derive(Debug)]
+pub struct LateGateways {
+    shared: Arc<LateShared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+#[cfg(not(target_os = "wasi"))]
+#[derive(Debug, Default)]
+struct LateShared {
+    gateways: Mutex<Vec<Gateway>>,
+    stopped: AtomicBool,
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl LateGateways {
+    /// The gateways collected so far, in the order they answered.
+    pub fn gateways(&self) -> Vec<Gateway> {
+        self.shared.gateways.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Whether the collection is over.
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|thread| thread.is_finished())
+    }
+
+    /// Wait for the collection to end, returning all the gateways collected.
+    pub fn wait(mut self) -> Vec<Gateway> {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.gateways()
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl Drop for LateGateways {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Fetches the gateways answering the search of `search_gateway_and_collect` until its time is
+/// up.
+#[cfg(not(target_os = "wasi"))]
+struct Collector {
+    socket: UdpSocket,
+    options: SearchOptions,
+    sent: Instant,
+    until: Instant,
+    /// Locations answered already
+    seen: HashSet<(SocketAddrV4, String)>,
+    /// UDNs of the gateways fetched already, which may answer from several locations
+    udns: HashSet<String>,
+    shared: Arc<LateShared>,
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl Collector {
+    fn run(mut self) {
+        loop {
+            let now = Instant::now();
+            if now >= self.until || self.shared.stopped.load(Ordering::Relaxed) {
+                return;
+            }
+            // A socket doesn't take a zero read timeout.
+            let timeout = (self.until - now).clamp(Duration::from_millis(1), STOP_CHECK_INTERVAL);
+            let mut buf = [0u8; 1500];
+            let received = self
+                .socket
+                .set_read_timeout(Some(timeout))
+                .and_then(|()| self.socket.recv_from(&mut buf));
+            match received {
+                Ok((read, from)) => {
+                    debug!("received a late search response from {}", from);
+                    self.handle(from, &buf[..read]);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => {
+                    debug!("collecting the late search responses failed: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, from: SocketAddr, data: &[u8]) {
+        let headers = Headers::parse(data);
+        if !self.options.request.accepts_response(&headers) {
+            return;
+        }
+        let (addr, root_url) = match parsing::parse_search_result(&headers) {
+            Ok(result) => result,
+            Err(_) => return,
+        };
+        if !self.seen.insert((addr, root_url.clone())) {
+            return;
+        }
+        if let Err(e) = check_location(self.options.url_policy, from.ip(), addr.into(), &root_url) {
+            debug!("skipping {}: {}", addr, e);
+            return;
+        }
+        let response_time = self.sent.elapsed();
+        match get_selected_gateway(
+            &self.options,
+            &headers,
+            addr,
+            root_url,
+            response_time,
+            Deadline::never(),
+            Deadline::at(self.until),
+        ) {
+            Ok(mut gateway) => {
+                if !self.udns.insert(gateway.device_info.udn.clone()) {
+                    return;
+                }
+                gateway.local_addr = discovered_from(&self.socket, gateway.addr);
+                debug!("found {} after the search returned", gateway);
+                self.shared
+                    .gateways
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(gateway);
+            }
+            Err(e) => debug!("skipping {}: {}", addr, e),
+        }
+    }
+}
+
+fn search_gateway_until<T: SearchTransport + ?Sized>(
+    transport: &T,
+    options: SearchOptions,
+    deadline: Deadline,
+) -> Result<Gateway, SearchError> {
+    if deadline.is_expired() {
+        return Err(SearchError::NoResponse(Duration::ZERO));
+    }
+    // A socket doesn't take a zero read timeout.
+    let options = SearchOptions {
+        timeout: deadline
+            .limit(options.timeout)
+            .map(|timeout| timeout.max(Duration::from_millis(1))),
+        ..options
+    };
+    let search_end = Deadline::from_timeout(Instant::now(), options.timeout);
+    let mut gateway = search_first(transport, &options, |headers, addr, root_url, response_time| {
+        get_selected_gateway(&options, headers, addr, root_url, response_time, deadline, search_end)
+    })?;
+    gateway.local_addr = discovered_from(transport, gateway.addr);
+    Ok(gateway)
+}
+
+/// How long the unicast search of `search_default_gateway` waits for the router to answer.
+#[cfg(feature = "route")]
+const UNICAST_SEARCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Search the gateway that is the router of the default route.
+///
+/// The search request is sent to the router directly, from its interface, so a gateway on
+/// another interface, e.g. of a VPN or a virtual machine, isn't found instead. If the default
+/// route can't be read, or the router doesn't answer within 2 seconds, this falls back to the
+/// multicast search of `search_gateway` with the given options, bound to the interface of the
+/// default route unless `bind_addr` is set.
+///
+/// The default route is only read on Linux, see the `route` module. On other platforms this is
+/// the multicast search.
+///
+/// # Example
+/// ```no_run
+/// let gateway = igd::search_default_gateway(Default::default()).unwrap();
+/// println!("{}", gateway);
+/// ```
+#[cfg(feature = "route")]
+pub fn search_default_gateway(options: SearchOptions) -> Result<Gateway, SearchError> {
+    let route = match crate::route::default_route() {
+        Ok(route) => route,
+        Err(e) => {
+            debug!("reading the default route failed: {}", e);
+            return search_gateway(options);
+        }
+    };
 
-    socket.send_to(messages::SEARCH_REQUEST.as_bytes(), options.broadcast_address)?;
+    let unicast = SearchOptions {
+        bind_addr: SocketAddr::V4(SocketAddrV4::new(route.local_addr, 0)),
+        broadcast_address: SocketAddr::V4(SocketAddrV4::new(route.gateway, 1900)),
+        timeout: Some(
+            options
+                .timeout
+                .map_or(UNICAST_SEARCH_TIMEOUT, |timeout| timeout.min(UNICAST_SEARCH_TIMEOUT)),
+        ),
+        ..options.clone()
+    };
+    match search_gateway(unicast) {
+        Ok(gateway) => return Ok(gateway),
+        Err(e) => debug!(
+            "unicast search of {} on {} failed: {}",
+            route.gateway, route.interface, e
+        ),
+    }
+
+    let bind_addr = if options.bind_addr.ip().is_unspecified() && options.broadcast_address.is_ipv4() {
+        SocketAddr::V4(SocketAddrV4::new(route.local_addr, options.bind_addr.port()))
+    } else {
+        options.bind_addr
+    };
+    search_gateway(SearchOptions { bind_addr, ..options })
+}
+
+/// The datagram socket search requests are sent and search responses received on.
+///
+/// It is implemented for `UdpSocket`. Other implementations can feed canned responses to the
+/// search functions, e.g. in tests.
+pub trait SearchTransport {
+    /// Send a datagram to the given address.
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    /// Receive a datagram, waiting at most as long as the read timeout.
+    ///
+    /// When the timeout expires, fails with an error of kind `WouldBlock` or `TimedOut`.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    /// Set the read timeout, or wait forever with `None`.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// The local address the transport is bound to.
+    ///
+    /// It is recorded on the gateways found. The default implementation fails.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the transport has no local address",
+        ))
+    }
+}
+
+impl SearchTransport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+fn search_first<T, G, F>(transport: &T, options: &SearchOptions, mut fetch: F) -> Result<G, SearchError>
+where
+    T: SearchTransport + ?Sized,
+    F: FnMut(&Headers, SocketAddrV4, String, Duration) -> Result<G, SearchError>,
+{
+    transport.set_read_timeout(options.timeout)?;
 
+    transport.send_to(options.request.to_string().as_bytes(), options.broadcast_address)?;
+    let sent = Instant::now();
+    debug!(
+        "searching for {} at {}",
+        options.request.search_target(),
+        options.broadcast_address
+    );
+
+    let mut retransmission = Retransmission::new(options, sent, options.timeout.map(|timeout| sent + timeout));
+    let mut attempts = SearchAttempts::default();
     loop {
+        if retransmission.is_enabled() {
+            transport.set_read_timeout(retransmission.read_timeout(options.timeout))?;
+        }
         let mut buf = [0u8; 1500];
-        let (read, _) = socket.recv_from(&mut buf)?;
-        let text = str::from_utf8(&buf[..read])?;
-
-        let (addr, root_url) = parsing::parse_search_result(text)?;
+        let (read, from) = match transport.recv_from(&mut buf) {
+            Ok((read, from)) => {
+                debug!("received a search response from {}", from);
+                (read, from)
+            }
+            Err(e) => {
+                let e = SearchError::from(e);
+                if e.is_timeout() && retransmission.keep_waiting(transport, options)? {
+                    continue;
+                }
+                return Err(match options.timeout {
+                    Some(timeout) if e.is_timeout() => attempts.into_error(timeout),
+                    _ => e,
+                });
+            }
+        };
+        let response_time = sent.elapsed();
+        let headers = Headers::parse(&buf[..read]);
+        if !options.request.accepts_response(&headers) {
+            continue;
+        }
 
-        let (control_schema_url, control_url) = match get_control_urls(&addr, &root_url) {
-            Ok(o) => o,
-            Err(..) => continue,
+        let (addr, root_url) = match parsing::parse_search_result(&headers) {
+            Ok(result) => result,
+            Err(e) => {
+                attempts.invalid(e.with_data(&buf[..read]));
+                continue;
+            }
         };
 
-        let control_schema = match get_schemas(&addr, &control_schema_url) {
-            Ok(o) => o,
-            Err(..) => continue,
+        let url = format!("http://{}{}", addr, root_url);
+        if let Err(e) = check_location(options.url_policy, from.ip(), addr.into(), &root_url) {
+            attempts.failed(url, e);
+            continue;
+        }
+        match fetch(&headers, addr, root_url, response_time) {
+            Ok(gateway) => return Ok(gateway),
+            Err(e) => attempts.failed(url, e),
+        }
+    }
+}
+
+/// Sends the search request again on the schedule of `SearchOptions::retransmission`.
+pub(crate) struct Retransmission<'a> {
+    backoff: Option<&'a dyn Backoff>,
+    retries: u32,
+    next: Option<Instant>,
+    deadline: Option<Instant>,
+}
+
+impl<'a> Retransmission<'a> {
+    /// Schedule the retransmissions of the request sent at `sent`, until `deadline`.
+    pub(crate) fn new(options: &'a SearchOptions, sent: Instant, deadline: Option<Instant>) -> Retransmission<'a> {
+        let mut retransmission = Retransmission {
+            backoff: options.retransmission.as_deref(),
+            retries: 0,
+            next: None,
+            deadline,
         };
+        retransmission.schedule(sent);
+        retransmission
+    }
 
-        return Ok(Gateway {
-            addr,
-            root_url,
-            control_url,
-            control_schema_url,
-            control_schema,
-        });
+    fn is_enabled(&self) -> bool {
+        self.backoff.is_some()
+    }
+
+    /// When the request is sent again, unless a gateway answers first.
+    #[cfg(feature = "aio")]
+    pub(crate) fn next(&self) -> Option<Instant> {
+        self.next
+    }
+
+    /// Schedule the next retransmission after the request was sent again at `sent`.
+    pub(crate) fn schedule(&mut self, sent: Instant) {
+        self.next = self
+            .backoff
+            .and_then(|backoff| backoff.delay(self.retries))
+            .map(|delay| sent + delay)
+            .filter(|next| self.deadline.is_none_or(|deadline| *next < deadline));
+        self.retries += 1;
+    }
+
+    /// How long to wait for a response, until the next retransmission or the deadline, or
+    /// `timeout` without retransmissions.
+    fn read_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        if !self.is_enabled() {
+            return timeout;
+        }
+        let now = Instant::now();
+        // A socket doesn't take a zero read timeout.
+        self.next
+            .into_iter()
+            .chain(self.deadline)
+            .min()
+            .map(|until| until.saturating_duration_since(now).max(Duration::from_millis(1)))
+    }
+
+    /// After waiting for a response timed out, send the request again if it is time to.
+    ///
+    /// Returns whether to keep waiting, which is until the deadline with retransmissions.
+    fn keep_waiting<T: SearchTransport + ?Sized>(
+        &mut self,
+        transport: &T,
+        options: &SearchOptions,
+    ) -> io::Result<bool> {
+        if !self.is_enabled() {
+            return Ok(false);
+        }
+        let now = Instant::now();
+        if self.next.is_some_and(|next| now >= next) {
+            debug!("sending the search request to {} again", options.broadcast_address);
+            transport.send_to(options.request.to_string().as_bytes(), options.broadcast_address)?;
+            self.schedule(now);
+        }
+        Ok(self.deadline.is_none_or(|deadline| now < deadline))
+    }
+}
+
+/// What came of the responses to a search that found no gateway, to tell why.
+#[derive(Default)]
+pub(crate) struct SearchAttempts {
+    received: usize,
+    last_invalid: Option<SearchError>,
+    failed: Vec<(String, SearchError)>,
+}
+
+impl SearchAttempts {
+    /// Record a response that couldn't be parsed.
+    pub(crate) fn invalid(&mut self, e: SearchError) {
+        self.received += 1;
+        self.last_invalid = Some(e);
+    }
+
+    /// Record a gateway whose description at `url` couldn't be fetched, or that wasn't selected.
+    pub(crate) fn failed(&mut self, url: String, e: SearchError) {
+        self.received += 1;
+        self.failed.push((url, e));
+    }
+
+    /// The error of the search, after waiting `timeout` for another response.
+    pub(crate) fn into_error(self, timeout: Duration) -> SearchError {
+        if !self.failed.is_empty() {
+            SearchError::DescriptionsFailed(self.failed)
+        } else if let Some(last_error) = self.last_invalid {
+            SearchError::InvalidResponses {
+                received: self.received,
+                last_error: Box::new(last_error),
+            }
+        } else {
+            SearchError::NoResponse(timeout)
+        }
+    }
+}
+
+fn search_all<T, G, F>(transport: &T, options: &SearchOptions, mut fetch: F) -> Result<Vec<G>, SearchError>
+where
+    T: SearchTransport + ?Sized,
+    F: FnMut(&Headers, SocketAddrV4, String, Duration) -> Result<G, SearchError>,
+{
+    let timeout = match options.timeout {
+        Some(timeout) => timeout,
+        None => return search_first(transport, options, fetch).map(|gateway| vec![gateway]),
+    };
+
+    transport.send_to(options.request.to_string().as_bytes(), options.broadcast_address)?;
+    debug!(
+        "searching for {} at {}",
+        options.request.search_target(),
+        options.broadcast_address
+    );
+
+    let begin = Instant::now();
+    let mut retransmission = Retransmission::new(options, begin, Some(begin + timeout));
+    let mut seen = HashSet::new();
+    let mut gateways = vec![];
+    loop {
+        let now = Instant::now();
+        if now >= begin + timeout {
+            break;
+        }
+        let timeout = Some(timeout - (now - begin));
+        transport.set_read_timeout(retransmission.read_timeout(timeout))?;
+
+        let mut buf = [0u8; 1500];
+        match transport.recv_from(&mut buf) {
+            Ok((read, from)) => {
+                debug!("received a search response from {}", from);
+                let headers = Headers::parse(&buf[..read]);
+                if !options.request.accepts_response(&headers) {
+                    continue;
+                }
+                if let Ok((addr, root_url)) = parsing::parse_search_result(&headers) {
+                    // Gateways often answer several times, e.g. once per network interface.
+                    if !seen.insert((addr, root_url.clone())) {
+                        continue;
+                    }
+                    if let Err(e) = check_location(options.url_policy, from.ip(), addr.into(), &root_url) {
+                        debug!("skipping {}: {}", addr, e);
+                        continue;
+                    }
+                    match fetch(&headers, addr, root_url, begin.elapsed()) {
+                        Ok(gateway) => gateways.push(gateway),
+                        Err(..) => continue,
+                    }
+                }
+            }
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock && e.kind() != io::ErrorKind::TimedOut {
+                    break;
+                }
+                retransmission.keep_waiting(transport, options)?;
+            }
+        }
+    }
+
+    Ok(gateways)
+}
+
+/// The local address a gateway answered the search on.
+fn discovered_from<T: SearchTransport + ?Sized>(transport: &T, addr: SocketAddrV4) -> Option<SocketAddrV4> {
+    let local_addr = transport.local_addr().ok()?;
+    common::mapping_addr(local_addr, addr).ok()
+}
+
+/// Fail if a device at `from` pointed to a description at `addr` that `policy` doesn't allow.
+pub(crate) fn check_location(
+    policy: UrlPolicy,
+    from: IpAddr,
+    addr: SocketAddr,
+    root_url: &str,
+) -> Result<(), SearchError> {
+    if policy.allows(from, addr.ip()) {
+        Ok(())
+    } else {
+        Err(SearchError::UrlNotAllowed(format!("http://{}{}", addr, root_url)))
+    }
+}
+
+/// Fetch the gateway at the `LOCATION` of a `NOTIFY` announcement, if `policy` allows it.
+pub(crate) fn get_announced_gateway(notification: &Notification, policy: UrlPolicy) -> Result<Gateway, SearchError> {
+    let location = notification.location().ok_or(SearchError::InvalidResponse)?;
+    let (addr, root_url) = parsing::parse_location(location).map_err(|e| e.with_data(location.as_bytes()))?;
+    check_location(policy, notification.from.ip(), addr.into(), &root_url)?;
+    let server = notification.header("server").unwrap_or_default();
+    get_gateway(addr, root_url, server, notification.max_age(), true, Deadline::never())
+}
+
+/// Fetch the descriptions of `gateway` again, bypassing the cache, from its root url, or else
+/// from the device with its UDN found by searching again with `options`, e.g. after a reboot
+/// moved it.
+pub(crate) fn refetch_gateway(gateway: &Gateway, options: SearchOptions) -> Result<Gateway, SearchError> {
+    let url = format!("http://{}{}", gateway.addr, gateway.root_url);
+    cache::remove(&url);
+    let udn = &gateway.device_info.udn;
+    let e = match get_gateway(
+        gateway.addr,
+        gateway.root_url.clone(),
+        &gateway.device_info.server,
+        None,
+        gateway.validate_arguments,
+        gateway.deadline,
+    ) {
+        Ok(fetched) if udn.is_empty() || fetched.device_info.udn.eq_ignore_ascii_case(udn) => return Ok(fetched),
+        Ok(_) => {
+            debug!("{} describes another device now, searching {}", url, udn);
+            SearchError::NotSelected
+        }
+        Err(e) => {
+            debug!("fetching {} failed, searching {}: {}", url, udn, e);
+            e
+        }
+    };
+    if udn.is_empty() {
+        return Err(e);
+    }
+    let options = SearchOptions {
+        filter: GatewayFilter::Udn(udn.clone()),
+        validate_arguments: gateway.validate_arguments,
+        ..options
+    };
+    search_gateway_within(options, gateway.deadline)
+}
+
+/// Fetch the gateway, if the filter of the options selects it.
+///
+/// The response was received `response_time` after the search request was sent. The fetch ends
+/// by `search_end` and after the `fetch_timeout` of the options, the gateway keeps `deadline`.
+fn get_selected_gateway(
+    options: &SearchOptions,
+    headers: &Headers,
+    addr: SocketAddrV4,
+    root_url: String,
+    response_time: Duration,
+    deadline: Deadline,
+    search_end: Deadline,
+) -> Result<Gateway, SearchError> {
+    let received = Instant::now();
+    let server = headers.get("server").unwrap_or_default();
+    let max_age = headers.get("cache-control").and_then(parsing::parse_max_age);
+    let fetch_end = deadline
+        .min(search_end)
+        .min(Deadline::from_timeout(received, options.fetch_timeout));
+    let mut gateway = get_gateway(addr, root_url, server, max_age, options.validate_arguments, fetch_end)?;
+    gateway.deadline = deadline;
+    gateway.discovery_timing = Some(DiscoveryTiming {
+        response: response_time,
+        description: response_time + received.elapsed(),
+    });
+    if options.filter.matches(&gateway.device_info) {
+        Ok(gateway)
+    } else {
+        debug!("skipping {}, not selected by the filter", gateway);
+        Err(SearchError::NotSelected)
+    }
+}
+
+/// Fetch the descriptions of the gateway, by `deadline`, which the gateway keeps. The SCPD is
+/// only fetched to `validate_arguments`.
+fn get_gateway(
+    addr: SocketAddrV4,
+    root_url: String,
+    server: &str,
+    max_age: Option<Duration>,
+    validate_arguments: bool,
+    deadline: Deadline,
+) -> Result<Gateway, SearchError> {
+    let (mut description, root_description) = get_description(&addr, &root_url, max_age, deadline)?;
+    description.device_info.server = server.to_string();
+    let control_schema = if validate_arguments {
+        get_schemas(
+            &addr,
+            &description.control_schema_url,
+            &description.device_info,
+            max_age,
+            deadline,
+        )?
+    } else {
+        HashMap::new()
+    };
+    let description_url = format!("http://{}{}", addr, root_url);
+    description.device_info.presentation_url = description
+        .device_info
+        .presentation_url
+        .map(|url| parsing::resolve_url(&description_url, &url));
+    let quirks = quirks::lookup(&description.device_info);
+
+    let mut gateway = Gateway {
+        addr,
+        local_addr: None,
+        root_url,
+        control_url: description.control_url,
+        control_schema_url: description.control_schema_url,
+        control_schema,
+        common_interface_control_url: description.common_interface_control_url,
+        firewall_control_url: description.firewall_control_url,
+        device_info: description.device_info,
+        request_format: quirks.request_format(),
+        allow_third_party: false,
+        permanent_lease_fallback: false,
+        quirks,
+        discovery_timing: None,
+        timeouts: Default::default(),
+        external_ip_cache: Default::default(),
+        rate_limiter: Default::default(),
+        retry_backoff: None,
+        description: Arc::new(root_description),
+        deadline,
+        validate_arguments,
+    };
+    select_default_connection(&mut gateway);
+    Ok(gateway)
+}
+
+/// Switch a gateway with several WAN connections to the default one of its Layer3Forwarding
+/// service. It keeps the first one if that fails.
+fn select_default_connection(gateway: &mut Gateway) {
+    if gateway.wan_connections().len() < 2 {
+        return;
+    }
+    match gateway.default_wan_connection() {
+        Ok(Some(connection)) if connection.service.control_url != gateway.control_url.trim() => {
+            if let Err(e) = gateway.select_wan_connection(&connection) {
+                debug!("selecting the default WAN connection of {} failed: {}", gateway, e);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => debug!("getting the default WAN connection of {} failed: {}", gateway, e),
     }
 }
 
-fn get_control_urls(addr: &SocketAddrV4, root_url: &str) -> Result<(String, String), SearchError> {
+fn get_description(
+    addr: &SocketAddrV4,
+    root_url: &str,
+    max_age: Option<Duration>,
+    deadline: Deadline,
+) -> Result<(Description, RootDescription), SearchError> {
     let url = format!("http://{}:{}{}", addr.ip(), addr.port(), root_url);
-    let response = attohttpc::get(&url).send()?;
-    parsing::parse_control_urls(&response.bytes()?[..])
+    get_cached(url, max_age, deadline, |document| {
+        Ok((parsing::parse_description(document)?, description::parse(document)?))
+    })
 }
 
-fn get_schemas(addr: &SocketAddrV4, control_schema_url: &str) -> Result<HashMap<String, Vec<String>>, SearchError> {
+/// Get the SCPD at `control_schema_url` of the device described by `device_info`, parsed, from
+/// the SCPDs of the same model if it is there.
+pub(crate) fn get_schemas(
+    addr: &SocketAddrV4,
+    control_schema_url: &str,
+    device_info: &DeviceInfo,
+    max_age: Option<Duration>,
+    deadline: Deadline,
+) -> Result<HashMap<String, Vec<String>>, SearchError> {
+    let key = cache::schema_key(device_info, SocketAddr::V4(*addr), control_schema_url);
+    if let Some(schemas) = cache::get_schemas(&key) {
+        debug!("using the cached SCPD of {}", key);
+        return Ok((*schemas).clone());
+    }
     let url = format!("http://{}:{}{}", addr.ip(), addr.port(), control_schema_url);
-    let response = attohttpc::get(&url).send()?;
-    parsing::parse_schemas(&response.bytes()?[..])
+    let schemas = get_cached(url, max_age, deadline, |document| parsing::parse_schemas(document))?;
+    cache::insert_schemas(key, schemas.clone());
+    Ok(schemas)
+}
+
+/// Get and parse the document at `url` by `deadline`, from the cache if it is there. It is
+/// cached for `max_age` if it parses.
+fn get_cached<T, F>(url: String, max_age: Option<Duration>, deadline: Deadline, parse: F) -> Result<T, SearchError>
+where
+    F: Fn(&[u8]) -> Result<T, SearchError>,
+{
+    if let Some(parsed) = cache::get(&url).and_then(|document| parse(&document).ok()) {
+        debug!("using the cached {}", url);
+        return Ok(parsed);
+    }
+    let document = get(&url, deadline).map_err(|e| e.at_url(&url))?;
+    let parsed = parse(&document).map_err(|e| e.with_data(&document))?;
+    if let Some(max_age) = max_age {
+        cache::insert(url, document, max_age);
+    }
+    Ok(parsed)
+}
+
+/// Forget the device descriptions kept from earlier searches.
+///
+/// Descriptions are kept for as long as the `CACHE-CONTROL` header of the search response
+/// allows, so searching again doesn't fetch them again, and the parsed SCPDs for as long as the
+/// process runs, shared by the devices of the same model. Clear them, e.g., after the firmware
+/// of the router was updated.
+pub fn clear_description_cache() {
+    cache::clear();
+}
+
+/// Also keep the parsed SCPDs in files in `dir`, so other processes reuse them, or stop with
+/// `None`.
+///
+/// Only the SCPDs of devices with a model name are written, one file for each model, firmware
+/// (as given by the `SERVER` header) and SCPD url path. `clear_description_cache` leaves the
+/// files, remove them to clear it.
+///
+/// ```no_run
+/// igd::set_schema_cache_dir(Some(std::env::temp_dir().join("igd-scpd")));
+/// let gateway = igd::search_gateway(Default::default()).unwrap();
+/// ```
+pub fn set_schema_cache_dir(dir: Option<PathBuf>) {
+    cache::set_schema_dir(dir);
+}
+
+fn get(url: &str, deadline: Deadline) -> Result<Vec<u8>, SearchError> {
+    get_with_status(url, deadline).map(|(_, body)| body)
+}
+
+/// Get the document at `url` by `deadline`, with the HTTP status of the response.
+pub(crate) fn get_with_status(url: &str, deadline: Deadline) -> Result<(u16, Vec<u8>), SearchError> {
+    let send = || -> Result<(u16, Vec<u8>), SearchError> {
+        deadline.check()?;
+        let mut request = attohttpc::get(url)
+            .header("Accept-Encoding", "identity")
+            .follow_redirects(false);
+        if let Some(remaining) = deadline.remaining() {
+            request = request.timeout(remaining);
+        }
+        let response = request.send()?;
+        common::check_content_encoding(
+            response
+                .headers()
+                .get("content-encoding")
+                .and_then(|value| value.to_str().ok()),
+        )?;
+        Ok((response.status().as_u16(), response.bytes()?))
+    };
+    #[cfg(feature = "cassette")]
+    let send = || cassette::http("GET", url, &[], send);
+    send()
 }
 
 // #[test]
@@ -80,6 +908,9 @@ fn get_schemas(addr: &SocketAddrV4, control_schema_url: &str) -> Result<HashMap<
 /// The default `SearchOptions` should suffice in most cases.
 /// It can be created with `Default::default()` or `SearchOptions::default()`.
 ///
+/// The router of this host comes first: the router of the default route when the `route`
+/// feature is enabled, then the gateways in the subnet they were found from, then the others.
+///
 /// # Example
 /// ```no_run
 /// use igd::{search_multi_gateways, SearchOptions, Result};
@@ -94,56 +925,426 @@ fn get_schemas(addr: &SocketAddrV4, control_schema_url: &str) -> Result<HashMap<
 /// }
 /// ```
 pub fn search_multi_gateways(options: SearchOptions) -> Result<Vec<Gateway>, SearchError> {
-    let socket = UdpSocket::bind(options.bind_addr)?;
+    let socket = common::bind_search_socket(&options)?;
+    #[cfg(feature = "cassette")]
+    let socket = cassette::Transport::new(&socket);
+    search_multi_gateways_with(&socket, options)
+}
 
-    socket.send_to(messages::SEARCH_REQUEST.as_bytes(), options.broadcast_address)?;
+/// Search multiple gateways over the given transport, using the given `SearchOptions`.
+///
+/// The `bind_addr` of the options is not used, the transport is expected to be bound already.
+pub fn search_multi_gateways_with<T: SearchTransport + ?Sized>(
+    transport: &T,
+    options: SearchOptions,
+) -> Result<Vec<Gateway>, SearchError> {
+    let search_end = Deadline::from_timeout(Instant::now(), options.timeout);
+    let mut gateways = search_all(transport, &options, |headers, addr, root_url, response_time| {
+        get_selected_gateway(
+            &options,
+            headers,
+            addr,
+            root_url,
+            response_time,
+            Deadline::never(),
+            search_end,
+        )
+    })?;
+    for gateway in &mut gateways {
+        gateway.local_addr = discovered_from(transport, gateway.addr);
+    }
+    sort_by_preference(&mut gateways, default_gateway(), default_local_ip());
+    Ok(gateways)
+}
 
-    let begin = Instant::now();
-    let mut gateways = vec![];
-    if let Some(timeout) = options.timeout {
-        loop {
-            let now = Instant::now();
-            if now >= begin + timeout {
-                break;
-            }
-            let timeout = Some(timeout - (now - begin));
-            socket.set_read_timeout(timeout)?;
+/// The router of the default route, if it can be read.
+#[cfg(feature = "route")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    crate::route::default_route().ok().map(|route| route.gateway)
+}
 
-            let mut buf = [0u8; 1500];
-            match socket.recv_from(&mut buf) {
-                Ok((read, _)) => {
-                    if let Ok(text) = str::from_utf8(&buf[..read]) {
-                        if let Ok((addr, root_url)) = parsing::parse_search_result(text) {
-                            let (control_schema_url, control_url) = match get_control_urls(&addr, &root_url) {
-                                Ok(o) => o,
-                                Err(..) => continue,
-                            };
-                            let control_schema = match get_schemas(&addr, &control_schema_url) {
-                                Ok(o) => o,
-                                Err(..) => continue,
-                            };
-                            let gateway = Gateway {
-                                addr,
-                                root_url,
-                                control_url,
-                                control_schema_url,
-                                control_schema,
-                            };
-                            gateways.push(gateway);
-                        }
-                    }
-                }
-                Err(e) => {
-                    if e.kind() != io::ErrorKind::WouldBlock || e.kind() != io::ErrorKind::TimedOut {
-                        break;
-                    }
-                }
+#[cfg(not(feature = "route"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+/// The local address used to reach the internet, which is on the interface of the default route.
+fn default_local_ip() -> Option<Ipv4Addr> {
+    common::local_ip_towards(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 9)).ok()
+}
+
+/// Sort gateways so the router of this host comes first: the router of the default route, then
+/// the gateways in the subnet of the address they were found from, then the others. The order
+/// they answered in is kept otherwise.
+///
+/// Without the default route, the gateways in the subnet of the interface of the default route
+/// come first instead. Subnets are assumed to be /24, the netmasks of the interfaces are not
+/// known.
+fn sort_by_preference(gateways: &mut [Gateway], default_gateway: Option<Ipv4Addr>, default_local_ip: Option<Ipv4Addr>) {
+    let same_subnet = |gateway: &Gateway| {
+        gateway
+            .local_addr
+            .is_some_and(|local_addr| local_addr.ip().octets()[..3] == gateway.addr.ip().octets()[..3])
+    };
+    gateways.sort_by_key(|gateway| {
+        let is_default = match default_gateway {
+            Some(ip) => *gateway.addr.ip() == ip,
+            None => same_subnet(gateway) && gateway.local_addr.map(|local_addr| *local_addr.ip()) == default_local_ip,
+        };
+        if is_default {
+            0
+        } else if same_subnet(gateway) {
+            1
+        } else {
+            2
+        }
+    });
+}
+
+#[cfg(test)]
+struct CannedTransport {
+    sent: std::cell::RefCell<Vec<(Vec<u8>, SocketAddr)>>,
+    responses: std::cell::RefCell<std::collections::VecDeque<&'static str>>,
+    timeout: std::cell::Cell<Option<Duration>>,
+}
+
+#[cfg(test)]
+impl CannedTransport {
+    fn new(responses: &[&'static str]) -> CannedTransport {
+        CannedTransport {
+            sent: Default::default(),
+            responses: std::cell::RefCell::new(responses.iter().cloned().collect()),
+            timeout: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl SearchTransport for CannedTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.sent.borrow_mut().push((buf.to_vec(), addr));
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.responses.borrow_mut().pop_front() {
+            Some(response) => {
+                buf[..response.len()].copy_from_slice(response.as_bytes());
+                Ok((response.len(), "192.168.0.1:1900".parse().unwrap()))
+            }
+            None => {
+                std::thread::sleep(self.timeout.get().unwrap_or_default());
+                Err(io::ErrorKind::WouldBlock.into())
             }
         }
-    } else {
-        let gateway = search_gateway(options)?;
-        gateways.push(gateway);
     }
 
-    Ok(gateways)
-}
\ No newline at end of file
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_search_first() {
+    let transport = CannedTransport::new(&[
+        "HTTP/1.1 200 OK\r\nLOCATION: http://192.168.0.1:1900/broken.xml\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nLOCATION: http://192.168.0.1:1900/rootDesc.xml\r\n\r\n",
+    ]);
+    let options = SearchOptions::default();
+    let found = search_first(&transport, &options, |_, addr, root_url, _| match root_url.as_str() {
+        "/rootDesc.xml" => Ok((addr, root_url)),
+        _ => Err(SearchError::InvalidResponse),
+    })
+    .unwrap();
+    assert_eq!(found.1, "/rootDesc.xml");
+    assert_eq!(transport.timeout.get(), options.timeout);
+
+    let sent = transport.sent.borrow();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].0, options.request.to_string().as_bytes());
+    assert_eq!(sent[0].1, options.broadcast_address);
+
+    let options = SearchOptions {
+        timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let transport = CannedTransport::new(&[]);
+    let result = search_first(&transport, &options, |_, addr, root_url, _| Ok((addr, root_url)));
+    assert!(matches!(result, Err(SearchError::NoResponse(..))));
+
+    let transport = CannedTransport::new(&["not a search response"]);
+    let result = search_first(&transport, &options, |_, addr, root_url, _| Ok((addr, root_url)));
+    match result {
+        Err(SearchError::InvalidResponses { received, last_error }) => {
+            assert_eq!(received, 1);
+            assert!(matches!(*last_error, SearchError::InvalidData(_, ref data) if data == "not a search response"));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let transport =
+        CannedTransport::new(&["HTTP/1.1 200 OK\r\nLOCATION: http://192.168.0.1:1900/rootDesc.xml\r\n\r\n"]);
+    let result = search_first(&transport, &options, |_, _, _, _| -> Result<(), _> {
+        Err(SearchError::InvalidResponse)
+    });
+    match result {
+        Err(SearchError::DescriptionsFailed(failures)) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].0, "http://192.168.0.1:1900/rootDesc.xml");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_search_loopback_location() {
+    // A device of the LAN pointing the fetch to a service of this host.
+    let transport = CannedTransport::new(&["HTTP/1.1 200 OK\r\nLOCATION: http://127.0.0.1:8080/rootDesc.xml\r\n\r\n"]);
+    let options = SearchOptions {
+        timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let result = search_first(&transport, &options, |_, _, _, _| -> Result<(), _> {
+        panic!("the loopback location was fetched")
+    });
+    match result {
+        Err(SearchError::DescriptionsFailed(failures)) => {
+            assert_eq!(failures.len(), 1);
+            assert!(matches!(failures[0].1, SearchError::UrlNotAllowed(..)));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_search_all() {
+    let transport = CannedTransport::new(&[
+        "HTTP/1.1 200 OK\r\nLOCATION: http://192.168.0.1:1900/rootDesc.xml\r\n\r\n",
+        "not a search response",
+        "HTTP/1.1 200 OK\r\nLOCATION: http://192.168.0.1:1900/rootDesc.xml\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nLOCATION: http://192.168.0.2:1900/rootDesc.xml\r\n\r\n",
+    ]);
+    let options = SearchOptions {
+        timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let found = search_all(&transport, &options, |_, addr, _, _| Ok(addr)).unwrap();
+    assert_eq!(
+        found,
+        vec![
+            "192.168.0.1:1900".parse::<SocketAddrV4>().unwrap(),
+            "192.168.0.2:1900".parse().unwrap()
+        ]
+    );
+    assert!(started.elapsed() >= Duration::from_millis(50));
+}
+
+#[test]
+fn test_search_multi_gateways_fetch_timeout() {
+    // Accepts the connections, and never answers.
+    let silent = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nLOCATION: http://{}/rootDesc.xml\r\n\r\n",
+        silent.local_addr().unwrap()
+    );
+    let response: &'static str = Box::leak(response.into_boxed_str());
+    for (timeout, fetch_timeout) in [(1000, Some(100)), (300, None)] {
+        let transport = CannedTransport::new(&[response]);
+        let options = SearchOptions {
+            timeout: Some(Duration::from_millis(timeout)),
+            fetch_timeout: fetch_timeout.map(Duration::from_millis),
+            // The canned responses come from another host.
+            url_policy: UrlPolicy::Any,
+            ..Default::default()
+        };
+        let started = Instant::now();
+        assert!(search_multi_gateways_with(&transport, options).unwrap().is_empty());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
+
+#[test]
+fn test_search_all_devices() {
+    let transport = CannedTransport::new(&[
+        "HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\nLOCATION: http://192.168.0.5:80/printer.xml\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:Printer:1\r\n\
+         LOCATION: http://192.168.0.5:80/printer.xml\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:service:WANIPConnection:1\r\n\
+         LOCATION: http://192.168.0.1:1900/rootDesc.xml\r\n\r\n",
+    ]);
+    let options = SearchOptions {
+        timeout: Some(Duration::from_millis(50)),
+        request: crate::ssdp::SearchRequest::new(crate::ssdp::ALL),
+        ..Default::default()
+    };
+    let found = search_all(&transport, &options, |_, addr, _, _| Ok(addr)).unwrap();
+    assert_eq!(found, vec!["192.168.0.1:1900".parse::<SocketAddrV4>().unwrap()]);
+    assert!(str::from_utf8(&transport.sent.borrow()[0].0)
+        .unwrap()
+        .contains("ST:ssdp:all\r\n"));
+}
+
+#[test]
+fn test_search_retransmission() {
+    let options = SearchOptions {
+        timeout: Some(Duration::from_millis(200)),
+        retransmission: Some(std::sync::Arc::new(crate::FixedBackoff {
+            delay: Duration::from_millis(20),
+            max_retries: Some(2),
+        })),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let transport = CannedTransport::new(&[]);
+    let result = search_first(&transport, &options, |_, addr, root_url, _| Ok((addr, root_url)));
+    assert!(matches!(result, Err(SearchError::NoResponse(..))));
+    assert_eq!(transport.sent.borrow().len(), 3);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    let transport = CannedTransport::new(&[]);
+    let found = search_all(&transport, &options, |_, addr, _, _| Ok(addr)).unwrap();
+    assert!(found.is_empty());
+    assert_eq!(transport.sent.borrow().len(), 3);
+}
+
+#[test]
+fn test_search_url_policy() {
+    let transport = CannedTransport::new(&["HTTP/1.1 200 OK\r\nLOCATION: http://203.0.113.1:80/rootDesc.xml\r\n\r\n"]);
+    let options = SearchOptions {
+        timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let result = search_first(&transport, &options, |_, addr, _, _| Ok(addr));
+    match result {
+        Err(SearchError::DescriptionsFailed(ref failures)) => {
+            assert!(
+                matches!(failures[0].1, SearchError::UrlNotAllowed(ref url) if url == "http://203.0.113.1:80/rootDesc.xml")
+            );
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let transport = CannedTransport::new(&["HTTP/1.1 200 OK\r\nLOCATION: http://203.0.113.1:80/rootDesc.xml\r\n\r\n"]);
+    let options = SearchOptions {
+        url_policy: UrlPolicy::Any,
+        ..options
+    };
+    assert!(search_first(&transport, &options, |_, addr, _, _| Ok(addr)).is_ok());
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_search_filter() {
+    use crate::GatewayFilter;
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    let search = |filter: GatewayFilter| {
+        search_gateway(SearchOptions {
+            timeout: Some(Duration::from_millis(500)),
+            filter,
+            ..mock.search_options()
+        })
+    };
+
+    let gateway = search(GatewayFilter::Udn("uuid:00000000-0000-0000-0000-000000000001".into())).unwrap();
+    assert_eq!(gateway.device_info.friendly_name, "Mock Gateway");
+    assert_eq!(gateway.description().device.friendly_name, "Mock Gateway");
+    assert!(gateway.description().xml.contains("<UDN>"));
+    assert_eq!(gateway.wan_connections()[0].service.control_url, gateway.control_url);
+    let timing = gateway.discovery_timing.unwrap();
+    assert!(timing.response <= timing.description);
+    assert!(search(GatewayFilter::FriendlyName("mock gate".into())).is_ok());
+    assert!(search(GatewayFilter::predicate(|info| info.udn.ends_with("0001"))).is_ok());
+    assert!(search(GatewayFilter::FriendlyName("FRITZ!Box".into())).is_err());
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_sort_by_preference() {
+    let mock = crate::test::MockGateway::start().unwrap();
+    let found = search_gateway(mock.search_options()).unwrap();
+    let gateway = |addr: &str, local_addr: &str| Gateway {
+        addr: addr.parse().unwrap(),
+        local_addr: Some(local_addr.parse().unwrap()),
+        ..found.clone()
+    };
+
+    let mut gateways = vec![
+        gateway("10.0.0.1:1900", "192.168.1.10:0"),
+        gateway("192.168.122.1:1900", "192.168.122.10:0"),
+        gateway("192.168.1.1:1900", "192.168.1.10:0"),
+    ];
+    let addrs = |gateways: &[Gateway]| {
+        gateways
+            .iter()
+            .map(|gateway| gateway.addr.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    sort_by_preference(&mut gateways, Some("192.168.1.1".parse().unwrap()), None);
+    assert_eq!(
+        addrs(&gateways),
+        ["192.168.1.1:1900", "192.168.122.1:1900", "10.0.0.1:1900"]
+    );
+
+    sort_by_preference(&mut gateways, None, Some("192.168.122.10".parse().unwrap()));
+    assert_eq!(
+        addrs(&gateways),
+        ["192.168.122.1:1900", "192.168.1.1:1900", "10.0.0.1:1900"]
+    );
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_search_gateway_and_collect() {
+    use crate::test::{MockGateway, DEFAULT_DESCRIPTION};
+
+    let mock = MockGateway::start().unwrap();
+    let backup = MockGateway::start().unwrap();
+    backup.set_description(DEFAULT_DESCRIPTION.replacen(
+        "uuid:00000000-0000-0000-0000-000000000001",
+        "uuid:00000000-0000-0000-0000-000000000002",
+        1,
+    ));
+    let (gateway, late) = search_gateway_and_collect(mock.search_options(), Duration::from_secs(1)).unwrap();
+    assert_eq!(gateway.addr, mock.http_addr());
+
+    let response = |http_addr: SocketAddrV4| {
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+             ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+             LOCATION: http://{}/rootDesc.xml\r\n\
+             \r\n",
+            http_addr
+        )
+    };
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    for http_addr in [mock.http_addr(), backup.http_addr(), backup.http_addr()] {
+        socket
+            .send_to(response(http_addr).as_bytes(), gateway.local_addr.unwrap())
+            .unwrap();
+    }
+
+    let gateways = late.wait();
+    assert_eq!(gateways.len(), 1);
+    assert_eq!(gateways[0].addr, backup.http_addr());
+    assert_eq!(gateways[0].device_info.udn, "uuid:00000000-0000-0000-0000-000000000002");
+    assert_eq!(gateways[0].local_addr, gateway.local_addr);
+    assert_eq!(gateways[0].deadline.instant(), None);
+
+    // A gateway that never sends its description doesn't hold the collection past its end.
+    let (gateway, late) = search_gateway_and_collect(mock.search_options(), Duration::from_secs(1)).unwrap();
+    let silent = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let silent_addr = match silent.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    socket
+        .send_to(response(silent_addr).as_bytes(), gateway.local_addr.unwrap())
+        .unwrap();
+    let started = Instant::now();
+    assert!(late.wait().is_empty());
+    assert!(started.elapsed() < Duration::from_secs(3));
+}
@@ -0,0 +1,183 @@
+use std::mem;
+use std::net::SocketAddrV4;
+use std::process;
+
+use rand::Rng;
+
+use crate::common::parsing::MappedPort;
+use crate::errors::{AddAnyPortError, AddPortError, RemovePortError};
+use crate::{Gateway, PortMappingProtocol};
+
+/// Port mappings that only live as long as the session.
+///
+/// Every mapping added through the session has a token unique to the session put in front of
+/// its description, and is removed when the session is closed or dropped. The token also makes
+/// the mappings of a session that wasn't closed, e.g. because the program crashed, easy to find
+/// with `Gateway::cleanup_matching`.
+///
+/// # Example
+/// ```no_run
+/// use std::net::SocketAddrV4;
+/// use igd::{MappingSession, PortMappingProtocol};
+///
+/// let gateway = igd::search_gateway(Default::default()).unwrap();
+/// let local_addr = SocketAddrV4::new(gateway.local_addr_hint().unwrap(), 8080);
+/// let mut session = MappingSession::new(gateway);
+/// session.add_port(PortMappingProtocol::TCP, 8080, local_addr, 3600, "web").unwrap();
+/// // The mapping is removed here.
+/// session.close().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct MappingSession {
+    gateway: Gateway,
+    token: String,
+    mappings: Vec<(PortMappingProtocol, u16)>,
+}
+
+impl MappingSession {
+    /// Start a session on `gateway` with a new token.
+    pub fn new(gateway: Gateway) -> MappingSession {
+        let token = format!("igd-{:x}-{:08x}", process::id(), rand::thread_rng().gen::<u32>());
+        MappingSession {
+            gateway,
+            token,
+            mappings: Vec::new(),
+        }
+    }
+
+    /// The gateway the mappings are made on.
+    pub fn gateway(&self) -> &Gateway {
+        &self.gateway
+    }
+
+    /// The token in front of the descriptions of the mappings of this session.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// The mappings of this session, by protocol and external port.
+    pub fn mappings(&self) -> &[(PortMappingProtocol, u16)] {
+        &self.mappings
+    }
+
+    /// Add a port mapping, as `Gateway::map_port` does.
+    pub fn add_port(
+        &mut self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<MappedPort, AddPortError> {
+        let description = self.description(description);
+        let mapped = self
+            .gateway
+            .map_port(protocol, external_port, local_addr, lease_duration, &description)?;
+        self.track(protocol, mapped.external_port);
+        Ok(mapped)
+    }
+
+    /// Add a port mapping with any external port, as `Gateway::add_any_port` does.
+    pub fn add_any_port(
+        &mut self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<u16, AddAnyPortError> {
+        let description = self.description(description);
+        let external_port = self
+            .gateway
+            .add_any_port(protocol, local_addr, lease_duration, &description)?;
+        self.track(protocol, external_port);
+        Ok(external_port)
+    }
+
+    /// Remove a port mapping of this session before the session ends.
+    pub fn remove_port(&mut self, protocol: PortMappingProtocol, external_port: u16) -> Result<(), RemovePortError> {
+        self.gateway.remove_port(protocol, external_port)?;
+        self.mappings.retain(|&mapping| mapping != (protocol, external_port));
+        Ok(())
+    }
+
+    /// End the session, removing all of its mappings.
+    ///
+    /// Every mapping is tried, the first error is returned.
+    pub fn close(mut self) -> Result<(), RemovePortError> {
+        self.remove_all()
+    }
+
+    fn description(&self, description: &str) -> String {
+        format!("{} {}", self.token, description)
+    }
+
+    fn track(&mut self, protocol: PortMappingProtocol, external_port: u16) {
+        if !self.mappings.contains(&(protocol, external_port)) {
+            self.mappings.push((protocol, external_port));
+        }
+    }
+
+    fn remove_all(&mut self) -> Result<(), RemovePortError> {
+        let mappings = mem::take(&mut self.mappings);
+        self.gateway
+            .remove_ports(&mappings)
+            .into_iter()
+            .zip(mappings)
+            .map(|(result, (protocol, external_port))| match result {
+                // Someone else removed it already.
+                Err(RemovePortError::NoSuchPortMapping) => Ok(()),
+                Err(e) => {
+                    debug!(
+                        "removing the mapping of {} port {} failed: {}",
+                        protocol, external_port, e
+                    );
+                    Err(e)
+                }
+                Ok(()) => Ok(()),
+            })
+            .fold(Ok(()), Result::and)
+    }
+}
+
+impl Drop for MappingSession {
+    fn drop(&mut self) {
+        let _ = self.remove_all();
+    }
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn test_mapping_session() {
+    use std::net::Ipv4Addr;
+
+    let mock = crate::test::MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080);
+
+    let mut session = MappingSession::new(gateway.clone());
+    session
+        .add_port(PortMappingProtocol::TCP, 4000, local_addr, 0, "a")
+        .unwrap();
+    let port = session
+        .add_any_port(PortMappingProtocol::UDP, local_addr, 0, "b")
+        .unwrap();
+    assert_eq!(
+        session.mappings(),
+        &[(PortMappingProtocol::TCP, 4000), (PortMappingProtocol::UDP, port)][..]
+    );
+    let token = session.token().to_string();
+    assert!(mock
+        .mappings()
+        .iter()
+        .all(|entry| entry.port_mapping_description.starts_with(&token)));
+    drop(session);
+    assert!(mock.mappings().is_empty());
+
+    let mut session = MappingSession::new(gateway);
+    assert_ne!(session.token(), token);
+    session
+        .add_port(PortMappingProtocol::TCP, 4000, local_addr, 0, "a")
+        .unwrap();
+    session.close().unwrap();
+    assert!(mock.mappings().is_empty());
+}
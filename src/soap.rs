@@ -0,0 +1,429 @@
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::common::{self, RequestFormat, RequestTimeouts};
+use crate::errors::{self, RequestError};
+
+/// Status, headers of interest and body of a SOAP response.
+pub struct Response {
+    pub status: u16,
+    /// The headers kept for `RequestError::HttpStatus`
+    pub headers: Vec<(String, String)>,
+    pub text: String,
+}
+
+/// Send a SOAP request.
+///
+/// The request is written by hand rather than with attohttpc, because some gateways care about
+/// the letter case of header names, which attohttpc always sends in lowercase.
+///
+/// The request fails with a `TimedOut` error once `deadline` passed.
+pub fn send(
+    url: &str,
+    action: &str,
+    body: &str,
+    format: &RequestFormat,
+    timeouts: &RequestTimeouts,
+    deadline: Option<Instant>,
+) -> Result<Response, RequestError> {
+    #[cfg(feature = "cassette")]
+    let response = {
+        // Cassettes don't keep the headers, replayed responses have none.
+        let mut headers = Vec::new();
+        let (status, text) = crate::cassette::http("POST", url, body.as_bytes(), || {
+            let response = send_request(url, action, body, format, timeouts, deadline)?;
+            headers = response.headers;
+            Ok::<_, RequestError>((response.status, response.text.into_bytes()))
+        })?;
+        Response {
+            status,
+            headers,
+            text: String::from_utf8(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        }
+    };
+    #[cfg(not(feature = "cassette"))]
+    let response = send_request(url, action, body, format, timeouts, deadline)?;
+    Ok(response)
+}
+
+fn send_request(
+    url: &str,
+    action: &str,
+    body: &str,
+    format: &RequestFormat,
+    timeouts: &RequestTimeouts,
+    deadline: Option<Instant>,
+) -> Result<Response, RequestError> {
+    let (host, port, request) = request(url, action, body, format)?;
+    let mut stream = connect(&host, port, timeouts.connect, deadline)?;
+    stream.set_write_timeout(limit(timeouts.read, deadline)?)?;
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    read_response(BufReader::new(TimedStream {
+        stream,
+        read: timeouts.read,
+        deadline,
+    }))
+}
+
+/// The host and port of `url`, and the request to send there, head and body in one buffer.
+///
+/// Shared with the async API, so that both send the same bytes.
+pub(crate) fn request(
+    url: &str,
+    action: &str,
+    body: &str,
+    format: &RequestFormat,
+) -> Result<(String, u16, String), RequestError> {
+    let url = Url::parse(url).map_err(|e| RequestError::InvalidResponse(format!("Invalid url {}: {}", url, e)))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| RequestError::InvalidResponse(format!("Url without host: {}", url)))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    let mut request = String::with_capacity(512 + body.len());
+    let _ = write!(
+        request,
+        "POST {path} {version}\r\n\
+         Host: {host}:{port}\r\n\
+         {content_type_name}: {content_type}\r\n\
+         {action_name}: {action}\r\n\
+         Content-Length: {length}\r\n\
+         Accept-Encoding: identity\r\n\
+         Connection: close\r\n\
+         \r\n",
+        path = path,
+        version = format.http_version(),
+        host = host,
+        port = port,
+        content_type_name = format.content_type_name(),
+        content_type = format.content_type(),
+        action_name = format.action_name(),
+        action = format.action_value(action),
+        length = body.len(),
+    );
+    request.push_str(body);
+    Ok((host.to_string(), port, request))
+}
+
+/// The shorter of `timeout` and the time left until `deadline`. Fails once `deadline` passed.
+fn limit(timeout: Option<Duration>, deadline: Option<Instant>) -> io::Result<Option<Duration>> {
+    let left = match deadline {
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if left > Duration::ZERO => left,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the deadline of the request passed",
+                ))
+            }
+        },
+        None => return Ok(timeout),
+    };
+    Ok(Some(timeout.map_or(left, |timeout| timeout.min(left))))
+}
+
+fn connect(host: &str, port: u16, timeout: Option<Duration>, deadline: Option<Instant>) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host));
+    for addr in (host, port).to_socket_addrs()? {
+        let result = match limit(timeout, deadline)? {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// A connection whose reads time out after `read`, and fail once `deadline` passed.
+struct TimedStream {
+    stream: TcpStream,
+    read: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(limit(self.read, self.deadline)?)?;
+        self.stream.read(buf).map_err(|e| match e.kind() {
+            // What an expired read timeout fails with on Unix.
+            io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, "reading the response timed out"),
+            _ => e,
+        })
+    }
+}
+
+/// Largest response body read, so that a device can't make the client allocate without bound.
+pub(crate) const MAX_BODY_SIZE: usize = 1 << 20;
+/// Largest status line and headers of a response.
+pub(crate) const MAX_HEAD_SIZE: usize = 64 << 10;
+/// Longest chunk size line of a chunked response.
+pub(crate) const MAX_LINE_SIZE: usize = 1024;
+
+/// Status, headers of interest and framing of the body of a response.
+pub(crate) struct Head {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub framing: Framing,
+}
+
+/// How the end of a response body is found.
+pub(crate) enum Framing {
+    Length(usize),
+    Chunked,
+    Close,
+}
+
+pub(crate) fn read_response<R: BufRead>(mut reader: R) -> Result<Response, RequestError> {
+    let head = read_head(&mut reader)?;
+    let body = read_body(reader, &head.framing)?;
+    let text = String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Response {
+        status: head.status,
+        headers: head.headers,
+        text,
+    })
+}
+
+/// Read the status line and the headers of a response, of `MAX_HEAD_SIZE` at most.
+pub(crate) fn read_head<R: BufRead>(mut reader: R) -> Result<Head, RequestError> {
+    let mut left = MAX_HEAD_SIZE;
+    let mut line = String::new();
+    left -= read_line(&mut reader, &mut line, left)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| RequestError::InvalidResponse(format!("Invalid status line: {}", line.trim())))?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut content_encoding = None;
+    let mut headers = Vec::new();
+    loop {
+        let read = read_line(&mut reader, &mut line, left)?;
+        if read == 0 {
+            break;
+        }
+        left -= read;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(colon) = header.find(':') {
+            let (name, value) = (header[..colon].trim(), header[colon + 1..].trim());
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse::<usize>().ok();
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.to_ascii_lowercase().contains("chunked");
+            } else if name.eq_ignore_ascii_case("content-encoding") {
+                content_encoding = Some(value.to_string());
+            }
+            if errors::is_http_error_header(name) {
+                headers.push((name.to_string(), value.to_string()));
+            }
+        }
+    }
+
+    common::check_content_encoding(content_encoding.as_deref())?;
+
+    let framing = match content_length {
+        _ if chunked => Framing::Chunked,
+        Some(length) if length > MAX_BODY_SIZE => return Err(body_too_large()),
+        Some(length) => Framing::Length(length),
+        None => Framing::Close,
+    };
+    Ok(Head {
+        status,
+        headers,
+        framing,
+    })
+}
+
+fn read_body<R: BufRead>(mut reader: R, framing: &Framing) -> Result<Vec<u8>, RequestError> {
+    let mut body = Vec::new();
+    match *framing {
+        Framing::Chunked => {
+            let mut line = String::new();
+            loop {
+                read_line(&mut reader, &mut line, MAX_LINE_SIZE)?;
+                let start = body.len();
+                match chunk_end(&line, start)? {
+                    Some(end) => body.resize(end, 0),
+                    None => break,
+                }
+                reader.read_exact(&mut body[start..])?;
+                read_line(&mut reader, &mut line, MAX_LINE_SIZE)?;
+            }
+        }
+        Framing::Length(length) => {
+            body.resize(length, 0);
+            reader.read_exact(&mut body)?;
+        }
+        Framing::Close => {
+            reader.take(MAX_BODY_SIZE as u64 + 1).read_to_end(&mut body)?;
+            if body.len() > MAX_BODY_SIZE {
+                return Err(body_too_large());
+            }
+        }
+    }
+    Ok(body)
+}
+
+/// Read a line of `max` bytes at most into `line`, returning its length, 0 at the end.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String, max: usize) -> Result<usize, RequestError> {
+    line.clear();
+    let read = reader.take(max as u64).read_line(line)?;
+    if read == max && !line.ends_with('\n') {
+        return Err(RequestError::InvalidResponse(format!("Line longer than {} bytes", max)));
+    }
+    Ok(read)
+}
+
+/// The length of a body of `length` bytes with the chunk of the size `line` appended, or `None`
+/// after the last chunk.
+pub(crate) fn chunk_end(line: &str, length: usize) -> Result<Option<usize>, RequestError> {
+    let size = line.split(';').next().unwrap_or("").trim();
+    let size = usize::from_str_radix(size, 16)
+        .map_err(|_| RequestError::InvalidResponse(format!("Invalid chunk size: {}", size)))?;
+    if size == 0 {
+        return Ok(None);
+    }
+    match length.checked_add(size) {
+        Some(end) if end <= MAX_BODY_SIZE => Ok(Some(end)),
+        _ => Err(body_too_large()),
+    }
+}
+
+pub(crate) fn body_too_large() -> RequestError {
+    RequestError::InvalidResponse(format!("Response body larger than {} bytes", MAX_BODY_SIZE))
+}
+
+#[test]
+fn test_read_response() {
+    let raw = "HTTP/1.1 200 OK\r\nCONTENT-LENGTH: 5\r\n\r\nhello, world";
+    let response = read_response(raw.as_bytes()).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text, "hello");
+
+    let raw = "HTTP/1.1 500 Internal Server Error\r\nTransfer-Encoding: chunked\r\nRetry-After: 30\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n";
+    let response = read_response(raw.as_bytes()).unwrap();
+    assert_eq!(response.status, 500);
+    assert_eq!(response.headers, [("Retry-After".to_string(), "30".to_string())]);
+    assert_eq!(response.text, "hello, world");
+
+    let raw = "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: 2\r\n\r\n\x1f\u{8b}";
+    match read_response(raw.as_bytes()) {
+        Err(RequestError::IoError(ref e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+        _ => panic!("a compressed response was accepted"),
+    }
+
+    let too_large = |raw: &[u8]| match read_response(raw) {
+        Err(RequestError::InvalidResponse(ref e)) => e.contains("larger than") || e.contains("longer than"),
+        _ => false,
+    };
+    assert!(too_large(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\n"
+    ));
+    assert!(too_large(
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n"
+    ));
+    assert!(too_large(
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nfffffffffffffff\r\n"
+    ));
+    let mut raw = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+    raw.resize(raw.len() + MAX_BODY_SIZE + 1, b'a');
+    assert!(too_large(&raw));
+    let mut raw = b"HTTP/1.1 200 OK\r\nServer: ".to_vec();
+    raw.resize(raw.len() + MAX_HEAD_SIZE, b'a');
+    assert!(too_large(&raw));
+}
+
+#[test]
+fn test_send_timeouts() {
+    use std::net::TcpListener;
+
+    // The connection is accepted by the kernel, but never answered.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/ctl", listener.local_addr().unwrap());
+    let timed_out = |timeouts: RequestTimeouts, deadline: Option<Instant>| {
+        let started = Instant::now();
+        match send(&url, "action", "", &RequestFormat::default(), &timeouts, deadline) {
+            Err(RequestError::IoError(ref e)) if e.kind() == io::ErrorKind::TimedOut => started.elapsed(),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(..) => panic!("unexpected response"),
+        }
+    };
+
+    let timeouts = RequestTimeouts {
+        read: Some(Duration::from_millis(100)),
+        deadline: None,
+        ..Default::default()
+    };
+    assert!(timed_out(timeouts, None) < Duration::from_secs(5));
+
+    let timeouts = RequestTimeouts {
+        read: None,
+        ..timeouts
+    };
+    let elapsed = timed_out(timeouts, Some(Instant::now() + Duration::from_millis(200)));
+    assert!(elapsed >= Duration::from_millis(150) && elapsed < Duration::from_secs(5));
+}
+
+#[test]
+fn test_send_request_bytes() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    for http_1_0 in [false, true] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ctl", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"<body/>") {
+                let read = stream.read(&mut buf).unwrap();
+                assert_ne!(read, 0);
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.0 200 OK\r\nContent-Length: 4\r\n\r\n<ok>")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let format = RequestFormat {
+            http_1_0,
+            ..Default::default()
+        };
+        let timeouts = RequestTimeouts::default();
+        let response = send(&url, "urn:test#Act", "<body/>", &format, &timeouts, None).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text, "<ok>");
+
+        let request = server.join().unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.split("\r\n");
+        let version = if http_1_0 { "HTTP/1.0" } else { "HTTP/1.1" };
+        assert_eq!(lines.next(), Some(format!("POST /ctl {}", version).as_str()));
+        let headers: Vec<&str> = lines.collect();
+        assert!(headers.contains(&"Content-Length: 7"), "{}", head);
+        assert!(headers.contains(&"Connection: close"), "{}", head);
+        assert!(headers.contains(&"SOAPAction: \"urn:test#Act\""), "{}", head);
+        assert!(!head.to_ascii_lowercase().contains("transfer-encoding"), "{}", head);
+        assert_eq!(body, "<body/>");
+    }
+}
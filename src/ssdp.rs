@@ -0,0 +1,513 @@
+//! SSDP discovery of any kind of UPnP device.
+//!
+//! The gateway search functions are built on the same requests and responses, but only keep
+//! Internet Gateway Devices. This module searches for an arbitrary search target (`ST`) and
+//! returns the headers of every response.
+//!
+//! # Example
+//! ```no_run
+//! use igd::ssdp;
+//!
+//! for response in ssdp::search(ssdp::ALL, Default::default()).unwrap() {
+//!     println!("{} {:?}", response.from, response.location());
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::str;
+use std::time::{Duration, Instant};
+
+use crate::common::{self, parsing};
+use crate::proto::Headers;
+use crate::SearchOptions;
+
+/// Search target matching every device and service.
+pub const ALL: &str = "ssdp:all";
+
+/// Search target matching root devices only.
+pub const ROOT_DEVICE: &str = "upnp:rootdevice";
+
+/// Search target matching Internet Gateway Devices.
+pub const INTERNET_GATEWAY_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// Types of the devices and services of an Internet Gateway Device, without their version.
+const GATEWAY_TYPES: &[&str] = &[
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:",
+    "urn:schemas-upnp-org:device:WANDevice:",
+    "urn:schemas-upnp-org:device:WANConnectionDevice:",
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+
+/// Whether a search or notification target (`ST` or `NT`) is an Internet Gateway Device, or one
+/// of the devices or services it is made of for port mapping, of any version.
+pub fn is_gateway_type(target: &str) -> bool {
+    GATEWAY_TYPES.iter().any(|prefix| target.starts_with(prefix))
+}
+
+/// Multicast group devices send their `NOTIFY` announcements to, on port 1900.
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+/// Format an M-SEARCH request for the given search target.
+///
+/// Devices wait up to `mx` seconds before answering, to spread the responses.
+pub fn format_search_request(search_target: &str, mx: u8) -> String {
+    SearchRequest::new(search_target).mx(mx).to_string()
+}
+
+/// Builder of an M-SEARCH request, formatted with `to_string`.
+///
+/// The default searches Internet Gateway Devices, with an `MX` of 3 seconds. Some equipment
+/// only answers requests with a `USER-AGENT`, or other headers, which can be added.
+///
+/// Some gateways only answer `ssdp:all`. When the gateway search functions send it with
+/// `SearchRequest::new(ssdp::ALL)`, the responses of other devices are skipped, see
+/// `is_gateway_type`.
+///
+/// # Example
+/// ```
+/// use igd::ssdp::SearchRequest;
+/// use igd::SearchOptions;
+///
+/// let options = SearchOptions {
+///     request: SearchRequest::default()
+///         .user_agent("Linux/5.10 UPnP/2.0 myapp/1.0")
+///         .header("X-Requested-By", "myapp"),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchRequest {
+    search_target: String,
+    mx: u8,
+    user_agent: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl SearchRequest {
+    /// A request for the given search target (`ST`).
+    pub fn new(search_target: &str) -> SearchRequest {
+        SearchRequest {
+            search_target: search_target.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Let devices wait up to `mx` seconds before answering.
+    pub fn mx(mut self, mx: u8) -> SearchRequest {
+        self.mx = mx;
+        self
+    }
+
+    /// Identify the control point with a `USER-AGENT` header, e.g. `OS/version UPnP/2.0
+    /// product/version`.
+    pub fn user_agent(mut self, user_agent: &str) -> SearchRequest {
+        self.user_agent = Some(strip_line_breaks(user_agent));
+        self
+    }
+
+    /// Add a header. Line breaks in the name or value are removed.
+    pub fn header(mut self, name: &str, value: &str) -> SearchRequest {
+        self.headers.push((strip_line_breaks(name), strip_line_breaks(value)));
+        self
+    }
+
+    /// The search target (`ST`).
+    pub fn search_target(&self) -> &str {
+        &self.search_target
+    }
+
+    /// Whether a response to this request may come from a gateway.
+    ///
+    /// Every device and service answers `ssdp:all`, so only the responses with a gateway type
+    /// as `ST` are kept then. Responses to other search targets are all kept.
+    pub(crate) fn accepts_response(&self, headers: &Headers) -> bool {
+        if self.search_target != ALL {
+            return true;
+        }
+        headers.get("st").is_none_or(is_gateway_type)
+    }
+}
+
+impl Default for SearchRequest {
+    fn default() -> SearchRequest {
+        SearchRequest {
+            search_target: INTERNET_GATEWAY_DEVICE.to_string(),
+            mx: 3,
+            user_agent: None,
+            headers: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for SearchRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "M-SEARCH * HTTP/1.1\r\n\
+             Host:239.255.255.250:1900\r\n\
+             ST:{}\r\n\
+             Man:\"ssdp:discover\"\r\n\
+             MX:{}\r\n",
+            self.search_target, self.mx
+        )?;
+        if let Some(ref user_agent) = self.user_agent {
+            write!(f, "USER-AGENT:{}\r\n", user_agent)?;
+        }
+        for (name, value) in &self.headers {
+            write!(f, "{}:{}\r\n", name, value)?;
+        }
+        write!(f, "\r\n")
+    }
+}
+
+fn strip_line_breaks(text: &str) -> String {
+    text.chars().filter(|c| *c != '\r' && *c != '\n').collect()
+}
+
+/// A response to an M-SEARCH request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchResponse {
+    /// Address the response was received from
+    pub from: SocketAddr,
+    /// Headers of the response, with lowercase names
+    pub headers: HashMap<String, String>,
+}
+
+impl SearchResponse {
+    /// Get the value of a header, matching the name case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    /// Url of the device description (`LOCATION`).
+    pub fn location(&self) -> Option<&str> {
+        self.header("location")
+    }
+
+    /// Search target the device answered for (`ST`).
+    pub fn search_target(&self) -> Option<&str> {
+        self.header("st")
+    }
+
+    /// Unique service name of the device (`USN`).
+    pub fn usn(&self) -> Option<&str> {
+        self.header("usn")
+    }
+
+    /// Operating system and product of the device (`SERVER`).
+    pub fn server(&self) -> Option<&str> {
+        self.header("server")
+    }
+
+    /// Whether the response comes from an Internet Gateway Device, judging by its `ST`.
+    pub fn is_gateway(&self) -> bool {
+        self.search_target().is_some_and(is_gateway_type)
+    }
+}
+
+/// Parse a response to an M-SEARCH request.
+///
+/// Returns `None` if the data is not a successful HTTP response, e.g. a search request of another
+/// control point or a `NOTIFY` announcement.
+pub fn parse_search_response(from: SocketAddr, data: &[u8]) -> Option<SearchResponse> {
+    let headers = Headers::parse(data);
+    if !headers.is_success() {
+        return None;
+    }
+
+    Some(SearchResponse {
+        from,
+        headers: to_map(&headers),
+    })
+}
+
+fn to_map(headers: &Headers) -> HashMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+        .collect()
+}
+
+/// A `NOTIFY` announcement of a device joining (`ssdp:alive`) or leaving (`ssdp:byebye`) the network.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+    /// Address the announcement was received from
+    pub from: SocketAddr,
+    /// Headers of the announcement, with lowercase names
+    pub headers: HashMap<String, String>,
+}
+
+impl Notification {
+    /// Get the value of a header, matching the name case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    /// Type of the device or service announced (`NT`).
+    pub fn notification_type(&self) -> Option<&str> {
+        self.header("nt")
+    }
+
+    /// Unique service name of the device (`USN`).
+    pub fn usn(&self) -> Option<&str> {
+        self.header("usn")
+    }
+
+    /// Unique device name, the part of the `USN` before `::`.
+    pub fn udn(&self) -> Option<&str> {
+        self.usn().map(|usn| usn.split("::").next().unwrap_or(usn))
+    }
+
+    /// Url of the device description (`LOCATION`), only sent with `ssdp:alive`.
+    pub fn location(&self) -> Option<&str> {
+        self.header("location")
+    }
+
+    /// Whether the device announced it is available.
+    pub fn is_alive(&self) -> bool {
+        self.header("nts") == Some("ssdp:alive")
+    }
+
+    /// Whether the device announced it is leaving.
+    pub fn is_byebye(&self) -> bool {
+        self.header("nts") == Some("ssdp:byebye")
+    }
+
+    /// How long the announcement is valid, from `CACHE-CONTROL: max-age`.
+    pub fn max_age(&self) -> Option<Duration> {
+        parsing::parse_max_age(self.header("cache-control")?)
+    }
+}
+
+/// Parse a `NOTIFY` announcement.
+///
+/// Returns `None` if the data is not a `NOTIFY` request, e.g. a search request of a control point.
+pub fn parse_notification(from: SocketAddr, data: &[u8]) -> Option<Notification> {
+    let headers = Headers::parse(data);
+    if headers.start_line()?.split_whitespace().next()? != "NOTIFY" {
+        return None;
+    }
+    Some(Notification {
+        from,
+        headers: to_map(&headers),
+    })
+}
+
+/// Receives the `NOTIFY` announcements devices multicast when they join or leave the network.
+#[derive(Debug)]
+pub struct NotifyListener {
+    socket: UdpSocket,
+}
+
+impl NotifyListener {
+    /// Listen on port 1900 of every interface.
+    ///
+    /// This fails if another process on this host, e.g. an SSDP daemon, bound the port without
+    /// allowing it to be shared. Use `from_socket` with a socket bound with `SO_REUSEADDR` then.
+    pub fn bind() -> io::Result<NotifyListener> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 1900))?;
+        socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+        Ok(NotifyListener { socket })
+    }
+
+    /// Listen on a socket that is already bound, and joined the multicast group.
+    pub fn from_socket(socket: UdpSocket) -> NotifyListener {
+        NotifyListener { socket }
+    }
+
+    /// Wait for the next announcement, at most `timeout` if given.
+    ///
+    /// Returns `None` when the timeout expires. Other messages are skipped.
+    pub fn recv(&self, timeout: Option<Duration>) -> io::Result<Option<Notification>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(None);
+                }
+                self.socket.set_read_timeout(Some(deadline - now))?;
+            } else {
+                self.socket.set_read_timeout(None)?;
+            }
+
+            let mut buf = [0u8; 1500];
+            match self.socket.recv_from(&mut buf) {
+                Ok((read, from)) => {
+                    if let Some(notification) = parse_notification(from, &buf[..read]) {
+                        return Ok(Some(notification));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Search for devices matching `search_target`, collecting responses until the timeout expires.
+///
+/// Without a timeout in the options, only the first response is returned.
+/// The request of the options is sent with this search target instead.
+pub fn search(search_target: &str, options: SearchOptions) -> io::Result<Vec<SearchResponse>> {
+    let socket = common::bind_search_socket(&options)?;
+    socket.send_to(
+        request_for(search_target, &options).as_bytes(),
+        options.broadcast_address,
+    )?;
+
+    let begin = Instant::now();
+    let mut responses = Vec::new();
+    loop {
+        if let Some(timeout) = options.timeout {
+            let elapsed = begin.elapsed();
+            if elapsed >= timeout {
+                break;
+            }
+            socket.set_read_timeout(Some(timeout - elapsed))?;
+        }
+
+        let mut buf = [0u8; 1500];
+        match socket.recv_from(&mut buf) {
+            Ok((read, from)) => {
+                if let Some(response) = parse_search_response(from, &buf[..read]) {
+                    responses.push(response);
+                    if options.timeout.is_none() {
+                        break;
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(responses)
+}
+
+fn request_for(search_target: &str, options: &SearchOptions) -> String {
+    SearchRequest {
+        search_target: search_target.to_string(),
+        ..options.request.clone()
+    }
+    .to_string()
+}
+
+/// Async version of `search`.
+#[cfg(feature = "aio")]
+pub async fn search_async(search_target: &str, options: SearchOptions) -> io::Result<Vec<SearchResponse>> {
+    let socket = common::bind_search_socket(&options)?;
+    socket.set_nonblocking(true)?;
+    let socket = tokio::net::UdpSocket::from_std(socket)?;
+    socket
+        .send_to(
+            request_for(search_target, &options).as_bytes(),
+            options.broadcast_address,
+        )
+        .await?;
+
+    let mut responses = Vec::new();
+    let receive = async {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (read, from) = socket.recv_from(&mut buf).await?;
+            if let Some(response) = parse_search_response(from, &buf[..read]) {
+                responses.push(response);
+                if options.timeout.is_none() {
+                    return Ok::<_, io::Error>(());
+                }
+            }
+        }
+    };
+    match options.timeout {
+        Some(timeout) => {
+            // The responses received until the timeout are kept.
+            if let Ok(result) = tokio::time::timeout(timeout, receive).await {
+                result?;
+            }
+        }
+        None => receive.await?,
+    }
+    Ok(responses)
+}
+
+#[test]
+fn test_parse_search_response() {
+    let from = "192.168.1.1:1900".parse().unwrap();
+    let data = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nST: upnp:rootdevice\r\n\
+                 Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+    let response = parse_search_response(from, data).unwrap();
+    assert_eq!(response.location(), Some("http://192.168.1.1:5000/rootDesc.xml"));
+    assert_eq!(response.search_target(), Some(ROOT_DEVICE));
+    assert_eq!(response.header("Cache-Control"), Some("max-age=120"));
+    assert_eq!(response.usn(), None);
+
+    assert!(parse_search_response(from, format_search_request(ALL, 3).as_bytes()).is_none());
+    assert_eq!(
+        format_search_request(INTERNET_GATEWAY_DEVICE, 3),
+        SearchRequest::default().to_string()
+    );
+}
+
+#[test]
+fn test_is_gateway_type() {
+    assert!(is_gateway_type(INTERNET_GATEWAY_DEVICE));
+    assert!(is_gateway_type("urn:schemas-upnp-org:device:InternetGatewayDevice:2"));
+    assert!(is_gateway_type("urn:schemas-upnp-org:service:WANPPPConnection:1"));
+    assert!(!is_gateway_type("urn:schemas-upnp-org:device:MediaRenderer:1"));
+    assert!(!is_gateway_type(ROOT_DEVICE));
+
+    let request = SearchRequest::new(ALL);
+    let accepts = |request: &SearchRequest, data: &[u8]| request.accepts_response(&Headers::parse(data));
+    assert!(accepts(
+        &request,
+        b"HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:service:WANIPConnection:2\r\n"
+    ));
+    assert!(!accepts(&request, b"HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\n"));
+    assert!(accepts(
+        &SearchRequest::new(ROOT_DEVICE),
+        b"HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\n"
+    ));
+}
+
+#[test]
+fn test_search_request() {
+    let request = SearchRequest::new(ROOT_DEVICE)
+        .mx(1)
+        .user_agent("Linux/5.10 UPnP/2.0 test/1.0")
+        .header("X-Test", "a\r\nInjected: b")
+        .to_string();
+    assert_eq!(
+        request,
+        "M-SEARCH * HTTP/1.1\r\n\
+         Host:239.255.255.250:1900\r\n\
+         ST:upnp:rootdevice\r\n\
+         Man:\"ssdp:discover\"\r\n\
+         MX:1\r\n\
+         USER-AGENT:Linux/5.10 UPnP/2.0 test/1.0\r\n\
+         X-Test:aInjected: b\r\n\
+         \r\n"
+    );
+}
+
+#[test]
+fn test_parse_notification() {
+    let from = "192.168.1.1:1900".parse().unwrap();
+    let data = b"NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nCACHE-CONTROL: max-age=1800\r\n\
+                 LOCATION: http://192.168.1.1:5000/rootDesc.xml\r\nNT: upnp:rootdevice\r\nNTS: ssdp:alive\r\n\
+                 USN: uuid:804e2e56-7bfe-4733-bae0-04bf6d569692::upnp:rootdevice\r\n\r\n";
+    let notification = parse_notification(from, data).unwrap();
+    assert!(notification.is_alive());
+    assert!(!notification.is_byebye());
+    assert_eq!(notification.notification_type(), Some(ROOT_DEVICE));
+    assert_eq!(notification.udn(), Some("uuid:804e2e56-7bfe-4733-bae0-04bf6d569692"));
+    assert_eq!(notification.max_age(), Some(Duration::from_secs(1800)));
+    assert_eq!(notification.location(), Some("http://192.168.1.1:5000/rootDesc.xml"));
+
+    assert!(parse_notification(from, format_search_request(ALL, 3).as_bytes()).is_none());
+}
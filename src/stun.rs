@@ -0,0 +1,260 @@
+//! A minimal STUN client (RFC 5389), used to learn the external address when the gateway can't tell.
+//!
+//! Only the binding request is implemented, which is all that is needed to find the address
+//! a packet appears to come from after passing through the NAT.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use rand::{self, Rng};
+
+/// Time to wait for an answer from each STUN server.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const CHANGE_REQUEST: u16 = 0x0003;
+const OTHER_ADDRESS: u16 = 0x802C;
+
+/// The useful attributes of a binding response.
+pub(crate) struct BindingResponse {
+    /// Address the server saw the request coming from
+    pub mapped: SocketAddr,
+    /// Alternate address of the server (RFC 5780 `OTHER-ADDRESS`)
+    pub other: Option<SocketAddr>,
+}
+
+pub(crate) fn binding_request(change_ip: bool, change_port: bool) -> (Vec<u8>, [u8; 12]) {
+    let transaction_id: [u8; 12] = rand::thread_rng().gen();
+    let mut request = Vec::with_capacity(28);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&[0, 0]);
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+    if change_ip || change_port {
+        let flags = (if change_ip { 0x04u32 } else { 0 }) | (if change_port { 0x02 } else { 0 });
+        request.extend_from_slice(&CHANGE_REQUEST.to_be_bytes());
+        request.extend_from_slice(&4u16.to_be_bytes());
+        request.extend_from_slice(&flags.to_be_bytes());
+        request[2..4].copy_from_slice(&8u16.to_be_bytes());
+    }
+    (request, transaction_id)
+}
+
+pub(crate) fn parse_binding_response(response: &[u8], transaction_id: &[u8; 12]) -> Option<BindingResponse> {
+    if response.len() < 20
+        || u16::from_be_bytes([response[0], response[1]]) != BINDING_RESPONSE
+        || response[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || &response[8..20] != transaction_id
+    {
+        return None;
+    }
+
+    let length = u16::from_be_bytes([response[2], response[3]]) as usize;
+    let attributes = response.get(20..20 + length)?;
+
+    let mut mapped = None;
+    let mut xor_mapped = None;
+    let mut other = None;
+    let mut offset = 0;
+    while offset + 4 <= attributes.len() {
+        let kind = u16::from_be_bytes([attributes[offset], attributes[offset + 1]]);
+        let len = u16::from_be_bytes([attributes[offset + 2], attributes[offset + 3]]) as usize;
+        let value = attributes.get(offset + 4..offset + 4 + len)?;
+        match kind {
+            XOR_MAPPED_ADDRESS => xor_mapped = parse_address(value, Some(transaction_id)),
+            MAPPED_ADDRESS => mapped = parse_address(value, None),
+            OTHER_ADDRESS => other = parse_address(value, None),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of 4 bytes.
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+    Some(BindingResponse {
+        mapped: xor_mapped.or(mapped)?,
+        other,
+    })
+}
+
+/// Send a binding request from `socket` and wait for the matching response.
+///
+/// The response may come from any address, as requested with `change_ip` and `change_port`.
+/// The request is sent up to `attempts` times, each time the read timeout of the socket passed
+/// without an answer.
+pub(crate) fn transact(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change_ip: bool,
+    change_port: bool,
+    attempts: u32,
+) -> io::Result<BindingResponse> {
+    let (request, transaction_id) = binding_request(change_ip, change_port);
+    socket.send_to(&request, server)?;
+
+    let mut sent = 1;
+    let mut buf = [0u8; 576];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((read, from)) => match parse_binding_response(&buf[..read], &transaction_id) {
+                Some(response) => return Ok(response),
+                None => debug!("ignoring invalid STUN response from {}", from),
+            },
+            // The request or its response got lost, retransmit it with the same transaction.
+            Err(ref e) if sent < attempts && is_timeout(e) => {
+                debug!("no answer from STUN server {}, sending the request again", server);
+                socket.send_to(&request, server)?;
+                sent += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether waiting for a datagram failed because the read timeout passed.
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+pub(crate) fn unspecified_addr(server: &SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+fn parse_address(value: &[u8], xor: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let mut port = u16::from_be_bytes([value[2], value[3]]);
+    if xor.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+
+    let ip = match value[1] {
+        0x01 if value.len() >= 8 => {
+            let mut octets = [value[4], value[5], value[6], value[7]];
+            if xor.is_some() {
+                for (octet, mask) in octets.iter_mut().zip(cookie.iter()) {
+                    *octet ^= mask;
+                }
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 if value.len() >= 20 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&value[4..20]);
+            if let Some(transaction_id) = xor {
+                let mask = cookie.iter().chain(transaction_id.iter());
+                for (octet, mask) in octets.iter_mut().zip(mask) {
+                    *octet ^= mask;
+                }
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Send a binding request to `server` and return the address it saw the request coming from.
+pub fn query(server: SocketAddr, timeout: Duration) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(unspecified_addr(&server))?;
+    socket.set_read_timeout(Some(timeout))?;
+    transact(&socket, server, false, false, 1).map(|response| response.mapped)
+}
+
+/// Ask each server in turn for our external IPv4 address, returning the first answer.
+///
+/// Servers are given as `host:port`, e.g. `stun.example.org:3478`.
+pub fn external_ip<S: AsRef<str>>(servers: &[S], timeout: Duration) -> io::Result<Ipv4Addr> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no STUN server given");
+    for server in servers {
+        let addrs = match server.as_ref().to_socket_addrs() {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                last_error = e;
+                continue;
+            }
+        };
+        for addr in addrs.filter(SocketAddr::is_ipv4) {
+            match query(addr, timeout) {
+                Ok(SocketAddr::V4(mapped)) => return Ok(*mapped.ip()),
+                Ok(mapped) => debug!("STUN server {} returned non-IPv4 address {}", addr, mapped),
+                Err(e) => {
+                    debug!("STUN request to {} failed: {}", addr, e);
+                    last_error = e;
+                }
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Async version of `query`.
+#[cfg(feature = "aio")]
+pub async fn query_async(server: SocketAddr, timeout: Duration) -> io::Result<SocketAddr> {
+    let socket = tokio::net::UdpSocket::bind(unspecified_addr(&server)).await?;
+
+    let (request, transaction_id) = binding_request(false, false);
+    socket.send_to(&request, server).await?;
+
+    let receive = async {
+        let mut buf = [0u8; 576];
+        loop {
+            let (read, from) = socket.recv_from(&mut buf).await?;
+            match parse_binding_response(&buf[..read], &transaction_id) {
+                Some(response) => return Ok(response.mapped),
+                None => debug!("ignoring invalid STUN response from {}", from),
+            }
+        }
+    };
+    tokio::time::timeout(timeout, receive)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "STUN request timed out"))?
+}
+
+/// Async version of `external_ip`.
+#[cfg(feature = "aio")]
+pub async fn external_ip_async<S: AsRef<str>>(servers: &[S], timeout: Duration) -> io::Result<Ipv4Addr> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no STUN server given");
+    for server in servers {
+        let addrs = match tokio::net::lookup_host(server.as_ref()).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                last_error = e;
+                continue;
+            }
+        };
+        for addr in addrs.filter(SocketAddr::is_ipv4) {
+            match query_async(addr, timeout).await {
+                Ok(SocketAddr::V4(mapped)) => return Ok(*mapped.ip()),
+                Ok(mapped) => debug!("STUN server {} returned non-IPv4 address {}", addr, mapped),
+                Err(e) => {
+                    debug!("STUN request to {} failed: {}", addr, e);
+                    last_error = e;
+                }
+            }
+        }
+    }
+    Err(last_error)
+}
+
+#[test]
+fn test_parse_binding_response() {
+    let transaction_id = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+    // Sample IPv4 response from RFC 5769, section 2.2, without the SOFTWARE and integrity attributes.
+    let response = [
+        0x01, 0x01, 0x00, 0x0c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87,
+        0xdf, 0xae, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+    ];
+    let parsed = parse_binding_response(&response, &transaction_id).unwrap();
+    assert_eq!(parsed.mapped, "192.0.2.1:32853".parse().unwrap());
+    assert_eq!(parsed.other, None);
+    assert!(parse_binding_response(&response, &[0; 12]).is_none());
+}
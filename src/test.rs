@@ -0,0 +1,1329 @@
+//! An in-process IGD for integration tests.
+//!
+//! `MockGateway` answers M-SEARCH requests on a loopback UDP socket and serves a device
+//! description, an SCPD and SOAP responses over HTTP. By default it emulates a gateway with a
+//! port mapping table; any action can be overridden to return a canned response or a fault.
+//!
+//! # Example
+//! ```
+//! use igd::test::{MockGateway, MockResponse};
+//!
+//! let mock = MockGateway::start().unwrap();
+//! let gateway = igd::search_gateway(mock.search_options()).unwrap();
+//! assert_eq!(gateway.get_external_ip().unwrap(), mock.external_ip());
+//!
+//! mock.respond("GetExternalIPAddress", MockResponse::Fault(501, "Action Failed".into()));
+//! assert!(gateway.get_external_ip().is_err());
+//! ```
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::common::messages::{
+    DEVICE_CONFIG_SERVICES, WAN_COMMON_INTERFACE_CONFIG_SERVICE, WAN_IPV6_FIREWALL_CONTROL_SERVICE,
+    WAN_IP_CONNECTION_SERVICE,
+};
+use crate::{PortMappingEntry, PortMappingProtocol, SearchOptions};
+
+const DESCRIPTION_PATH: &str = "/rootDesc.xml";
+const SCPD_PATH: &str = "/WANIPCn.xml";
+const CONTROL_PATH: &str = "/ctl/IPConn";
+const COMMON_INTERFACE_CONTROL_PATH: &str = "/ctl/CmnIfCfg";
+const FIREWALL_CONTROL_PATH: &str = "/ctl/IP6FCtl";
+const DEVICE_CONFIG_CONTROL_PATH: &str = "/ctl/DevCfg";
+
+/// The device description served by default.
+pub const DEFAULT_DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+    <specVersion><major>1</major><minor>0</minor></specVersion>
+    <device>
+        <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+        <friendlyName>Mock Gateway</friendlyName>
+        <manufacturer>rust-igd</manufacturer>
+        <modelName>MockGateway</modelName>
+        <modelNumber>1</modelNumber>
+        <UDN>uuid:00000000-0000-0000-0000-000000000001</UDN>
+        <deviceList>
+            <device>
+                <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+                <serviceList>
+                    <service>
+                        <serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType>
+                        <serviceId>urn:upnp-org:serviceId:WANCommonIFC1</serviceId>
+                        <SCPDURL>/WANCfg.xml</SCPDURL>
+                        <controlURL>/ctl/CmnIfCfg</controlURL>
+                        <eventSubURL>/evt/CmnIfCfg</eventSubURL>
+                    </service>
+                </serviceList>
+                <deviceList>
+                    <device>
+                        <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+                        <serviceList>
+                            <service>
+                                <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+                                <serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+                                <SCPDURL>/WANIPCn.xml</SCPDURL>
+                                <controlURL>/ctl/IPConn</controlURL>
+                                <eventSubURL>/evt/IPConn</eventSubURL>
+                            </service>
+                            <service>
+                                <serviceType>urn:schemas-upnp-org:service:WANIPv6FirewallControl:1</serviceType>
+                                <serviceId>urn:upnp-org:serviceId:WANIPv6Firewall1</serviceId>
+                                <SCPDURL>/WANIPv6FC.xml</SCPDURL>
+                                <controlURL>/ctl/IP6FCtl</controlURL>
+                                <eventSubURL>/evt/IP6FCtl</eventSubURL>
+                            </service>
+                        </serviceList>
+                    </device>
+                </deviceList>
+            </device>
+        </deviceList>
+    </device>
+</root>"#;
+
+/// Actions of the default SCPD, with their input arguments.
+const ACTIONS: &[(&str, &[&str])] = &[
+    ("GetExternalIPAddress", &[]),
+    ("GetStatusInfo", &[]),
+    (
+        "AddPortMapping",
+        &[
+            "NewRemoteHost",
+            "NewExternalPort",
+            "NewProtocol",
+            "NewInternalPort",
+            "NewInternalClient",
+            "NewEnabled",
+            "NewPortMappingDescription",
+            "NewLeaseDuration",
+        ],
+    ),
+    (
+        "AddAnyPortMapping",
+        &[
+            "NewRemoteHost",
+            "NewExternalPort",
+            "NewProtocol",
+            "NewInternalPort",
+            "NewInternalClient",
+            "NewEnabled",
+            "NewPortMappingDescription",
+            "NewLeaseDuration",
+        ],
+    ),
+    (
+        "DeletePortMapping",
+        &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+    ),
+    (
+        "DeletePortMappingRange",
+        &["NewStartPort", "NewEndPort", "NewProtocol", "NewManage"],
+    ),
+    ("GetGenericPortMappingEntry", &["NewPortMappingIndex"]),
+    (
+        "GetSpecificPortMappingEntry",
+        &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+    ),
+];
+
+/// The SCPD of the WANIPConnection service served by default.
+pub fn default_scpd() -> String {
+    let mut actions = String::new();
+    for (name, arguments) in ACTIONS {
+        actions.push_str("<action><name>");
+        actions.push_str(name);
+        actions.push_str("</name><argumentList>");
+        for argument in arguments.iter() {
+            actions.push_str("<argument><name>");
+            actions.push_str(argument);
+            actions.push_str("</name><direction>in</direction></argument>");
+        }
+        actions.push_str("</argumentList></action>");
+    }
+    format!(
+        r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+    <specVersion><major>1</major><minor>0</minor></specVersion>
+    <actionList>{}</actionList>
+</scpd>"#,
+        actions
+    )
+}
+
+/// A canned answer to a SOAP action.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MockResponse {
+    /// A successful response with the given output arguments
+    Ok(Vec<(String, String)>),
+    /// A UPnP fault with the given error code and description
+    Fault(u16, String),
+    /// A raw HTTP response with the given status and body
+    Raw(u16, String),
+}
+
+/// A SOAP request received by the mock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockRequest {
+    /// Name of the action, taken from the `SOAPAction` header
+    pub action: String,
+    /// Argument names and values, in the order they were sent
+    pub arguments: Vec<(String, String)>,
+    /// Header names and values, in the order they were sent
+    pub headers: Vec<(String, String)>,
+    /// Address of the host that sent the request
+    pub client: IpAddr,
+}
+
+impl MockRequest {
+    /// Get the value of an argument.
+    pub fn argument(&self, name: &str) -> Option<&str> {
+        self.arguments
+            .iter()
+            .find(|(argument, _)| argument == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A pinhole of the emulated IPv6 firewall.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockPinhole {
+    /// Id given to the pinhole
+    pub unique_id: u16,
+    /// IPv6 address the pinhole lets traffic through to
+    pub internal_client: String,
+    /// Port the pinhole lets traffic through to
+    pub internal_port: u16,
+    /// IANA protocol number, 6 for TCP and 17 for UDP
+    pub protocol: u16,
+    /// Lease time in seconds, as last set
+    pub lease_time: u32,
+}
+
+struct State {
+    description: String,
+    scpd: String,
+    external_ip: Ipv4Addr,
+    started: Instant,
+    mappings: Vec<PortMappingEntry>,
+    pinholes: Vec<MockPinhole>,
+    next_pinhole_id: u16,
+    only_permanent_leases: bool,
+    responses: HashMap<String, MockResponse>,
+    requests: Vec<MockRequest>,
+    scpd_requests: usize,
+}
+
+/// A mock IGD running on background threads until it is dropped.
+pub struct MockGateway {
+    search_addr: SocketAddr,
+    http_addr: SocketAddrV4,
+    state: Arc<Mutex<State>>,
+    running: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl MockGateway {
+    /// Start a mock gateway on loopback sockets with random ports.
+    pub fn start() -> io::Result<MockGateway> {
+        let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        udp.set_read_timeout(Some(Duration::from_millis(50)))?;
+        let tcp = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        tcp.set_nonblocking(true)?;
+
+        let search_addr = udp.local_addr()?;
+        let http_addr = match tcp.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!("bound to an IPv4 address"),
+        };
+        let state = Arc::new(Mutex::new(State {
+            description: DEFAULT_DESCRIPTION.to_string(),
+            scpd: default_scpd(),
+            external_ip: Ipv4Addr::new(203, 0, 113, 1),
+            started: Instant::now(),
+            mappings: Vec::new(),
+            pinholes: Vec::new(),
+            next_pinhole_id: 1,
+            only_permanent_leases: false,
+            responses: HashMap::new(),
+            requests: Vec::new(),
+            scpd_requests: 0,
+        }));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let threads = vec![
+            {
+                let running = running.clone();
+                thread::spawn(move || serve_ssdp(udp, http_addr, &running))
+            },
+            {
+                let running = running.clone();
+                let state = state.clone();
+                thread::spawn(move || serve_http(tcp, &state, &running))
+            },
+        ];
+
+        Ok(MockGateway {
+            search_addr,
+            http_addr,
+            state,
+            running,
+            threads,
+        })
+    }
+
+    /// Address the mock answers M-SEARCH requests on.
+    pub fn search_addr(&self) -> SocketAddr {
+        self.search_addr
+    }
+
+    /// Address of the HTTP server of the mock.
+    pub fn http_addr(&self) -> SocketAddrV4 {
+        self.http_addr
+    }
+
+    /// Search options that find this mock.
+    pub fn search_options(&self) -> SearchOptions {
+        SearchOptions {
+            bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            broadcast_address: self.search_addr,
+            timeout: Some(Duration::from_secs(5)),
+            request: Default::default(),
+            filter: Default::default(),
+            retransmission: None,
+            url_policy: Default::default(),
+            validate_arguments: true,
+            tos: None,
+            fetch_timeout: Some(Duration::from_secs(5)),
+        }
+    }
+
+    /// Serve another device description.
+    pub fn set_description<S: Into<String>>(&self, description: S) {
+        self.state().description = description.into();
+    }
+
+    /// Serve another SCPD for the WANIPConnection service.
+    pub fn set_scpd<S: Into<String>>(&self, scpd: S) {
+        self.state().scpd = scpd.into();
+    }
+
+    /// External IP address returned by `GetExternalIPAddress`.
+    pub fn external_ip(&self) -> Ipv4Addr {
+        self.state().external_ip
+    }
+
+    /// Change the external IP address returned by `GetExternalIPAddress`.
+    pub fn set_external_ip(&self, ip: Ipv4Addr) {
+        self.state().external_ip = ip;
+    }
+
+    /// Answer every following call of `action` with `response` instead of emulating it.
+    pub fn respond(&self, action: &str, response: MockResponse) {
+        self.state().responses.insert(action.to_string(), response);
+    }
+
+    /// Go back to emulating `action`.
+    pub fn clear_response(&self, action: &str) {
+        self.state().responses.remove(action);
+    }
+
+    /// The port mappings currently in the table of the mock.
+    pub fn mappings(&self) -> Vec<PortMappingEntry> {
+        self.state().mappings.clone()
+    }
+
+    /// Drop all mappings, as a gateway restarting does.
+    pub fn clear_mappings(&self) {
+        self.state().mappings.clear();
+    }
+
+    /// The pinholes of the emulated IPv6 firewall.
+    pub fn pinholes(&self) -> Vec<MockPinhole> {
+        self.state().pinholes.clone()
+    }
+
+    /// Reject port mappings with a non-zero lease duration, like some firmwares do.
+    pub fn set_only_permanent_leases(&self, only_permanent_leases: bool) {
+        self.state().only_permanent_leases = only_permanent_leases;
+    }
+
+    /// The SOAP requests received so far.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.state().requests.clone()
+    }
+
+    /// The number of SCPDs requested so far, i.e. of the GET requests for anything but the
+    /// device description.
+    pub fn scpd_requests(&self) -> usize {
+        self.state().scpd_requests
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for MockGateway {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve_ssdp(socket: UdpSocket, http_addr: SocketAddrV4, running: &AtomicBool) {
+    let mut buf = [0u8; 1500];
+    while running.load(Ordering::SeqCst) {
+        let (read, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        if !buf[..read].starts_with(b"M-SEARCH") {
+            continue;
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             CACHE-CONTROL: max-age=120\r\n\
+             ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+             USN: uuid:00000000-0000-0000-0000-000000000001::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+             EXT:\r\n\
+             SERVER: rust-igd/MockGateway/{} UPnP/1.0\r\n\
+             LOCATION: http://{}{}\r\n\
+             \r\n",
+            http_addr.port(),
+            http_addr,
+            DESCRIPTION_PATH
+        );
+        let _ = socket.send_to(response.as_bytes(), from);
+    }
+}
+
+fn serve_http(listener: TcpListener, state: &Mutex<State>, running: &AtomicBool) {
+    while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = handle_connection(stream, state) {
+                    debug!("mock gateway connection failed: {}", e);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(5)),
+            Err(_) => return,
+        }
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let client = stream.peer_addr()?.ip();
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("").to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+    let length = header("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    if method == "GET" && path != DESCRIPTION_PATH {
+        state.scpd_requests += 1;
+    }
+    let (status, body) = match (method.as_str(), path.as_str()) {
+        ("GET", DESCRIPTION_PATH) => (200, state.description.clone()),
+        ("GET", SCPD_PATH) => (200, state.scpd.clone()),
+        ("POST", CONTROL_PATH)
+        | ("POST", COMMON_INTERFACE_CONTROL_PATH)
+        | ("POST", FIREWALL_CONTROL_PATH)
+        | ("POST", DEVICE_CONFIG_CONTROL_PATH) => {
+            let service_type = match path.as_str() {
+                CONTROL_PATH => WAN_IP_CONNECTION_SERVICE,
+                COMMON_INTERFACE_CONTROL_PATH => WAN_COMMON_INTERFACE_CONFIG_SERVICE,
+                DEVICE_CONFIG_CONTROL_PATH => DEVICE_CONFIG_SERVICES[0],
+                _ => WAN_IPV6_FIREWALL_CONTROL_SERVICE,
+            };
+            let action = header("soapaction")
+                .and_then(|value| value.trim_matches('"').split('#').nth(1).map(str::to_string))
+                .unwrap_or_default();
+            let request = MockRequest {
+                arguments: parse_arguments(&body),
+                action,
+                headers: headers.clone(),
+                client,
+            };
+            let response = match state.responses.get(&request.action) {
+                Some(response) => response.clone(),
+                None => emulate(&mut state, &request),
+            };
+            let action = request.action.clone();
+            state.requests.push(request);
+            format_response(service_type, &action, response)
+        }
+        _ => (404, String::new()),
+    };
+    drop(state);
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        if status == 200 { "OK" } else { "Error" },
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn parse_arguments(body: &[u8]) -> Vec<(String, String)> {
+    let xml = match xmltree::Element::parse(body) {
+        Ok(xml) => xml,
+        Err(_) => return Vec::new(),
+    };
+    let action = xml
+        .get_child("Body")
+        .and_then(|body| body.children.iter().find_map(|child| child.as_element()));
+    match action {
+        Some(action) => action
+            .children
+            .iter()
+            .filter_map(|child| child.as_element())
+            .map(|argument| {
+                let value = argument.get_text().map(|text| text.into_owned()).unwrap_or_default();
+                (argument.name.clone(), value)
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+fn emulate(state: &mut State, request: &MockRequest) -> MockResponse {
+    fn ok(arguments: &[(&str, String)]) -> MockResponse {
+        MockResponse::Ok(
+            arguments
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+        )
+    }
+    fn fault(code: u16, description: &str) -> MockResponse {
+        MockResponse::Fault(code, description.to_string())
+    }
+    fn entry_arguments(entry: &PortMappingEntry) -> Vec<(&'static str, String)> {
+        vec![
+            ("NewRemoteHost", entry.remote_host.clone()),
+            ("NewExternalPort", entry.external_port.to_string()),
+            ("NewProtocol", entry.protocol.to_string()),
+            ("NewInternalPort", entry.internal_port.to_string()),
+            ("NewInternalClient", entry.internal_client.clone()),
+            ("NewEnabled", (entry.enabled as u8).to_string()),
+            ("NewPortMappingDescription", entry.port_mapping_description.clone()),
+            ("NewLeaseDuration", entry.lease_duration.to_string()),
+        ]
+    }
+
+    let argument = |name: &str| request.argument(name).unwrap_or("");
+    let protocol = match argument("NewProtocol") {
+        "TCP" => Some(PortMappingProtocol::TCP),
+        "UDP" => Some(PortMappingProtocol::UDP),
+        _ => None,
+    };
+    let external_port = argument("NewExternalPort").parse::<u16>().ok();
+    let position = |mappings: &[PortMappingEntry]| {
+        mappings
+            .iter()
+            .position(|entry| Some(entry.protocol) == protocol && Some(entry.external_port) == external_port)
+    };
+
+    match request.action.as_str() {
+        "GetExternalIPAddress" => ok(&[("NewExternalIPAddress", state.external_ip.to_string())]),
+        "GetStatusInfo" => ok(&[
+            ("NewConnectionStatus", "Connected".to_string()),
+            ("NewLastConnectionError", "ERROR_NONE".to_string()),
+            ("NewUptime", state.started.elapsed().as_secs().to_string()),
+        ]),
+        "GetTotalBytesSent" => ok(&[("NewTotalBytesSent", "0".to_string())]),
+        "GetTotalBytesReceived" => ok(&[("NewTotalBytesReceived", "0".to_string())]),
+        "GetTotalPacketsSent" => ok(&[("NewTotalPacketsSent", "0".to_string())]),
+        "GetTotalPacketsReceived" => ok(&[("NewTotalPacketsReceived", "0".to_string())]),
+        "AddPortMapping" | "AddAnyPortMapping" => {
+            let (protocol, mut external_port) = match (protocol, external_port) {
+                (Some(protocol), Some(port)) => (protocol, port),
+                _ => return fault(402, "Invalid Args"),
+            };
+            let entry = PortMappingEntry {
+                remote_host: argument("NewRemoteHost").to_string(),
+                external_port,
+                protocol,
+                internal_port: match argument("NewInternalPort").parse() {
+                    Ok(port) => port,
+                    Err(_) => return fault(402, "Invalid Args"),
+                },
+                internal_client: argument("NewInternalClient").to_string(),
+                enabled: argument("NewEnabled") != "0",
+                port_mapping_description: argument("NewPortMappingDescription").to_string(),
+                lease_duration: argument("NewLeaseDuration").parse().unwrap_or(0),
+            };
+            if state.only_permanent_leases && entry.lease_duration != 0 {
+                fault(725, "OnlyPermanentLeasesSupported")
+            } else if request.action == "AddAnyPortMapping" {
+                let taken = |port: u16| {
+                    state
+                        .mappings
+                        .iter()
+                        .any(|other| other.protocol == protocol && other.external_port == port)
+                };
+                while external_port == 0 || taken(external_port) {
+                    external_port = external_port.wrapping_add(1).max(1024);
+                }
+                state.mappings.push(PortMappingEntry { external_port, ..entry });
+                ok(&[("NewReservedPort", external_port.to_string())])
+            } else if external_port == 0 {
+                fault(716, "WildCardNotPermittedInExtPort")
+            } else {
+                match position(&state.mappings) {
+                    Some(index) if state.mappings[index].internal_client != entry.internal_client => {
+                        fault(718, "ConflictInMappingEntry")
+                    }
+                    Some(index) => {
+                        state.mappings[index] = entry;
+                        ok(&[])
+                    }
+                    None => {
+                        state.mappings.push(entry);
+                        ok(&[])
+                    }
+                }
+            }
+        }
+        "DeletePortMapping" => match position(&state.mappings) {
+            Some(index) => {
+                state.mappings.remove(index);
+                ok(&[])
+            }
+            None => fault(714, "NoSuchEntryInArray"),
+        },
+        "DeletePortMappingRange" => {
+            let (start, end) = match (argument("NewStartPort").parse(), argument("NewEndPort").parse()) {
+                (Ok(start), Ok(end)) if start <= end => (start, end),
+                _ => return fault(733, "InconsistentParameters"),
+            };
+            // Without NewManage, only the mappings of the client are removed.
+            let manage = argument("NewManage") == "1";
+            let client = request.client.to_string();
+            let count = state.mappings.len();
+            state.mappings.retain(|entry| {
+                Some(entry.protocol) != protocol
+                    || !(start..=end).contains(&entry.external_port)
+                    || !manage && entry.internal_client != client
+            });
+            if state.mappings.len() == count {
+                fault(730, "PortMappingNotFound")
+            } else {
+                ok(&[])
+            }
+        }
+        "GetGenericPortMappingEntry" => {
+            let entry = argument("NewPortMappingIndex")
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| state.mappings.get(index));
+            match entry {
+                Some(entry) => ok(&entry_arguments(entry)),
+                None => fault(713, "SpecifiedArrayIndexInvalid"),
+            }
+        }
+        "GetSpecificPortMappingEntry" => match position(&state.mappings) {
+            Some(index) => ok(&entry_arguments(&state.mappings[index])[3..]),
+            None => fault(714, "NoSuchEntryInArray"),
+        },
+        "AddPinhole" => {
+            let lease_time = match argument("LeaseTime").parse::<u32>() {
+                Ok(lease_time) if (1..=86400).contains(&lease_time) => lease_time,
+                _ => return fault(402, "Invalid Args"),
+            };
+            let (internal_port, protocol) = match (argument("InternalPort").parse(), argument("Protocol").parse()) {
+                (Ok(port), Ok(protocol)) => (port, protocol),
+                _ => return fault(402, "Invalid Args"),
+            };
+            let unique_id = state.next_pinhole_id;
+            state.next_pinhole_id = state.next_pinhole_id.wrapping_add(1);
+            state.pinholes.push(MockPinhole {
+                unique_id,
+                internal_client: argument("InternalClient").to_string(),
+                internal_port,
+                protocol,
+                lease_time,
+            });
+            ok(&[("UniqueID", unique_id.to_string())])
+        }
+        "GetOutboundPinholeTimeout" => match argument("Protocol") {
+            "17" => ok(&[("OutboundPinholeTimeout", "120".to_string())]),
+            _ => ok(&[("OutboundPinholeTimeout", "3600".to_string())]),
+        },
+        "CheckPinholeWorking" | "GetPinholePackets" => {
+            let unique_id = argument("UniqueID").parse::<u16>().ok();
+            if !state
+                .pinholes
+                .iter()
+                .any(|pinhole| Some(pinhole.unique_id) == unique_id)
+            {
+                return fault(704, "NoSuchEntry");
+            }
+            if request.action == "CheckPinholeWorking" {
+                ok(&[("IsWorking", "1".to_string())])
+            } else {
+                ok(&[("PinholePackets", "0".to_string())])
+            }
+        }
+        "UpdatePinhole" | "DeletePinhole" => {
+            let unique_id = argument("UniqueID").parse::<u16>().ok();
+            let index = match state
+                .pinholes
+                .iter()
+                .position(|pinhole| Some(pinhole.unique_id) == unique_id)
+            {
+                Some(index) => index,
+                None => return fault(704, "NoSuchEntry"),
+            };
+            if request.action == "DeletePinhole" {
+                state.pinholes.remove(index);
+            } else {
+                match argument("NewLeaseTime").parse::<u32>() {
+                    Ok(lease_time) if (1..=86400).contains(&lease_time) => {
+                        state.pinholes[index].lease_time = lease_time
+                    }
+                    _ => return fault(402, "Invalid Args"),
+                }
+            }
+            ok(&[])
+        }
+        "Reboot" | "ConfigurationStarted" => ok(&[]),
+        "ConfigurationFinished" => ok(&[("NewStatus", "RebootRequired".to_string())]),
+        "GetPersistentData" => ok(&[("NewPersistentData", "mock".to_string())]),
+        _ => fault(401, "Invalid Action"),
+    }
+}
+
+fn format_response(service_type: &str, action: &str, response: MockResponse) -> (u16, String) {
+    let (status, body) = match response {
+        MockResponse::Ok(arguments) => {
+            let arguments: String = arguments
+                .iter()
+                .map(|(name, value)| format!("<{name}>{value}</{name}>", name = name, value = escape(value)))
+                .collect();
+            (
+                200,
+                format!(
+                    r#"<u:{action}Response xmlns:u="{service_type}">{arguments}</u:{action}Response>"#,
+                    action = action,
+                    service_type = service_type,
+                    arguments = arguments
+                ),
+            )
+        }
+        MockResponse::Fault(code, description) => (
+            500,
+            format!(
+                r#"<s:Fault>
+            <faultcode>s:Client</faultcode>
+            <faultstring>UPnPError</faultstring>
+            <detail>
+                <UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+                    <errorCode>{}</errorCode>
+                    <errorDescription>{}</errorDescription>
+                </UPnPError>
+            </detail>
+        </s:Fault>"#,
+                code,
+                escape(&description)
+            ),
+        ),
+        MockResponse::Raw(status, body) => return (status, body),
+    };
+    (
+        status,
+        format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+    <s:Body>{}</s:Body>
+</s:Envelope>"#,
+            body
+        ),
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[test]
+fn test_mock_gateway() {
+    use std::net::{Ipv6Addr, SocketAddrV6};
+
+    let mock = MockGateway::start().unwrap();
+    let mut gateway = crate::search_gateway(mock.search_options()).unwrap();
+    assert_eq!(gateway.device_info.model_name, "MockGateway");
+    assert!(gateway.common_interface_control_url.is_some());
+    assert!(gateway.firewall_control_url.is_some());
+
+    let status = gateway.wait_for_connected(Duration::from_secs(1)).unwrap();
+    assert_eq!(status.connection_status, crate::ConnectionStatus::Connected);
+
+    let ttl = Duration::from_secs(60);
+    assert_eq!(gateway.external_ip_cached(ttl).unwrap(), mock.external_ip());
+    mock.set_external_ip(Ipv4Addr::new(198, 51, 100, 1));
+    assert_eq!(gateway.external_ip_cached(ttl).unwrap(), Ipv4Addr::new(203, 0, 113, 1));
+    gateway.invalidate_external_ip();
+    assert_eq!(gateway.external_ip_cached(ttl).unwrap(), Ipv4Addr::new(198, 51, 100, 1));
+
+    assert_eq!(gateway.local_addr.map(|addr| *addr.ip()), Some(Ipv4Addr::LOCALHOST));
+    assert_eq!(gateway.local_addr_hint().unwrap(), Ipv4Addr::LOCALHOST);
+
+    // The mock doesn't forward, but the listener is reached when the external address is its own.
+    assert!(!gateway.supports_hairpinning(Duration::from_millis(100)).unwrap());
+    mock.set_external_ip(Ipv4Addr::LOCALHOST);
+    assert!(gateway.supports_hairpinning(Duration::from_secs(1)).unwrap());
+    assert!(mock.mappings().is_empty());
+    mock.set_external_ip(Ipv4Addr::new(198, 51, 100, 1));
+
+    let services = gateway.services();
+    assert_eq!(services.len(), 3);
+    let service = services
+        .iter()
+        .find(|service| service.service_type == WAN_IP_CONNECTION_SERVICE)
+        .unwrap();
+    let output = gateway.call_action(service, "GetExternalIPAddress", &[]).unwrap();
+    assert_eq!(output["NewExternalIPAddress"], "198.51.100.1");
+
+    let local_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 8080);
+    match gateway.add_port(PortMappingProtocol::TCP, 8080, local_addr, 60, "igd test") {
+        Err(crate::AddPortError::InternalClientNotLocal) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    gateway.allow_third_party = true;
+    gateway
+        .add_port(PortMappingProtocol::TCP, 8080, local_addr, 60, "igd test")
+        .unwrap();
+    let entry = gateway.get_generic_port_mapping_entry(0).unwrap();
+    assert_eq!(entry.internal_client, "192.168.1.2");
+    assert_eq!(entry.port_mapping_description, "igd test");
+    let requests = mock.requests();
+    let add = requests
+        .iter()
+        .find(|request| request.action == "AddPortMapping")
+        .unwrap();
+    assert_eq!(add.argument("NewLeaseDuration"), Some("60"));
+    #[cfg(feature = "export")]
+    assert!(gateway
+        .export_port_mappings(crate::ExportFormat::Csv)
+        .unwrap()
+        .ends_with(",8080,TCP,8080,192.168.1.2,true,igd test,60\r\n"));
+
+    gateway.remove_port(PortMappingProtocol::TCP, 8080).unwrap();
+    assert!(mock.mappings().is_empty());
+
+    gateway
+        .add_port(PortMappingProtocol::TCP, 8081, local_addr, 60, "a & <b> \"c\"")
+        .unwrap();
+    assert_eq!(mock.mappings()[0].port_mapping_description, "a & <b> \"c\"");
+    gateway.remove_port(PortMappingProtocol::TCP, 8081).unwrap();
+    match gateway.add_port(PortMappingProtocol::TCP, 8081, local_addr, 60, &"x".repeat(257)) {
+        Err(crate::AddPortError::DescriptionTooLong) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let mapped = gateway
+        .map_port(PortMappingProtocol::UDP, 0, local_addr, 60, "igd test")
+        .unwrap();
+    assert_ne!(mapped.external_port, 0);
+    assert_eq!(mock.mappings()[0].external_port, mapped.external_port);
+    gateway
+        .remove_port(PortMappingProtocol::UDP, mapped.external_port)
+        .unwrap();
+
+    let options = crate::AnyPortOptions {
+        ports: 1024..=1024,
+        attempts: 1,
+        ..Default::default()
+    };
+    let addr = gateway
+        .get_any_address_with(PortMappingProtocol::UDP, local_addr, 60, "igd test", &options)
+        .unwrap();
+    assert_eq!(addr, SocketAddrV4::new(mock.external_ip(), 1024));
+    gateway.remove_port(PortMappingProtocol::UDP, 1024).unwrap();
+
+    let requests: Vec<_> = (9000..9006)
+        .map(|port| crate::PortMappingRequest {
+            protocol: PortMappingProtocol::TCP,
+            external_port: port,
+            local_addr,
+            lease_duration: 60,
+            description: "igd test".to_string(),
+        })
+        .collect();
+    let results = gateway.add_ports(&requests);
+    assert!(results.iter().all(|result| result.is_ok()));
+    assert_eq!(mock.mappings().len(), 6);
+    let mappings: Vec<_> = (9000..9007).map(|port| (PortMappingProtocol::TCP, port)).collect();
+    let results = gateway.remove_ports(&mappings);
+    assert!(results[..6].iter().all(|result| result.is_ok()));
+    match results[6] {
+        Err(crate::RemovePortError::NoSuchPortMapping) => {}
+        ref r => panic!("unexpected result {:?}", r),
+    }
+
+    let internal_start = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 21000);
+    gateway
+        .add_port(PortMappingProtocol::TCP, 9205, local_addr, 60, "other host")
+        .unwrap();
+    match gateway.add_port_range(9200..=9209, internal_start, PortMappingProtocol::TCP, 60, "igd test") {
+        Err(crate::AddPortError::PortInUse) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(mock.mappings().len(), 1);
+    gateway.remove_port(PortMappingProtocol::TCP, 9205).unwrap();
+    let mut progress = Vec::new();
+    let mapped = gateway
+        .add_port_range_with(
+            9200..=9209,
+            internal_start,
+            PortMappingProtocol::TCP,
+            60,
+            "igd test",
+            |added, total| progress.push((added, total)),
+        )
+        .unwrap();
+    assert_eq!(mapped.len(), 10);
+    assert_eq!(progress, [(4, 10), (8, 10), (10, 10)]);
+    assert_eq!(mock.mappings()[9].internal_port, 21009);
+    gateway
+        .remove_port_range(PortMappingProtocol::TCP, 9200..=9209)
+        .unwrap();
+    assert!(mock.mappings().is_empty());
+    gateway
+        .remove_port_range(PortMappingProtocol::TCP, 9200..=9209)
+        .unwrap();
+
+    gateway
+        .add_port(PortMappingProtocol::TCP, 9100, local_addr, 60, "leaked:1")
+        .unwrap();
+    gateway
+        .add_port(PortMappingProtocol::TCP, 9101, local_addr, 60, "leaked:2")
+        .unwrap();
+    let removed = gateway
+        .cleanup_matching(|entry: &PortMappingEntry| entry.port_mapping_description == "leaked:1")
+        .unwrap();
+    assert_eq!(removed[0].external_port, 9100);
+    assert_eq!(gateway.cleanup_matching("leaked:").unwrap().len(), 1);
+    assert!(mock.mappings().is_empty());
+
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    let mapped = gateway.add_port_for(&listener, 0, 60, "igd test").unwrap();
+    let entry = &mock.mappings()[0];
+    assert_eq!(entry.internal_client, "127.0.0.1");
+    assert_eq!(entry.internal_port, listener.local_addr().unwrap().port());
+    gateway
+        .remove_port(PortMappingProtocol::TCP, mapped.external_port)
+        .unwrap();
+
+    mock.set_only_permanent_leases(true);
+    match gateway.map_port(PortMappingProtocol::TCP, 8080, local_addr, 60, "igd test") {
+        Err(crate::AddPortError::OnlyPermanentLeasesSupported) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    let mut fallback = gateway.clone();
+    fallback.permanent_lease_fallback = true;
+    let mapped = fallback
+        .map_port(PortMappingProtocol::TCP, 8080, local_addr, 60, "igd test")
+        .unwrap();
+    assert_eq!(mapped.lease_duration, 0);
+    assert_eq!(mock.mappings()[0].lease_duration, 0);
+    gateway.remove_port(PortMappingProtocol::TCP, 8080).unwrap();
+
+    match gateway.add_any_port(PortMappingProtocol::UDP, local_addr, 60, "igd test") {
+        Err(crate::AddAnyPortError::OnlyPermanentLeasesSupported) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    let mapped = fallback
+        .map_any_port(PortMappingProtocol::UDP, local_addr, 60, "igd test")
+        .unwrap();
+    assert_eq!(mapped.lease_duration, 0);
+    let addr = fallback
+        .get_any_address(PortMappingProtocol::TCP, local_addr, 60, "igd test")
+        .unwrap();
+    assert_eq!(*addr.ip(), mock.external_ip());
+    assert!(mock.mappings().iter().all(|entry| entry.lease_duration == 0));
+    gateway
+        .remove_port(PortMappingProtocol::UDP, mapped.external_port)
+        .unwrap();
+    gateway.remove_port(PortMappingProtocol::TCP, addr.port()).unwrap();
+
+    let internal = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 8080, 0, 0);
+    assert_eq!(
+        gateway
+            .get_outbound_pinhole_timeout(PortMappingProtocol::UDP, internal)
+            .unwrap(),
+        Duration::from_secs(120)
+    );
+    let unique_id = gateway.add_pinhole(PortMappingProtocol::UDP, internal, 3600).unwrap();
+    assert!(gateway.check_pinhole_working(unique_id).unwrap());
+    assert_eq!(gateway.get_pinhole_packets(unique_id).unwrap(), 0);
+    gateway.delete_pinhole(unique_id).unwrap();
+    assert!(gateway.check_pinhole_working(unique_id).is_err());
+
+    mock.respond(
+        "DeletePortMapping",
+        MockResponse::Fault(606, "Action not authorized".into()),
+    );
+    assert!(gateway.remove_port(PortMappingProtocol::TCP, 8080).is_err());
+
+    let mut moved = gateway.clone();
+    moved.control_url = "/ctl/Moved".to_string();
+    let e = moved.get_status_info().unwrap_err();
+    assert!(e.is_permanent());
+    match *e.inner() {
+        crate::RequestError::HttpStatus {
+            status: 404,
+            ref headers,
+            ..
+        } => assert!(headers.iter().any(|(name, _)| name == "Content-Type")),
+        ref e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_random_port() {
+    let mock = MockGateway::start().unwrap();
+    mock.set_scpd(default_scpd().replace("AddAnyPortMapping", "Unsupported"));
+    let mut gateway = crate::search_gateway(mock.search_options()).unwrap();
+    gateway.allow_third_party = true;
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080);
+
+    let other_host = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 8080);
+    for port in 50_000..=50_002 {
+        gateway
+            .add_port(PortMappingProtocol::TCP, port, other_host, 60, "other host")
+            .unwrap();
+    }
+    let options = crate::AnyPortOptions {
+        ports: 50_000..=50_003,
+        attempts: 1,
+        ..Default::default()
+    };
+    let port = gateway
+        .add_any_port_with(PortMappingProtocol::TCP, local_addr, 60, "igd test", &options)
+        .unwrap();
+    assert_eq!(port, 50_003);
+
+    let port = gateway
+        .add_any_port(PortMappingProtocol::UDP, local_addr, 60, "igd test")
+        .unwrap();
+    assert!(port >= 49_152);
+}
+
+#[test]
+fn test_deadline() {
+    let mock = MockGateway::start().unwrap();
+    let deadline = crate::Deadline::after(Duration::from_secs(30));
+    let gateway = crate::search_gateway_within(mock.search_options(), deadline).unwrap();
+    assert_eq!(gateway.deadline(), deadline);
+    assert_eq!(gateway.get_external_ip().unwrap(), mock.external_ip());
+
+    let passed = crate::Deadline::at(std::time::Instant::now());
+    match crate::search_gateway_within(mock.search_options(), passed) {
+        Err(crate::SearchError::NoResponse(_)) => {}
+        r => panic!("unexpected result {:?}", r.map(|gateway| gateway.to_string())),
+    }
+    match *gateway.with_deadline(passed).get_status_info().unwrap_err().inner() {
+        crate::RequestError::IoError(ref e) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+        ref e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_no_validation() {
+    let mock = MockGateway::start().unwrap();
+    let options = SearchOptions {
+        validate_arguments: false,
+        ..mock.search_options()
+    };
+    let gateway = crate::search_gateway(options).unwrap();
+    assert!(!gateway.validate_arguments);
+    assert!(gateway.control_schema.is_empty());
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080);
+    gateway
+        .add_port(PortMappingProtocol::TCP, 50_100, local_addr, 60, "igd test")
+        .unwrap();
+    assert_eq!(mock.mappings().len(), 1);
+    gateway.remove_port(PortMappingProtocol::TCP, 50_100).unwrap();
+    assert!(mock.mappings().is_empty());
+    assert_eq!(mock.scpd_requests(), 0);
+
+    // With two WAN connections, switching to the default one doesn't fetch its SCPD either.
+    let mock = MockGateway::start().unwrap();
+    mock.set_description(DEFAULT_DESCRIPTION.replacen(
+        "</deviceList>\n            </device>",
+        r#"    <device>
+                        <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+                        <UDN>uuid:00000000-0000-0000-0000-000000000003</UDN>
+                        <serviceList>
+                            <service>
+                                <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+                                <serviceId>urn:upnp-org:serviceId:WANIPConn2</serviceId>
+                                <SCPDURL>/WANIPCn2.xml</SCPDURL>
+                                <controlURL>/ctl/IPConn2</controlURL>
+                                <eventSubURL>/evt/IPConn2</eventSubURL>
+                            </service>
+                        </serviceList>
+                    </device>
+                </deviceList>
+            </device>
+            <device>
+                <deviceType>urn:schemas-upnp-org:device:Layer3Forwarding:1</deviceType>
+                <serviceList>
+                    <service>
+                        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+                        <serviceId>urn:upnp-org:serviceId:L3Forwarding1</serviceId>
+                        <SCPDURL>/L3F.xml</SCPDURL>
+                        <controlURL>/ctl/IPConn</controlURL>
+                        <eventSubURL>/evt/L3F</eventSubURL>
+                    </service>
+                </serviceList>
+            </device>"#,
+        1,
+    ));
+    mock.respond(
+        "GetDefaultConnectionService",
+        MockResponse::Ok(vec![(
+            "NewDefaultConnectionService".to_string(),
+            "uuid:00000000-0000-0000-0000-000000000003:WANConnectionDevice:1,urn:upnp-org:serviceId:WANIPConn2"
+                .to_string(),
+        )]),
+    );
+    let options = SearchOptions {
+        validate_arguments: false,
+        ..mock.search_options()
+    };
+    let gateway = crate::search_gateway(options).unwrap();
+    assert_eq!(gateway.wan_connections().len(), 2);
+    assert_eq!(gateway.control_url, "/ctl/IPConn2");
+    assert!(gateway.control_schema.is_empty());
+    assert_eq!(mock.scpd_requests(), 0);
+
+    // The count is of the SCPDs fetched to validate the arguments.
+    crate::search_gateway(mock.search_options()).unwrap();
+    assert!(mock.scpd_requests() > 0);
+}
+
+#[test]
+fn test_port_enabled() {
+    let mock = MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080);
+
+    gateway
+        .add_port_disabled(PortMappingProtocol::TCP, 50_200, local_addr, 60, "igd test")
+        .unwrap();
+    assert!(!mock.mappings()[0].enabled);
+    gateway
+        .set_port_enabled(PortMappingProtocol::TCP, 50_200, true)
+        .unwrap();
+    let mappings = mock.mappings();
+    assert!(mappings[0].enabled);
+    assert_eq!(mappings[0].port_mapping_description, "igd test");
+    assert_eq!(mappings[0].internal_port, 8080);
+    gateway
+        .set_port_enabled(PortMappingProtocol::TCP, 50_200, false)
+        .unwrap();
+    assert!(!mock.mappings()[0].enabled);
+
+    let e = gateway
+        .set_port_enabled(PortMappingProtocol::UDP, 50_200, true)
+        .unwrap_err();
+    assert!(matches!(e.inner(), crate::RequestError::NoSuchEntryInArray));
+}
+
+#[test]
+fn test_add_port_zero() {
+    let mock = MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080);
+
+    assert!(matches!(
+        gateway.add_port(PortMappingProtocol::TCP, 0, local_addr, 60, "igd test"),
+        Err(crate::AddPortError::ExternalPortZeroInvalid)
+    ));
+    assert!(mock.mappings().is_empty());
+
+    // `map_port` returns the port the gateway chose.
+    let mapped = gateway
+        .map_port(PortMappingProtocol::TCP, 0, local_addr, 60, "igd test")
+        .unwrap();
+    assert_ne!(mapped.external_port, 0);
+    assert_eq!(mock.mappings()[0].external_port, mapped.external_port);
+}
+
+#[test]
+fn test_quirks() {
+    let mock = MockGateway::start().unwrap();
+    let mut gateway = crate::search_gateway(mock.search_options()).unwrap();
+    gateway.quirks.same_port_only = true;
+    gateway.quirks.standard_argument_order = true;
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080);
+
+    let port = gateway
+        .add_any_port(PortMappingProtocol::TCP, local_addr, 60, "igd test")
+        .unwrap();
+    assert_eq!(port, 8080);
+    let requests = mock.requests();
+    let request = requests.last().unwrap();
+    assert_eq!(request.action, "AddPortMapping");
+    let arguments: Vec<&str> = request.arguments.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(arguments, crate::common::messages::standard_arguments("AddPortMapping"));
+}
+
+#[test]
+fn test_update_port() {
+    let mock = MockGateway::start().unwrap();
+    let mut gateway = crate::search_gateway(mock.search_options()).unwrap();
+    gateway.allow_third_party = true;
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080);
+    gateway
+        .add_port(PortMappingProtocol::TCP, 50_300, local_addr, 0, "igd test")
+        .unwrap();
+
+    // Overwritten in place.
+    let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8081);
+    gateway
+        .update_port(PortMappingProtocol::TCP, 50_300, local_addr, 0, "igd update")
+        .unwrap();
+    let mappings = mock.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].internal_port, 8081);
+    assert_eq!(mappings[0].port_mapping_description, "igd update");
+    assert!(!mock
+        .requests()
+        .iter()
+        .any(|request| request.action == "DeletePortMapping"));
+
+    // Removed and added again for another client.
+    let other_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 2), 8081);
+    gateway
+        .update_port(PortMappingProtocol::TCP, 50_300, other_addr, 0, "igd update")
+        .unwrap();
+    let mappings = mock.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].internal_client, "127.0.0.2");
+
+    // Restored when adding it again fails.
+    mock.set_only_permanent_leases(true);
+    gateway.permanent_lease_fallback = false;
+    let e = gateway
+        .update_port(PortMappingProtocol::TCP, 50_300, local_addr, 60, "igd update")
+        .unwrap_err();
+    assert!(matches!(e, crate::AddPortError::OnlyPermanentLeasesSupported));
+    let mappings = mock.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].internal_client, "127.0.0.2");
+
+    assert!(gateway
+        .update_port(PortMappingProtocol::UDP, 50_300, local_addr, 0, "igd update")
+        .is_err());
+}
+
+#[test]
+fn test_refresh() {
+    let mock = MockGateway::start().unwrap();
+    let mut gateway = crate::search_gateway(mock.search_options()).unwrap();
+
+    // Moved by a firmware update, as far as the gateway knows.
+    gateway.control_url = "/ctl/old".to_string();
+    assert!(gateway.get_external_ip().is_err());
+    gateway.refresh_with(mock.search_options()).unwrap();
+    assert_eq!(gateway.control_url, CONTROL_PATH);
+    assert_eq!(gateway.get_external_ip().unwrap(), mock.external_ip());
+
+    // Found again by its UDN when the description moved too.
+    gateway.root_url = "/old.xml".to_string();
+    gateway.control_url = "/ctl/old".to_string();
+    gateway.refresh_with(mock.search_options()).unwrap();
+    assert_eq!(gateway.root_url, DESCRIPTION_PATH);
+    assert_eq!(gateway.control_url, CONTROL_PATH);
+
+    gateway.root_url = "/old.xml".to_string();
+    gateway.device_info.udn = "uuid:another".to_string();
+    let options = SearchOptions {
+        timeout: Some(Duration::from_millis(200)),
+        ..mock.search_options()
+    };
+    assert!(gateway.refresh_with(options).is_err());
+}
+
+#[test]
+fn test_device_config() {
+    let mock = MockGateway::start().unwrap();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    assert!(gateway.device_config_service().is_none());
+    assert!(matches!(
+        gateway.reboot().unwrap_err().inner(),
+        crate::RequestError::UnsupportedAction(_)
+    ));
+
+    mock.set_description(DEFAULT_DESCRIPTION.replacen(
+        "<serviceList>",
+        r#"<serviceList>
+                    <service>
+                        <serviceType>urn:dslforum-org:service:DeviceConfig:1</serviceType>
+                        <serviceId>urn:DeviceConfig-com:serviceId:DeviceConfig1</serviceId>
+                        <SCPDURL>/DevCfg.xml</SCPDURL>
+                        <controlURL>/ctl/DevCfg</controlURL>
+                        <eventSubURL>/evt/DevCfg</eventSubURL>
+                    </service>"#,
+        1,
+    ));
+    crate::clear_description_cache();
+    let gateway = crate::search_gateway(mock.search_options()).unwrap();
+    assert!(gateway.capabilities().device_config);
+    gateway
+        .configuration_started("uuid:00000000-0000-0000-0000-000000000002")
+        .unwrap();
+    assert!(gateway.configuration_finished().unwrap());
+    assert_eq!(gateway.get_persistent_data().unwrap(), "mock");
+    gateway.reboot().unwrap();
+    let requests = mock.requests();
+    let started = requests
+        .iter()
+        .find(|request| request.action == "ConfigurationStarted")
+        .unwrap();
+    assert_eq!(
+        started.argument("NewSessionID"),
+        Some("uuid:00000000-0000-0000-0000-000000000002")
+    );
+    assert!(requests.iter().any(|request| request.action == "Reboot"));
+}
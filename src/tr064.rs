@@ -0,0 +1,363 @@
+//! This module implements port mapping through the TR-064 interface of AVM FRITZ!Box routers.
+//!
+//! FRITZ!Box devices expose TR-064 on a separate port (49000) even when plain IGD is disabled.
+//! The port mapping actions are the same as in IGD, but the services live in the
+//! `urn:dslforum-org` namespace and most actions require HTTP digest authentication.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Instant;
+
+use attohttpc::{header, StatusCode};
+use rand::{self, Rng};
+
+use crate::common::{self, messages, parsing, parsing::RequestResult, parsing::StatusInfo};
+use crate::errors::{
+    self, AddPortError, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError, RequestContext,
+    RequestError, SearchError,
+};
+use crate::PortMappingProtocol;
+
+/// Default port of the TR-064 interface.
+pub const DEFAULT_PORT: u16 = 49000;
+
+const DESCRIPTION_PATH: &str = "/tr64desc.xml";
+
+const WAN_CONNECTION_SERVICES: &[&str] = &[
+    "urn:dslforum-org:service:WANIPConnection:1",
+    "urn:dslforum-org:service:WANPPPConnection:1",
+];
+
+/// This structure represents a TR-064 device, obtained with `connect`.
+#[derive(Clone)]
+pub struct Gateway {
+    /// Socket address of the TR-064 interface
+    pub addr: SocketAddrV4,
+    /// Service type of the WAN connection service in use
+    pub service_type: String,
+    /// Control url of the service
+    pub control_url: String,
+    /// Url to get schema data from
+    pub control_schema_url: String,
+    /// Control schema for all actions
+    pub control_schema: HashMap<String, Vec<String>>,
+    username: String,
+    password: String,
+}
+
+/// Connect to the TR-064 interface at `addr` with the given credentials.
+///
+/// The address is usually `192.168.178.1:49000`. Credentials are those of a FRITZ!Box user
+/// allowed to change settings; the user name may be empty on boxes without named users.
+///
+/// # Example
+/// ```no_run
+/// use igd::tr064;
+///
+/// fn main() -> igd::Result {
+///     let gateway = tr064::connect("192.168.178.1:49000".parse().unwrap(), "admin", "secret")?;
+///     let ip = gateway.get_external_ip()?;
+///     println!("External IP address: {}", ip);
+///     Ok(())
+/// }
+/// ```
+pub fn connect(addr: SocketAddrV4, username: &str, password: &str) -> Result<Gateway, SearchError> {
+    let url = format!("http://{}{}", addr, DESCRIPTION_PATH);
+    let response = attohttpc::get(&url).send()?;
+    let (control_schema_url, control_url, service_type) =
+        parsing::parse_service_urls(&response.bytes()?[..], WAN_CONNECTION_SERVICES)?;
+
+    let url = format!("http://{}{}", addr, control_schema_url);
+    let response = attohttpc::get(&url).send()?;
+    let control_schema = parsing::parse_schemas(&response.bytes()?[..])?;
+
+    Ok(Gateway {
+        addr,
+        service_type,
+        control_url,
+        control_schema_url,
+        control_schema,
+        username: username.into(),
+        password: password.into(),
+    })
+}
+
+impl Gateway {
+    fn perform_request(&self, action: &str, body: &str, ok: &str) -> RequestResult {
+        let url = format!("http://{}{}", self.addr, self.control_url);
+        let header = messages::format_action_header(&self.service_type, action);
+        let sent = Instant::now();
+        self.send_request(&url, &header, body, ok)
+            .map_err(|e| e.with_context(RequestContext::new(self.addr, &url, &header, sent.elapsed())))
+    }
+
+    fn send_request(&self, url: &str, header: &str, body: &str, ok: &str) -> RequestResult {
+        let mut response = self.post(url, header, body, None)?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(header::WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(DigestChallenge::parse)
+                .ok_or_else(|| RequestError::InvalidResponse("Missing digest authentication challenge".into()))?;
+            let authorization = challenge.authorization(&self.username, &self.password, "POST", &self.control_url);
+            response = self.post(url, header, body, Some(authorization))?;
+        }
+
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| errors::is_http_error_header(name.as_str()))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let result = parsing::parse_response_with_status(status, headers, response.text()?, ok);
+        common::log_response(url, header, status, &result);
+        result
+    }
+
+    fn post(
+        &self,
+        url: &str,
+        header: &str,
+        body: &str,
+        authorization: Option<String>,
+    ) -> Result<attohttpc::Response, RequestError> {
+        let mut request = attohttpc::post(url)
+            .header("SOAPAction", header)
+            .header("Content-Type", "text/xml");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        Ok(request.text(body).send()?)
+    }
+
+    fn schema(&self, action: &str) -> Result<&[String], RequestError> {
+        self.control_schema
+            .get(action)
+            .map(|schema| &schema[..])
+            .ok_or_else(|| RequestError::UnsupportedAction(action.to_string()))
+    }
+
+    /// Get the external IP address of the gateway.
+    pub fn get_external_ip(&self) -> Result<Ipv4Addr, GetExternalIpError> {
+        parsing::parse_get_external_ip_response(self.perform_request(
+            "GetExternalIPAddress",
+            &messages::format_get_external_ip_message(&self.service_type),
+            "GetExternalIPAddressResponse",
+        ))
+    }
+
+    /// Get the state of the WAN connection.
+    pub fn get_status_info(&self) -> Result<StatusInfo, RequestError> {
+        parsing::parse_get_status_info_response(self.perform_request(
+            "GetStatusInfo",
+            &messages::format_get_status_info_message(&self.service_type),
+            "GetStatusInfoResponse",
+        ))
+    }
+
+    /// Add a port mapping.
+    ///
+    /// The local_addr is the address where the traffic is sent to.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    pub fn add_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(), AddPortError> {
+        if external_port == 0 {
+            return Err(AddPortError::ExternalPortZeroInvalid);
+        }
+        if local_addr.port() == 0 {
+            return Err(AddPortError::InternalPortZeroInvalid);
+        }
+
+        let schema = self.schema("AddPortMapping").map_err(AddPortError::RequestError)?;
+        self.perform_request(
+            "AddPortMapping",
+            &messages::format_add_port_mapping_message(
+                &self.service_type,
+                schema,
+                protocol,
+                external_port,
+                local_addr,
+                lease_duration,
+                description,
+                true,
+            ),
+            "AddPortMappingResponse",
+        )
+        .map(|_| ())
+        .map_err(parsing::convert_add_port_error)
+    }
+
+    /// Remove a port mapping.
+    pub fn remove_port(&self, protocol: PortMappingProtocol, external_port: u16) -> Result<(), RemovePortError> {
+        let schema = self
+            .schema("DeletePortMapping")
+            .map_err(RemovePortError::RequestError)?;
+        parsing::parse_delete_port_mapping_response(self.perform_request(
+            "DeletePortMapping",
+            &messages::format_delete_port_message(&self.service_type, schema, protocol, external_port),
+            "DeletePortMappingResponse",
+        ))
+    }
+
+    /// Get one port mapping entry
+    ///
+    /// Gets one port mapping entry by its index.
+    /// If the index is out of bound, GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid will be returned
+    pub fn get_generic_port_mapping_entry(
+        &self,
+        index: u32,
+    ) -> Result<parsing::PortMappingEntry, GetGenericPortMappingEntryError> {
+        parsing::parse_get_generic_port_mapping_entry(self.perform_request(
+            "GetGenericPortMappingEntry",
+            &messages::formate_get_generic_port_mapping_entry_message(&self.service_type, index),
+            "GetGenericPortMappingEntryResponse",
+        ))
+    }
+}
+
+impl fmt::Debug for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep the credentials out of logs.
+        f.debug_struct("Gateway")
+            .field("addr", &self.addr)
+            .field("service_type", &self.service_type)
+            .field("control_url", &self.control_url)
+            .field("control_schema_url", &self.control_schema_url)
+            .field("control_schema", &self.control_schema)
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}{}", self.addr, self.control_url)
+    }
+}
+
+/// A `WWW-Authenticate: Digest` challenge (RFC 2617).
+#[derive(Debug, PartialEq)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    qop_auth: bool,
+}
+
+impl DigestChallenge {
+    fn parse(header: &str) -> Option<DigestChallenge> {
+        let header = header.trim();
+        if !header
+            .get(..7)
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case("digest "))
+        {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        let mut rest = header[7..].trim_start();
+        while !rest.is_empty() {
+            let eq = rest.find('=')?;
+            let key = rest[..eq].trim().to_ascii_lowercase();
+            rest = rest[eq + 1..].trim_start();
+            let value = if let Some(quoted) = rest.strip_prefix('"') {
+                let end = quoted.find('"')?;
+                rest = &quoted[end + 1..];
+                &quoted[..end]
+            } else {
+                let end = rest.find(',').unwrap_or(rest.len());
+                let value = rest[..end].trim();
+                rest = &rest[end..];
+                value
+            };
+            params.insert(key, value.to_string());
+            rest = rest.trim_start().trim_start_matches(',').trim_start();
+        }
+
+        Some(DigestChallenge {
+            realm: params.remove("realm")?,
+            nonce: params.remove("nonce")?,
+            opaque: params.remove("opaque"),
+            qop_auth: params
+                .get("qop")
+                .map(|qop| qop.split(',').any(|q| q.trim() == "auth"))
+                .unwrap_or(false),
+        })
+    }
+
+    fn authorization(&self, username: &str, password: &str, method: &str, uri: &str) -> String {
+        let cnonce = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        self.authorization_with_cnonce(username, password, method, uri, &cnonce)
+    }
+
+    fn authorization_with_cnonce(
+        &self,
+        username: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+        cnonce: &str,
+    ) -> String {
+        const NC: &str = "00000001";
+
+        let ha1 = md5::compute(format!("{}:{}:{}", username, self.realm, password));
+        let ha2 = md5::compute(format!("{}:{}", method, uri));
+        let mut authorization = if self.qop_auth {
+            let response = md5::compute(format!("{:x}:{}:{}:{}:auth:{:x}", ha1, self.nonce, NC, cnonce, ha2));
+            format!(
+                r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm=MD5, qop=auth, nc={}, cnonce="{}", response="{:x}""#,
+                username, self.realm, self.nonce, uri, NC, cnonce, response
+            )
+        } else {
+            let response = md5::compute(format!("{:x}:{}:{:x}", ha1, self.nonce, ha2));
+            format!(
+                r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm=MD5, response="{:x}""#,
+                username, self.realm, self.nonce, uri, response
+            )
+        };
+        if let Some(ref opaque) = self.opaque {
+            authorization.push_str(&format!(r#", opaque="{}""#, opaque));
+        }
+        authorization
+    }
+}
+
+#[test]
+fn test_parse_digest_challenge() {
+    let challenge =
+        DigestChallenge::parse(r#"Digest realm="F!Box SOAP-Auth", nonce="2E3C1A5B7D", algorithm=MD5, qop="auth""#)
+            .unwrap();
+    assert_eq!(challenge.realm, "F!Box SOAP-Auth");
+    assert_eq!(challenge.nonce, "2E3C1A5B7D");
+    assert_eq!(challenge.opaque, None);
+    assert!(challenge.qop_auth);
+
+    assert!(DigestChallenge::parse(r#"Basic realm="router""#).is_none());
+    // The header comes from the network, byte 7 may be inside a character.
+    assert!(DigestChallenge::parse(r#"Digést realm="router", nonce="1""#).is_none());
+    assert!(DigestChallenge::parse("Dig").is_none());
+}
+
+#[test]
+fn test_digest_authorization() {
+    // Example from RFC 2617, section 3.5.
+    let challenge = DigestChallenge {
+        realm: "testrealm@host.com".into(),
+        nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".into(),
+        opaque: Some("5ccc069c403ebaf9f0171e9517f40e41".into()),
+        qop_auth: true,
+    };
+    let authorization =
+        challenge.authorization_with_cnonce("Mufasa", "Circle Of Life", "GET", "/dir/index.html", "0a4f113b");
+    assert!(authorization.contains(r#"response="6629fae49393a05397450978507c4ef1""#));
+    assert!(authorization.ends_with(r#"opaque="5ccc069c403ebaf9f0171e9517f40e41""#));
+}
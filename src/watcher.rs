@@ -0,0 +1,153 @@
+use std::net::Ipv4Addr;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::Gateway;
+
+/// Number of consecutive polls a new address has to be returned by before it is reported.
+const CONFIRMATIONS: u32 = 2;
+
+/// Watches the external IP address of a gateway by polling it on a background thread.
+///
+/// This is meant for routers without working eventing, e.g. to update a dynamic DNS entry.
+/// The first address is reported as soon as it is known. A change is only reported once the
+/// gateway returned the new address twice in a row, and `0.0.0.0` is never reported, so the
+/// flapping while the WAN connection is re-established goes unnoticed.
+///
+/// The thread stops when the watcher is stopped or dropped.
+///
+/// # Example
+/// ```no_run
+/// use std::time::Duration;
+/// use igd::ExternalIpWatcher;
+///
+/// let gateway = igd::search_gateway(Default::default()).unwrap();
+/// let (_watcher, changes) = ExternalIpWatcher::spawn(gateway, Duration::from_secs(60));
+/// for ip in changes {
+///     println!("External IP address is now {}", ip);
+/// }
+/// ```
+pub struct ExternalIpWatcher {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ExternalIpWatcher {
+    /// Poll the gateway every `interval`, sending each new address to the returned channel.
+    pub fn spawn(gateway: Gateway, interval: Duration) -> (ExternalIpWatcher, mpsc::Receiver<Ipv4Addr>) {
+        let (sender, receiver) = mpsc::channel();
+        let watcher = ExternalIpWatcher::spawn_with_callback(gateway, interval, move |ip| {
+            let _ = sender.send(ip);
+        });
+        (watcher, receiver)
+    }
+
+    /// Poll the gateway every `interval`, calling `callback` with each new address.
+    pub fn spawn_with_callback<F>(gateway: Gateway, interval: Duration, mut callback: F) -> ExternalIpWatcher
+    where
+        F: FnMut(Ipv4Addr) + Send + 'static,
+    {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut debouncer = Debouncer::default();
+                loop {
+                    match gateway.get_external_ip() {
+                        Ok(ip) => {
+                            if let Some(ip) = debouncer.observe(ip) {
+                                callback(ip);
+                            }
+                        }
+                        Err(e) => debug!("polling the external IP address of {} failed: {}", gateway, e),
+                    }
+
+                    let (ref lock, ref condvar) = *stopped;
+                    let guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+                    let (guard, _) = condvar
+                        .wait_timeout_while(guard, interval, |stopped| !*stopped)
+                        .unwrap_or_else(|e| e.into_inner());
+                    if *guard {
+                        return;
+                    }
+                }
+            })
+        };
+        ExternalIpWatcher {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop polling and wait for the background thread to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let (ref lock, ref condvar) = *self.stopped;
+        *lock.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ExternalIpWatcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[derive(Default)]
+struct Debouncer {
+    current: Option<Ipv4Addr>,
+    candidate: Option<(Ipv4Addr, u32)>,
+}
+
+impl Debouncer {
+    /// Record a polled address, returning it if it has to be reported.
+    fn observe(&mut self, ip: Ipv4Addr) -> Option<Ipv4Addr> {
+        if ip.is_unspecified() || self.current == Some(ip) {
+            self.candidate = None;
+            return None;
+        }
+        if self.current.is_none() {
+            self.current = Some(ip);
+            return Some(ip);
+        }
+
+        let seen = match self.candidate {
+            Some((candidate, seen)) if candidate == ip => seen + 1,
+            _ => 1,
+        };
+        if seen >= CONFIRMATIONS {
+            self.current = Some(ip);
+            self.candidate = None;
+            Some(ip)
+        } else {
+            self.candidate = Some((ip, seen));
+            None
+        }
+    }
+}
+
+#[test]
+fn test_debouncer() {
+    let a = Ipv4Addr::new(203, 0, 113, 1);
+    let b = Ipv4Addr::new(198, 51, 100, 1);
+    let mut debouncer = Debouncer::default();
+    assert_eq!(debouncer.observe(Ipv4Addr::UNSPECIFIED), None);
+    assert_eq!(debouncer.observe(a), Some(a));
+    assert_eq!(debouncer.observe(a), None);
+
+    // A single different answer is not a change.
+    assert_eq!(debouncer.observe(b), None);
+    assert_eq!(debouncer.observe(a), None);
+
+    assert_eq!(debouncer.observe(b), None);
+    assert_eq!(debouncer.observe(b), Some(b));
+    assert_eq!(debouncer.observe(b), None);
+}